@@ -0,0 +1,307 @@
+//! Opt-in record of every completed generation, for deployments that must
+//! retain prompts and completions. Records are redacted, then handed to a
+//! writer thread through a bounded queue; when the queue is full they are
+//! dropped and counted rather than slowing the request down.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    config::AppConfig,
+    model::{EffectiveParams, GenerationResponse},
+};
+
+/// Records waiting for the writer before new ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub timestamp_ms: u64,
+    pub request_id: Option<String>,
+    pub model: String,
+    pub params: EffectiveParams,
+    pub prompt: String,
+    pub completion: String,
+    pub tokens_generated: usize,
+    pub latency_ms: u128,
+}
+
+/// Where audit records end up. Called on the writer thread, so it may block.
+pub trait AuditSink: Send + 'static {
+    fn write(&mut self, record: &AuditRecord) -> io::Result<()>;
+}
+
+/// Appends one JSON object per line.
+pub struct JsonlFileSink {
+    writer: BufWriter<File>,
+}
+
+impl JsonlFileSink {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl AuditSink for JsonlFileSink {
+    fn write(&mut self, record: &AuditRecord) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+/// Masks every match of the configured patterns.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(patterns: &[String]) -> Result<Self, regex::Error> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        self.patterns
+            .iter()
+            .fold(text.to_string(), |text, pattern| {
+                pattern.replace_all(&text, REDACTED).into_owned()
+            })
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    queue: mpsc::Sender<AuditRecord>,
+    redactor: Arc<Redactor>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    /// Opens `audit_log_path`, if set, and starts its writer.
+    pub fn from_config(config: &AppConfig) -> anyhow::Result<Option<Self>> {
+        let Some(path) = config.audit_log_path.as_deref() else {
+            return Ok(None);
+        };
+        let sink = JsonlFileSink::open(path)
+            .map_err(|e| anyhow::anyhow!("failed to open audit log {}: {e}", path.display()))?;
+        let redactor = Redactor::new(&config.audit_redact_patterns)?;
+        Ok(Some(Self::start(sink, redactor, QUEUE_CAPACITY)?))
+    }
+
+    /// Starts a writer thread draining into `sink`. It exits once every
+    /// clone of the returned log has been dropped.
+    pub fn start(
+        mut sink: impl AuditSink,
+        redactor: Redactor,
+        capacity: usize,
+    ) -> io::Result<Self> {
+        let (queue, mut records) = mpsc::channel::<AuditRecord>(capacity);
+        thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || {
+                while let Some(record) = records.blocking_recv() {
+                    if let Err(err) = sink.write(&record) {
+                        tracing::error!(error = %err, "failed to write audit record");
+                    }
+                }
+            })?;
+        Ok(Self {
+            queue,
+            redactor: Arc::new(redactor),
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Queues a redacted record of `response`; never waits.
+    pub fn record(&self, request_id: Option<&str>, response: &GenerationResponse) {
+        let record = AuditRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or(0),
+            request_id: request_id.map(str::to_owned),
+            model: response.model.name.clone(),
+            params: response.params,
+            prompt: self.redactor.redact(&response.raw_prompt),
+            completion: self.redactor.redact(&response.completion),
+            tokens_generated: response.tokens_generated,
+            latency_ms: response.total_time_ms,
+        };
+        match self.queue.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped == 1 || dropped.is_multiple_of(1000) {
+                    tracing::warn!(dropped, "audit queue full, records dropped");
+                }
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Records dropped because the writer could not keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        sync::mpsc as std_mpsc,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::model::{
+        Backend,
+        testing::{FakeModel, gpt2, greedy, next_token},
+    };
+
+    fn default_redactor() -> Redactor {
+        Redactor::new(&AppConfig::default().audit_redact_patterns).expect("default patterns")
+    }
+
+    fn response(prompt: &str) -> GenerationResponse {
+        let mut response = FakeModel::new("fake", next_token)
+            .generate(&gpt2(), "Hello", &greedy(1), None)
+            .expect("fake generation");
+        response.raw_prompt = prompt.to_string();
+        response
+    }
+
+    #[test]
+    fn default_patterns_mask_emails_and_phone_numbers() {
+        let redactor = default_redactor();
+        for (text, expected) in [
+            (
+                "mail jane.doe+work@mail.example.co.uk today",
+                "mail [REDACTED] today",
+            ),
+            ("call +1 (555) 123-4567 now", "call [REDACTED] now"),
+            ("or 555.123.4567", "or [REDACTED]"),
+            ("or 5551234567", "or [REDACTED]"),
+            ("a@b.io, c@d.org", "[REDACTED], [REDACTED]"),
+            // Too short for a phone number, no domain for an email.
+            ("order 12345 from @handle", "order 12345 from @handle"),
+        ] {
+            assert_eq!(redactor.redact(text), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn no_patterns_leave_text_alone() {
+        let text = "jane@example.com 555-123-4567";
+        assert_eq!(Redactor::default().redact(text), text);
+    }
+
+    #[test]
+    fn invalid_pattern_is_an_error() {
+        assert!(Redactor::new(&["(unclosed".to_string()]).is_err());
+    }
+
+    #[test]
+    fn file_sink_appends_redacted_json_lines() {
+        let path = env::temp_dir().join(format!("qls-audit-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = AppConfig {
+            audit_log_path: Some(path.clone()),
+            ..AppConfig::default()
+        };
+        let log = AuditLog::from_config(&config)
+            .unwrap()
+            .expect("audit enabled");
+        log.record(Some("req-1"), &response("reach me at jane@example.com"));
+        log.record(None, &response("second"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let lines = loop {
+            let written = fs::read_to_string(&path).unwrap_or_default();
+            if written.lines().count() == 2 || Instant::now() > deadline {
+                break written;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let records: Vec<serde_json::Value> = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2, "{lines}");
+        assert_eq!(records[0]["request_id"], "req-1");
+        assert_eq!(records[0]["prompt"], "reach me at [REDACTED]");
+        assert_eq!(records[0]["model"], "fake");
+        assert_eq!(records[1]["request_id"], serde_json::Value::Null);
+        let _ = fs::remove_file(&path);
+    }
+
+    /// Hands each record over only once the test releases it.
+    struct GatedSink {
+        entered: std_mpsc::Sender<()>,
+        release: std_mpsc::Receiver<()>,
+        written: std_mpsc::Sender<String>,
+    }
+
+    impl AuditSink for GatedSink {
+        fn write(&mut self, record: &AuditRecord) -> io::Result<()> {
+            let _ = self.entered.send(());
+            let _ = self.release.recv();
+            let _ = self.written.send(record.prompt.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_and_counts_instead_of_blocking() {
+        let (entered, entered_rx) = std_mpsc::channel();
+        let (release_tx, release) = std_mpsc::channel();
+        let (written, written_rx) = std_mpsc::channel();
+        let sink = GatedSink {
+            entered,
+            release,
+            written,
+        };
+        let log = AuditLog::start(sink, Redactor::default(), 1).unwrap();
+
+        log.record(None, &response("1"));
+        // The writer holds the first record, so the queue has one free slot.
+        entered_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let responses = ["2", "3", "4"].map(response);
+        let started = Instant::now();
+        for response in &responses {
+            log.record(None, response);
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(log.dropped(), 2);
+
+        for _ in 0..2 {
+            release_tx.send(()).unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+        assert_eq!(written_rx.recv_timeout(timeout).unwrap(), "1");
+        assert_eq!(written_rx.recv_timeout(timeout).unwrap(), "2");
+    }
+}
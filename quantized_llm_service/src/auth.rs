@@ -0,0 +1,271 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{config::AppConfig, error::ServiceError};
+
+/// Label of the API key that authenticated a request, stored in request
+/// extensions for downstream attribution. The secret itself never leaves
+/// this module.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ApiKeyLabel(pub String);
+
+#[derive(Debug, Clone)]
+struct ApiKey {
+    label: String,
+    secret: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+    admin_keys: Vec<ApiKey>,
+}
+
+impl ApiKeys {
+    /// Entries are `label:secret`; an entry without a colon is treated as a
+    /// bare secret and labelled by its position.
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            keys: parse_keys(&config.api_keys, "key"),
+            admin_keys: parse_keys(&config.admin_api_keys, "admin"),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty() || !self.admin_keys.is_empty()
+    }
+
+    fn find<'a>(keys: &'a [ApiKey], presented: &str) -> Option<&'a ApiKey> {
+        // Check every key so timing doesn't reveal which one matched.
+        let mut found = None;
+        for key in keys {
+            if constant_time_eq(key.secret.as_bytes(), presented.as_bytes()) {
+                found = Some(key);
+            }
+        }
+        found
+    }
+}
+
+fn parse_keys(entries: &[String], prefix: &str) -> Vec<ApiKey> {
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| !entry.trim().is_empty())
+        .map(|(idx, entry)| match entry.trim().split_once(':') {
+            Some((label, secret)) => ApiKey {
+                label: label.to_string(),
+                secret: secret.to_string(),
+            },
+            None => ApiKey {
+                label: format!("{prefix}-{idx}"),
+                secret: entry.trim().to_string(),
+            },
+        })
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= usize::from(x ^ y);
+    }
+    diff == 0
+}
+
+fn is_admin_route(path: &str) -> bool {
+    path.starts_with("/admin/")
+        || path.starts_with("/debug/")
+        || path == "/evaluate"
+        || path.starts_with("/evaluate/")
+}
+
+/// The playground page, API document and its viewer are static; the API
+/// calls they make carry the key.
+fn is_public_route(path: &str) -> bool {
+    path == "/health"
+        || path.starts_with("/health/")
+        || path == "/"
+        || path == "/playground"
+        || path == "/openapi.json"
+        || path == "/docs"
+        || path.starts_with("/docs/")
+}
+
+fn presented_key(request: &Request) -> Option<&str> {
+    let headers = request.headers();
+    if let Some(value) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(value.trim());
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Rejects requests without a valid key once any keys are configured.
+/// Admin routes require an admin key when admin keys are configured.
+pub async fn require_api_key(
+    State(keys): State<Arc<ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if !keys.is_enabled() || is_public_route(path) {
+        return next.run(request).await;
+    }
+
+    let admin_route = is_admin_route(path);
+    let required = if admin_route && !keys.admin_keys.is_empty() {
+        &keys.admin_keys
+    } else if keys.keys.is_empty() {
+        // Only admin keys are configured and this is a regular route.
+        return next.run(request).await;
+    } else {
+        &keys.keys
+    };
+
+    let Some(presented) = presented_key(&request) else {
+        return ServiceError::Unauthorized("missing API key".into()).into_response();
+    };
+
+    let Some(key) = ApiKeys::find(required, presented) else {
+        if admin_route && ApiKeys::find(&keys.keys, presented).is_some() {
+            return ServiceError::Forbidden("this route requires an admin API key".into())
+                .into_response();
+        }
+        return ServiceError::Unauthorized("invalid API key".into()).into_response();
+    };
+
+    tracing::Span::current().record("api_key", key.label.as_str());
+    let label = ApiKeyLabel(key.label.clone());
+    request.extensions_mut().insert(label.clone());
+    // Also on the response, for the access log outside this layer.
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(label);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{Reply, get, post_json, router, send};
+
+    fn with_keys(config: &mut AppConfig) {
+        config.api_keys = vec!["alice:user-secret".into()];
+        config.admin_api_keys = vec!["ops:admin-secret".into()];
+    }
+
+    async fn send_with(router: &Router, uri: &str, header: &str, value: &str) -> Reply {
+        let request = Request::get(uri)
+            .header(header, value)
+            .body(Body::empty())
+            .unwrap();
+        send(router, request).await
+    }
+
+    #[tokio::test]
+    async fn a_missing_key_is_a_structured_401() {
+        let router = router("auth-missing", with_keys);
+        let reply = send(&router, get("/metadata")).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            reply.json(),
+            json!({"error": {
+                "code": "unauthorized",
+                "message": "unauthorized: missing API key",
+                "request_id": reply.header("x-request-id").unwrap(),
+            }})
+        );
+        let reply = send(&router, post_json("/generate", json!({"prompt": "Hi"}))).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn a_wrong_key_is_a_401() {
+        let router = router("auth-wrong", with_keys);
+        for (header, value) in [
+            ("authorization", "Bearer user-secreT"),
+            ("authorization", "user-secret"),
+            ("x-api-key", "user-secret-and-more"),
+        ] {
+            let reply = send_with(&router, "/metadata", header, value).await;
+            assert_eq!(reply.status, StatusCode::UNAUTHORIZED, "{header}: {value}");
+            assert_eq!(reply.error_code(), "unauthorized");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_valid_key_passes_in_either_header() {
+        let router = router("auth-valid", with_keys);
+        let reply = send_with(&router, "/metadata", "authorization", "Bearer user-secret").await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        let reply = send_with(&router, "/metadata", "x-api-key", "user-secret").await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+    }
+
+    #[tokio::test]
+    async fn health_needs_no_key() {
+        let router = router("auth-health", with_keys);
+        for uri in ["/health", "/health/live", "/health/ready"] {
+            assert_eq!(
+                send(&router, get(uri)).await.status,
+                StatusCode::OK,
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_an_admin_key() {
+        let router = router("auth-admin", with_keys);
+        for uri in ["/admin/usage", "/evaluate/history"] {
+            let reply = send_with(&router, uri, "x-api-key", "user-secret").await;
+            assert_eq!(reply.status, StatusCode::FORBIDDEN, "{uri}");
+            assert_eq!(reply.error_code(), "forbidden");
+        }
+        let request = Request::post("/evaluate")
+            .header("authorization", "Bearer user-secret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&router, request).await.status, StatusCode::FORBIDDEN);
+
+        let reply = send_with(&router, "/admin/usage", "x-api-key", "admin-secret").await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+    }
+
+    #[tokio::test]
+    async fn without_admin_keys_any_key_reaches_admin_routes() {
+        let router = router("auth-no-admin", |config| {
+            config.api_keys = vec!["alice:user-secret".into()];
+        });
+        let reply = send_with(&router, "/admin/usage", "x-api-key", "user-secret").await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        let reply = send(&router, get("/admin/usage")).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn keys_compare_by_content_and_length() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret\0"));
+        assert!(!constant_time_eq(b"", b"s"));
+    }
+}
@@ -0,0 +1,37 @@
+//! Runs the benchmark against locally loaded models and exits non-zero when
+//! a threshold is violated; no server or ports involved.
+use std::{path::PathBuf, process::ExitCode, sync::Arc};
+
+use clap::Parser;
+
+use quantized_llm_service::{
+    AppConfig,
+    offline::{self, EvaluateArgs},
+    telemetry::init_tracing,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Offline benchmark for the quantized LLM service")]
+struct Cli {
+    /// TOML config file; environment variables override its values.
+    #[arg(long, env = "CONFIG_PATH")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    evaluate: EvaluateArgs,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+    let config = match cli.config.as_deref() {
+        Some(path) => AppConfig::load_with_file(path)?,
+        None => AppConfig::from_env()?,
+    };
+    let _telemetry = init_tracing(&config)?;
+    config.validate()?;
+    #[cfg(feature = "tch-backend")]
+    quantized_llm_service::model::tch_backend::configure_threads(&config);
+
+    offline::evaluate(Arc::new(config), cli.evaluate).await
+}
@@ -0,0 +1,247 @@
+//! Typed HTTP client for the REST API; enabled by the `client` feature.
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, de::DeserializeOwned};
+use thiserror::Error;
+
+use crate::{
+    evaluation::{EvaluationMode, EvaluationReport},
+    model::{
+        GenerationRequest, GenerationResponse, ModelMetadata, ReadinessReport, TokenizerMetadata,
+    },
+};
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Transport(#[from] reqwest::Error),
+    /// The service answered with its structured error body.
+    #[error("{status} {code}: {message}")]
+    Api {
+        status: u16,
+        code: String,
+        message: String,
+        details: Option<serde_json::Value>,
+        request_id: Option<String>,
+    },
+    /// A failure response without the structured body, e.g. from a proxy.
+    #[error("unexpected {status} response: {body}")]
+    Unexpected { status: u16, body: String },
+}
+
+/// The parts of `/metadata` describing the loaded models.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Metadata {
+    pub quantized: Option<ModelMetadata>,
+    pub baseline: Option<ModelMetadata>,
+    pub tokenizer_sha256: String,
+    /// Absent from servers that predate it.
+    #[serde(default)]
+    pub tokenizer: Option<TokenizerMetadata>,
+    pub evaluation: Option<EvaluationReport>,
+    pub cuda_oom_events: u64,
+    pub generation_timeouts: u64,
+    pub audit_records_dropped: u64,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorPayload,
+}
+
+#[derive(Deserialize)]
+struct ErrorPayload {
+    code: String,
+    message: String,
+    details: Option<serde_json::Value>,
+    request_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl ApiClient {
+    /// `base_url` is the server root, e.g. `http://localhost:8080`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+        }
+    }
+
+    /// Sent as a bearer token on every request.
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub async fn generate(
+        &self,
+        request: &GenerationRequest,
+    ) -> Result<GenerationResponse, ClientError> {
+        self.send(self.request(Method::POST, "/generate").json(request))
+            .await
+    }
+
+    pub async fn generate_baseline(
+        &self,
+        request: &GenerationRequest,
+    ) -> Result<GenerationResponse, ClientError> {
+        self.send(
+            self.request(Method::POST, "/generate/baseline")
+                .json(request),
+        )
+        .await
+    }
+
+    pub async fn metadata(&self) -> Result<Metadata, ClientError> {
+        self.send(self.request(Method::GET, "/metadata")).await
+    }
+
+    /// Runs the server's configured benchmark; needs an admin key when
+    /// admin keys are configured.
+    pub async fn evaluate(&self, mode: EvaluationMode) -> Result<EvaluationReport, ClientError> {
+        self.send(
+            self.request(Method::POST, "/evaluate")
+                .query(&[("mode", mode)]),
+        )
+        .await
+    }
+
+    /// Readiness, including while the server reports itself not ready.
+    pub async fn health(&self) -> Result<ReadinessReport, ClientError> {
+        let response = self.request(Method::GET, "/health/ready").send().await?;
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            return Ok(response.json().await?);
+        }
+        parse(response).await
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let builder = self
+            .http
+            .request(method, format!("{}{path}", self.base_url));
+        match &self.api_key {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, builder: RequestBuilder) -> Result<T, ClientError> {
+        parse(builder.send().await?).await
+    }
+}
+
+async fn parse<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response.json().await?);
+    }
+    let body = response.text().await?;
+    Err(match serde_json::from_str::<ErrorBody>(&body) {
+        Ok(ErrorBody { error }) => ClientError::Api {
+            status: status.as_u16(),
+            code: error.code,
+            message: error.message,
+            details: error.details,
+            request_id: error.request_id,
+        },
+        Err(_) => ClientError::Unexpected {
+            status: status.as_u16(),
+            body,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::testing::router;
+
+    /// Serves `router` on a free local port for the rest of the test.
+    async fn serve(router: Router) -> ApiClient {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        ApiClient::new(format!("http://{addr}/"))
+    }
+
+    #[tokio::test]
+    async fn drives_the_real_router() {
+        let client = serve(router("client", |config| {
+            config.eval_warmup_iters = 0;
+            config.eval_benchmark_iters = 1;
+            config.max_new_tokens = 4;
+        }))
+        .await;
+
+        let request = GenerationRequest::new("Hello")
+            .with_max_new_tokens(3)
+            .with_temperature(0.0);
+        let quantized = client.generate(&request).await.unwrap();
+        assert_eq!(quantized.tokens_generated, 3);
+        assert!(
+            quantized.model.name.contains("quantized"),
+            "{}",
+            quantized.model.name
+        );
+        let baseline = client.generate_baseline(&request).await.unwrap();
+        assert_eq!(baseline.completion, quantized.completion);
+        assert!(
+            baseline.model.name.contains("baseline"),
+            "{}",
+            baseline.model.name
+        );
+
+        let metadata = client.metadata().await.unwrap();
+        assert!(metadata.quantized.is_some() && metadata.baseline.is_some());
+        assert!(metadata.tokenizer.is_some());
+
+        let report = client.evaluate(EvaluationMode::Assert).await.unwrap();
+        assert!(!report.samples.is_empty());
+        assert_eq!(report.aggregate.failed_samples, 0);
+
+        assert!(client.health().await.unwrap().ready);
+    }
+
+    #[tokio::test]
+    async fn error_bodies_become_api_errors() {
+        let client = serve(router("client-errors", |config| {
+            config.api_keys = vec!["alice:user-secret".into()];
+        }))
+        .await;
+        let err = client
+            .generate(&GenerationRequest::new("Hello"))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, ClientError::Api { status: 401, code, .. } if code == "unauthorized"),
+            "{err}"
+        );
+
+        let client = client.with_api_key("user-secret");
+        let err = client
+            .generate(&GenerationRequest::new(""))
+            .await
+            .unwrap_err();
+        let ClientError::Api {
+            status,
+            details,
+            request_id,
+            ..
+        } = &err
+        else {
+            panic!("{err}");
+        };
+        assert_eq!(*status, 400);
+        assert_eq!(details.as_ref().unwrap()["field"], "prompt");
+        assert!(request_id.is_some());
+    }
+}
@@ -1,128 +1,1221 @@
 use std::{
-    env,
+    collections::BTreeMap,
+    env, fs,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
     time::Duration,
 };
 
+use anyhow::Context;
+use axum::http::HeaderValue;
+use serde::{Deserialize, Deserializer};
 #[cfg(feature = "tch-backend")]
 use tch::Device;
 
-#[derive(Debug, Clone)]
+use crate::{
+    model::{BackendKind, ModelDtype, ModelKind, ModelSlot, TruncationSide, check_aliases},
+    presets::{GenerationPreset, check_presets},
+    telemetry::{LogFormat, LogPrompts},
+    templates::check_templates,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct AppConfig {
     pub listen_addr: SocketAddr,
+    /// PEM certificate chain and private key; with both set the REST server
+    /// speaks HTTPS. Requires the `tls` feature.
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    /// Re-read the certificate and key this often; 0 loads them once.
+    pub tls_reload_secs: u64,
+    /// Serves the gRPC API here as well; requires the `grpc` feature.
+    pub grpc_addr: Option<SocketAddr>,
+    /// Inference engine for both models; the module paths must be in its
+    /// format (TorchScript for tch, safetensors for candle).
+    pub backend: BackendKind,
     pub model_id: String,
     pub revision: Option<String>,
     pub baseline_module_path: PathBuf,
     pub quantized_module_path: PathBuf,
+    /// Serves the quantized model from this ONNX file on ONNX Runtime
+    /// instead of `quantized_module_path`; requires the `ort-backend` feature.
+    pub quantized_onnx_path: Option<PathBuf>,
+    /// Architecture of each module; `seq2seq` needs the tch backend.
+    pub baseline_model_kind: ModelKind,
+    pub quantized_model_kind: ModelKind,
+    /// First decoder input of seq2seq models (T5 starts from its pad id).
+    pub decoder_start_token_id: i64,
+    /// Token that ends generation; defaults to GPT-2's `<|endoftext|>` for
+    /// causal models and T5's `</s>` for seq2seq ones.
+    pub eos_token_id: Option<i64>,
     pub tokenizer_path: PathBuf,
+    /// Truncate prompts to this many tokens in the tokenizer, replacing any
+    /// truncation tokenizer.json configures; unset turns it off and leaves
+    /// overlong prompts to `context_strategy`.
+    pub tokenizer_max_length: Option<usize>,
+    /// Which end of the prompt `tokenizer_max_length` cuts.
+    pub tokenizer_truncation_side: TruncationSide,
+    /// Keep the padding tokenizer.json configures. Prompts are encoded one
+    /// at a time, so padding only adds tokens the model then attends to.
+    pub tokenizer_padding: bool,
+    /// Expected hex SHA-256 digests; a mismatching file is refused at load.
+    pub baseline_module_sha256: Option<String>,
+    pub quantized_module_sha256: Option<String>,
+    pub tokenizer_sha256: Option<String>,
+    /// Fetch missing artifacts for `model_id`/`revision` from the Hugging
+    /// Face Hub instead of failing at startup.
+    pub auto_download: bool,
+    /// Where downloaded artifacts are cached; defaults to the Hub cache.
+    pub model_cache_dir: Option<PathBuf>,
     pub max_new_tokens: usize,
+    /// Prompt plus generated tokens the models can attend over.
+    pub max_context_tokens: usize,
+    /// Leading prompt tokens kept in place when a sliding context window
+    /// moves, e.g. a system instruction.
+    pub context_sentinel_tokens: usize,
     pub temperature: f64,
     pub top_k: usize,
+    /// Completed deterministic responses kept in memory; 0 disables caching.
+    pub response_cache_size: usize,
+    /// Responses kept for replay to requests retried with the same
+    /// `Idempotency-Key`; 0 ignores the header.
+    pub idempotency_cache_size: usize,
+    /// How long a response stays available for replay.
+    #[serde(rename = "idempotency_ttl_secs", deserialize_with = "deserialize_secs")]
+    pub idempotency_ttl: Duration,
+    /// Report each generation's peak resident memory growth in its response.
+    pub measure_memory: bool,
+    /// Longest a single generation may run before it is abandoned with a
+    /// 504; 0 means no limit.
+    #[serde(
+        rename = "generation_timeout_secs",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub generation_timeout: Duration,
+    /// How long a stream may go without output before a heartbeat is sent
+    /// to keep proxies from closing it; 0 disables heartbeats.
+    #[serde(
+        rename = "stream_keepalive_secs",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub stream_keepalive: Duration,
+    /// Longest a generation waits for its model behind other requests
+    /// before failing with 503; 0 waits indefinitely.
+    pub max_lock_wait_ms: u64,
+    /// Most generations run together in one batch; 1 runs each on its own.
+    pub max_batch_size: usize,
+    /// How long the first generation of a batch waits for others to join.
+    pub batch_wait_ms: u64,
+    /// Short generations run against each model before serving; 0 skips warmup.
+    pub warmup_iters: usize,
+    /// Runs a short fixed-prompt generation on each model after loading and
+    /// records the outcome in its metadata.
+    pub self_test: bool,
+    /// Refuse to start when a self-test fails, instead of reporting the
+    /// service not ready.
+    pub self_test_strict: bool,
+    /// Most tokens `/debug/compare` generates per model; it runs both
+    /// models one after the other.
+    pub compare_max_new_tokens: usize,
+    /// Reload the models when the module or tokenizer files change.
+    pub watch_models: bool,
+    /// How long the watched files must stay unchanged before reloading.
+    pub watch_settle_ms: u64,
+    /// Share of `/generate` traffic, 0–100, routed to the quantized model
+    /// when both are loaded; adjustable at runtime via `/admin/canary`.
+    pub canary_quantized_percent: f64,
+    /// Serve `/generate` from the baseline when the quantized model is not
+    /// loaded; when off such requests fail with 503 instead.
+    pub fallback_to_baseline: bool,
+    /// Fraction of quantized `/generate` responses re-run on the baseline in
+    /// the background to check they agree; 0 disables shadowing.
+    pub shadow_sample_rate: f64,
+    /// How long a batch generation can wait before it is let onto a model
+    /// ahead of interactive ones.
+    #[serde(
+        rename = "batch_promote_after_secs",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub batch_promote_after: Duration,
+    /// Recent requests per model that `/stats` averages are taken over.
+    pub stats_window: usize,
+    /// Most texts accepted by a single `/embed` request.
+    pub embed_max_batch: usize,
+    /// Named prompt wrappers a request selects with `template`, each with a
+    /// `{prompt}` and optionally a `{system}` placeholder.
+    pub prompt_templates: BTreeMap<String, String>,
+    /// TOML file of further `name = "template"` entries, read at startup;
+    /// `prompt_templates` wins where both define a name.
+    pub prompt_templates_path: Option<PathBuf>,
+    /// Named generation settings a request selects with `preset`.
+    pub presets: BTreeMap<String, GenerationPreset>,
+    /// Names clients may send as a request's `model`, each pointing at
+    /// `baseline`, `quantized` or another alias. Replaceable at runtime
+    /// through `PUT /admin/aliases`.
+    pub aliases: BTreeMap<String, String>,
+    /// Model or alias used when a request names none; unset leaves
+    /// `/generate` to the canary split.
+    pub default_model: Option<String>,
     pub eval_prompts_path: Option<PathBuf>,
     pub eval_reference_path: Option<PathBuf>,
+    /// SQLite file for evaluation history and hourly request metrics;
+    /// unset keeps everything in memory.
+    pub database_path: Option<PathBuf>,
     pub eval_warmup_iters: usize,
     pub eval_benchmark_iters: usize,
+    pub eval_concurrency: usize,
+    /// Inclusive upper prompt-token bounds splitting evaluation latency into
+    /// buckets; the last bucket takes everything longer.
+    pub eval_length_buckets: Vec<usize>,
+    #[serde(rename = "eval_timeout_secs", deserialize_with = "deserialize_secs")]
     pub eval_timeout: Duration,
     #[cfg(feature = "tch-backend")]
-    pub device: Device,
+    #[serde(deserialize_with = "deserialize_device")]
+    pub baseline_device: Device,
+    /// Precision the baseline is converted to after loading; half precision
+    /// needs the tch backend, and float16 a CUDA device.
+    pub baseline_dtype: ModelDtype,
+    #[cfg(feature = "tch-backend")]
+    #[serde(deserialize_with = "deserialize_device")]
+    pub quantized_device: Device,
+    /// Load one copy of the baseline per device and spread requests across
+    /// them; when set, `baseline_device` is ignored.
+    #[cfg(feature = "tch-backend")]
+    #[serde(deserialize_with = "deserialize_devices")]
+    pub baseline_devices: Vec<Device>,
+    #[cfg(feature = "tch-backend")]
+    #[serde(deserialize_with = "deserialize_devices")]
+    pub quantized_devices: Vec<Device>,
+    /// Refuse to start when any replica fails to load, instead of serving
+    /// from the rest.
+    pub strict_devices: bool,
+    /// Run on CPU with a warning instead of refusing to start when a
+    /// requested CUDA device is unavailable or loading on it fails.
+    pub allow_device_fallback: bool,
+    /// LibTorch intra-op thread pool size; unset keeps LibTorch's default.
+    pub torch_num_threads: Option<usize>,
+    pub torch_num_interop_threads: Option<usize>,
+    /// ONNX Runtime intra-op thread count for `quantized_onnx_path`; unset
+    /// keeps ONNX Runtime's default.
+    pub ort_intra_threads: Option<usize>,
+    /// `label:secret` entries; when non-empty every route but `/health`
+    /// requires one of these keys.
+    pub api_keys: Vec<String>,
+    /// `label:secret` entries required for `/admin/*` and `/evaluate`.
+    pub admin_api_keys: Vec<String>,
+    /// Sustained requests per second allowed per client; 0 disables limiting.
+    pub rate_limit_rps: f64,
+    pub rate_limit_burst: u32,
+    /// Tokens, prompt and completion together, each API key may use until
+    /// its usage is reset; unset means unlimited.
+    pub token_quota: Option<u64>,
+    /// Quotas for particular key labels, `anonymous` included, in place of
+    /// `token_quota`.
+    pub token_quotas: BTreeMap<String, u64>,
+    /// `Retry-After` sent with 503s (model loading, overload, device memory
+    /// exhaustion) that don't carry a more specific hint.
+    pub retry_after_secs: u64,
+    /// Serve the browser playground at `/`; unset means on only for
+    /// loopback binds.
+    pub playground_enabled: Option<bool>,
+    /// Serve Swagger UI for `/openapi.json` at `/docs`.
+    pub swagger_ui_enabled: bool,
+    /// Exact origins (`https://app.example.com`) or `*` allowed to call the
+    /// API from a browser; empty sends no CORS headers.
+    pub cors_allowed_origins: Vec<String>,
+    /// How long browsers may cache a preflight response.
+    #[serde(rename = "cors_max_age_secs", deserialize_with = "deserialize_secs")]
+    pub cors_max_age: Duration,
+    /// Largest request body accepted; bigger ones get a 413 before they are
+    /// buffered.
+    pub max_request_bytes: usize,
+    pub log_format: LogFormat,
+    /// Generations slower than this are logged and kept for
+    /// `/admin/slow-requests`; 0 disables.
+    pub slow_request_ms: u64,
+    /// Whether prompt and completion text may appear in logs: in debug
+    /// events per generation and in slow-request logs, which otherwise
+    /// carry only a salted fingerprint.
+    pub log_prompts: LogPrompts,
+    /// Characters kept of each text when `log_prompts` is `truncated`.
+    pub log_prompts_max_chars: usize,
+    /// Salt for prompt fingerprints, so they stay comparable across
+    /// restarts; a random one is used when unset.
+    pub prompt_fingerprint_salt: Option<String>,
+    /// JSONL file every completed generation is appended to, prompt and
+    /// completion included; unset disables auditing.
+    pub audit_log_path: Option<PathBuf>,
+    /// Regexes whose matches are masked in audit records.
+    pub audit_redact_patterns: Vec<String>,
+    /// Emit one `access` log line per HTTP request.
+    pub access_log: bool,
+    /// Fraction of requests the access log records; server errors are
+    /// always logged.
+    pub access_log_sample_rate: f64,
+    /// OTLP gRPC collector spans are exported to; requires the `otel` feature.
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_reload_secs: 0,
+            grpc_addr: None,
+            backend: BackendKind::default(),
+            model_id: "distilgpt2".to_string(),
+            revision: None,
+            baseline_module_path: PathBuf::from("models/distilgpt2_baseline.ts"),
+            quantized_module_path: PathBuf::from("models/distilgpt2_quantized.ts"),
+            quantized_onnx_path: None,
+            baseline_model_kind: ModelKind::Causal,
+            quantized_model_kind: ModelKind::Causal,
+            decoder_start_token_id: 0,
+            eos_token_id: None,
+            tokenizer_path: PathBuf::from("models/tokenizer.json"),
+            tokenizer_max_length: None,
+            tokenizer_truncation_side: TruncationSide::Right,
+            tokenizer_padding: false,
+            baseline_module_sha256: None,
+            quantized_module_sha256: None,
+            tokenizer_sha256: None,
+            auto_download: false,
+            model_cache_dir: None,
+            max_new_tokens: 64,
+            max_context_tokens: 1024,
+            context_sentinel_tokens: 0,
+            temperature: 0.8,
+            top_k: 40,
+            response_cache_size: 0,
+            idempotency_cache_size: 1024,
+            idempotency_ttl: Duration::from_secs(24 * 60 * 60),
+            measure_memory: false,
+            generation_timeout: Duration::ZERO,
+            stream_keepalive: Duration::from_secs(15),
+            max_lock_wait_ms: 0,
+            max_batch_size: 1,
+            batch_wait_ms: 5,
+            warmup_iters: 2,
+            self_test: true,
+            self_test_strict: false,
+            compare_max_new_tokens: 64,
+            watch_models: false,
+            watch_settle_ms: 2000,
+            canary_quantized_percent: 100.0,
+            fallback_to_baseline: true,
+            shadow_sample_rate: 0.0,
+            batch_promote_after: Duration::from_secs(30),
+            stats_window: 100,
+            embed_max_batch: 32,
+            prompt_templates: BTreeMap::new(),
+            prompt_templates_path: None,
+            presets: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+            default_model: None,
+            eval_prompts_path: None,
+            eval_reference_path: None,
+            database_path: None,
+            eval_warmup_iters: 3,
+            eval_benchmark_iters: 10,
+            eval_concurrency: 1,
+            eval_length_buckets: vec![32, 128, 512],
+            eval_timeout: Duration::from_secs(30),
+            #[cfg(feature = "tch-backend")]
+            baseline_device: Device::Cpu,
+            baseline_dtype: ModelDtype::Float32,
+            #[cfg(feature = "tch-backend")]
+            quantized_device: Device::Cpu,
+            #[cfg(feature = "tch-backend")]
+            baseline_devices: Vec::new(),
+            #[cfg(feature = "tch-backend")]
+            quantized_devices: Vec::new(),
+            strict_devices: false,
+            allow_device_fallback: false,
+            torch_num_threads: None,
+            torch_num_interop_threads: None,
+            ort_intra_threads: None,
+            api_keys: Vec::new(),
+            admin_api_keys: Vec::new(),
+            rate_limit_rps: 0.0,
+            rate_limit_burst: 10,
+            token_quota: None,
+            token_quotas: BTreeMap::new(),
+            retry_after_secs: 5,
+            playground_enabled: None,
+            swagger_ui_enabled: false,
+            cors_allowed_origins: Vec::new(),
+            cors_max_age: Duration::from_secs(600),
+            max_request_bytes: 1024 * 1024,
+            log_format: LogFormat::default(),
+            slow_request_ms: 0,
+            log_prompts: LogPrompts::Off,
+            log_prompts_max_chars: 200,
+            prompt_fingerprint_salt: None,
+            audit_log_path: None,
+            audit_redact_patterns: vec![
+                // Email addresses.
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}".to_string(),
+                // Phone numbers like +1 (555) 123-4567 or 555.123.4567.
+                r"(?:\+\d{1,3}[\s.-]?)?\(?\d{3}\)?[\s.-]?\d{3}[\s.-]?\d{4}".to_string(),
+            ],
+            access_log: false,
+            access_log_sample_rate: 1.0,
+            otlp_endpoint: None,
+        }
+    }
 }
 
 impl AppConfig {
+    /// Loads `CONFIG_PATH` when set, otherwise starts from the built-in
+    /// defaults; environment variables are applied on top in both cases.
+    pub fn load() -> anyhow::Result<Self> {
+        match env::var("CONFIG_PATH") {
+            Ok(path) => Self::load_with_file(Path::new(&path)),
+            Err(_) => Self::from_env(),
+        }
+    }
+
+    pub fn load_with_file(path: &Path) -> anyhow::Result<Self> {
+        let mut config = Self::from_file(path)?;
+        config.apply_env_overrides()?;
+        config.load_prompt_templates()?;
+        Ok(config)
+    }
+
     pub fn from_env() -> anyhow::Result<Self> {
-        let listen_addr = env::var("SERVER_ADDR")
-            .unwrap_or_else(|_| "127.0.0.1:8080".into())
+        let mut config = Self::default();
+        config.apply_env_overrides()?;
+        config.load_prompt_templates()?;
+        Ok(config)
+    }
+
+    /// Merges the templates in `prompt_templates_path`, if set, under the
+    /// ones configured inline.
+    pub fn load_prompt_templates(&mut self) -> anyhow::Result<()> {
+        let Some(path) = self.prompt_templates_path.as_ref() else {
+            return Ok(());
+        };
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed to read prompt templates {}", path.display()))?;
+        let templates: BTreeMap<String, String> = toml::from_str(&raw)
+            .with_context(|| format!("invalid prompt templates file {}", path.display()))?;
+        for (name, template) in templates {
+            self.prompt_templates.entry(name).or_insert(template);
+        }
+        Ok(())
+    }
+
+    /// Parses a TOML config file. Keys that are absent keep their defaults,
+    /// unknown keys are rejected, and relative paths are resolved against the
+    /// directory containing the file.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let table: toml::Table = raw
             .parse()
-            .unwrap_or_else(|_| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080));
+            .with_context(|| format!("invalid TOML in {}", path.display()))?;
+        let mut config: AppConfig = toml::Value::Table(table.clone())
+            .try_into()
+            .with_context(|| format!("invalid config file {}", path.display()))?;
 
-        let model_id = env::var("MODEL_ID").unwrap_or_else(|_| "distilgpt2".to_string());
-        let revision = env::var("MODEL_REVISION").ok();
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let resolve = |key: &str, value: &mut PathBuf| {
+            if table.contains_key(key) && value.is_relative() {
+                *value = base_dir.join(&*value);
+            }
+        };
+        if let Some(path) = config.tls_cert_path.as_mut() {
+            resolve("tls_cert_path", path);
+        }
+        if let Some(path) = config.tls_key_path.as_mut() {
+            resolve("tls_key_path", path);
+        }
+        resolve("baseline_module_path", &mut config.baseline_module_path);
+        resolve("quantized_module_path", &mut config.quantized_module_path);
+        resolve("tokenizer_path", &mut config.tokenizer_path);
+        if let Some(path) = config.quantized_onnx_path.as_mut() {
+            resolve("quantized_onnx_path", path);
+        }
+        if let Some(path) = config.model_cache_dir.as_mut() {
+            resolve("model_cache_dir", path);
+        }
+        if let Some(path) = config.prompt_templates_path.as_mut() {
+            resolve("prompt_templates_path", path);
+        }
+        if let Some(path) = config.eval_prompts_path.as_mut() {
+            resolve("eval_prompts_path", path);
+        }
+        if let Some(path) = config.eval_reference_path.as_mut() {
+            resolve("eval_reference_path", path);
+        }
+        if let Some(path) = config.database_path.as_mut() {
+            resolve("database_path", path);
+        }
+        if let Some(path) = config.audit_log_path.as_mut() {
+            resolve("audit_log_path", path);
+        }
 
-        let baseline_module_path = PathBuf::from(
-            env::var("BASELINE_MODULE_PATH")
-                .unwrap_or_else(|_| "models/distilgpt2_baseline.ts".to_string()),
-        );
-        let quantized_module_path = PathBuf::from(
-            env::var("QUANTIZED_MODULE_PATH")
-                .unwrap_or_else(|_| "models/distilgpt2_quantized.ts".to_string()),
-        );
-        let tokenizer_path = PathBuf::from(
-            env::var("TOKENIZER_PATH").unwrap_or_else(|_| "models/tokenizer.json".to_string()),
-        );
+        Ok(config)
+    }
+
+    /// Absent variables leave the current value alone; a variable that is set
+    /// but cannot be parsed is an error rather than a silent fallback.
+    pub fn apply_env_overrides(&mut self) -> anyhow::Result<()> {
+        override_from_env("SERVER_ADDR", &mut self.listen_addr)?;
+        override_option_from_env("GRPC_ADDR", &mut self.grpc_addr)?;
+        if let Ok(path) = env::var("TLS_CERT_PATH") {
+            self.tls_cert_path = Some(PathBuf::from(path));
+        }
+        if let Ok(path) = env::var("TLS_KEY_PATH") {
+            self.tls_key_path = Some(PathBuf::from(path));
+        }
+        override_from_env("TLS_RELOAD_SECS", &mut self.tls_reload_secs)?;
+        override_from_env("BACKEND", &mut self.backend)?;
+
+        if let Ok(model_id) = env::var("MODEL_ID") {
+            self.model_id = model_id;
+        }
+        if let Ok(revision) = env::var("MODEL_REVISION") {
+            self.revision = Some(revision);
+        }
+
+        if let Ok(path) = env::var("BASELINE_MODULE_PATH") {
+            self.baseline_module_path = PathBuf::from(path);
+        }
+        if let Ok(path) = env::var("QUANTIZED_MODULE_PATH") {
+            self.quantized_module_path = PathBuf::from(path);
+        }
+        if let Ok(path) = env::var("QUANTIZED_ONNX_PATH") {
+            self.quantized_onnx_path = Some(PathBuf::from(path));
+        }
+        override_from_env("BASELINE_MODEL_KIND", &mut self.baseline_model_kind)?;
+        override_from_env("QUANTIZED_MODEL_KIND", &mut self.quantized_model_kind)?;
+        override_from_env("DECODER_START_TOKEN_ID", &mut self.decoder_start_token_id)?;
+        override_option_from_env("EOS_TOKEN_ID", &mut self.eos_token_id)?;
+        if let Ok(path) = env::var("TOKENIZER_PATH") {
+            self.tokenizer_path = PathBuf::from(path);
+        }
+        override_option_from_env("TOKENIZER_MAX_LENGTH", &mut self.tokenizer_max_length)?;
+        override_from_env(
+            "TOKENIZER_TRUNCATION_SIDE",
+            &mut self.tokenizer_truncation_side,
+        )?;
+        override_from_env("TOKENIZER_PADDING", &mut self.tokenizer_padding)?;
+        override_option_from_env("BASELINE_MODULE_SHA256", &mut self.baseline_module_sha256)?;
+        override_option_from_env("QUANTIZED_MODULE_SHA256", &mut self.quantized_module_sha256)?;
+        override_option_from_env("TOKENIZER_SHA256", &mut self.tokenizer_sha256)?;
+        override_from_env("AUTO_DOWNLOAD", &mut self.auto_download)?;
+        if let Ok(path) = env::var("MODEL_CACHE_DIR") {
+            self.model_cache_dir = Some(PathBuf::from(path));
+        }
+
+        override_from_env("MAX_NEW_TOKENS", &mut self.max_new_tokens)?;
+        override_from_env("MAX_CONTEXT_TOKENS", &mut self.max_context_tokens)?;
+        override_from_env("CONTEXT_SENTINEL_TOKENS", &mut self.context_sentinel_tokens)?;
+        override_from_env("TEMPERATURE", &mut self.temperature)?;
+        override_from_env("TOP_K", &mut self.top_k)?;
+        override_from_env("RESPONSE_CACHE_SIZE", &mut self.response_cache_size)?;
+        override_from_env("IDEMPOTENCY_CACHE_SIZE", &mut self.idempotency_cache_size)?;
+        let mut idempotency_ttl_secs = self.idempotency_ttl.as_secs();
+        override_from_env("IDEMPOTENCY_TTL_SECS", &mut idempotency_ttl_secs)?;
+        self.idempotency_ttl = Duration::from_secs(idempotency_ttl_secs);
+        override_from_env("MEASURE_MEMORY", &mut self.measure_memory)?;
+        let mut generation_timeout_secs = self.generation_timeout.as_secs();
+        override_from_env("GENERATION_TIMEOUT_SECS", &mut generation_timeout_secs)?;
+        self.generation_timeout = Duration::from_secs(generation_timeout_secs);
+        let mut stream_keepalive_secs = self.stream_keepalive.as_secs();
+        override_from_env("STREAM_KEEPALIVE_SECS", &mut stream_keepalive_secs)?;
+        self.stream_keepalive = Duration::from_secs(stream_keepalive_secs);
+        override_from_env("MAX_LOCK_WAIT_MS", &mut self.max_lock_wait_ms)?;
+        override_from_env("MAX_BATCH_SIZE", &mut self.max_batch_size)?;
+        override_from_env("BATCH_WAIT_MS", &mut self.batch_wait_ms)?;
+        override_from_env("WARMUP_ITERS", &mut self.warmup_iters)?;
+        override_from_env("SELF_TEST", &mut self.self_test)?;
+        override_from_env("SELF_TEST_STRICT", &mut self.self_test_strict)?;
+        override_from_env("COMPARE_MAX_NEW_TOKENS", &mut self.compare_max_new_tokens)?;
+        override_from_env("WATCH_MODELS", &mut self.watch_models)?;
+        override_from_env("WATCH_SETTLE_MS", &mut self.watch_settle_ms)?;
+        override_from_env(
+            "CANARY_QUANTIZED_PERCENT",
+            &mut self.canary_quantized_percent,
+        )?;
+        override_from_env("FALLBACK_TO_BASELINE", &mut self.fallback_to_baseline)?;
+        override_from_env("SHADOW_SAMPLE_RATE", &mut self.shadow_sample_rate)?;
+        let mut batch_promote_after_secs = self.batch_promote_after.as_secs();
+        override_from_env("BATCH_PROMOTE_AFTER_SECS", &mut batch_promote_after_secs)?;
+        self.batch_promote_after = Duration::from_secs(batch_promote_after_secs);
+        override_from_env("STATS_WINDOW", &mut self.stats_window)?;
+        override_from_env("EMBED_MAX_BATCH", &mut self.embed_max_batch)?;
 
-        let max_new_tokens = env::var("MAX_NEW_TOKENS")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(64);
-        let temperature = env::var("TEMPERATURE")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(0.8);
-        let top_k = env::var("TOP_K")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(40);
-
-        let eval_prompts_path = env::var("EVAL_PROMPTS_PATH").ok().map(PathBuf::from);
-        let eval_reference_path = env::var("EVAL_REFERENCE_PATH").ok().map(PathBuf::from);
-        let eval_warmup_iters = env::var("EVAL_WARMUP_ITERS")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(3);
-        let eval_benchmark_iters = env::var("EVAL_BENCHMARK_ITERS")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(10);
-        let eval_timeout = env::var("EVAL_TIMEOUT_SECS")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .map(Duration::from_secs)
-            .unwrap_or_else(|| Duration::from_secs(30));
+        if let Ok(path) = env::var("PROMPT_TEMPLATES_PATH") {
+            self.prompt_templates_path = Some(PathBuf::from(path));
+        }
+        if let Ok(raw) = env::var("MODEL_ALIASES") {
+            self.aliases = split_list(&raw)
+                .iter()
+                .map(|entry| {
+                    entry
+                        .split_once('=')
+                        .map(|(alias, target)| {
+                            (alias.trim().to_string(), target.trim().to_string())
+                        })
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("MODEL_ALIASES: expected alias=model, got {entry:?}")
+                        })
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        override_option_from_env("DEFAULT_MODEL", &mut self.default_model)?;
+        if let Ok(path) = env::var("EVAL_PROMPTS_PATH") {
+            self.eval_prompts_path = Some(PathBuf::from(path));
+        }
+        if let Ok(path) = env::var("EVAL_REFERENCE_PATH") {
+            self.eval_reference_path = Some(PathBuf::from(path));
+        }
+        if let Ok(path) = env::var("DATABASE_PATH") {
+            self.database_path = Some(PathBuf::from(path));
+        }
+        override_from_env("EVAL_WARMUP_ITERS", &mut self.eval_warmup_iters)?;
+        override_from_env("EVAL_BENCHMARK_ITERS", &mut self.eval_benchmark_iters)?;
+        override_from_env("EVAL_CONCURRENCY", &mut self.eval_concurrency)?;
+        if let Ok(raw) = env::var("EVAL_LENGTH_BUCKETS") {
+            self.eval_length_buckets = split_list(&raw)
+                .iter()
+                .map(|edge| edge.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| anyhow::anyhow!("EVAL_LENGTH_BUCKETS: {e}"))?;
+        }
+        override_from_env("BASELINE_DTYPE", &mut self.baseline_dtype)?;
+        let mut eval_timeout_secs = self.eval_timeout.as_secs();
+        override_from_env("EVAL_TIMEOUT_SECS", &mut eval_timeout_secs)?;
+        self.eval_timeout = Duration::from_secs(eval_timeout_secs);
 
         #[cfg(feature = "tch-backend")]
-        let device = {
-            let raw = env::var("DEVICE").unwrap_or_else(|_| "cpu".into());
-            parse_device(&raw)
+        {
+            // DEVICE predates the per-model settings and still sets both.
+            if let Ok(raw) = env::var("DEVICE") {
+                let device = parse_device(&raw).map_err(|e| anyhow::anyhow!("DEVICE: {e}"))?;
+                self.baseline_device = device;
+                self.quantized_device = device;
+            }
+            if let Ok(raw) = env::var("BASELINE_DEVICE") {
+                self.baseline_device =
+                    parse_device(&raw).map_err(|e| anyhow::anyhow!("BASELINE_DEVICE: {e}"))?;
+            }
+            if let Ok(raw) = env::var("QUANTIZED_DEVICE") {
+                self.quantized_device =
+                    parse_device(&raw).map_err(|e| anyhow::anyhow!("QUANTIZED_DEVICE: {e}"))?;
+            }
+            if let Ok(raw) = env::var("DEVICES") {
+                let devices = parse_devices(&raw).map_err(|e| anyhow::anyhow!("DEVICES: {e}"))?;
+                self.baseline_devices = devices.clone();
+                self.quantized_devices = devices;
+            }
+            if let Ok(raw) = env::var("BASELINE_DEVICES") {
+                self.baseline_devices =
+                    parse_devices(&raw).map_err(|e| anyhow::anyhow!("BASELINE_DEVICES: {e}"))?;
+            }
+            if let Ok(raw) = env::var("QUANTIZED_DEVICES") {
+                self.quantized_devices =
+                    parse_devices(&raw).map_err(|e| anyhow::anyhow!("QUANTIZED_DEVICES: {e}"))?;
+            }
+        }
+        override_from_env("STRICT_DEVICES", &mut self.strict_devices)?;
+        // DEVICE_FALLBACK is accepted as a shorter alias.
+        override_from_env("DEVICE_FALLBACK", &mut self.allow_device_fallback)?;
+        override_from_env("ALLOW_DEVICE_FALLBACK", &mut self.allow_device_fallback)?;
+        override_option_from_env("TORCH_NUM_THREADS", &mut self.torch_num_threads)?;
+        override_option_from_env(
+            "TORCH_NUM_INTEROP_THREADS",
+            &mut self.torch_num_interop_threads,
+        )?;
+        override_option_from_env("ORT_INTRA_THREADS", &mut self.ort_intra_threads)?;
+
+        if let Ok(raw) = env::var("API_KEYS") {
+            self.api_keys = split_list(&raw);
+        }
+        if let Ok(raw) = env::var("ADMIN_API_KEYS") {
+            self.admin_api_keys = split_list(&raw);
+        }
+        override_from_env("RATE_LIMIT_RPS", &mut self.rate_limit_rps)?;
+        override_from_env("RATE_LIMIT_BURST", &mut self.rate_limit_burst)?;
+        override_option_from_env("TOKEN_QUOTA", &mut self.token_quota)?;
+        if let Ok(raw) = env::var("TOKEN_QUOTAS") {
+            self.token_quotas = split_list(&raw)
+                .iter()
+                .map(|entry| {
+                    entry
+                        .split_once('=')
+                        .and_then(|(key, tokens)| {
+                            Some((key.trim().to_string(), tokens.trim().parse().ok()?))
+                        })
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("TOKEN_QUOTAS: expected label=tokens, got {entry:?}")
+                        })
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        override_from_env("RETRY_AFTER_SECS", &mut self.retry_after_secs)?;
+        override_option_from_env("PLAYGROUND_ENABLED", &mut self.playground_enabled)?;
+        override_from_env("SWAGGER_UI_ENABLED", &mut self.swagger_ui_enabled)?;
+        if let Ok(raw) = env::var("CORS_ALLOWED_ORIGINS") {
+            self.cors_allowed_origins = split_list(&raw);
+        }
+        let mut cors_max_age_secs = self.cors_max_age.as_secs();
+        override_from_env("CORS_MAX_AGE_SECS", &mut cors_max_age_secs)?;
+        self.cors_max_age = Duration::from_secs(cors_max_age_secs);
+        override_from_env("MAX_REQUEST_BYTES", &mut self.max_request_bytes)?;
+        override_from_env("LOG_FORMAT", &mut self.log_format)?;
+        override_from_env("SLOW_REQUEST_MS", &mut self.slow_request_ms)?;
+        override_from_env("LOG_PROMPTS", &mut self.log_prompts)?;
+        override_from_env("LOG_PROMPTS_MAX_CHARS", &mut self.log_prompts_max_chars)?;
+        override_option_from_env("PROMPT_FINGERPRINT_SALT", &mut self.prompt_fingerprint_salt)?;
+        if let Ok(path) = env::var("AUDIT_LOG_PATH") {
+            self.audit_log_path = Some(PathBuf::from(path));
+        }
+        override_from_env("ACCESS_LOG", &mut self.access_log)?;
+        override_from_env("ACCESS_LOG_SAMPLE_RATE", &mut self.access_log_sample_rate)?;
+        override_option_from_env("OTEL_EXPORTER_OTLP_ENDPOINT", &mut self.otlp_endpoint)?;
+
+        Ok(())
+    }
+
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        (!self.stream_keepalive.is_zero()).then_some(self.stream_keepalive)
+    }
+
+    pub fn max_lock_wait(&self) -> Option<Duration> {
+        (self.max_lock_wait_ms > 0).then(|| Duration::from_millis(self.max_lock_wait_ms))
+    }
+
+    pub fn playground_enabled(&self) -> bool {
+        self.playground_enabled
+            .unwrap_or_else(|| self.listen_addr.ip().is_loopback())
+    }
+
+    pub fn model_kind(&self, slot: ModelSlot) -> ModelKind {
+        match slot {
+            ModelSlot::Baseline => self.baseline_model_kind,
+            ModelSlot::Quantized => self.quantized_model_kind,
+        }
+    }
+
+    /// The devices `slot` is loaded on, one replica each.
+    #[cfg(feature = "tch-backend")]
+    pub fn devices(&self, slot: ModelSlot) -> Vec<Device> {
+        let (devices, device) = match slot {
+            ModelSlot::Baseline => (&self.baseline_devices, self.baseline_device),
+            ModelSlot::Quantized => (&self.quantized_devices, self.quantized_device),
         };
+        if devices.is_empty() {
+            vec![device]
+        } else {
+            devices.clone()
+        }
+    }
 
-        Ok(Self {
-            listen_addr,
-            model_id,
-            revision,
-            baseline_module_path,
-            quantized_module_path,
-            tokenizer_path,
-            max_new_tokens,
-            temperature,
-            top_k,
-            eval_prompts_path,
-            eval_reference_path,
-            eval_warmup_iters,
-            eval_benchmark_iters,
-            eval_timeout,
-            #[cfg(feature = "tch-backend")]
-            device,
-        })
+    /// One config per replica of `slot`, each placing it on its own device.
+    pub fn replica_configs(&self, slot: ModelSlot) -> Vec<AppConfig> {
+        #[cfg(feature = "tch-backend")]
+        {
+            let devices = self.devices(slot);
+            let replicated = devices.len() > 1;
+            devices
+                .into_iter()
+                .map(|device| {
+                    let mut config = self.clone();
+                    match slot {
+                        ModelSlot::Baseline => config.baseline_device = device,
+                        ModelSlot::Quantized => config.quantized_device = device,
+                    }
+                    // A replica moved to CPU would serve its share of the
+                    // traffic far slower than the rest.
+                    config.allow_device_fallback &= !replicated;
+                    config
+                })
+                .collect()
+        }
+        #[cfg(not(feature = "tch-backend"))]
+        {
+            let _ = slot;
+            vec![self.clone()]
+        }
     }
+
+    pub fn eos_token_id(&self, kind: ModelKind) -> i64 {
+        self.eos_token_id
+            .unwrap_or_else(|| kind.default_eos_token_id())
+    }
+
+    /// Checks value ranges and that the artifacts on disk exist, reporting
+    /// every problem at once instead of stopping at the first.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+
+        if !self.backend.is_compiled_in() {
+            problems.push(format!(
+                "backend {} is not compiled in; rebuild with --features {}-backend",
+                self.backend, self.backend
+            ));
+        }
+        if self.quantized_onnx_path.is_some() && !cfg!(feature = "ort-backend") {
+            problems.push(
+                "quantized_onnx_path requires a build with --features ort-backend".to_string(),
+            );
+        }
+        for slot in [ModelSlot::Baseline, ModelSlot::Quantized] {
+            if self.model_kind(slot) != ModelKind::Seq2Seq {
+                continue;
+            }
+            let onnx = slot == ModelSlot::Quantized && self.quantized_onnx_path.is_some();
+            if self.backend != BackendKind::Tch || onnx {
+                problems.push(format!(
+                    "{}_model_kind seq2seq is only supported for TorchScript modules on the tch backend",
+                    slot.name()
+                ));
+            }
+        }
+        if self.tokenizer_max_length == Some(0) {
+            problems.push("tokenizer_max_length must be at least 1".to_string());
+        }
+        if self.baseline_dtype != ModelDtype::Float32 && self.backend != BackendKind::Tch {
+            problems.push(format!(
+                "baseline_dtype {} is only supported on the tch backend",
+                self.baseline_dtype
+            ));
+        }
+        #[cfg(feature = "tch-backend")]
+        for slot in [ModelSlot::Baseline, ModelSlot::Quantized] {
+            let devices = self.devices(slot);
+            if devices.len() > 1 && self.backend != BackendKind::Tch {
+                problems.push(format!(
+                    "{}_devices is only supported on the tch backend",
+                    slot.name()
+                ));
+            }
+            if let Some(device) = devices
+                .iter()
+                .enumerate()
+                .find_map(|(i, device)| devices[..i].contains(device).then_some(device))
+            {
+                problems.push(format!(
+                    "{}_devices lists {} more than once",
+                    slot.name(),
+                    crate::model::tch_backend::device_label(*device)
+                ));
+            }
+        }
+        #[cfg(feature = "tch-backend")]
+        if self.baseline_dtype == ModelDtype::Float16
+            && self.devices(ModelSlot::Baseline).contains(&Device::Cpu)
+        {
+            problems.push(
+                "baseline_dtype float16 is poorly supported by LibTorch on CPU; use bfloat16 \
+                 or run the baseline on cuda"
+                    .to_string(),
+            );
+        }
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) | (None, Some(_)) => {
+                problems.push("tls_cert_path and tls_key_path must be set together".to_string());
+            }
+            (Some(_), Some(_)) if !cfg!(feature = "tls") => {
+                problems.push("TLS requires a build with --features tls".to_string());
+            }
+            _ => {}
+        }
+        if self.grpc_addr == Some(self.listen_addr) {
+            problems.push(format!(
+                "grpc_addr must differ from listen_addr ({})",
+                self.listen_addr
+            ));
+        }
+        if !(self.temperature.is_finite() && self.temperature >= 0.0) {
+            problems.push(format!(
+                "temperature must be zero (greedy) or positive, got {}",
+                self.temperature
+            ));
+        }
+        if self.top_k == 0 {
+            problems.push("top_k must be at least 1".to_string());
+        }
+        if self.max_new_tokens == 0 {
+            problems.push("max_new_tokens must be at least 1".to_string());
+        }
+        if self.max_batch_size == 0 {
+            problems.push("max_batch_size must be at least 1".to_string());
+        }
+        if self.compare_max_new_tokens == 0 {
+            problems.push("compare_max_new_tokens must be at least 1".to_string());
+        }
+        if self.watch_models && self.watch_settle_ms == 0 {
+            problems.push("watch_settle_ms must be at least 1 with watch_models".to_string());
+        }
+        if !(0.0..=100.0).contains(&self.canary_quantized_percent) {
+            problems.push(format!(
+                "canary_quantized_percent must be between 0 and 100, got {}",
+                self.canary_quantized_percent
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.shadow_sample_rate) {
+            problems.push(format!(
+                "shadow_sample_rate must be between 0 and 1, got {}",
+                self.shadow_sample_rate
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.access_log_sample_rate) {
+            problems.push(format!(
+                "access_log_sample_rate must be between 0 and 1, got {}",
+                self.access_log_sample_rate
+            ));
+        }
+        if self.stats_window == 0 {
+            problems.push("stats_window must be at least 1".to_string());
+        }
+        if self.embed_max_batch == 0 {
+            problems.push("embed_max_batch must be at least 1".to_string());
+        }
+        if self.context_sentinel_tokens >= self.max_context_tokens {
+            problems.push(format!(
+                "context_sentinel_tokens ({}) must be smaller than max_context_tokens ({})",
+                self.context_sentinel_tokens, self.max_context_tokens
+            ));
+        }
+        if self.max_new_tokens >= self.max_context_tokens {
+            problems.push(format!(
+                "max_new_tokens ({}) must be smaller than max_context_tokens ({})",
+                self.max_new_tokens, self.max_context_tokens
+            ));
+        }
+        if !(self.rate_limit_rps.is_finite() && self.rate_limit_rps >= 0.0) {
+            problems.push(format!(
+                "rate_limit_rps must be zero or positive, got {}",
+                self.rate_limit_rps
+            ));
+        }
+        if self.rate_limit_burst == 0 {
+            problems.push("rate_limit_burst must be at least 1".to_string());
+        }
+        if self.retry_after_secs == 0 {
+            problems.push("retry_after_secs must be at least 1".to_string());
+        }
+        if self.max_request_bytes == 0 {
+            problems.push("max_request_bytes must be at least 1".to_string());
+        }
+        let wildcard = self.cors_allowed_origins.iter().any(|origin| origin == "*");
+        if wildcard && self.cors_allowed_origins.len() > 1 {
+            problems.push("cors_allowed_origins cannot mix '*' with other origins".to_string());
+        }
+        for origin in self
+            .cors_allowed_origins
+            .iter()
+            .filter(|origin| *origin != "*")
+        {
+            let scheme_ok = origin.starts_with("http://") || origin.starts_with("https://");
+            if !scheme_ok || origin.ends_with('/') || HeaderValue::from_str(origin).is_err() {
+                problems.push(format!(
+                    "cors_allowed_origins entry {origin:?} must be '*' or an origin like \
+                     https://app.example.com"
+                ));
+            }
+        }
+        for (name, threads) in [
+            ("torch_num_threads", self.torch_num_threads),
+            ("torch_num_interop_threads", self.torch_num_interop_threads),
+            ("ort_intra_threads", self.ort_intra_threads),
+        ] {
+            if threads == Some(0) {
+                problems.push(format!("{name} must be at least 1 when set"));
+            }
+        }
+        for (name, digest) in [
+            ("baseline_module_sha256", &self.baseline_module_sha256),
+            ("quantized_module_sha256", &self.quantized_module_sha256),
+            ("tokenizer_sha256", &self.tokenizer_sha256),
+        ] {
+            if let Some(digest) = digest {
+                let digest = digest.trim();
+                if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                    problems.push(format!("{name} must be 64 hex characters"));
+                }
+            }
+        }
+        check_templates(self, &mut problems);
+        check_presets(self, &mut problems);
+        problems.extend(check_aliases(&self.aliases, self.default_model.as_deref()));
+        for pattern in &self.audit_redact_patterns {
+            if let Err(err) = regex::Regex::new(pattern) {
+                problems.push(format!("invalid audit_redact_patterns entry: {err}"));
+            }
+        }
+        if self.eval_concurrency == 0 {
+            problems.push("eval_concurrency must be at least 1".to_string());
+        }
+        if !self.eval_length_buckets.is_sorted_by(|a, b| a < b) {
+            problems.push("eval_length_buckets must be strictly increasing".to_string());
+        }
+
+        // Model artifacts may be fetched at load time instead.
+        let downloadable = |path| (!self.auto_download).then_some(path);
+        let required = [
+            (
+                "baseline_module_path",
+                downloadable(&self.baseline_module_path),
+            ),
+            ("tokenizer_path", downloadable(&self.tokenizer_path)),
+            ("tls_cert_path", self.tls_cert_path.as_ref()),
+            ("tls_key_path", self.tls_key_path.as_ref()),
+            ("eval_prompts_path", self.eval_prompts_path.as_ref()),
+            ("eval_reference_path", self.eval_reference_path.as_ref()),
+        ];
+        for (name, path) in required {
+            if let Some(path) = path
+                && !path.exists()
+            {
+                problems.push(format!("{name} does not exist: {}", path.display()));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!("invalid configuration:\n  - {}", problems.join("\n  - "))
+        }
+    }
+}
+
+fn split_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn override_from_env<T>(key: &str, target: &mut T) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(raw) = env::var(key) {
+        *target = raw
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid value for {key}: {raw:?} ({e})"))?;
+    }
+    Ok(())
+}
+
+fn override_option_from_env<T>(key: &str, target: &mut Option<T>) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(raw) = env::var(key) {
+        let value = raw
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid value for {key}: {raw:?} ({e})"))?;
+        *target = Some(value);
+    }
+    Ok(())
+}
+
+fn deserialize_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(Duration::from_secs)
 }
 
 #[cfg(feature = "tch-backend")]
-fn parse_device(raw: &str) -> Device {
-    let lower = raw.to_lowercase();
-    if lower == "cpu" {
-        Device::Cpu
-    } else if lower.starts_with("cuda") {
-        let idx = lower
-            .split(':')
-            .nth(1)
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(0);
-        if tch::Cuda::is_available() {
-            Device::Cuda(idx)
-        } else {
-            Device::Cpu
+fn deserialize_device<'de, D>(deserializer: D) -> Result<Device, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+    parse_device(&raw).map_err(serde::de::Error::custom)
+}
+
+#[cfg(feature = "tch-backend")]
+fn deserialize_devices<'de, D>(deserializer: D) -> Result<Vec<Device>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|raw| parse_device(raw))
+        .collect::<Result<_, _>>()
+        .map_err(serde::de::Error::custom)
+}
+
+/// A comma-separated list of devices, as `parse_device` reads each.
+#[cfg(feature = "tch-backend")]
+fn parse_devices(raw: &str) -> Result<Vec<Device>, String> {
+    split_list(raw)
+        .iter()
+        .map(|raw| parse_device(raw))
+        .collect()
+}
+
+/// Parses `cpu`, `cuda`, or `cuda:N`. Availability is checked when the model
+/// is loaded so the fallback policy can be applied there.
+#[cfg(feature = "tch-backend")]
+pub fn parse_device(raw: &str) -> Result<Device, String> {
+    let lower = raw.trim().to_lowercase();
+    match lower.split_once(':') {
+        None if lower == "cpu" => Ok(Device::Cpu),
+        None if lower == "cuda" => Ok(Device::Cuda(0)),
+        Some(("cuda", idx)) => idx
+            .parse::<usize>()
+            .map(Device::Cuda)
+            .map_err(|_| format!("invalid CUDA device index in {raw:?}")),
+        _ => Err(format!(
+            "unknown device {raw:?} (expected cpu, cuda, or cuda:N)"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "tch-backend")]
+    const EXAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/config.example.toml");
+
+    /// Writes `contents` as `config.toml` in a fresh directory of its own.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("qls-config-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// The example with every commented-out setting switched on, so each
+    /// documented key is known to parse.
+    #[cfg(feature = "tch-backend")]
+    #[test]
+    fn every_example_setting_parses() {
+        let example = fs::read_to_string(EXAMPLE).unwrap();
+        let full: String = example
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(setting)
+                    if setting.split_once(" = ").is_some_and(|(key, _)| {
+                        key.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+                    }) =>
+                {
+                    setting
+                }
+                _ => line,
+            })
+            .map(|line| format!("{line}\n"))
+            .collect();
+        assert_ne!(full, example);
+        let path = config_file("full", &full);
+        let config = AppConfig::from_file(&path).unwrap();
+        let dir = path.parent().unwrap();
+
+        assert_eq!(config.max_new_tokens, 64);
+        assert_eq!(config.eos_token_id, Some(50256));
+        assert_eq!(config.tokenizer_max_length, Some(512));
+        assert_eq!(
+            config.baseline_devices,
+            vec![Device::Cuda(0), Device::Cuda(1)]
+        );
+        assert_eq!(config.tokenizer_path, dir.join("models/tokenizer.json"));
+        assert_eq!(config.database_path, Some(dir.join("metrics.db")));
+        assert_eq!(config.aliases["fast"], "quantized");
+    }
+
+    #[cfg(feature = "tch-backend")]
+    #[test]
+    fn example_paths_resolve_against_its_directory() {
+        let config = AppConfig::from_file(Path::new(EXAMPLE)).unwrap();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(config.tokenizer_path, dir.join("models/tokenizer.json"));
+        assert_eq!(
+            config.baseline_module_path,
+            dir.join("models/distilgpt2_baseline.ts")
+        );
+        // Paths the file leaves out keep their defaults.
+        assert_eq!(config.database_path, None);
+    }
+
+    #[test]
+    fn absolute_and_unset_paths_are_left_alone() {
+        let path = config_file(
+            "paths",
+            "tokenizer_path = \"/opt/models/tokenizer.json\"\naudit_log_path = \"logs/audit.jsonl\"\n",
+        );
+        let config = AppConfig::from_file(&path).unwrap();
+        let defaults = AppConfig::default();
+        assert_eq!(
+            config.tokenizer_path,
+            Path::new("/opt/models/tokenizer.json")
+        );
+        assert_eq!(
+            config.audit_log_path,
+            Some(path.parent().unwrap().join("logs/audit.jsonl"))
+        );
+        assert_eq!(config.baseline_module_path, defaults.baseline_module_path);
+    }
+
+    #[test]
+    fn unknown_keys_are_named() {
+        let path = config_file("unknown", "max_new_token = 5\n");
+        let err = AppConfig::from_file(&path).unwrap_err();
+        assert!(format!("{err:#}").contains("max_new_token"), "{err:#}");
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let path = config_file("overrides", "max_new_tokens = 5\ntop_k = 3\n");
+        // SAFETY: no other test reads or writes these variables.
+        unsafe {
+            env::set_var("MAX_NEW_TOKENS", "7");
+            env::set_var("TOP_K", "not a number");
+        }
+        let err = AppConfig::load_with_file(&path).unwrap_err();
+        assert!(err.to_string().contains("TOP_K"), "{err}");
+
+        unsafe { env::remove_var("TOP_K") };
+        let config = AppConfig::load_with_file(&path);
+        unsafe { env::remove_var("MAX_NEW_TOKENS") };
+        let config = config.unwrap();
+        assert_eq!(config.max_new_tokens, 7);
+        assert_eq!(config.top_k, 3);
+    }
+
+    #[test]
+    fn baseline_dtypes_are_checked_against_backend_and_device() {
+        let config = AppConfig {
+            backend: BackendKind::Candle,
+            baseline_dtype: ModelDtype::Bfloat16,
+            ..AppConfig::default()
+        };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("baseline_dtype bfloat16 is only supported on the tch backend"),
+            "{err}"
+        );
+
+        #[cfg(feature = "tch-backend")]
+        {
+            let on_cpu = |baseline_dtype| AppConfig {
+                backend: BackendKind::Tch,
+                baseline_dtype,
+                ..AppConfig::default()
+            };
+            let err = on_cpu(ModelDtype::Float16)
+                .validate()
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("float16 is poorly supported"), "{err}");
+            on_cpu(ModelDtype::Bfloat16).validate().unwrap();
         }
-    } else {
-        Device::Cpu
     }
 }
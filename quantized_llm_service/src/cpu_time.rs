@@ -0,0 +1,70 @@
+//! CPU time spent by the current thread, for charging generations by compute
+//! rather than by wall time spent waiting on a contended model.
+//!
+//! Linux only: every reading is `None` where `/proc/thread-self/schedstat`
+//! is unavailable. Work LibTorch hands to its intra-op thread pool runs on
+//! other threads and is not counted.
+
+use std::time::Duration;
+
+/// Time the calling thread has spent running on a CPU, from the first
+/// field of `/proc/thread-self/schedstat` (nanoseconds).
+pub fn thread_cpu_time() -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    {
+        let schedstat = std::fs::read_to_string("/proc/thread-self/schedstat").ok()?;
+        let nanos = schedstat.split_whitespace().next()?.parse().ok()?;
+        Some(Duration::from_nanos(nanos))
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// CPU time the current thread spends between `start` and `elapsed`; both
+/// must be called on the same thread.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadCpuTimer {
+    start: Duration,
+}
+
+impl ThreadCpuTimer {
+    pub fn start() -> Option<Self> {
+        thread_cpu_time().map(|start| Self { start })
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        thread_cpu_time().map(|now| now.saturating_sub(self.start))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{hint::black_box, thread, time::Instant};
+
+    use super::*;
+
+    #[test]
+    fn busy_work_is_counted_and_never_exceeds_wall_time() {
+        let wall = Instant::now();
+        let timer = ThreadCpuTimer::start().expect("schedstat is readable");
+        let mut x = 0u64;
+        while wall.elapsed() < Duration::from_millis(20) {
+            x = black_box(x.wrapping_mul(31).wrapping_add(7));
+        }
+        let cpu = timer.elapsed().unwrap();
+        assert!(cpu > Duration::ZERO);
+        assert!(
+            cpu <= wall.elapsed(),
+            "{cpu:?} of CPU in {:?}",
+            wall.elapsed()
+        );
+    }
+
+    #[test]
+    fn sleeping_costs_next_to_nothing() {
+        let timer = ThreadCpuTimer::start().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let cpu = timer.elapsed().unwrap();
+        assert!(cpu < Duration::from_millis(25), "{cpu:?}");
+    }
+}
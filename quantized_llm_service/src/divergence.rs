@@ -0,0 +1,161 @@
+//! Where the quantized model's greedy output first departs from the
+//! baseline's, for `/debug/compare`.
+
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    config::AppConfig,
+    error::ServiceError,
+    model::{
+        ContextStrategy, GenerationParams, GenerationResponse, ModelRegistry, ModelSlot, OnTimeout,
+        Priority, ResponseFormat, TokenDetail,
+    },
+};
+
+/// Candidates reported from each model at the divergence.
+const CANDIDATES: usize = 5;
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CompareRequest {
+    pub prompt: String,
+    /// Defaults to `max_new_tokens`; at most `compare_max_new_tokens`.
+    pub max_new_tokens: Option<usize>,
+    pub context_strategy: Option<ContextStrategy>,
+    pub add_special_tokens: Option<bool>,
+    pub skip_special_tokens: Option<bool>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DivergenceReport {
+    pub prompt_tokens: usize,
+    /// Index of the first generated token the models disagree on; absent
+    /// when both generated the same tokens.
+    pub first_divergence: Option<usize>,
+    /// What both models generated before diverging.
+    pub common_prefix: String,
+    pub baseline: ComparedCompletion,
+    pub quantized: ComparedCompletion,
+    pub total_time_ms: u128,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ComparedCompletion {
+    pub completion: String,
+    pub token_ids: Vec<u32>,
+    /// The model's likeliest next tokens at `first_divergence`, best first;
+    /// empty without a divergence or once the model had stopped.
+    pub candidates: Vec<Candidate>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Candidate {
+    pub id: u32,
+    pub text: String,
+    pub logprob: f32,
+    pub probability: f32,
+}
+
+/// Decodes `request` greedily on both models, one after the other. Greedy
+/// decoding is deterministic, so up to the first differing token both saw
+/// the same prefix and the candidates there are comparable step for step.
+pub async fn compare(
+    registry: &ModelRegistry,
+    config: &AppConfig,
+    request: CompareRequest,
+) -> Result<DivergenceReport, ServiceError> {
+    if !(registry.has_baseline() && registry.has_quantized()) {
+        return Err(ServiceError::BadRequest(
+            "comparing needs both the baseline and quantized models loaded".into(),
+        ));
+    }
+    let max_new_tokens = match request.max_new_tokens {
+        Some(0) => {
+            return Err(ServiceError::validation(
+                "max_new_tokens",
+                "must be at least 1",
+            ));
+        }
+        Some(n) if n > config.compare_max_new_tokens => {
+            return Err(ServiceError::validation(
+                "max_new_tokens",
+                format!(
+                    "is {n}, more than compare_max_new_tokens ({})",
+                    config.compare_max_new_tokens
+                ),
+            ));
+        }
+        Some(n) => n,
+        None => config.max_new_tokens.min(config.compare_max_new_tokens),
+    };
+    let params = GenerationParams {
+        max_new_tokens,
+        temperature: 0.0,
+        top_k: 1,
+        context_strategy: request.context_strategy.unwrap_or_default(),
+        sentinel_tokens: config.context_sentinel_tokens,
+        seed: None,
+        priority: Priority::Batch,
+        add_special_tokens: request.add_special_tokens.unwrap_or(true),
+        skip_special_tokens: request.skip_special_tokens.unwrap_or(true),
+        measure_memory: false,
+        token_details: true,
+        top_logprobs: CANDIDATES,
+        timeout: (!config.generation_timeout.is_zero()).then_some(config.generation_timeout),
+        on_timeout: OnTimeout::Error,
+        response_format: ResponseFormat::Text,
+        max_lock_wait: config.max_lock_wait(),
+    };
+
+    let started = Instant::now();
+    let baseline = registry
+        .generate_with_params(ModelSlot::Baseline, request.prompt.clone(), params)
+        .await?;
+    let quantized = registry
+        .generate_with_params(ModelSlot::Quantized, request.prompt, params)
+        .await?;
+
+    let baseline_details = baseline.token_details.clone().unwrap_or_default();
+    let quantized_details = quantized.token_details.clone().unwrap_or_default();
+    let first_divergence = (0..baseline_details.len().max(quantized_details.len()))
+        .find(|&i| baseline_details.get(i).map(|d| d.id) != quantized_details.get(i).map(|d| d.id));
+    let agreed = first_divergence.unwrap_or(baseline_details.len());
+    Ok(DivergenceReport {
+        prompt_tokens: baseline.usage.prompt_tokens,
+        first_divergence,
+        common_prefix: baseline_details[..agreed]
+            .iter()
+            .map(|detail| detail.text.as_str())
+            .collect(),
+        baseline: compared(baseline, &baseline_details, first_divergence),
+        quantized: compared(quantized, &quantized_details, first_divergence),
+        total_time_ms: started.elapsed().as_millis(),
+    })
+}
+
+fn compared(
+    response: GenerationResponse,
+    details: &[TokenDetail],
+    divergence: Option<usize>,
+) -> ComparedCompletion {
+    let candidates = divergence
+        .and_then(|i| details.get(i))
+        .and_then(|detail| detail.top_logprobs.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|candidate| Candidate {
+            id: candidate.id,
+            text: candidate.text,
+            logprob: candidate.logprob,
+            probability: candidate.logprob.exp(),
+        })
+        .collect();
+    ComparedCompletion {
+        completion: response.completion,
+        token_ids: details.iter().map(|detail| detail.id).collect(),
+        candidates,
+    }
+}
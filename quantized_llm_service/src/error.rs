@@ -1,41 +1,554 @@
-use axum::http::StatusCode;
+use axum::http::{HeaderValue, StatusCode, header};
 use axum::response::{IntoResponse, Response};
+use serde::Serialize;
 use thiserror::Error;
+use utoipa::ToSchema;
 
 #[derive(Debug, Error)]
 pub enum ServiceError {
+    /// The 503 variants carry how long clients should wait before retrying;
+    /// without one the server's configured default is used.
     #[error("model is still loading")]
-    ModelLoading,
+    ModelLoading { retry_after_secs: Option<u64> },
+    #[error("model unavailable: {0}")]
+    ModelUnavailable(String),
     #[error("invalid request: {0}")]
     BadRequest(String),
+    #[error("invalid request: field '{field}' {message}")]
+    Validation { field: String, message: String },
+    #[error("invalid request body: {0}")]
+    Unprocessable(String),
+    #[error("idempotency key {0:?} was already used with a different request body")]
+    IdempotencyConflict(String),
     #[error("tokenizer error: {0}")]
     Tokenizer(String),
     #[error("model execution failed: {0}")]
     Inference(String),
     #[error("quantization error: {0}")]
     Quantization(String),
+    #[error("artifact download failed: {0}")]
+    Download(String),
+    #[error("generation timed out after {elapsed_ms} ms with {tokens_generated} tokens generated")]
+    Timeout {
+        elapsed_ms: u64,
+        tokens_generated: usize,
+    },
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("method not allowed: {0}")]
+    MethodNotAllowed(String),
+    #[error("unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+    #[error("rate limit exceeded, retry after {retry_after_secs}s")]
+    RateLimited { retry_after_secs: u64 },
+    #[error(
+        "token quota of key {key:?} exceeded: {requested} tokens needed, {remaining} of {quota} left"
+    )]
+    QuotaExceeded {
+        key: String,
+        quota: u64,
+        remaining: u64,
+        requested: u64,
+    },
+    #[error("request body exceeds the {limit_bytes}-byte limit")]
+    PayloadTooLarge { limit_bytes: usize },
+    #[error("service overloaded: {message}")]
+    Overloaded {
+        message: String,
+        retry_after_secs: Option<u64>,
+    },
+    #[error("resource exhausted: {message}")]
+    ResourceExhausted {
+        message: String,
+        sequence_length: Option<usize>,
+        retry_after_secs: Option<u64>,
+    },
+    #[error("database error: {0}")]
+    Database(String),
+    #[error("not supported: {0}")]
+    NotImplemented(String),
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
     #[error("other: {0}")]
     Other(String),
 }
 
-impl IntoResponse for ServiceError {
-    fn into_response(self) -> Response {
-        let status = match self {
-            ServiceError::ModelLoading => StatusCode::SERVICE_UNAVAILABLE,
-            ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: ErrorPayload,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ErrorPayload {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    /// Filled in by the request-id layer once the response leaves the handler.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ServiceError {
+    pub fn validation(field: impl Into<String>, message: impl Into<String>) -> Self {
+        ServiceError::Validation {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn model_loading() -> Self {
+        ServiceError::ModelLoading {
+            retry_after_secs: None,
+        }
+    }
+
+    /// Seconds clients should wait before retrying, when the error says.
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            ServiceError::RateLimited { retry_after_secs } => Some(*retry_after_secs),
+            ServiceError::ModelLoading { retry_after_secs }
+            | ServiceError::Overloaded {
+                retry_after_secs, ..
+            }
+            | ServiceError::ResourceExhausted {
+                retry_after_secs, ..
+            } => *retry_after_secs,
+            _ => None,
+        }
+    }
+
+    /// Stable machine-readable identifier clients can branch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServiceError::ModelLoading { .. } => "model_loading",
+            ServiceError::ModelUnavailable(_) => "model_unavailable",
+            ServiceError::BadRequest(_) | ServiceError::Validation { .. } => "bad_request",
+            ServiceError::Unprocessable(_) => "unprocessable",
+            ServiceError::IdempotencyConflict(_) => "idempotency_conflict",
+            ServiceError::Tokenizer(_) => "tokenizer",
+            ServiceError::Inference(_) => "inference",
+            ServiceError::Quantization(_) => "quantization",
+            ServiceError::Download(_) => "download",
+            ServiceError::Unauthorized(_) => "unauthorized",
+            ServiceError::Forbidden(_) => "forbidden",
+            ServiceError::NotFound(_) => "not_found",
+            ServiceError::MethodNotAllowed(_) => "method_not_allowed",
+            ServiceError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ServiceError::RateLimited { .. } => "rate_limited",
+            ServiceError::QuotaExceeded { .. } => "quota_exceeded",
+            ServiceError::Timeout { .. } => "timeout",
+            ServiceError::PayloadTooLarge { .. } => "payload_too_large",
+            ServiceError::Overloaded { .. } => "overloaded",
+            ServiceError::ResourceExhausted { .. } => "resource_exhausted",
+            ServiceError::Database(_) => "database",
+            ServiceError::NotImplemented(_) => "not_implemented",
+            ServiceError::Io(_) => "io",
+            ServiceError::Other(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServiceError::ModelLoading { .. }
+            | ServiceError::ModelUnavailable(_)
+            | ServiceError::Overloaded { .. }
+            | ServiceError::ResourceExhausted { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceError::BadRequest(_) | ServiceError::Validation { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            ServiceError::Unprocessable(_) | ServiceError::IdempotencyConflict(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ServiceError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServiceError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ServiceError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ServiceError::RateLimited { .. } | ServiceError::QuotaExceeded { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ServiceError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            ServiceError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
             ServiceError::Tokenizer(_)
             | ServiceError::Inference(_)
             | ServiceError::Quantization(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            ServiceError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ServiceError::Download(_) => StatusCode::BAD_GATEWAY,
+            ServiceError::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            ServiceError::Io(_) | ServiceError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ServiceError::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+        }
+    }
 
-        let body = serde_json::json!({
-            "error": self.to_string(),
-        });
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            ServiceError::Validation { field, .. } => Some(serde_json::json!({ "field": field })),
+            ServiceError::ResourceExhausted {
+                sequence_length,
+                retry_after_secs,
+                ..
+            } if sequence_length.is_some() || retry_after_secs.is_some() => {
+                let mut details = serde_json::Map::new();
+                if let Some(len) = sequence_length {
+                    details.insert("sequence_length".into(), (*len).into());
+                }
+                if let Some(secs) = retry_after_secs {
+                    details.insert("retry_after_secs".into(), (*secs).into());
+                }
+                Some(details.into())
+            }
+            ServiceError::RateLimited { .. }
+            | ServiceError::ModelLoading { .. }
+            | ServiceError::Overloaded { .. } => self
+                .retry_after_secs()
+                .map(|secs| serde_json::json!({ "retry_after_secs": secs })),
+            ServiceError::PayloadTooLarge { limit_bytes } => {
+                Some(serde_json::json!({ "limit_bytes": limit_bytes }))
+            }
+            ServiceError::QuotaExceeded {
+                quota,
+                remaining,
+                requested,
+                ..
+            } => Some(serde_json::json!({
+                "quota": quota,
+                "remaining": remaining,
+                "requested": requested,
+            })),
+            ServiceError::Timeout {
+                elapsed_ms,
+                tokens_generated,
+            } => Some(serde_json::json!({
+                "elapsed_ms": elapsed_ms,
+                "tokens_generated": tokens_generated,
+            })),
+            _ => None,
+        }
+    }
+
+    pub fn to_body(&self) -> ErrorBody {
+        ErrorBody {
+            error: ErrorPayload {
+                code: self.code(),
+                message: self.to_string(),
+                details: self.details(),
+                request_id: None,
+            },
+        }
+    }
+}
+
+/// Lets one failure be handed to every caller sharing a coalesced request.
+/// I/O errors are rebuilt from their kind and message.
+impl Clone for ServiceError {
+    fn clone(&self) -> Self {
+        match self {
+            ServiceError::ModelLoading { retry_after_secs } => ServiceError::ModelLoading {
+                retry_after_secs: *retry_after_secs,
+            },
+            ServiceError::ModelUnavailable(m) => ServiceError::ModelUnavailable(m.clone()),
+            ServiceError::BadRequest(m) => ServiceError::BadRequest(m.clone()),
+            ServiceError::Validation { field, message } => ServiceError::Validation {
+                field: field.clone(),
+                message: message.clone(),
+            },
+            ServiceError::Unprocessable(m) => ServiceError::Unprocessable(m.clone()),
+            ServiceError::IdempotencyConflict(k) => ServiceError::IdempotencyConflict(k.clone()),
+            ServiceError::Tokenizer(m) => ServiceError::Tokenizer(m.clone()),
+            ServiceError::Inference(m) => ServiceError::Inference(m.clone()),
+            ServiceError::Quantization(m) => ServiceError::Quantization(m.clone()),
+            ServiceError::Download(m) => ServiceError::Download(m.clone()),
+            ServiceError::Timeout {
+                elapsed_ms,
+                tokens_generated,
+            } => ServiceError::Timeout {
+                elapsed_ms: *elapsed_ms,
+                tokens_generated: *tokens_generated,
+            },
+            ServiceError::Unauthorized(m) => ServiceError::Unauthorized(m.clone()),
+            ServiceError::Forbidden(m) => ServiceError::Forbidden(m.clone()),
+            ServiceError::NotFound(m) => ServiceError::NotFound(m.clone()),
+            ServiceError::MethodNotAllowed(m) => ServiceError::MethodNotAllowed(m.clone()),
+            ServiceError::UnsupportedMediaType(m) => ServiceError::UnsupportedMediaType(m.clone()),
+            ServiceError::RateLimited { retry_after_secs } => ServiceError::RateLimited {
+                retry_after_secs: *retry_after_secs,
+            },
+            ServiceError::QuotaExceeded {
+                key,
+                quota,
+                remaining,
+                requested,
+            } => ServiceError::QuotaExceeded {
+                key: key.clone(),
+                quota: *quota,
+                remaining: *remaining,
+                requested: *requested,
+            },
+            ServiceError::PayloadTooLarge { limit_bytes } => ServiceError::PayloadTooLarge {
+                limit_bytes: *limit_bytes,
+            },
+            ServiceError::Overloaded {
+                message,
+                retry_after_secs,
+            } => ServiceError::Overloaded {
+                message: message.clone(),
+                retry_after_secs: *retry_after_secs,
+            },
+            ServiceError::ResourceExhausted {
+                message,
+                sequence_length,
+                retry_after_secs,
+            } => ServiceError::ResourceExhausted {
+                message: message.clone(),
+                sequence_length: *sequence_length,
+                retry_after_secs: *retry_after_secs,
+            },
+            ServiceError::Database(m) => ServiceError::Database(m.clone()),
+            ServiceError::NotImplemented(m) => ServiceError::NotImplemented(m.clone()),
+            ServiceError::Io(e) => ServiceError::Io(std::io::Error::new(e.kind(), e.to_string())),
+            ServiceError::Other(m) => ServiceError::Other(m.clone()),
+        }
+    }
+}
+
+impl IntoResponse for ServiceError {
+    fn into_response(self) -> Response {
+        let body = self.to_body();
+        let mut response = (self.status(), axum::Json(body.clone())).into_response();
+        // Lets outer layers re-render the body with request-scoped context.
+        response.extensions_mut().insert(body);
+        if let Some(secs) = self.retry_after_secs() {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Every variant's status and body, pinned so the error contract only
+    /// changes on purpose.
+    #[test]
+    fn bodies_are_stable() {
+        let cases = [
+            (
+                ServiceError::model_loading(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({"code": "model_loading", "message": "model is still loading"}),
+            ),
+            (
+                ServiceError::ModelLoading {
+                    retry_after_secs: Some(5),
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({
+                    "code": "model_loading",
+                    "message": "model is still loading",
+                    "details": {"retry_after_secs": 5},
+                }),
+            ),
+            (
+                ServiceError::ModelUnavailable("quantized".into()),
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({"code": "model_unavailable", "message": "model unavailable: quantized"}),
+            ),
+            (
+                ServiceError::BadRequest("prompt is empty".into()),
+                StatusCode::BAD_REQUEST,
+                json!({"code": "bad_request", "message": "invalid request: prompt is empty"}),
+            ),
+            (
+                ServiceError::validation("temperature", "must be positive"),
+                StatusCode::BAD_REQUEST,
+                json!({
+                    "code": "bad_request",
+                    "message": "invalid request: field 'temperature' must be positive",
+                    "details": {"field": "temperature"},
+                }),
+            ),
+            (
+                ServiceError::Unprocessable("unknown field `foo`".into()),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({
+                    "code": "unprocessable",
+                    "message": "invalid request body: unknown field `foo`",
+                }),
+            ),
+            (
+                ServiceError::IdempotencyConflict("abc".into()),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({
+                    "code": "idempotency_conflict",
+                    "message": "idempotency key \"abc\" was already used with a different request body",
+                }),
+            ),
+            (
+                ServiceError::Tokenizer("bad merges".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"code": "tokenizer", "message": "tokenizer error: bad merges"}),
+            ),
+            (
+                ServiceError::Inference("shape mismatch".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"code": "inference", "message": "model execution failed: shape mismatch"}),
+            ),
+            (
+                ServiceError::Quantization("no fbgemm".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"code": "quantization", "message": "quantization error: no fbgemm"}),
+            ),
+            (
+                ServiceError::Download("404".into()),
+                StatusCode::BAD_GATEWAY,
+                json!({"code": "download", "message": "artifact download failed: 404"}),
+            ),
+            (
+                ServiceError::Timeout {
+                    elapsed_ms: 30000,
+                    tokens_generated: 12,
+                },
+                StatusCode::GATEWAY_TIMEOUT,
+                json!({
+                    "code": "timeout",
+                    "message": "generation timed out after 30000 ms with 12 tokens generated",
+                    "details": {"elapsed_ms": 30000, "tokens_generated": 12},
+                }),
+            ),
+            (
+                ServiceError::Unauthorized("missing API key".into()),
+                StatusCode::UNAUTHORIZED,
+                json!({"code": "unauthorized", "message": "unauthorized: missing API key"}),
+            ),
+            (
+                ServiceError::Forbidden("admin only".into()),
+                StatusCode::FORBIDDEN,
+                json!({"code": "forbidden", "message": "forbidden: admin only"}),
+            ),
+            (
+                ServiceError::NotFound("/nope".into()),
+                StatusCode::NOT_FOUND,
+                json!({"code": "not_found", "message": "not found: /nope"}),
+            ),
+            (
+                ServiceError::MethodNotAllowed("GET /generate".into()),
+                StatusCode::METHOD_NOT_ALLOWED,
+                json!({
+                    "code": "method_not_allowed",
+                    "message": "method not allowed: GET /generate",
+                }),
+            ),
+            (
+                ServiceError::UnsupportedMediaType("text/plain".into()),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                json!({
+                    "code": "unsupported_media_type",
+                    "message": "unsupported media type: text/plain",
+                }),
+            ),
+            (
+                ServiceError::RateLimited {
+                    retry_after_secs: 2,
+                },
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({
+                    "code": "rate_limited",
+                    "message": "rate limit exceeded, retry after 2s",
+                    "details": {"retry_after_secs": 2},
+                }),
+            ),
+            (
+                ServiceError::QuotaExceeded {
+                    key: "team-a".into(),
+                    quota: 1000,
+                    remaining: 10,
+                    requested: 64,
+                },
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({
+                    "code": "quota_exceeded",
+                    "message": "token quota of key \"team-a\" exceeded: 64 tokens needed, 10 of 1000 left",
+                    "details": {"quota": 1000, "remaining": 10, "requested": 64},
+                }),
+            ),
+            (
+                ServiceError::PayloadTooLarge { limit_bytes: 1024 },
+                StatusCode::PAYLOAD_TOO_LARGE,
+                json!({
+                    "code": "payload_too_large",
+                    "message": "request body exceeds the 1024-byte limit",
+                    "details": {"limit_bytes": 1024},
+                }),
+            ),
+            (
+                ServiceError::Overloaded {
+                    message: "queue full".into(),
+                    retry_after_secs: None,
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({"code": "overloaded", "message": "service overloaded: queue full"}),
+            ),
+            (
+                ServiceError::ResourceExhausted {
+                    message: "CUDA out of memory".into(),
+                    sequence_length: Some(2048),
+                    retry_after_secs: Some(1),
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({
+                    "code": "resource_exhausted",
+                    "message": "resource exhausted: CUDA out of memory",
+                    "details": {"sequence_length": 2048, "retry_after_secs": 1},
+                }),
+            ),
+            (
+                ServiceError::Database("locked".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"code": "database", "message": "database error: locked"}),
+            ),
+            (
+                ServiceError::NotImplemented("/score".into()),
+                StatusCode::NOT_IMPLEMENTED,
+                json!({"code": "not_implemented", "message": "not supported: /score"}),
+            ),
+            (
+                ServiceError::Io(std::io::Error::other("disk full")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"code": "io", "message": "io error: disk full"}),
+            ),
+            (
+                ServiceError::Other("boom".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"code": "internal", "message": "other: boom"}),
+            ),
+        ];
+        for (err, status, error) in cases {
+            assert_eq!(err.status(), status, "{err:?}");
+            assert_eq!(
+                serde_json::to_value(err.to_body()).unwrap(),
+                json!({ "error": error }),
+                "{err:?}"
+            );
+        }
+    }
 
-        (status, axum::Json(body)).into_response()
+    #[test]
+    fn retry_after_header_matches_details() {
+        let response = ServiceError::RateLimited {
+            retry_after_secs: 7,
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+        assert!(response.extensions().get::<ErrorBody>().is_some());
     }
 }
@@ -1,48 +1,748 @@
-use std::{fs, path::Path, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, fs,
+    future::{self, Future},
+    path::Path,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::stream::{self, StreamExt};
 
-use serde::Serialize;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use crate::{
     config::AppConfig,
     error::ServiceError,
-    model::{GenerationRequest, GenerationResponse, ModelRegistry},
+    memory::{self, PeakProbe},
+    model::{
+        FinishReason, GenerationRequest, GenerationResponse, ModelRegistry, OnTimeout, Priority,
+    },
+    progress::ProgressRun,
+    store::StoredReport,
+    telemetry::ContentLogging,
+    templates,
 };
 
+/// Seed used for every generation in assertion mode, so golden outputs stay
+/// reproducible even if a model ignores greedy decoding.
+const ASSERT_SEED: u64 = 0;
+
 #[derive(Debug, Clone, Serialize)]
 pub struct BenchmarkSample {
     pub prompt: String,
+    /// Prompt template the sample is rendered with before generation.
+    pub template: Option<String>,
     pub reference_substring: Option<String>,
+    pub reference_alternatives: Vec<String>,
+    pub match_mode: MatchMode,
+    pub case_sensitive: bool,
+    /// Golden output checked in assertion mode.
+    pub expected_completion: Option<String>,
+    pub tolerance: Tolerance,
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// How a run is decoded and judged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EvaluationMode {
+    /// Configured sampling settings; reports latency and reference matches.
+    #[default]
+    Benchmark,
+    /// Greedy decoding with a fixed seed; each sample with an
+    /// `expected_completion` also passes or fails.
+    Assert,
+}
+
+/// How far the quantized completion may drift from `expected_completion`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tolerance {
+    #[default]
+    Exact,
+    /// The completion starts with the expected text.
+    Prefix,
+    /// At most this many character insertions, deletions or substitutions.
+    MaxEditDistance(usize),
+}
+
+impl Tolerance {
+    pub fn accepts(self, expected: &str, completion: &str) -> bool {
+        match self {
+            Tolerance::Exact => completion == expected,
+            Tolerance::Prefix => completion.starts_with(expected),
+            Tolerance::MaxEditDistance(max) => edit_distance(expected, completion) <= max,
+        }
+    }
+}
+
+/// Levenshtein distance in characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    #[default]
+    Substring,
+    Exact,
+    Regex,
+    AnyOf,
+}
+
+impl MatchMode {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "substring" => Some(MatchMode::Substring),
+            "exact" => Some(MatchMode::Exact),
+            "regex" => Some(MatchMode::Regex),
+            "any_of" => Some(MatchMode::AnyOf),
+            _ => None,
+        }
+    }
+}
+
+/// Reference check for a single sample, built once and applied to every
+/// response generated for that sample.
+#[derive(Debug, Clone)]
+pub enum ReferenceMatcher {
+    Substring {
+        needle: String,
+        case_sensitive: bool,
+    },
+    Exact {
+        expected: String,
+        case_sensitive: bool,
+    },
+    Regex(Regex),
+    AnyOf {
+        needles: Vec<String>,
+        case_sensitive: bool,
+    },
+}
+
+impl ReferenceMatcher {
+    pub fn from_sample(sample: &BenchmarkSample) -> Result<Option<Self>, String> {
+        Self::build(
+            sample.match_mode,
+            sample.reference_substring.as_deref(),
+            &sample.reference_alternatives,
+            sample.case_sensitive,
+        )
+    }
+
+    fn build(
+        match_mode: MatchMode,
+        reference: Option<&str>,
+        alternatives: &[String],
+        case_sensitive: bool,
+    ) -> Result<Option<Self>, String> {
+        let fold = |s: &str| {
+            if case_sensitive {
+                s.to_string()
+            } else {
+                s.to_lowercase()
+            }
+        };
+
+        if match_mode == MatchMode::AnyOf {
+            if alternatives.is_empty() {
+                return Err("match_mode 'any_of' requires 'reference_alternatives'".into());
+            }
+            return Ok(Some(ReferenceMatcher::AnyOf {
+                needles: alternatives.iter().map(|s| fold(s)).collect(),
+                case_sensitive,
+            }));
+        }
+
+        let Some(reference) = reference else {
+            return Ok(None);
+        };
+
+        let matcher = match match_mode {
+            MatchMode::Substring => ReferenceMatcher::Substring {
+                needle: fold(reference),
+                case_sensitive,
+            },
+            MatchMode::Exact => ReferenceMatcher::Exact {
+                expected: fold(reference.trim()),
+                case_sensitive,
+            },
+            MatchMode::Regex => {
+                let regex = RegexBuilder::new(reference)
+                    .case_insensitive(!case_sensitive)
+                    .build()
+                    .map_err(|e| format!("invalid regex '{reference}': {e}"))?;
+                ReferenceMatcher::Regex(regex)
+            }
+            MatchMode::AnyOf => unreachable!("handled above"),
+        };
+        Ok(Some(matcher))
+    }
+
+    pub fn matches(&self, completion: &str) -> bool {
+        let fold = |case_sensitive: bool| {
+            if case_sensitive {
+                completion.to_string()
+            } else {
+                completion.to_lowercase()
+            }
+        };
+        match self {
+            ReferenceMatcher::Substring {
+                needle,
+                case_sensitive,
+            } => fold(*case_sensitive).contains(needle.as_str()),
+            ReferenceMatcher::Exact {
+                expected,
+                case_sensitive,
+            } => fold(*case_sensitive).trim() == expected,
+            ReferenceMatcher::Regex(regex) => regex.is_match(completion),
+            ReferenceMatcher::AnyOf {
+                needles,
+                case_sensitive,
+            } => {
+                let haystack = fold(*case_sensitive);
+                needles
+                    .iter()
+                    .any(|needle| haystack.contains(needle.as_str()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct SampleReport {
     pub prompt: String,
-    pub quantized: GenerationResponse,
+    /// As the quantized model saw it; absent when the sample failed.
+    pub prompt_tokens: Option<usize>,
+    /// Absent when the sample failed.
+    pub quantized: Option<GenerationResponse>,
     pub baseline: Option<GenerationResponse>,
     pub reference_match_quantized: Option<bool>,
     pub reference_match_baseline: Option<bool>,
+    /// Why the sample produced no results; the rest of the run is unaffected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether the quantized completion is within tolerance of the expected
+    /// one; set only in assertion mode for samples that have one.
+    pub passed: Option<bool>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl SampleReport {
+    fn failed(prompt: String, err: &ServiceError, asserted: bool) -> Self {
+        Self {
+            prompt,
+            prompt_tokens: None,
+            quantized: None,
+            baseline: None,
+            reference_match_quantized: None,
+            reference_match_baseline: None,
+            error: Some(err.to_string()),
+            passed: asserted.then_some(false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct AggregateMetrics {
     pub quantized_avg_latency_ms: f64,
+    /// Deprecated: the mean of the legacy `tokens_per_second`.
+    #[schema(deprecated)]
     pub quantized_avg_tokens_per_s: f64,
+    #[serde(default)]
+    pub quantized_avg_prefill_tokens_per_s: f64,
+    pub quantized_avg_decode_tokens_per_s: f64,
+    pub quantized_avg_time_to_first_token_ms: f64,
     pub baseline_avg_latency_ms: Option<f64>,
+    /// Deprecated: the mean of the legacy `tokens_per_second`.
+    #[schema(deprecated)]
     pub baseline_avg_tokens_per_s: Option<f64>,
+    #[serde(default)]
+    pub baseline_avg_prefill_tokens_per_s: Option<f64>,
+    pub baseline_avg_decode_tokens_per_s: Option<f64>,
+    pub baseline_avg_time_to_first_token_ms: Option<f64>,
     pub quantized_reference_match_rate: Option<f64>,
     pub baseline_reference_match_rate: Option<f64>,
+    /// Number of samples allowed in flight at once. Generations still
+    /// serialize on each model's module lock, so a flat throughput at higher
+    /// concurrency is expected rather than a measurement error.
+    pub concurrency: usize,
+    pub wall_clock_ms: u128,
+    pub aggregate_tokens_per_s: f64,
+    /// Samples that errored; every other metric covers only the rest.
+    #[serde(default)]
+    pub failed_samples: usize,
+    /// Share of asserted samples that passed, failed samples included.
+    #[serde(default)]
+    pub pass_rate: Option<f64>,
+    /// Averages per prompt-length bucket; buckets without samples are left out.
+    #[serde(default)]
+    pub by_prompt_length: Vec<LengthBucket>,
+    /// Quantized minus baseline latency over samples timed on both models;
+    /// absent with fewer than two such samples.
+    #[serde(default)]
+    pub latency_difference: Option<PairedComparison>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// A paired t-test on per-sample differences.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct PairedComparison {
+    pub pairs: usize,
+    /// Negative when the quantized model is faster.
+    pub mean_difference_ms: f64,
+    pub ci95_low_ms: f64,
+    pub ci95_high_ms: f64,
+    /// The 95% interval excludes zero, i.e. p < 0.05 two-sided.
+    pub significant: bool,
+}
+
+/// Averages over the samples whose prompt length falls in
+/// `min_prompt_tokens..=max_prompt_tokens`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct LengthBucket {
+    pub min_prompt_tokens: usize,
+    /// Absent for the open-ended last bucket.
+    pub max_prompt_tokens: Option<usize>,
+    pub samples: usize,
+    pub quantized_avg_latency_ms: f64,
+    pub quantized_avg_time_to_first_token_ms: f64,
+    /// Deprecated: the mean of the legacy `tokens_per_second`.
+    #[schema(deprecated)]
+    pub quantized_avg_tokens_per_s: f64,
+    #[serde(default)]
+    pub quantized_avg_prefill_tokens_per_s: f64,
+    #[serde(default)]
+    pub quantized_avg_decode_tokens_per_s: f64,
+    pub baseline_avg_latency_ms: Option<f64>,
+    pub baseline_avg_time_to_first_token_ms: Option<f64>,
+    /// Deprecated: the mean of the legacy `tokens_per_second`.
+    #[schema(deprecated)]
+    pub baseline_avg_tokens_per_s: Option<f64>,
+    #[serde(default)]
+    pub baseline_avg_prefill_tokens_per_s: Option<f64>,
+    #[serde(default)]
+    pub baseline_avg_decode_tokens_per_s: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct EvaluationReport {
     pub samples: Vec<SampleReport>,
     pub aggregate: AggregateMetrics,
+    pub memory: MemoryReport,
+    /// The run was stopped early; only finished samples are included.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+}
+
+/// Encodings `/evaluate/report` serves a report in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    /// An aggregate table and a per-sample table, for pasting into documents.
+    Markdown,
+    /// One row per sample.
+    Csv,
+}
+
+/// Prompt characters the Markdown sample table shows before cutting off.
+pub const MARKDOWN_PROMPT_CHARS: usize = 60;
+
+impl EvaluationReport {
+    /// The aggregate metrics and one row per sample as Markdown tables, with
+    /// prompts cut to `prompt_chars` characters.
+    pub fn to_markdown(&self, prompt_chars: usize) -> String {
+        fn opt(value: Option<f64>, precision: usize) -> String {
+            value.map_or_else(|| "-".to_string(), |v| format!("{v:.precision$}"))
+        }
+        fn flag(value: Option<bool>) -> &'static str {
+            match value {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "-",
+            }
+        }
+
+        let aggregate = &self.aggregate;
+        let mut rows = vec![
+            (
+                "avg latency (ms)".to_string(),
+                format!("{:.1}", aggregate.quantized_avg_latency_ms),
+                opt(aggregate.baseline_avg_latency_ms, 1),
+            ),
+            (
+                "avg first token (ms)".to_string(),
+                format!("{:.1}", aggregate.quantized_avg_time_to_first_token_ms),
+                opt(aggregate.baseline_avg_time_to_first_token_ms, 1),
+            ),
+            (
+                "avg prefill tokens/s".to_string(),
+                format!("{:.2}", aggregate.quantized_avg_prefill_tokens_per_s),
+                opt(aggregate.baseline_avg_prefill_tokens_per_s, 2),
+            ),
+            (
+                "avg decode tokens/s".to_string(),
+                format!("{:.2}", aggregate.quantized_avg_decode_tokens_per_s),
+                opt(aggregate.baseline_avg_decode_tokens_per_s, 2),
+            ),
+            (
+                "avg tokens/s (legacy)".to_string(),
+                format!("{:.2}", aggregate.quantized_avg_tokens_per_s),
+                opt(aggregate.baseline_avg_tokens_per_s, 2),
+            ),
+            (
+                "reference match rate".to_string(),
+                opt(aggregate.quantized_reference_match_rate, 3),
+                opt(aggregate.baseline_reference_match_rate, 3),
+            ),
+        ];
+        if let Some(pass_rate) = aggregate.pass_rate {
+            rows.push((
+                "pass rate".to_string(),
+                format!("{pass_rate:.3}"),
+                "-".into(),
+            ));
+        }
+        for bucket in &aggregate.by_prompt_length {
+            let range = match bucket.max_prompt_tokens {
+                Some(max) => format!("{}-{max}", bucket.min_prompt_tokens),
+                None => format!("{}+", bucket.min_prompt_tokens),
+            };
+            rows.push((
+                format!("latency, {range} tok (ms)"),
+                format!("{:.1}", bucket.quantized_avg_latency_ms),
+                opt(bucket.baseline_avg_latency_ms, 1),
+            ));
+        }
+
+        let mut out =
+            String::from("## Aggregate\n\n| metric | quantized | baseline |\n|---|---:|---:|\n");
+        for (metric, quantized, baseline) in rows {
+            out.push_str(&format!("| {metric} | {quantized} | {baseline} |\n"));
+        }
+        out.push_str(&format!(
+            "\nConcurrency {}, wall clock {} ms, {:.2} tokens/s overall, {} failed.\n",
+            aggregate.concurrency,
+            aggregate.wall_clock_ms,
+            aggregate.aggregate_tokens_per_s,
+            aggregate.failed_samples
+        ));
+        if let Some(diff) = &aggregate.latency_difference {
+            out.push_str(&format!(
+                "Quantized minus baseline latency {:+.1} ms (95% CI {:+.1} to {:+.1}, n={}{}).\n",
+                diff.mean_difference_ms,
+                diff.ci95_low_ms,
+                diff.ci95_high_ms,
+                diff.pairs,
+                if diff.significant {
+                    ", significant"
+                } else {
+                    ""
+                }
+            ));
+        }
+        if self.interrupted {
+            out.push_str("The run was interrupted; only finished samples are included.\n");
+        }
+
+        out.push_str(
+            "\n## Samples\n\n\
+             | # | prompt | quantized ms | baseline ms | quantized match | baseline match | passed | error |\n\
+             |---:|---|---:|---:|---|---|---|---|\n",
+        );
+        for (index, sample) in self.samples.iter().enumerate() {
+            let latency = |response: &Option<GenerationResponse>| {
+                response
+                    .as_ref()
+                    .map_or_else(|| "-".to_string(), |r| r.total_time_ms.to_string())
+            };
+            out.push_str(&format!(
+                "| {index} | {} | {} | {} | {} | {} | {} | {} |\n",
+                markdown_cell(&shorten(&sample.prompt, prompt_chars)),
+                latency(&sample.quantized),
+                latency(&sample.baseline),
+                flag(sample.reference_match_quantized),
+                flag(sample.reference_match_baseline),
+                flag(sample.passed),
+                markdown_cell(sample.error.as_deref().unwrap_or("")),
+            ));
+        }
+        out
+    }
+
+    /// One row per sample with its prompt, timings and match flags; cells
+    /// that don't apply are left empty.
+    pub fn to_csv(&self) -> Result<String, ServiceError> {
+        fn cell<T: ToString>(value: Option<T>) -> String {
+            value.map(|v| v.to_string()).unwrap_or_default()
+        }
+
+        let failed = |e: csv::Error| ServiceError::Other(format!("writing CSV failed: {e}"));
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer
+            .write_record([
+                "index",
+                "prompt",
+                "prompt_tokens",
+                "quantized_latency_ms",
+                "quantized_time_to_first_token_ms",
+                "quantized_tokens_generated",
+                "quantized_prefill_tokens_per_s",
+                "quantized_decode_tokens_per_s",
+                "baseline_latency_ms",
+                "baseline_time_to_first_token_ms",
+                "baseline_tokens_generated",
+                "baseline_prefill_tokens_per_s",
+                "baseline_decode_tokens_per_s",
+                "reference_match_quantized",
+                "reference_match_baseline",
+                "passed",
+                "error",
+            ])
+            .map_err(failed)?;
+        for (index, sample) in self.samples.iter().enumerate() {
+            let timings = |response: &Option<GenerationResponse>| {
+                let response = response.as_ref();
+                [
+                    cell(response.map(|r| r.total_time_ms)),
+                    cell(response.map(|r| r.timings.time_to_first_token_ms)),
+                    cell(response.map(|r| r.tokens_generated)),
+                    cell(response.map(|r| r.prefill_tokens_per_second)),
+                    cell(response.map(|r| r.decode_tokens_per_second)),
+                ]
+            };
+            let mut record = vec![
+                index.to_string(),
+                sample.prompt.clone(),
+                cell(sample.prompt_tokens),
+            ];
+            record.extend(timings(&sample.quantized));
+            record.extend(timings(&sample.baseline));
+            record.extend([
+                cell(sample.reference_match_quantized),
+                cell(sample.reference_match_baseline),
+                cell(sample.passed),
+                sample.error.clone().unwrap_or_default(),
+            ]);
+            writer.write_record(&record).map_err(failed)?;
+        }
+        let bytes = writer
+            .into_inner()
+            .map_err(|e| ServiceError::Other(format!("writing CSV failed: {e}")))?;
+        String::from_utf8(bytes).map_err(|e| ServiceError::Other(e.to_string()))
+    }
+}
+
+/// `text` cut to `max_chars` characters, marked with an ellipsis when cut.
+fn shorten(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// `text` made safe for a Markdown table cell: pipes escaped and line
+/// breaks kept as `<br>`.
+fn markdown_cell(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace(['\n', '\r'], "<br>")
+}
+
+/// Process memory around the run; every field is absent where it can't be
+/// read (see [`memory`]).
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MemoryReport {
+    pub rss_before_bytes: Option<u64>,
+    pub rss_after_bytes: Option<u64>,
+    /// Largest rise in resident memory during a single generation.
+    pub quantized_peak_rss_delta_bytes: Option<u64>,
+    pub baseline_peak_rss_delta_bytes: Option<u64>,
+}
+
+/// How two stored runs differ, as returned by `/evaluate/compare`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ReportComparison {
+    pub base_id: i64,
+    pub candidate_id: i64,
+    pub metrics: Vec<MetricDelta>,
+    /// Prompts in both runs whose reference match changed.
+    pub flipped: Vec<MatchFlip>,
+    pub unmatched: UnmatchedSamples,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct MetricDelta {
+    pub metric: &'static str,
+    pub base: Option<f64>,
+    pub candidate: Option<f64>,
+    /// `candidate - base`, when both runs have the metric.
+    pub delta: Option<f64>,
+    /// `delta` as a percentage of `base`; absent when `base` is zero.
+    pub percent_change: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct MatchFlip {
+    pub prompt: String,
+    /// `quantized` or `baseline`.
+    pub model: &'static str,
+    pub base_match: bool,
+    pub candidate_match: bool,
+}
+
+/// Prompts present in only one of the two runs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct UnmatchedSamples {
+    pub base_only: Vec<String>,
+    pub candidate_only: Vec<String>,
+}
+
+impl fmt::Display for AggregateMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn opt(value: Option<f64>, precision: usize) -> String {
+            value.map_or_else(|| "-".to_string(), |v| format!("{v:.precision$}"))
+        }
+
+        writeln!(f, "{:<24} {:>12} {:>12}", "metric", "quantized", "baseline")?;
+        writeln!(
+            f,
+            "{:<24} {:>12.1} {:>12}",
+            "avg latency (ms)",
+            self.quantized_avg_latency_ms,
+            opt(self.baseline_avg_latency_ms, 1)
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>12.1} {:>12}",
+            "avg first token (ms)",
+            self.quantized_avg_time_to_first_token_ms,
+            opt(self.baseline_avg_time_to_first_token_ms, 1)
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>12.2} {:>12}",
+            "avg prefill tokens/s",
+            self.quantized_avg_prefill_tokens_per_s,
+            opt(self.baseline_avg_prefill_tokens_per_s, 2)
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>12.2} {:>12}",
+            "avg decode tokens/s",
+            self.quantized_avg_decode_tokens_per_s,
+            opt(self.baseline_avg_decode_tokens_per_s, 2)
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>12.2} {:>12}",
+            "avg tokens/s (legacy)",
+            self.quantized_avg_tokens_per_s,
+            opt(self.baseline_avg_tokens_per_s, 2)
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>12} {:>12}",
+            "reference match rate",
+            opt(self.quantized_reference_match_rate, 3),
+            opt(self.baseline_reference_match_rate, 3)
+        )?;
+        if let Some(pass_rate) = self.pass_rate {
+            writeln!(f, "{:<24} {:>12.3} {:>12}", "pass rate", pass_rate, "-")?;
+        }
+        if let Some(diff) = &self.latency_difference {
+            writeln!(
+                f,
+                "quantized - baseline latency {:+.1} ms (95% CI {:+.1} to {:+.1}, n={}{})",
+                diff.mean_difference_ms,
+                diff.ci95_low_ms,
+                diff.ci95_high_ms,
+                diff.pairs,
+                if diff.significant {
+                    ", significant"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        for bucket in &self.by_prompt_length {
+            let range = match bucket.max_prompt_tokens {
+                Some(max) => format!("{}-{max}", bucket.min_prompt_tokens),
+                None => format!("{}+", bucket.min_prompt_tokens),
+            };
+            writeln!(
+                f,
+                "{:<24} {:>12.1} {:>12}",
+                format!("latency, {range} tok (ms)"),
+                bucket.quantized_avg_latency_ms,
+                opt(bucket.baseline_avg_latency_ms, 1)
+            )?;
+        }
+        write!(
+            f,
+            "concurrency {} | wall clock {} ms | {:.2} tokens/s overall | {} failed",
+            self.concurrency, self.wall_clock_ms, self.aggregate_tokens_per_s, self.failed_samples
+        )
+    }
 }
 
 pub async fn run_benchmark(
     registry: Arc<ModelRegistry>,
     config: &AppConfig,
     samples: Vec<BenchmarkSample>,
+    mode: EvaluationMode,
+) -> Result<EvaluationReport, ServiceError> {
+    run_benchmark_until(registry, config, samples, mode, future::pending()).await
+}
+
+/// Like [`run_benchmark`], publishing each finished sample to `progress`.
+pub async fn run_benchmark_with_progress(
+    registry: Arc<ModelRegistry>,
+    config: &AppConfig,
+    samples: Vec<BenchmarkSample>,
+    mode: EvaluationMode,
+    progress: Option<&ProgressRun<'_>>,
+) -> Result<EvaluationReport, ServiceError> {
+    benchmark(registry, config, samples, mode, future::pending(), progress).await
+}
+
+/// Like [`run_benchmark`], but once `stop` resolves the samples still in
+/// flight are abandoned and the report covers only those that finished.
+pub async fn run_benchmark_until(
+    registry: Arc<ModelRegistry>,
+    config: &AppConfig,
+    samples: Vec<BenchmarkSample>,
+    mode: EvaluationMode,
+    stop: impl Future<Output = ()>,
+) -> Result<EvaluationReport, ServiceError> {
+    benchmark(registry, config, samples, mode, stop, None).await
+}
+
+async fn benchmark(
+    registry: Arc<ModelRegistry>,
+    config: &AppConfig,
+    samples: Vec<BenchmarkSample>,
+    mode: EvaluationMode,
+    stop: impl Future<Output = ()>,
+    progress: Option<&ProgressRun<'_>>,
 ) -> Result<EvaluationReport, ServiceError> {
     if samples.is_empty() {
         return Err(ServiceError::BadRequest(
@@ -50,134 +750,709 @@ pub async fn run_benchmark(
         ));
     }
 
-    let mut reports = Vec::with_capacity(samples.len());
-
-    for sample in samples {
-        let request = GenerationRequest {
-            prompt: sample.prompt.clone(),
-            max_new_tokens: Some(config.max_new_tokens),
-            temperature: Some(config.temperature),
-            top_k: Some(config.top_k),
-        };
-
-        let quantized = registry.generate_quantized(request, config).await?;
+    // Each generation gets `eval_timeout`; one that runs over keeps its
+    // partial output and counts as a mismatch.
+    let config = &AppConfig {
+        generation_timeout: config.eval_timeout,
+        ..config.clone()
+    };
 
-        let baseline = if registry.has_baseline() {
-            let request = GenerationRequest {
-                prompt: sample.prompt.clone(),
-                max_new_tokens: Some(config.max_new_tokens),
-                temperature: Some(config.temperature),
-                top_k: Some(config.top_k),
+    // Unknown templates and oversized prompts are caught before any
+    // generation runs; like generation errors, they only fail their sample.
+    let prompts: Vec<Result<String, ServiceError>> = samples
+        .iter()
+        .map(|sample| {
+            let prompt = match sample.template.as_deref() {
+                Some(name) => templates::render(config, name, &sample.prompt, None)?,
+                None => sample.prompt.clone(),
             };
-            Some(registry.generate_baseline(request, config).await?)
-        } else {
-            None
-        };
+            registry.check_prompt_fits(&prompt, config.max_new_tokens)?;
+            Ok(prompt)
+        })
+        .collect();
 
-        let reference_match_quantized = sample.reference_substring.as_ref().map(|needle| {
-            quantized
-                .completion
-                .to_lowercase()
-                .contains(&needle.to_lowercase())
-        });
-        let reference_match_baseline = sample.reference_substring.as_ref().and_then(|needle| {
-            baseline.as_ref().map(|resp| {
-                resp.completion
-                    .to_lowercase()
-                    .contains(&needle.to_lowercase())
-            })
-        });
+    let rss_before_bytes = memory::current().map(|usage| usage.rss_bytes);
+    let concurrency = config.eval_concurrency.max(1);
+    let mut slots: Vec<Option<SampleReport>> = vec![None; samples.len()];
+    let mut first_error: Option<(usize, ServiceError)> = None;
+    let started = Instant::now();
 
-        reports.push(SampleReport {
-            prompt: sample.prompt,
-            quantized,
-            baseline,
-            reference_match_quantized,
-            reference_match_baseline,
+    let mut pending = stream::iter(samples.into_iter().zip(prompts).enumerate())
+        .map(|(idx, (sample, prompt))| {
+            let registry = registry.clone();
+            async move {
+                let raw_prompt = sample.prompt.clone();
+                let asserted =
+                    mode == EvaluationMode::Assert && sample.expected_completion.is_some();
+                let result = match prompt {
+                    Ok(prompt) => {
+                        evaluate_sample(&registry, config, idx, sample, prompt, mode).await
+                    }
+                    Err(err) => Err(err),
+                };
+                (idx, raw_prompt, asserted, result)
+            }
+        })
+        .buffer_unordered(concurrency);
+
+    let total = slots.len();
+    let mut finished = 0;
+    let mut interrupted = false;
+    let mut stop = pin!(stop);
+    loop {
+        let next = tokio::select! {
+            next = pending.next() => next,
+            () = &mut stop => {
+                interrupted = true;
+                break;
+            }
+        };
+        let Some((idx, raw_prompt, asserted, result)) = next else {
+            break;
+        };
+        let report = result.unwrap_or_else(|err| {
+            tracing::warn!(idx, error = %err, "benchmark sample failed");
+            let report = SampleReport::failed(raw_prompt, &err, asserted);
+            if first_error.as_ref().is_none_or(|(first, _)| idx < *first) {
+                first_error = Some((idx, err));
+            }
+            report
         });
+        finished += 1;
+        tracing::info!(
+            idx,
+            finished,
+            total,
+            failed = report.error.is_some(),
+            latency_ms = report.quantized.as_ref().map(|q| q.total_time_ms),
+            "benchmark sample finished"
+        );
+        if let Some(progress) = progress {
+            progress.sample(idx, &report);
+        }
+        slots[idx] = Some(report);
+    }
+    if interrupted {
+        tracing::warn!(finished, total, "benchmark interrupted");
     }
 
-    let aggregate = summarize(&reports);
+    let wall_clock = started.elapsed();
+    let reports: Vec<SampleReport> = slots.into_iter().flatten().collect();
+    if reports.is_empty() {
+        return Err(ServiceError::Other(
+            "benchmark interrupted before any sample finished".into(),
+        ));
+    }
+    if reports.iter().all(|report| report.error.is_some())
+        && let Some((_, err)) = first_error
+    {
+        return Err(err);
+    }
+    let aggregate = summarize(
+        &reports,
+        wall_clock,
+        concurrency,
+        &config.eval_length_buckets,
+    );
+    let peak_delta = |model: fn(&SampleReport) -> Option<&GenerationResponse>| {
+        reports
+            .iter()
+            .filter_map(model)
+            .filter_map(|response| response.peak_rss_delta_bytes)
+            .max()
+    };
+    let memory = MemoryReport {
+        rss_before_bytes,
+        rss_after_bytes: memory::current().map(|usage| usage.rss_bytes),
+        quantized_peak_rss_delta_bytes: peak_delta(|r| r.quantized.as_ref()),
+        baseline_peak_rss_delta_bytes: peak_delta(|r| r.baseline.as_ref()),
+    };
 
     Ok(EvaluationReport {
         samples: reports,
         aggregate,
+        memory,
+        interrupted,
     })
 }
 
+async fn evaluate_sample(
+    registry: &ModelRegistry,
+    config: &AppConfig,
+    idx: usize,
+    sample: BenchmarkSample,
+    prompt: String,
+    mode: EvaluationMode,
+) -> Result<SampleReport, ServiceError> {
+    let matcher = ReferenceMatcher::from_sample(&sample)
+        .map_err(|e| ServiceError::BadRequest(format!("benchmark item {idx}: {e}")))?;
+
+    let request = GenerationRequest::new(prompt)
+        .with_max_new_tokens(config.max_new_tokens)
+        .with_top_k(config.top_k)
+        .with_priority(Priority::Batch)
+        .with_on_timeout(OnTimeout::Partial);
+    let request = match mode {
+        EvaluationMode::Benchmark => request.with_temperature(config.temperature),
+        EvaluationMode::Assert => request.with_temperature(0.0).with_seed(ASSERT_SEED),
+    };
+
+    // Measured here rather than via `measure_memory` so reports always have it.
+    let probe = PeakProbe::start();
+    let mut quantized = registry.generate_quantized(request.clone(), config).await?;
+    quantized.peak_rss_delta_bytes = probe.and_then(|probe| probe.peak_delta_bytes());
+
+    let baseline = if registry.has_baseline() {
+        let probe = PeakProbe::start();
+        let mut baseline = registry.generate_baseline(request, config).await?;
+        baseline.peak_rss_delta_bytes = probe.and_then(|probe| probe.peak_delta_bytes());
+        Some(baseline)
+    } else {
+        None
+    };
+
+    // A completion cut short by the timeout never counts as a match.
+    let complete = |resp: &GenerationResponse| resp.finish_reason != FinishReason::Timeout;
+    let reference_match_quantized = matcher
+        .as_ref()
+        .map(|m| complete(&quantized) && m.matches(&quantized.completion));
+    let reference_match_baseline = matcher.as_ref().and_then(|m| {
+        baseline
+            .as_ref()
+            .map(|resp| complete(resp) && m.matches(&resp.completion))
+    });
+    let passed = match (mode, sample.expected_completion.as_deref()) {
+        (EvaluationMode::Assert, Some(expected)) => {
+            Some(complete(&quantized) && sample.tolerance.accepts(expected, &quantized.completion))
+        }
+        _ => None,
+    };
+    let logging = ContentLogging::from_config(config);
+    if logging.enabled() {
+        tracing::debug!(
+            idx,
+            prompt = %logging.text(&quantized.prompt),
+            quantized_completion = %logging.text(&quantized.completion),
+            baseline_completion = baseline.as_ref().map(|b| logging.text(&b.completion)).map(tracing::field::display),
+            reference_match_quantized,
+            passed,
+            params = ?quantized.params,
+            "benchmark sample content"
+        );
+    }
+
+    Ok(SampleReport {
+        prompt: sample.prompt,
+        prompt_tokens: Some(quantized.usage.prompt_tokens),
+        quantized: Some(quantized),
+        baseline,
+        reference_match_quantized,
+        reference_match_baseline,
+        error: None,
+        passed,
+    })
+}
+
+/// Layouts a benchmark file can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// An array of sample objects.
+    Json,
+    /// One sample object per line.
+    Jsonl,
+    /// A header row naming the sample fields, then one sample per row.
+    Csv,
+}
+
+impl SampleFormat {
+    /// Picks the format from a file name's extension, defaulting to JSON.
+    pub fn from_file_name(name: &str) -> Self {
+        let extension = Path::new(name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("jsonl" | "ndjson") => SampleFormat::Jsonl,
+            Some("csv") => SampleFormat::Csv,
+            _ => SampleFormat::Json,
+        }
+    }
+}
+
 pub fn load_samples_from_path(path: &Path) -> Result<Vec<BenchmarkSample>, ServiceError> {
     let raw = fs::read_to_string(path)?;
-    let value: serde_json::Value = serde_json::from_str(&raw)
-        .map_err(|e| ServiceError::BadRequest(format!("invalid benchmark file: {e}")))?;
-
-    match value {
-        serde_json::Value::Array(items) => {
-            let mut samples = Vec::with_capacity(items.len());
-            for (idx, item) in items.into_iter().enumerate() {
-                let prompt = item.get("prompt").and_then(|v| v.as_str()).ok_or_else(|| {
+    let format = SampleFormat::from_file_name(&path.to_string_lossy());
+    parse_samples(&raw, format)
+}
+
+/// Parses benchmark samples from the contents of a benchmark file.
+pub fn parse_samples(
+    raw: &str,
+    format: SampleFormat,
+) -> Result<Vec<BenchmarkSample>, ServiceError> {
+    let items = match format {
+        SampleFormat::Json => {
+            let value: serde_json::Value = serde_json::from_str(raw)
+                .map_err(|e| ServiceError::BadRequest(format!("invalid benchmark file: {e}")))?;
+            match value {
+                serde_json::Value::Array(items) => items,
+                _ => {
+                    return Err(ServiceError::BadRequest(
+                        "benchmark file must be a JSON array".into(),
+                    ));
+                }
+            }
+        }
+        SampleFormat::Jsonl => raw
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(line_no, line)| {
+                serde_json::from_str(line).map_err(|e| {
+                    ServiceError::BadRequest(format!(
+                        "invalid benchmark file: line {}: {e}",
+                        line_no + 1
+                    ))
+                })
+            })
+            .collect::<Result<_, _>>()?,
+        SampleFormat::Csv => csv_items(raw)?,
+    };
+    items
+        .iter()
+        .enumerate()
+        .map(|(idx, item)| sample_from_value(idx, item))
+        .collect()
+}
+
+/// Turns CSV rows into the objects the JSON formats carry, so every format
+/// goes through the same field checks. Empty cells count as absent and
+/// `reference_alternatives` is split on `|`.
+fn csv_items(raw: &str) -> Result<Vec<serde_json::Value>, ServiceError> {
+    let invalid = |e: csv::Error| ServiceError::BadRequest(format!("invalid benchmark file: {e}"));
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::Headers)
+        .from_reader(raw.as_bytes());
+    let headers = reader.headers().map_err(invalid)?.clone();
+
+    let mut items = Vec::new();
+    for (idx, record) in reader.records().enumerate() {
+        let record = record.map_err(invalid)?;
+        let mut item = serde_json::Map::new();
+        for (column, cell) in headers.iter().zip(record.iter()) {
+            if cell.is_empty() {
+                continue;
+            }
+            let value = match column {
+                "reference_alternatives" => cell
+                    .split('|')
+                    .map(|alt| serde_json::Value::from(alt.trim()))
+                    .collect(),
+                "case_sensitive" => serde_json::Value::Bool(cell.trim().parse().map_err(|_| {
+                    ServiceError::BadRequest(format!(
+                        "benchmark item {idx}: case_sensitive must be true or false"
+                    ))
+                })?),
+                "max_edit_distance" => {
+                    serde_json::Value::from(cell.trim().parse::<u64>().map_err(|_| {
+                        ServiceError::BadRequest(format!(
+                            "benchmark item {idx}: max_edit_distance must be an integer"
+                        ))
+                    })?)
+                }
+                _ => serde_json::Value::from(cell),
+            };
+            item.insert(column.to_string(), value);
+        }
+        items.push(serde_json::Value::Object(item));
+    }
+    Ok(items)
+}
+
+fn sample_from_value(
+    idx: usize,
+    item: &serde_json::Value,
+) -> Result<BenchmarkSample, ServiceError> {
+    let prompt = item.get("prompt").and_then(|v| v.as_str()).ok_or_else(|| {
+        ServiceError::BadRequest(format!(
+            "benchmark item {idx} missing string field 'prompt'"
+        ))
+    })?;
+    let template = item
+        .get("template")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let reference = reference_from_value(&format!("benchmark item {idx}"), item)?;
+
+    let mut sample = BenchmarkSample {
+        prompt: prompt.to_string(),
+        template,
+        reference_substring: None,
+        reference_alternatives: Vec::new(),
+        match_mode: MatchMode::default(),
+        case_sensitive: false,
+        expected_completion: None,
+        tolerance: Tolerance::Exact,
+    };
+    reference.apply(&mut sample);
+    Ok(sample)
+}
+
+/// The reference fields of a sample, shared by benchmark files and
+/// reference files.
+#[derive(Debug, Clone)]
+pub struct SampleReference {
+    pub reference_substring: Option<String>,
+    pub reference_alternatives: Vec<String>,
+    pub match_mode: MatchMode,
+    pub case_sensitive: bool,
+    pub expected_completion: Option<String>,
+    pub tolerance: Tolerance,
+}
+
+impl SampleReference {
+    fn apply(self, sample: &mut BenchmarkSample) {
+        sample.reference_substring = self.reference_substring;
+        sample.reference_alternatives = self.reference_alternatives;
+        sample.match_mode = self.match_mode;
+        sample.case_sensitive = self.case_sensitive;
+        sample.expected_completion = self.expected_completion;
+        sample.tolerance = self.tolerance;
+    }
+}
+
+/// Reads and checks the reference fields of a sample or reference entry;
+/// `label` names the item in errors.
+fn reference_from_value(
+    label: &str,
+    item: &serde_json::Value,
+) -> Result<SampleReference, ServiceError> {
+    let reference_substring = item
+        .get("reference_substring")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let reference_alternatives: Vec<String> = item
+        .get("reference_alternatives")
+        .and_then(|v| v.as_array())
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let match_mode = match item.get("match_mode").and_then(|v| v.as_str()) {
+        Some(raw) => MatchMode::parse(raw).ok_or_else(|| {
+            ServiceError::BadRequest(format!("{label} has unknown match_mode '{raw}'"))
+        })?,
+        None => MatchMode::default(),
+    };
+    let case_sensitive = item
+        .get("case_sensitive")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let expected_completion = item
+        .get("expected_completion")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let tolerance = match item.get("tolerance").and_then(|v| v.as_str()) {
+        None | Some("exact") => Tolerance::Exact,
+        Some("prefix") => Tolerance::Prefix,
+        Some("edit_distance") => {
+            let max = item
+                .get("max_edit_distance")
+                .and_then(|v| v.as_u64())
+                .ok_or_else(|| {
                     ServiceError::BadRequest(format!(
-                        "benchmark item {idx} missing string field 'prompt'"
+                        "{label}: tolerance 'edit_distance' requires \
+                             an integer 'max_edit_distance'"
                     ))
                 })?;
-                let reference_substring = item
-                    .get("reference_substring")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string());
-                samples.push(BenchmarkSample {
-                    prompt: prompt.to_string(),
-                    reference_substring,
-                });
+            Tolerance::MaxEditDistance(max as usize)
+        }
+        Some(raw) => {
+            return Err(ServiceError::BadRequest(format!(
+                "{label} has unknown tolerance '{raw}'"
+            )));
+        }
+    };
+
+    ReferenceMatcher::build(
+        match_mode,
+        reference_substring.as_deref(),
+        &reference_alternatives,
+        case_sensitive,
+    )
+    .map_err(|e| ServiceError::BadRequest(format!("{label}: {e}")))?;
+    Ok(SampleReference {
+        reference_substring,
+        reference_alternatives,
+        match_mode,
+        case_sensitive,
+        expected_completion,
+        tolerance,
+    })
+}
+
+/// Reference data from `EVAL_REFERENCE_PATH`, keyed by sample index or by
+/// prompt.
+#[derive(Debug, Clone, Default)]
+pub struct ReferenceSet {
+    by_index: HashMap<usize, SampleReference>,
+    by_prompt: HashMap<String, SampleReference>,
+}
+
+impl ReferenceSet {
+    pub fn len(&self) -> usize {
+        self.by_index.len() + self.by_prompt.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies each entry's reference fields onto its sample. An entry keyed
+    /// by index wins over one keyed by the same sample's prompt, and either
+    /// replaces reference data the sample carries inline.
+    pub fn apply(&self, samples: &mut [BenchmarkSample]) {
+        let mut matched = 0;
+        for (idx, sample) in samples.iter_mut().enumerate() {
+            let Some(reference) = self
+                .by_index
+                .get(&idx)
+                .or_else(|| self.by_prompt.get(&sample.prompt))
+            else {
+                continue;
+            };
+            matched += 1;
+            if sample.reference_substring.is_some()
+                || !sample.reference_alternatives.is_empty()
+                || sample.expected_completion.is_some()
+            {
+                tracing::warn!(
+                    idx,
+                    "benchmark sample has inline reference data; using the reference file's"
+                );
             }
-            Ok(samples)
+            reference.clone().apply(sample);
+        }
+        if matched < self.len() {
+            tracing::warn!(
+                entries = self.len(),
+                matched,
+                "some reference entries match no benchmark sample"
+            );
         }
-        _ => Err(ServiceError::BadRequest(
-            "benchmark file must be a JSON array".into(),
-        )),
     }
 }
 
+pub fn load_references_from_path(path: &Path) -> Result<ReferenceSet, ServiceError> {
+    let raw = fs::read_to_string(path)?;
+    let format = SampleFormat::from_file_name(&path.to_string_lossy());
+    parse_references(&raw, format)
+}
+
+/// Parses a reference file. A JSON object maps prompts to entries; a JSON
+/// array, JSONL or CSV holds entries that each name their sample with
+/// `index` or `prompt`.
+pub fn parse_references(raw: &str, format: SampleFormat) -> Result<ReferenceSet, ServiceError> {
+    let invalid = |msg: String| ServiceError::BadRequest(format!("invalid reference file: {msg}"));
+    let mut set = ReferenceSet::default();
+    let items: Vec<serde_json::Value> = match format {
+        SampleFormat::Json => {
+            match serde_json::from_str(raw).map_err(|e| invalid(e.to_string()))? {
+                serde_json::Value::Object(entries) => {
+                    for (prompt, item) in entries {
+                        let reference =
+                            reference_from_value(&format!("reference entry '{prompt}'"), &item)?;
+                        set.by_prompt.insert(prompt, reference);
+                    }
+                    return Ok(set);
+                }
+                serde_json::Value::Array(items) => items,
+                _ => return Err(invalid("expected a JSON object or array".into())),
+            }
+        }
+        SampleFormat::Jsonl => raw
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(line_no, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| invalid(format!("line {}: {e}", line_no + 1)))
+            })
+            .collect::<Result<_, _>>()?,
+        SampleFormat::Csv => csv_items(raw)?,
+    };
+
+    for (n, item) in items.iter().enumerate() {
+        let label = format!("reference entry {n}");
+        let reference = reference_from_value(&label, item)?;
+        let duplicate = match (item.get("index"), item.get("prompt")) {
+            (Some(index), None) => {
+                let index = index
+                    .as_u64()
+                    .or_else(|| index.as_str().and_then(|s| s.trim().parse().ok()))
+                    .ok_or_else(|| {
+                        ServiceError::BadRequest(format!(
+                            "{label}: 'index' must be a non-negative integer"
+                        ))
+                    })?;
+                set.by_index.insert(index as usize, reference).is_some()
+            }
+            (None, Some(prompt)) => {
+                let prompt = prompt.as_str().ok_or_else(|| {
+                    ServiceError::BadRequest(format!("{label}: 'prompt' must be a string"))
+                })?;
+                set.by_prompt
+                    .insert(prompt.to_string(), reference)
+                    .is_some()
+            }
+            _ => {
+                return Err(ServiceError::BadRequest(format!(
+                    "{label} needs exactly one of 'index' or 'prompt'"
+                )));
+            }
+        };
+        if duplicate {
+            return Err(ServiceError::BadRequest(format!(
+                "{label} repeats an earlier entry's sample"
+            )));
+        }
+    }
+    Ok(set)
+}
+
+/// Loads `prompts` and, when a reference file is given, merges its
+/// reference data into the samples.
+pub fn load_samples_with_references(
+    prompts: &Path,
+    references: Option<&Path>,
+) -> Result<Vec<BenchmarkSample>, ServiceError> {
+    let mut samples = load_samples_from_path(prompts)?;
+    if let Some(path) = references {
+        load_references_from_path(path)?.apply(&mut samples);
+    }
+    Ok(samples)
+}
+
 pub fn fallback_samples() -> Vec<BenchmarkSample> {
     vec![
         BenchmarkSample {
             prompt: "Explain the benefits of quantizing a transformer model to int8 precision."
                 .to_string(),
+            template: None,
             reference_substring: Some("quant".to_string()),
+            reference_alternatives: Vec::new(),
+            match_mode: MatchMode::Substring,
+            case_sensitive: false,
+            expected_completion: None,
+            tolerance: Tolerance::Exact,
         },
         BenchmarkSample {
             prompt: "Summarize the rust borrow checker in one sentence.".to_string(),
+            template: None,
             reference_substring: Some("borrow".to_string()),
+            reference_alternatives: Vec::new(),
+            match_mode: MatchMode::Substring,
+            case_sensitive: false,
+            expected_completion: None,
+            tolerance: Tolerance::Exact,
         },
         BenchmarkSample {
             prompt: "Write a haiku about efficient machine learning inference.".to_string(),
+            template: None,
             reference_substring: Some("haiku".to_string()),
+            reference_alternatives: Vec::new(),
+            match_mode: MatchMode::Substring,
+            case_sensitive: false,
+            expected_completion: None,
+            tolerance: Tolerance::Exact,
         },
     ]
 }
 
-fn summarize(reports: &[SampleReport]) -> AggregateMetrics {
-    let quantized_avg_latency_ms = mean(reports.iter().map(|r| r.quantized.total_time_ms as f64));
-    let quantized_avg_tokens_per_s = mean(reports.iter().map(|r| r.quantized.tokens_per_second));
+fn summarize(
+    reports: &[SampleReport],
+    wall_clock: Duration,
+    concurrency: usize,
+    length_edges: &[usize],
+) -> AggregateMetrics {
+    // Cache hits replay an earlier run's timings, so they'd skew latency.
+    let quantized_runs = || {
+        reports
+            .iter()
+            .filter_map(|r| r.quantized.as_ref())
+            .filter(|r| !r.cached)
+    };
+    let quantized_avg_latency_ms = mean(quantized_runs().map(|r| r.total_time_ms as f64));
+    let quantized_avg_tokens_per_s = mean(quantized_runs().map(|r| r.tokens_per_second));
+    let quantized_avg_prefill_tokens_per_s =
+        mean(quantized_runs().map(|r| r.prefill_tokens_per_second));
+    let quantized_avg_decode_tokens_per_s =
+        mean(quantized_runs().map(|r| r.decode_tokens_per_second));
+    let quantized_avg_time_to_first_token_ms =
+        mean(quantized_runs().map(|r| r.timings.time_to_first_token_ms));
 
     let baseline_latencies: Vec<f64> = reports
         .iter()
         .filter_map(|r| r.baseline.as_ref())
+        .filter(|r| !r.cached)
         .map(|r| r.total_time_ms as f64)
         .collect();
     let baseline_avg_latency_ms = if baseline_latencies.is_empty() {
         None
     } else {
-        Some(mean(baseline_latencies.into_iter()))
+        Some(mean(baseline_latencies))
     };
 
     let baseline_tps: Vec<f64> = reports
         .iter()
         .filter_map(|r| r.baseline.as_ref())
+        .filter(|r| !r.cached)
         .map(|r| r.tokens_per_second)
         .collect();
     let baseline_avg_tokens_per_s = if baseline_tps.is_empty() {
         None
     } else {
-        Some(mean(baseline_tps.into_iter()))
+        Some(mean(baseline_tps))
+    };
+
+    let baseline_prefill_tps: Vec<f64> = reports
+        .iter()
+        .filter_map(|r| r.baseline.as_ref())
+        .filter(|r| !r.cached)
+        .map(|r| r.prefill_tokens_per_second)
+        .collect();
+    let baseline_avg_prefill_tokens_per_s = if baseline_prefill_tps.is_empty() {
+        None
+    } else {
+        Some(mean(baseline_prefill_tps))
+    };
+
+    let baseline_decode_tps: Vec<f64> = reports
+        .iter()
+        .filter_map(|r| r.baseline.as_ref())
+        .filter(|r| !r.cached)
+        .map(|r| r.decode_tokens_per_second)
+        .collect();
+    let baseline_avg_decode_tokens_per_s = if baseline_decode_tps.is_empty() {
+        None
+    } else {
+        Some(mean(baseline_decode_tps))
+    };
+
+    let baseline_ttft: Vec<f64> = reports
+        .iter()
+        .filter_map(|r| r.baseline.as_ref())
+        .filter(|r| !r.cached)
+        .map(|r| r.timings.time_to_first_token_ms)
+        .collect();
+    let baseline_avg_time_to_first_token_ms = if baseline_ttft.is_empty() {
+        None
+    } else {
+        Some(mean(baseline_ttft))
     };
 
     let quantized_reference_match_rate =
@@ -185,13 +1460,272 @@ fn summarize(reports: &[SampleReport]) -> AggregateMetrics {
     let baseline_reference_match_rate =
         compute_match_rate(reports.iter().filter_map(|r| r.reference_match_baseline));
 
+    let total_tokens: usize = reports
+        .iter()
+        .map(|r| {
+            r.quantized.as_ref().map_or(0, |q| q.tokens_generated)
+                + r.baseline.as_ref().map_or(0, |b| b.tokens_generated)
+        })
+        .sum();
+    let wall_secs = wall_clock.as_secs_f64();
+    let aggregate_tokens_per_s = if wall_secs > 0.0 {
+        total_tokens as f64 / wall_secs
+    } else {
+        0.0
+    };
+
     AggregateMetrics {
         quantized_avg_latency_ms,
         quantized_avg_tokens_per_s,
+        quantized_avg_prefill_tokens_per_s,
+        quantized_avg_decode_tokens_per_s,
+        quantized_avg_time_to_first_token_ms,
         baseline_avg_latency_ms,
         baseline_avg_tokens_per_s,
+        baseline_avg_prefill_tokens_per_s,
+        baseline_avg_decode_tokens_per_s,
+        baseline_avg_time_to_first_token_ms,
         quantized_reference_match_rate,
         baseline_reference_match_rate,
+        concurrency,
+        wall_clock_ms: wall_clock.as_millis(),
+        aggregate_tokens_per_s,
+        failed_samples: reports.iter().filter(|r| r.error.is_some()).count(),
+        pass_rate: compute_match_rate(reports.iter().filter_map(|r| r.passed)),
+        by_prompt_length: bucket_by_prompt_length(reports, length_edges),
+        latency_difference: paired_comparison(&latency_differences(reports)),
+    }
+}
+
+/// Quantized minus baseline latency for each sample run uncached on both.
+fn latency_differences(reports: &[SampleReport]) -> Vec<f64> {
+    reports
+        .iter()
+        .filter_map(|r| r.quantized.as_ref().zip(r.baseline.as_ref()))
+        .filter(|(quantized, baseline)| !quantized.cached && !baseline.cached)
+        .map(|(quantized, baseline)| quantized.total_time_ms as f64 - baseline.total_time_ms as f64)
+        .collect()
+}
+
+/// Mean of `differences` with a 95% t-interval. Needs at least two
+/// observations; identical differences give a zero-width interval.
+pub fn paired_comparison(differences: &[f64]) -> Option<PairedComparison> {
+    let n = differences.len();
+    if n < 2 {
+        return None;
+    }
+    let mean_difference = mean(differences.iter().copied());
+    let variance = differences
+        .iter()
+        .map(|d| (d - mean_difference).powi(2))
+        .sum::<f64>()
+        / (n - 1) as f64;
+    let half_width = t_critical_975(n - 1) * (variance / n as f64).sqrt();
+    let (low, high) = (mean_difference - half_width, mean_difference + half_width);
+    Some(PairedComparison {
+        pairs: n,
+        mean_difference_ms: mean_difference,
+        ci95_low_ms: low,
+        ci95_high_ms: high,
+        significant: low > 0.0 || high < 0.0,
+    })
+}
+
+/// Two-sided 95% critical value of Student's t with `df` degrees of freedom.
+fn t_critical_975(df: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+        2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+        2.052, 2.048, 2.045, 2.042,
+    ];
+    if let Some(&t) = df.checked_sub(1).and_then(|idx| TABLE.get(idx)) {
+        return t;
+    }
+    // Cornish-Fisher expansion around the normal quantile; within 1e-4
+    // of the exact value past the table.
+    let z: f64 = 1.959_963_985;
+    let v = df as f64;
+    z + (z.powi(3) + z) / (4.0 * v)
+        + (5.0 * z.powi(5) + 16.0 * z.powi(3) + 3.0 * z) / (96.0 * v.powi(2))
+        + (3.0 * z.powi(7) + 19.0 * z.powi(5) + 17.0 * z.powi(3) - 15.0 * z) / (384.0 * v.powi(3))
+}
+
+/// Splits samples at the inclusive upper bounds in `edges` (ascending) and
+/// averages each non-empty bucket. Failed samples have no length and are
+/// skipped; cached runs are left out of the averages as in [`summarize`].
+pub fn bucket_by_prompt_length(reports: &[SampleReport], edges: &[usize]) -> Vec<LengthBucket> {
+    let mut buckets: Vec<Vec<&SampleReport>> = vec![Vec::new(); edges.len() + 1];
+    for report in reports {
+        if let Some(tokens) = report.prompt_tokens {
+            buckets[edges.partition_point(|&edge| edge < tokens)].push(report);
+        }
+    }
+    buckets
+        .into_iter()
+        .enumerate()
+        .filter(|(_, members)| !members.is_empty())
+        .map(|(idx, members)| {
+            let quantized: Vec<&GenerationResponse> = members
+                .iter()
+                .filter_map(|r| r.quantized.as_ref())
+                .filter(|r| !r.cached)
+                .collect();
+            let baseline: Vec<&GenerationResponse> = members
+                .iter()
+                .filter_map(|r| r.baseline.as_ref())
+                .filter(|r| !r.cached)
+                .collect();
+            let baseline_mean = |value: fn(&GenerationResponse) -> f64| {
+                (!baseline.is_empty()).then(|| mean(baseline.iter().map(|r| value(r))))
+            };
+            LengthBucket {
+                min_prompt_tokens: if idx == 0 { 0 } else { edges[idx - 1] + 1 },
+                max_prompt_tokens: edges.get(idx).copied(),
+                samples: members.len(),
+                quantized_avg_latency_ms: mean(quantized.iter().map(|r| r.total_time_ms as f64)),
+                quantized_avg_time_to_first_token_ms: mean(
+                    quantized.iter().map(|r| r.timings.time_to_first_token_ms),
+                ),
+                quantized_avg_tokens_per_s: mean(quantized.iter().map(|r| r.tokens_per_second)),
+                quantized_avg_prefill_tokens_per_s: mean(
+                    quantized.iter().map(|r| r.prefill_tokens_per_second),
+                ),
+                quantized_avg_decode_tokens_per_s: mean(
+                    quantized.iter().map(|r| r.decode_tokens_per_second),
+                ),
+                baseline_avg_latency_ms: baseline_mean(|r| r.total_time_ms as f64),
+                baseline_avg_time_to_first_token_ms: baseline_mean(|r| {
+                    r.timings.time_to_first_token_ms
+                }),
+                baseline_avg_tokens_per_s: baseline_mean(|r| r.tokens_per_second),
+                baseline_avg_prefill_tokens_per_s: baseline_mean(|r| r.prefill_tokens_per_second),
+                baseline_avg_decode_tokens_per_s: baseline_mean(|r| r.decode_tokens_per_second),
+            }
+        })
+        .collect()
+}
+
+/// Diffs two stored runs. Samples are paired by exact prompt; a prompt that
+/// repeats is paired occurrence by occurrence.
+pub fn compare_reports(base: &StoredReport, candidate: &StoredReport) -> ReportComparison {
+    type Metric = fn(&AggregateMetrics) -> Option<f64>;
+    const METRICS: &[(&str, Metric)] = &[
+        ("quantized_avg_latency_ms", |m| {
+            Some(m.quantized_avg_latency_ms)
+        }),
+        ("quantized_avg_time_to_first_token_ms", |m| {
+            Some(m.quantized_avg_time_to_first_token_ms)
+        }),
+        ("quantized_avg_tokens_per_s", |m| {
+            Some(m.quantized_avg_tokens_per_s)
+        }),
+        ("quantized_avg_prefill_tokens_per_s", |m| {
+            Some(m.quantized_avg_prefill_tokens_per_s)
+        }),
+        ("quantized_avg_decode_tokens_per_s", |m| {
+            Some(m.quantized_avg_decode_tokens_per_s)
+        }),
+        ("quantized_reference_match_rate", |m| {
+            m.quantized_reference_match_rate
+        }),
+        ("baseline_avg_latency_ms", |m| m.baseline_avg_latency_ms),
+        ("baseline_avg_time_to_first_token_ms", |m| {
+            m.baseline_avg_time_to_first_token_ms
+        }),
+        ("baseline_avg_tokens_per_s", |m| m.baseline_avg_tokens_per_s),
+        ("baseline_avg_prefill_tokens_per_s", |m| {
+            m.baseline_avg_prefill_tokens_per_s
+        }),
+        ("baseline_avg_decode_tokens_per_s", |m| {
+            m.baseline_avg_decode_tokens_per_s
+        }),
+        ("baseline_reference_match_rate", |m| {
+            m.baseline_reference_match_rate
+        }),
+        ("aggregate_tokens_per_s", |m| Some(m.aggregate_tokens_per_s)),
+        ("pass_rate", |m| m.pass_rate),
+    ];
+    let metrics = METRICS
+        .iter()
+        .map(|&(metric, get)| {
+            let base = get(&base.evaluation.aggregate);
+            let candidate = get(&candidate.evaluation.aggregate);
+            let delta = base
+                .zip(candidate)
+                .map(|(base, candidate)| candidate - base);
+            let percent_change = base
+                .zip(delta)
+                .filter(|&(base, _)| base != 0.0)
+                .map(|(base, delta)| delta / base.abs() * 100.0);
+            MetricDelta {
+                metric,
+                base,
+                candidate,
+                delta,
+                percent_change,
+            }
+        })
+        .collect();
+
+    let mut by_prompt: HashMap<&str, VecDeque<usize>> = HashMap::new();
+    for (idx, sample) in candidate.samples.iter().enumerate() {
+        by_prompt
+            .entry(sample.prompt.as_str())
+            .or_default()
+            .push_back(idx);
+    }
+    let mut paired = vec![false; candidate.samples.len()];
+    let mut flipped = Vec::new();
+    let mut unmatched = UnmatchedSamples::default();
+    for before in &base.samples {
+        let Some(idx) = by_prompt
+            .get_mut(before.prompt.as_str())
+            .and_then(VecDeque::pop_front)
+        else {
+            unmatched.base_only.push(before.prompt.clone());
+            continue;
+        };
+        paired[idx] = true;
+        let after = &candidate.samples[idx];
+        let checks = [
+            (
+                "quantized",
+                before.reference_match_quantized,
+                after.reference_match_quantized,
+            ),
+            (
+                "baseline",
+                before.reference_match_baseline,
+                after.reference_match_baseline,
+            ),
+        ];
+        for (model, base_match, candidate_match) in checks {
+            if let (Some(base_match), Some(candidate_match)) = (base_match, candidate_match)
+                && base_match != candidate_match
+            {
+                flipped.push(MatchFlip {
+                    prompt: before.prompt.clone(),
+                    model,
+                    base_match,
+                    candidate_match,
+                });
+            }
+        }
+    }
+    unmatched.candidate_only = candidate
+        .samples
+        .iter()
+        .zip(paired)
+        .filter(|(_, paired)| !paired)
+        .map(|(sample, _)| sample.prompt.clone())
+        .collect();
+
+    ReportComparison {
+        base_id: base.evaluation.id,
+        candidate_id: candidate.evaluation.id,
+        metrics,
+        flipped,
+        unmatched,
     }
 }
 
@@ -226,3 +1760,626 @@ where
         Some(matches as f64 / count as f64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::{
+        model::{
+            Backend,
+            testing::{FakeModel, gpt2, greedy, next_token},
+        },
+        store::{StoredEvaluation, StoredSample},
+    };
+
+    fn matcher(sample: serde_json::Value) -> ReferenceMatcher {
+        let samples = parse_samples(&serde_json::json!([sample]).to_string(), SampleFormat::Json)
+            .expect("valid sample");
+        ReferenceMatcher::from_sample(&samples[0])
+            .expect("valid reference")
+            .expect("sample has a reference")
+    }
+
+    #[test]
+    fn substring_is_the_default_and_ignores_case() {
+        let matcher = matcher(serde_json::json!({
+            "prompt": "Neither a borrower nor a lender",
+            "reference_substring": "Borrow",
+        }));
+        assert!(matcher.matches("be; for loan oft loses both itself and BORROWED trouble"));
+        assert!(!matcher.matches("be"));
+    }
+
+    #[test]
+    fn exact_compares_the_trimmed_completion() {
+        let matcher = matcher(serde_json::json!({
+            "prompt": "The capital of France is",
+            "reference_substring": "Paris",
+            "match_mode": "exact",
+        }));
+        assert!(matcher.matches(" paris\n"));
+        assert!(!matcher.matches(" Paris, of course"));
+    }
+
+    #[test]
+    fn regex_honours_case_sensitivity() {
+        let matcher = matcher(serde_json::json!({
+            "prompt": "Neither a borrower nor a lender",
+            "reference_substring": r"\bborrow\b",
+            "match_mode": "regex",
+            "case_sensitive": true,
+        }));
+        assert!(matcher.matches("never borrow"));
+        assert!(!matcher.matches("borrowed trouble"));
+        assert!(!matcher.matches("never Borrow"));
+    }
+
+    #[test]
+    fn any_of_accepts_each_alternative() {
+        let matcher = matcher(serde_json::json!({
+            "prompt": "2 + 2 =",
+            "match_mode": "any_of",
+            "reference_alternatives": ["4", "four"],
+        }));
+        assert!(matcher.matches(" 4"));
+        assert!(matcher.matches(" Four"));
+        assert!(!matcher.matches(" 5"));
+    }
+
+    #[test]
+    fn any_of_without_alternatives_is_rejected() {
+        let raw = r#"[{"prompt": "2 + 2 =", "match_mode": "any_of"}]"#;
+        let err = parse_samples(raw, SampleFormat::Json).unwrap_err();
+        assert!(
+            matches!(&err, ServiceError::BadRequest(message) if message.contains("benchmark item 0")),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn invalid_regex_names_the_sample() {
+        let raw = r#"[
+            {"prompt": "fine", "reference_substring": "ok"},
+            {"prompt": "broken", "reference_substring": "(unclosed", "match_mode": "regex"}
+        ]"#;
+        let err = parse_samples(raw, SampleFormat::Json).unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            err.to_string().contains("benchmark item 1") && err.to_string().contains("(unclosed"),
+            "{err}"
+        );
+    }
+
+    /// Three samples; the last carries an inline reference.
+    fn referenced(references: &str, format: SampleFormat) -> Vec<BenchmarkSample> {
+        let mut samples = parse_samples(
+            r#"[
+                {"prompt": "one"},
+                {"prompt": "two"},
+                {"prompt": "three", "reference_substring": "inline"}
+            ]"#,
+            SampleFormat::Json,
+        )
+        .unwrap();
+        parse_references(references, format)
+            .unwrap()
+            .apply(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn references_keyed_by_index_reach_their_samples() {
+        let samples = referenced(
+            "{\"index\": 0, \"reference_substring\": \"uno\"}\n\
+             {\"index\": \"2\", \"expected_completion\": \" tres\", \"tolerance\": \"prefix\"}\n",
+            SampleFormat::Jsonl,
+        );
+        assert_eq!(samples[0].reference_substring.as_deref(), Some("uno"));
+        // Without an entry a sample is left as it was.
+        assert_eq!(samples[1].reference_substring, None);
+        assert_eq!(samples[1].expected_completion, None);
+        // The file's entry replaces the inline reference.
+        assert_eq!(samples[2].reference_substring, None);
+        assert_eq!(samples[2].expected_completion.as_deref(), Some(" tres"));
+        assert_eq!(samples[2].tolerance, Tolerance::Prefix);
+    }
+
+    #[test]
+    fn references_keyed_by_prompt_reach_their_samples() {
+        let samples = referenced(
+            r#"{"two": {"reference_alternatives": ["dos", "deux"], "match_mode": "any_of"}}"#,
+            SampleFormat::Json,
+        );
+        assert!(samples[0].reference_alternatives.is_empty());
+        assert_eq!(samples[1].reference_alternatives, ["dos", "deux"]);
+        assert_eq!(samples[1].match_mode, MatchMode::AnyOf);
+        assert_eq!(samples[2].reference_substring.as_deref(), Some("inline"));
+
+        // An index beats the same sample's prompt.
+        let samples = referenced(
+            "index,prompt,reference_substring\n1,,by index\n,two,by prompt\n",
+            SampleFormat::Csv,
+        );
+        assert_eq!(samples[1].reference_substring.as_deref(), Some("by index"));
+    }
+
+    #[test]
+    fn malformed_reference_entries_are_named() {
+        let cases = [
+            (
+                r#"[{"reference_substring": "x"}]"#,
+                "reference entry 0 needs exactly one of",
+            ),
+            (
+                r#"[{"index": 0, "prompt": "one"}]"#,
+                "reference entry 0 needs exactly one of",
+            ),
+            (r#"[{"index": -1}]"#, "reference entry 0: 'index' must be"),
+            (
+                r#"[{"prompt": 7}]"#,
+                "reference entry 0: 'prompt' must be a string",
+            ),
+            (
+                r#"[{"index": 0}, {"index": 0}]"#,
+                "reference entry 1 repeats an earlier entry's sample",
+            ),
+            (
+                r#"{"one": {"match_mode": "fuzzy"}}"#,
+                "reference entry 'one' has unknown match_mode 'fuzzy'",
+            ),
+            (r#""one""#, "expected a JSON object or array"),
+        ];
+        for (raw, message) in cases {
+            let err = parse_references(raw, SampleFormat::Json).unwrap_err();
+            assert_eq!(err.status(), StatusCode::BAD_REQUEST, "{raw}");
+            assert!(err.to_string().contains(message), "{raw}: {err}");
+        }
+        let err = parse_references("{\"index\": 0}\nnot json\n", SampleFormat::Jsonl).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+    }
+
+    #[test]
+    fn reference_files_are_merged_on_load() {
+        let dir = std::env::temp_dir().join(format!("qls-references-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let prompts = dir.join("prompts.jsonl");
+        let references = dir.join("references.json");
+        fs::write(&prompts, "{\"prompt\": \"one\"}\n{\"prompt\": \"two\"}\n").unwrap();
+        fs::write(&references, r#"{"two": {"reference_substring": "dos"}}"#).unwrap();
+        let samples = load_samples_with_references(&prompts, Some(&references)).unwrap();
+        assert_eq!(samples[0].reference_substring, None);
+        assert_eq!(samples[1].reference_substring.as_deref(), Some("dos"));
+    }
+
+    fn aggregate() -> AggregateMetrics {
+        AggregateMetrics {
+            quantized_avg_latency_ms: 0.0,
+            quantized_avg_tokens_per_s: 0.0,
+            quantized_avg_prefill_tokens_per_s: 0.0,
+            quantized_avg_decode_tokens_per_s: 0.0,
+            quantized_avg_time_to_first_token_ms: 0.0,
+            baseline_avg_latency_ms: None,
+            baseline_avg_tokens_per_s: None,
+            baseline_avg_prefill_tokens_per_s: None,
+            baseline_avg_decode_tokens_per_s: None,
+            baseline_avg_time_to_first_token_ms: None,
+            quantized_reference_match_rate: None,
+            baseline_reference_match_rate: None,
+            concurrency: 1,
+            wall_clock_ms: 0,
+            aggregate_tokens_per_s: 0.0,
+            failed_samples: 0,
+            pass_rate: None,
+            by_prompt_length: Vec::new(),
+            latency_difference: None,
+        }
+    }
+
+    fn stored(
+        id: i64,
+        aggregate: AggregateMetrics,
+        samples: &[(&str, Option<bool>, Option<bool>)],
+    ) -> StoredReport {
+        StoredReport {
+            evaluation: StoredEvaluation {
+                id,
+                created_at: 0,
+                aggregate,
+            },
+            samples: samples
+                .iter()
+                .map(|&(prompt, quantized, baseline)| StoredSample {
+                    prompt: prompt.to_string(),
+                    quantized_completion: String::new(),
+                    quantized_latency_ms: 0,
+                    baseline_completion: None,
+                    baseline_latency_ms: None,
+                    reference_match_quantized: quantized,
+                    reference_match_baseline: baseline,
+                    error: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn metric<'a>(comparison: &'a ReportComparison, name: &str) -> &'a MetricDelta {
+        comparison
+            .metrics
+            .iter()
+            .find(|m| m.metric == name)
+            .unwrap_or_else(|| panic!("no {name} in the comparison"))
+    }
+
+    #[test]
+    fn metric_deltas_are_candidate_minus_base() {
+        let base = stored(
+            1,
+            AggregateMetrics {
+                quantized_avg_latency_ms: 200.0,
+                baseline_avg_latency_ms: Some(400.0),
+                ..aggregate()
+            },
+            &[],
+        );
+        let candidate = stored(
+            2,
+            AggregateMetrics {
+                quantized_avg_latency_ms: 150.0,
+                aggregate_tokens_per_s: 12.5,
+                ..aggregate()
+            },
+            &[],
+        );
+        let comparison = compare_reports(&base, &candidate);
+        assert_eq!((comparison.base_id, comparison.candidate_id), (1, 2));
+        assert_eq!(
+            metric(&comparison, "quantized_avg_latency_ms"),
+            &MetricDelta {
+                metric: "quantized_avg_latency_ms",
+                base: Some(200.0),
+                candidate: Some(150.0),
+                delta: Some(-50.0),
+                percent_change: Some(-25.0),
+            }
+        );
+        // Only one side has it, so there is nothing to subtract.
+        let baseline = metric(&comparison, "baseline_avg_latency_ms");
+        assert_eq!((baseline.delta, baseline.percent_change), (None, None));
+        // A zero base has a delta but no percentage.
+        let throughput = metric(&comparison, "aggregate_tokens_per_s");
+        assert_eq!(throughput.delta, Some(12.5));
+        assert_eq!(throughput.percent_change, None);
+    }
+
+    #[test]
+    fn flips_are_reported_per_model() {
+        let base = stored(
+            1,
+            aggregate(),
+            &[
+                ("a", Some(true), Some(true)),
+                ("b", Some(false), Some(true)),
+                ("c", None, Some(false)),
+            ],
+        );
+        let candidate = stored(
+            2,
+            aggregate(),
+            &[
+                ("c", Some(true), Some(false)),
+                ("b", Some(true), Some(false)),
+                ("a", Some(true), Some(true)),
+            ],
+        );
+        let comparison = compare_reports(&base, &candidate);
+        assert_eq!(
+            comparison.flipped,
+            [
+                MatchFlip {
+                    prompt: "b".into(),
+                    model: "quantized",
+                    base_match: false,
+                    candidate_match: true,
+                },
+                MatchFlip {
+                    prompt: "b".into(),
+                    model: "baseline",
+                    base_match: true,
+                    candidate_match: false,
+                },
+            ]
+        );
+        assert_eq!(comparison.unmatched, UnmatchedSamples::default());
+    }
+
+    #[test]
+    fn repeated_prompts_pair_in_order_and_leftovers_are_unmatched() {
+        let base = stored(
+            1,
+            aggregate(),
+            &[
+                ("same", Some(true), None),
+                ("same", Some(false), None),
+                ("gone", Some(true), None),
+            ],
+        );
+        let candidate = stored(
+            2,
+            aggregate(),
+            &[
+                ("new", Some(true), None),
+                ("same", Some(true), None),
+                ("same", Some(true), None),
+                ("same", Some(false), None),
+            ],
+        );
+        let comparison = compare_reports(&base, &candidate);
+        // The second base "same" meets the second candidate "same".
+        assert_eq!(comparison.flipped.len(), 1);
+        assert_eq!(comparison.flipped[0].prompt, "same");
+        assert!(!comparison.flipped[0].base_match);
+        assert_eq!(
+            comparison.unmatched,
+            UnmatchedSamples {
+                base_only: vec!["gone".into()],
+                candidate_only: vec!["new".into(), "same".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("flaw", "lawn"), 2);
+        // One substitution, not two bytes.
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn each_tolerance_accepts_what_it_promises() {
+        assert!(Tolerance::Exact.accepts(" Paris", " Paris"));
+        assert!(!Tolerance::Exact.accepts(" Paris", " Paris."));
+        assert!(!Tolerance::Exact.accepts(" Paris", "Paris"));
+
+        assert!(Tolerance::Prefix.accepts(" Paris", " Paris, France"));
+        assert!(!Tolerance::Prefix.accepts(" Paris, France", " Paris"));
+
+        assert!(Tolerance::MaxEditDistance(0).accepts("abc", "abc"));
+        assert!(!Tolerance::MaxEditDistance(0).accepts("abc", "abd"));
+        assert!(Tolerance::MaxEditDistance(2).accepts("kitten", "sittin"));
+        assert!(!Tolerance::MaxEditDistance(2).accepts("kitten", "sitting"));
+    }
+
+    /// A real generation with its timings pinned so reports come out the
+    /// same on every run.
+    fn response(total_time_ms: u128, time_to_first_token_ms: f64) -> GenerationResponse {
+        let mut response = FakeModel::new("fake", next_token)
+            .generate(&gpt2(), "Hello", &greedy(2), None)
+            .expect("fake generation");
+        response.total_time_ms = total_time_ms;
+        response.timings.time_to_first_token_ms = time_to_first_token_ms;
+        response.tokens_per_second = 20.0;
+        response.prefill_tokens_per_second = 100.0;
+        response.decode_tokens_per_second = 10.0;
+        response
+    }
+
+    fn sample(
+        prompt: &str,
+        prompt_tokens: usize,
+        quantized: GenerationResponse,
+        baseline: Option<GenerationResponse>,
+    ) -> SampleReport {
+        SampleReport {
+            prompt: prompt.to_string(),
+            prompt_tokens: Some(prompt_tokens),
+            quantized: Some(quantized),
+            baseline,
+            reference_match_quantized: None,
+            reference_match_baseline: None,
+            error: None,
+            passed: None,
+        }
+    }
+
+    #[test]
+    fn buckets_split_at_inclusive_edges() {
+        let reports = [
+            sample("a", 5, response(100, 10.0), None),
+            sample("b", 32, response(200, 30.0), Some(response(300, 50.0))),
+            sample("c", 33, response(40, 4.0), None),
+            sample("d", 600, response(50, 5.0), Some(response(70, 7.0))),
+            SampleReport::failed(
+                "e".into(),
+                &ServiceError::Inference("out of memory".into()),
+                false,
+            ),
+        ];
+        let buckets = bucket_by_prompt_length(&reports, &[32, 128, 512]);
+        let summary: Vec<_> = buckets
+            .iter()
+            .map(|b| {
+                (
+                    b.min_prompt_tokens,
+                    b.max_prompt_tokens,
+                    b.samples,
+                    b.quantized_avg_latency_ms,
+                    b.quantized_avg_time_to_first_token_ms,
+                    b.baseline_avg_latency_ms,
+                )
+            })
+            .collect();
+        // 129-512 has no samples and is left out; the failed sample has no
+        // length and lands nowhere.
+        assert_eq!(
+            summary,
+            [
+                (0, Some(32), 2, 150.0, 20.0, Some(300.0)),
+                (33, Some(128), 1, 40.0, 4.0, None),
+                (513, None, 1, 50.0, 5.0, Some(70.0)),
+            ]
+        );
+        assert_eq!(buckets[0].quantized_avg_decode_tokens_per_s, 10.0);
+        assert_eq!(buckets[0].baseline_avg_prefill_tokens_per_s, Some(100.0));
+    }
+
+    #[test]
+    fn cached_runs_count_as_samples_but_not_in_averages() {
+        let mut cached = response(1, 1.0);
+        cached.cached = true;
+        let reports = [
+            sample("a", 10, response(100, 10.0), None),
+            sample("a", 10, cached, None),
+        ];
+        let buckets = bucket_by_prompt_length(&reports, &[]);
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(
+            (buckets[0].min_prompt_tokens, buckets[0].max_prompt_tokens),
+            (0, None)
+        );
+        assert_eq!(buckets[0].samples, 2);
+        assert_eq!(buckets[0].quantized_avg_latency_ms, 100.0);
+    }
+
+    #[test]
+    fn no_samples_means_no_buckets() {
+        assert!(bucket_by_prompt_length(&[], &[32, 128, 512]).is_empty());
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    // Reference intervals are R's `t.test(x)$conf.int`.
+    #[test]
+    fn paired_comparison_matches_a_t_test() {
+        let faster = paired_comparison(&[-12.0, -8.0, -15.0, -3.0, -9.0, -11.0]).unwrap();
+        assert_eq!(faster.pairs, 6);
+        assert_close(faster.mean_difference_ms, -9.666_667, 1e-6);
+        assert_close(faster.ci95_low_ms, -13.950_97, 1e-3);
+        assert_close(faster.ci95_high_ms, -5.382_36, 1e-3);
+        assert!(faster.significant);
+
+        let noise = paired_comparison(&[1.0, 2.0, 3.0, 4.0, 5.0]).unwrap();
+        assert_close(noise.ci95_low_ms, 1.036_757, 1e-3);
+        assert_close(noise.ci95_high_ms, 4.963_243, 1e-3);
+
+        let straddling = paired_comparison(&[-10.0, 2.0]).unwrap();
+        assert_close(straddling.ci95_low_ms, -80.236_18, 1e-2);
+        assert_close(straddling.ci95_high_ms, 72.236_18, 1e-2);
+        assert!(!straddling.significant);
+    }
+
+    // R's `qt(0.975, df)`: the table is rounded to three places, the
+    // expansion past it is closer.
+    #[test]
+    fn t_critical_values_match_r() {
+        for (df, expected) in [(1, 12.706_20), (4, 2.776_445), (30, 2.042_272)] {
+            assert_close(t_critical_975(df), expected, 5e-4);
+        }
+        for (df, expected) in [(31, 2.039_513), (60, 2.000_298), (120, 1.979_930)] {
+            assert_close(t_critical_975(df), expected, 1e-4);
+        }
+    }
+
+    #[test]
+    fn fewer_than_two_pairs_is_none() {
+        assert_eq!(paired_comparison(&[]), None);
+        assert_eq!(paired_comparison(&[4.0]), None);
+    }
+
+    #[test]
+    fn identical_differences_give_a_zero_width_interval() {
+        let constant = paired_comparison(&[5.0, 5.0, 5.0]).unwrap();
+        assert_eq!(
+            (
+                constant.ci95_low_ms,
+                constant.mean_difference_ms,
+                constant.ci95_high_ms
+            ),
+            (5.0, 5.0, 5.0)
+        );
+        assert!(constant.significant);
+
+        let ties = paired_comparison(&[0.0, 0.0]).unwrap();
+        assert_eq!((ties.ci95_low_ms, ties.ci95_high_ms), (0.0, 0.0));
+        assert!(!ties.significant);
+    }
+
+    fn fixture_report() -> EvaluationReport {
+        let mut matched = sample(
+            "Say \"hi\" | then,\nbye",
+            12,
+            response(120, 15.5),
+            Some(response(180, 25.0)),
+        );
+        matched.reference_match_quantized = Some(true);
+        matched.reference_match_baseline = Some(false);
+        EvaluationReport {
+            samples: vec![
+                matched,
+                SampleReport::failed(
+                    "a prompt long enough to be cut short".into(),
+                    &ServiceError::Inference("bad, worse | worst".into()),
+                    false,
+                ),
+            ],
+            aggregate: AggregateMetrics {
+                quantized_avg_latency_ms: 120.0,
+                quantized_avg_tokens_per_s: 20.0,
+                quantized_avg_prefill_tokens_per_s: 100.0,
+                quantized_avg_decode_tokens_per_s: 10.0,
+                quantized_avg_time_to_first_token_ms: 15.5,
+                baseline_avg_latency_ms: Some(180.0),
+                baseline_avg_time_to_first_token_ms: Some(25.0),
+                quantized_reference_match_rate: Some(1.0),
+                baseline_reference_match_rate: Some(0.0),
+                wall_clock_ms: 300,
+                aggregate_tokens_per_s: 13.333,
+                failed_samples: 1,
+                ..aggregate()
+            },
+            memory: MemoryReport {
+                rss_before_bytes: None,
+                rss_after_bytes: None,
+                quantized_peak_rss_delta_bytes: None,
+                baseline_peak_rss_delta_bytes: None,
+            },
+            interrupted: false,
+        }
+    }
+
+    // Regenerate with `to_markdown(24)` and `to_csv()` on the fixture and
+    // check the diff by hand.
+    #[test]
+    fn markdown_matches_the_golden_file() {
+        assert_eq!(
+            fixture_report().to_markdown(24),
+            include_str!("../testdata/evaluation_report.md")
+        );
+    }
+
+    #[test]
+    fn csv_matches_the_golden_file() {
+        assert_eq!(
+            fixture_report().to_csv().unwrap(),
+            include_str!("../testdata/evaluation_report.csv")
+        );
+    }
+
+    #[test]
+    fn csv_round_trips_awkward_prompts() {
+        let csv = fixture_report().to_csv().unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(&rows[0][1], "Say \"hi\" | then,\nbye");
+        assert_eq!(&rows[1][16], "model execution failed: bad, worse | worst");
+    }
+}
@@ -0,0 +1,190 @@
+use axum::{
+    Json, async_trait,
+    extract::{
+        FromRequest, FromRequestParts, Query, Request,
+        rejection::{JsonRejection, QueryRejection},
+    },
+    http::{StatusCode, request::Parts},
+    response::{IntoResponse, Response},
+};
+
+use crate::error::ServiceError;
+
+/// `Json` whose rejections use the service's error shape: unparseable JSON
+/// is a 400 `bad_request`, JSON that doesn't fit the type (a missing or
+/// unknown field, a wrong type) a 422 `unprocessable`, and a body not sent
+/// as `application/json` a 415 `unsupported_media_type`. An oversized body
+/// passes through for the body limit's own error.
+pub struct ApiJson<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => Ok(ApiJson(value)),
+            Err(JsonRejection::JsonDataError(err)) => {
+                Err(ServiceError::Unprocessable(err.body_text()).into_response())
+            }
+            Err(JsonRejection::JsonSyntaxError(err)) => {
+                Err(ServiceError::BadRequest(err.body_text()).into_response())
+            }
+            Err(JsonRejection::MissingJsonContentType(err)) => {
+                Err(ServiceError::UnsupportedMediaType(err.body_text()).into_response())
+            }
+            Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                Err(rejection.into_response())
+            }
+            Err(rejection) => Err(ServiceError::BadRequest(rejection.body_text()).into_response()),
+        }
+    }
+}
+
+/// `Query` whose rejection is a structured 400 `bad_request`.
+pub struct ApiQuery<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for ApiQuery<T>
+where
+    Query<T>: FromRequestParts<S, Rejection = QueryRejection>,
+    S: Send + Sync,
+{
+    type Rejection = ServiceError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Query::<T>::from_request_parts(parts, state)
+            .await
+            .map(|Query(value)| ApiQuery(value))
+            .map_err(|rejection| ServiceError::BadRequest(rejection.body_text()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, header},
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{Reply, get, post_json, router, send};
+
+    async fn post(router: &Router, content_type: Option<&str>, body: &'static str) -> Reply {
+        let mut request = Request::post("/generate");
+        if let Some(content_type) = content_type {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        send(router, request.body(Body::from(body)).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn garbage_is_a_structured_400() {
+        let router = router("extract-garbage", |_| {});
+        let reply = post(&router, Some("application/json"), "{\"prompt\": \"Hi\",").await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert_eq!(reply.error_code(), "bad_request");
+        let message = reply.json()["error"]["message"].to_string();
+        assert!(message.contains("line 1 column"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn other_content_types_are_a_structured_415() {
+        let router = router("extract-content-type", |_| {});
+        for content_type in [Some("text/plain"), None] {
+            let reply = post(&router, content_type, "{\"prompt\": \"Hi\"}").await;
+            assert_eq!(reply.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            assert_eq!(reply.error_code(), "unsupported_media_type");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_missing_prompt_is_a_structured_422_naming_it() {
+        let router = router("extract-missing", |_| {});
+        let reply = send(
+            &router,
+            post_json("/generate", json!({"max_new_tokens": 4})),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(reply.error_code(), "unprocessable");
+        let message = reply.json()["error"]["message"].to_string();
+        assert!(message.contains("missing field `prompt`"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn unknown_fields_are_a_structured_422() {
+        let router = router("extract-unknown", |_| {});
+        for uri in ["/generate", "/generate/baseline"] {
+            let body = json!({"prompt": "Hi", "max_tokens": 4});
+            let reply = send(&router, post_json(uri, body)).await;
+            assert_eq!(reply.status, StatusCode::UNPROCESSABLE_ENTITY, "{uri}");
+            let body = reply.json();
+            assert_eq!(body["error"]["code"], "unprocessable");
+            assert_eq!(
+                body["error"]["request_id"],
+                reply.header("x-request-id").unwrap()
+            );
+            let message = body["error"]["message"].as_str().unwrap();
+            assert!(
+                message.starts_with("invalid request body: ")
+                    && message.contains("unknown field `max_tokens`, expected one of"),
+                "{message}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_wrong_type_is_a_structured_422() {
+        let router = router("extract-type", |_| {});
+        let body = json!({"prompt": "Hi", "max_new_tokens": "four"});
+        let reply = send(&router, post_json("/generate", body)).await;
+        assert_eq!(reply.status, StatusCode::UNPROCESSABLE_ENTITY);
+        let message = reply.json()["error"]["message"].to_string();
+        assert!(message.contains("max_new_tokens"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn wrong_methods_are_a_structured_405() {
+        let router = router("extract-405", |_| {});
+        let reply = send(&router, get("/generate")).await;
+        assert_eq!(reply.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(reply.error_code(), "method_not_allowed");
+        let message = reply.json()["error"]["message"].to_string();
+        assert!(
+            message.contains("/generate does not accept GET"),
+            "{message}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_are_a_structured_404() {
+        let router = router("extract-404", |_| {});
+        let reply = send(
+            &router,
+            post_json("/generate/fast", json!({"prompt": "Hi"})),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::NOT_FOUND);
+        assert_eq!(reply.error_code(), "not_found");
+        let message = reply.json()["error"]["message"].to_string();
+        assert!(
+            message.contains("no route for POST /generate/fast"),
+            "{message}"
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_queries_are_a_structured_400() {
+        let router = router("extract-query", |_| {});
+        let reply = send(&router, get("/evaluate/history?limit=lots")).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert_eq!(reply.error_code(), "bad_request");
+    }
+}
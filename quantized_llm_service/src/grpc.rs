@@ -0,0 +1,556 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, wrappers::ReceiverStream};
+use tonic::{Code, Request, Response, Status, transport::Server};
+
+use crate::{
+    audit::AuditLog,
+    config::AppConfig,
+    error::ServiceError,
+    evaluation::{
+        self, EvaluationMode, fallback_samples, load_samples_with_references, run_benchmark,
+    },
+    model::{
+        self, ContextStrategy, FinishReason, GenerationRequest, ModelRegistry, ModelSlot,
+        OnTimeout, Priority, ResponseFormat, SpecialToken, TokenizerMetadata,
+    },
+    store::Store,
+    templates::apply_template,
+    version::{GIT_COMMIT, VERSION},
+};
+
+#[allow(clippy::large_enum_variant)]
+pub mod proto {
+    tonic::include_proto!("quantized_llm.v1");
+}
+
+use proto::{
+    generate_stream_chunk::Chunk,
+    generation_server::{Generation, GenerationServer},
+};
+
+/// Serves the `Generation` service on `addr` until `shutdown` resolves,
+/// sharing the registry with the REST server.
+pub async fn serve(
+    addr: SocketAddr,
+    config: Arc<AppConfig>,
+    registry: Arc<ModelRegistry>,
+    store: Option<Store>,
+    audit: Option<AuditLog>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    let service = GenerationService {
+        config: config.clone(),
+        registry,
+        store,
+        audit,
+    };
+    tracing::info!(%addr, "gRPC server ready");
+    // HTTP/2 pings keep idle streams open through proxies.
+    Server::builder()
+        .http2_keepalive_interval(config.keepalive_interval())
+        .add_service(GenerationServer::new(service))
+        .serve_with_shutdown(addr, shutdown)
+        .await?;
+    tracing::info!("gRPC server stopped");
+    Ok(())
+}
+
+struct GenerationService {
+    config: Arc<AppConfig>,
+    registry: Arc<ModelRegistry>,
+    store: Option<Store>,
+    audit: Option<AuditLog>,
+}
+
+type ChunkStream = Pin<Box<dyn Stream<Item = Result<proto::GenerateStreamChunk, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Generation for GenerationService {
+    async fn generate(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<proto::GenerateResponse>, Status> {
+        let request = request.into_inner();
+        let route = if request.baseline {
+            None
+        } else {
+            self.registry.route(None).map_err(status)?
+        };
+        let (use_baseline, fallback) = match &route {
+            Some(route) => (route.slot == ModelSlot::Baseline, false),
+            None if request.baseline => (true, false),
+            None => {
+                let (slot, fallback) = self.registry.default_slot(&self.config).map_err(status)?;
+                (slot == ModelSlot::Baseline, fallback)
+            }
+        };
+        let mut request = generation_request(request);
+        let raw_prompt = apply_template(&mut request, &self.config).map_err(status)?;
+        let mut response = if use_baseline {
+            self.registry.generate_baseline(request, &self.config).await
+        } else {
+            self.registry
+                .generate_quantized(request, &self.config)
+                .await
+        }
+        .map_err(status)?;
+        response.raw_prompt = raw_prompt;
+        response.served_via_alias = route.and_then(|route| route.alias);
+        response.fallback = fallback;
+        if let Some(audit) = &self.audit {
+            audit.record(None, &response);
+        }
+        Ok(Response::new(response.into()))
+    }
+
+    type GenerateStreamStream = ChunkStream;
+
+    async fn generate_stream(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let mut request = generation_request(request.into_inner());
+        let raw_prompt = apply_template(&mut request, &self.config).map_err(status)?;
+        let (chunks_tx, chunks_rx) = mpsc::channel(32);
+        let registry = self.registry.clone();
+        let config = self.config.clone();
+        let audit = self.audit.clone();
+        tokio::spawn(async move {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let (tokens_tx, mut tokens_rx) = mpsc::unbounded_channel();
+            let stream_cancel = cancel.clone();
+            let generation = tokio::spawn(async move {
+                registry
+                    .generate_stream(request, &config, tokens_tx, stream_cancel)
+                    .await
+            });
+            while let Some(text) = tokens_rx.recv().await {
+                let chunk = proto::GenerateStreamChunk {
+                    chunk: Some(Chunk::Token(text)),
+                };
+                if chunks_tx.send(Ok(chunk)).await.is_err() {
+                    // The client went away; stop generating for it.
+                    cancel.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
+            let result = generation
+                .await
+                .map_err(|err| ServiceError::Inference(format!("inference task failed: {err}")))
+                .and_then(|result| result);
+            let last = result
+                .map(|mut response| {
+                    response.raw_prompt = raw_prompt;
+                    if let Some(audit) = &audit {
+                        audit.record(None, &response);
+                    }
+                    proto::GenerateStreamChunk {
+                        chunk: Some(Chunk::Done(response.into())),
+                    }
+                })
+                .map_err(status);
+            let _ = chunks_tx.send(last).await;
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(chunks_rx))))
+    }
+
+    async fn tokenize(
+        &self,
+        request: Request<proto::TokenizeRequest>,
+    ) -> Result<Response<proto::TokenizeResponse>, Status> {
+        let request = request.into_inner();
+        let (ids, tokens) = self
+            .registry
+            .tokenize(&request.text, request.add_special_tokens)
+            .map_err(status)?;
+        Ok(Response::new(proto::TokenizeResponse { ids, tokens }))
+    }
+
+    async fn get_metadata(
+        &self,
+        _request: Request<proto::GetMetadataRequest>,
+    ) -> Result<Response<proto::GetMetadataResponse>, Status> {
+        let (quantized, baseline) = self.registry.metadata();
+        Ok(Response::new(proto::GetMetadataResponse {
+            version: VERSION.to_string(),
+            git_commit: GIT_COMMIT.to_string(),
+            model_id: self.config.model_id.clone(),
+            quantized: quantized.map(Into::into),
+            baseline: baseline.map(Into::into),
+            tokenizer_sha256: self.registry.tokenizer_sha256(),
+            tokenizer: Some(self.registry.tokenizer_metadata().into()),
+        }))
+    }
+
+    async fn evaluate(
+        &self,
+        request: Request<proto::EvaluateRequest>,
+    ) -> Result<Response<proto::EvaluateResponse>, Status> {
+        let mode = if request.into_inner().assert {
+            EvaluationMode::Assert
+        } else {
+            EvaluationMode::Benchmark
+        };
+        let samples = match self.config.eval_prompts_path.as_ref() {
+            Some(path) => {
+                load_samples_with_references(path, self.config.eval_reference_path.as_deref())
+                    .map_err(status)?
+            }
+            None => fallback_samples(),
+        };
+        tracing::info!(count = samples.len(), "running evaluation benchmark");
+        let report = run_benchmark(self.registry.clone(), &self.config, samples, mode)
+            .await
+            .map_err(status)?;
+        let report_id = match self.store.as_ref() {
+            Some(store) => Some(store.save_evaluation(&report).await.map_err(status)?),
+            None => None,
+        };
+        Ok(Response::new(proto::EvaluateResponse {
+            aggregate: Some(report.aggregate.into()),
+            report_id,
+        }))
+    }
+}
+
+fn generation_request(request: proto::GenerateRequest) -> GenerationRequest {
+    GenerationRequest {
+        context_strategy: match request.context_strategy() {
+            proto::ContextStrategy::Unspecified => None,
+            proto::ContextStrategy::Error => Some(ContextStrategy::Error),
+            proto::ContextStrategy::TruncateLeft => Some(ContextStrategy::TruncateLeft),
+            proto::ContextStrategy::SlidingWindow => Some(ContextStrategy::SlidingWindow),
+        },
+        response_format: match request.response_format() {
+            proto::ResponseFormat::Unspecified => None,
+            proto::ResponseFormat::Text => Some(ResponseFormat::Text),
+            proto::ResponseFormat::Json => Some(ResponseFormat::Json),
+        },
+        priority: match request.priority() {
+            proto::Priority::Unspecified => None,
+            proto::Priority::Interactive => Some(Priority::Interactive),
+            proto::Priority::Batch => Some(Priority::Batch),
+        },
+        prompt: request.prompt,
+        model: None,
+        max_new_tokens: request.max_new_tokens.map(|n| n as usize),
+        temperature: request.temperature,
+        top_k: request.top_k.map(|k| k as usize),
+        truncate_prompt: request.truncate_prompt,
+        seed: request.seed,
+        template: request.template,
+        system: request.system,
+        preset: None,
+        add_special_tokens: request.add_special_tokens,
+        skip_special_tokens: request.skip_special_tokens,
+        return_token_details: request.return_token_details.then_some(true),
+        on_timeout: request.partial_on_timeout.then_some(OnTimeout::Partial),
+        // The gRPC server has no authentication, so its calls are charged
+        // to the anonymous bucket.
+        api_key: None,
+    }
+}
+
+/// Maps the REST status of an error onto the closest gRPC code.
+fn status(err: ServiceError) -> Status {
+    let code = match err.status().as_u16() {
+        400 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        422 => Code::InvalidArgument,
+        429 => Code::ResourceExhausted,
+        501 => Code::Unimplemented,
+        503 => Code::Unavailable,
+        504 => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    };
+    Status::new(code, err.to_string())
+}
+
+impl From<model::GenerationResponse> for proto::GenerateResponse {
+    fn from(response: model::GenerationResponse) -> Self {
+        Self {
+            prompt: response.prompt,
+            raw_prompt: response.raw_prompt,
+            completion: response.completion,
+            tokens_generated: response.tokens_generated as u32,
+            finish_reason: match response.finish_reason {
+                FinishReason::Stop => proto::FinishReason::Stop,
+                FinishReason::Length => proto::FinishReason::Length,
+                FinishReason::Cancelled => proto::FinishReason::Cancelled,
+                FinishReason::Timeout => proto::FinishReason::Timeout,
+            } as i32,
+            total_time_ms: response.total_time_ms as u64,
+            timings: Some(proto::GenerationTimings {
+                tokenize_ms: response.timings.tokenize_ms,
+                queue_wait_ms: response.timings.queue_wait_ms,
+                time_to_first_token_ms: response.timings.time_to_first_token_ms,
+                decode_ms: response.timings.decode_ms,
+                cpu_time_ms: response.timings.cpu_time_ms,
+                deadline_ms: response.timings.deadline_ms,
+            }),
+            tokens_per_second: response.tokens_per_second,
+            prefill_tokens_per_second: response.prefill_tokens_per_second,
+            decode_tokens_per_second: response.decode_tokens_per_second,
+            usage: Some(proto::Usage {
+                prompt_tokens: response.usage.prompt_tokens as u32,
+                completion_tokens: response.usage.completion_tokens as u32,
+                total_tokens: response.usage.total_tokens as u32,
+                evicted_prompt_tokens: response.usage.evicted_prompt_tokens as u32,
+                truncated_prompt_tokens: response.usage.truncated_prompt_tokens as u32,
+            }),
+            model: Some(response.model.into()),
+            cached: response.cached,
+            fallback: response.fallback,
+            prompt_truncated: response.prompt_truncated,
+            params: Some(proto::EffectiveParams {
+                max_new_tokens: response.params.max_new_tokens as u32,
+                temperature: response.params.temperature,
+                top_k: response.params.top_k as u32,
+                seed: response.params.seed,
+            }),
+            generated_token_ids: response.generated_token_ids.unwrap_or_default(),
+            peak_rss_delta_bytes: response.peak_rss_delta_bytes,
+            token_details: response
+                .token_details
+                .unwrap_or_default()
+                .into_iter()
+                .map(|detail| proto::TokenDetail {
+                    id: detail.id,
+                    text: detail.text,
+                    logprob: detail.logprob,
+                    offset_ms: detail.offset_ms,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<model::ModelMetadata> for proto::ModelMetadata {
+    fn from(metadata: model::ModelMetadata) -> Self {
+        Self {
+            name: metadata.name,
+            quantized: metadata.quantized,
+            dtype: metadata.dtype,
+            size_bytes: metadata.size_bytes,
+            sha256: metadata.sha256,
+            device: metadata.device,
+            device_fallback_reason: metadata.device_fallback_reason,
+            max_context_tokens: metadata.max_context_tokens as u32,
+            model_kind: metadata.model_kind.to_string(),
+            eos_token_id: metadata.eos_token_id,
+            decoder_start_token_id: metadata.decoder_start_token_id,
+            load_time_ms: metadata.load_time_ms,
+            num_parameters: metadata.num_parameters,
+            vocab_size: metadata.vocab_size as u32,
+            hidden_states: metadata.hidden_states,
+            warmup_latency_ms: metadata.warmup_latency_ms,
+        }
+    }
+}
+
+impl From<TokenizerMetadata> for proto::TokenizerMetadata {
+    fn from(metadata: TokenizerMetadata) -> Self {
+        let special = |token: SpecialToken| proto::SpecialToken {
+            id: token.id,
+            token: token.token,
+        };
+        Self {
+            sha256: metadata.sha256,
+            vocab_size: metadata.vocab_size as u32,
+            byte_level_bpe: metadata.byte_level_bpe,
+            bos: metadata.bos.map(special),
+            eos: metadata.eos.map(special),
+            pad: metadata.pad.map(special),
+        }
+    }
+}
+
+impl From<evaluation::AggregateMetrics> for proto::AggregateMetrics {
+    fn from(metrics: evaluation::AggregateMetrics) -> Self {
+        Self {
+            quantized_avg_latency_ms: metrics.quantized_avg_latency_ms,
+            quantized_avg_tokens_per_s: metrics.quantized_avg_tokens_per_s,
+            quantized_avg_prefill_tokens_per_s: metrics.quantized_avg_prefill_tokens_per_s,
+            quantized_avg_decode_tokens_per_s: metrics.quantized_avg_decode_tokens_per_s,
+            quantized_avg_time_to_first_token_ms: metrics.quantized_avg_time_to_first_token_ms,
+            baseline_avg_latency_ms: metrics.baseline_avg_latency_ms,
+            baseline_avg_tokens_per_s: metrics.baseline_avg_tokens_per_s,
+            baseline_avg_prefill_tokens_per_s: metrics.baseline_avg_prefill_tokens_per_s,
+            baseline_avg_decode_tokens_per_s: metrics.baseline_avg_decode_tokens_per_s,
+            baseline_avg_time_to_first_token_ms: metrics.baseline_avg_time_to_first_token_ms,
+            quantized_reference_match_rate: metrics.quantized_reference_match_rate,
+            baseline_reference_match_rate: metrics.baseline_reference_match_rate,
+            concurrency: metrics.concurrency as u32,
+            wall_clock_ms: metrics.wall_clock_ms as u64,
+            aggregate_tokens_per_s: metrics.aggregate_tokens_per_s,
+            failed_samples: metrics.failed_samples as u32,
+            pass_rate: metrics.pass_rate,
+            by_prompt_length: metrics
+                .by_prompt_length
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            latency_difference: metrics
+                .latency_difference
+                .map(|diff| proto::PairedComparison {
+                    pairs: diff.pairs as u32,
+                    mean_difference_ms: diff.mean_difference_ms,
+                    ci95_low_ms: diff.ci95_low_ms,
+                    ci95_high_ms: diff.ci95_high_ms,
+                    significant: diff.significant,
+                }),
+        }
+    }
+}
+
+impl From<evaluation::LengthBucket> for proto::LengthBucket {
+    fn from(bucket: evaluation::LengthBucket) -> Self {
+        Self {
+            min_prompt_tokens: bucket.min_prompt_tokens as u32,
+            max_prompt_tokens: bucket.max_prompt_tokens.map(|max| max as u32),
+            samples: bucket.samples as u32,
+            quantized_avg_latency_ms: bucket.quantized_avg_latency_ms,
+            quantized_avg_time_to_first_token_ms: bucket.quantized_avg_time_to_first_token_ms,
+            quantized_avg_tokens_per_s: bucket.quantized_avg_tokens_per_s,
+            quantized_avg_prefill_tokens_per_s: bucket.quantized_avg_prefill_tokens_per_s,
+            quantized_avg_decode_tokens_per_s: bucket.quantized_avg_decode_tokens_per_s,
+            baseline_avg_latency_ms: bucket.baseline_avg_latency_ms,
+            baseline_avg_time_to_first_token_ms: bucket.baseline_avg_time_to_first_token_ms,
+            baseline_avg_tokens_per_s: bucket.baseline_avg_tokens_per_s,
+            baseline_avg_prefill_tokens_per_s: bucket.baseline_avg_prefill_tokens_per_s,
+            baseline_avg_decode_tokens_per_s: bucket.baseline_avg_decode_tokens_per_s,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use tokio::sync::oneshot;
+    use tonic::transport::Channel;
+
+    use super::*;
+    use crate::model::testing::{fake_config, fake_registry};
+    use proto::generation_client::GenerationClient;
+
+    /// A server over fake models on a free local port, a client for it,
+    /// and the trigger that shuts it down.
+    async fn start(
+        name: &str,
+    ) -> (
+        GenerationClient<Channel>,
+        oneshot::Sender<()>,
+        tokio::task::JoinHandle<Result<(), tonic::transport::Error>>,
+    ) {
+        let config = fake_config(name, true);
+        let registry = Arc::new(fake_registry(&config));
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let server = tokio::spawn(serve(addr, Arc::new(config), registry, None, None, async {
+            let _ = stopped.await;
+        }));
+        let endpoint = format!("http://{addr}");
+        for _ in 0..100 {
+            if let Ok(client) = GenerationClient::connect(endpoint.clone()).await {
+                return (client, stop, server);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("gRPC server on {addr} never came up");
+    }
+
+    fn greedy_request(max_new_tokens: u32) -> proto::GenerateRequest {
+        proto::GenerateRequest {
+            prompt: "Hello".to_string(),
+            max_new_tokens: Some(max_new_tokens),
+            temperature: Some(0.0),
+            ..proto::GenerateRequest::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn generate_round_trips_over_the_wire() {
+        let (mut client, stop, server) = start("grpc-generate").await;
+        let response = client
+            .generate(greedy_request(3))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.tokens_generated, 3);
+        assert_eq!(response.raw_prompt, "Hello");
+        assert_eq!(response.finish_reason(), proto::FinishReason::Length);
+        assert_eq!(response.model.unwrap().name, "quantized");
+
+        let baseline = client
+            .generate(proto::GenerateRequest {
+                baseline: true,
+                ..greedy_request(1)
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(baseline.model.unwrap().name, "baseline");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn generate_stream_sends_tokens_then_the_response() {
+        let (mut client, stop, server) = start("grpc-stream").await;
+        let mut stream = client
+            .generate_stream(greedy_request(3))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut tokens = Vec::new();
+        let mut done = None;
+        while let Some(chunk) = stream.message().await.unwrap() {
+            match chunk.chunk.unwrap() {
+                Chunk::Token(text) => {
+                    assert!(done.is_none(), "token after the final response");
+                    tokens.push(text);
+                }
+                Chunk::Done(response) => done = Some(response),
+            }
+        }
+        let done = done.expect("the stream ends with the response");
+        assert_eq!(done.tokens_generated, 3);
+        assert_eq!(tokens.concat(), done.completion);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors_map_to_grpc_codes() {
+        let (mut client, stop, server) = start("grpc-errors").await;
+        let err = client
+            .generate(proto::GenerateRequest {
+                temperature: Some(-1.0),
+                ..greedy_request(1)
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument, "{err}");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+//! Self-contained HTML pages for evaluation reports, for sharing outside the
+//! API. The body is streamed a sample at a time so large reports are never
+//! held as one page.
+use std::{convert::Infallible, fmt::Write, iter};
+
+use axum::{
+    body::Body,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use futures::stream::{self, StreamExt};
+
+use crate::{
+    evaluation::{AggregateMetrics, SampleReport},
+    store::StoredSample,
+};
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2rem;color:#222}\
+table{border-collapse:collapse;margin-bottom:1.5rem}\
+td,th{border:1px solid #ccc;padding:.3rem .6rem;text-align:right}\
+th:first-child,td:first-child{text-align:left}\
+details{border:1px solid #ddd;border-radius:4px;margin:.4rem 0;padding:.4rem .6rem}\
+summary{cursor:pointer}\
+.bars{margin:.3rem 0 0 2rem}\
+.bar{height:.9rem;margin:2px 0;font-size:.7rem;color:#fff;white-space:nowrap;padding-left:.3rem}\
+.q{background:#2b6cb0}.b{background:#718096}\
+.badge{display:inline-block;border-radius:3px;padding:0 .35rem;margin-left:.3rem;font-size:.75rem}\
+.ok{background:#c6f6d5}.bad{background:#fed7d7}.info{background:#e2e8f0}\
+.cols{display:grid;grid-template-columns:1fr 1fr;gap:1rem}\
+pre{white-space:pre-wrap;background:#f7fafc;padding:.5rem}\
+mark{background:#fefcbf}";
+
+/// What the page shows for one sample, whether the report is live or stored.
+pub struct SampleRow {
+    pub prompt: String,
+    pub quantized_completion: Option<String>,
+    pub baseline_completion: Option<String>,
+    pub quantized_latency_ms: Option<f64>,
+    pub baseline_latency_ms: Option<f64>,
+    pub reference_match_quantized: Option<bool>,
+    pub reference_match_baseline: Option<bool>,
+    pub error: Option<String>,
+}
+
+impl From<SampleReport> for SampleRow {
+    fn from(sample: SampleReport) -> Self {
+        Self {
+            quantized_latency_ms: sample.quantized.as_ref().map(|q| q.total_time_ms as f64),
+            baseline_latency_ms: sample.baseline.as_ref().map(|b| b.total_time_ms as f64),
+            quantized_completion: sample.quantized.map(|q| q.completion),
+            baseline_completion: sample.baseline.map(|b| b.completion),
+            prompt: sample.prompt,
+            reference_match_quantized: sample.reference_match_quantized,
+            reference_match_baseline: sample.reference_match_baseline,
+            error: sample.error,
+        }
+    }
+}
+
+impl From<StoredSample> for SampleRow {
+    fn from(sample: StoredSample) -> Self {
+        // Failed samples are stored with an empty completion.
+        let failed = sample.error.is_some();
+        Self {
+            prompt: sample.prompt,
+            quantized_completion: (!failed).then_some(sample.quantized_completion),
+            baseline_completion: sample.baseline_completion,
+            quantized_latency_ms: (!failed).then_some(sample.quantized_latency_ms as f64),
+            baseline_latency_ms: sample.baseline_latency_ms.map(|ms| ms as f64),
+            reference_match_quantized: sample.reference_match_quantized,
+            reference_match_baseline: sample.reference_match_baseline,
+            error: sample.error,
+        }
+    }
+}
+
+/// Renders the report as a `text/html` response.
+pub fn render(title: &str, aggregate: &AggregateMetrics, samples: Vec<SampleRow>) -> Response {
+    let max_latency = samples
+        .iter()
+        .flat_map(|s| [s.quantized_latency_ms, s.baseline_latency_ms])
+        .flatten()
+        .fold(0.0, f64::max);
+    let head = page_head(title, aggregate, samples.len());
+    let body = iter::once(head)
+        .chain(
+            samples
+                .into_iter()
+                .enumerate()
+                .map(move |(idx, sample)| render_sample(idx, &sample, max_latency)),
+        )
+        .chain(iter::once("</body></html>\n".to_string()));
+    (
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        Body::from_stream(stream::iter(body).map(Ok::<_, Infallible>)),
+    )
+        .into_response()
+}
+
+fn page_head(title: &str, aggregate: &AggregateMetrics, sample_count: usize) -> String {
+    fn opt(value: Option<f64>, precision: usize) -> String {
+        value.map_or_else(|| "–".to_string(), |v| format!("{v:.precision$}"))
+    }
+
+    let title = escape(title);
+    let mut html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <style>{STYLE}</style></head><body><h1>{title}</h1>\
+         <table><tr><th>metric</th><th>quantized</th><th>baseline</th></tr>"
+    );
+    let rows = [
+        (
+            "avg latency (ms)",
+            format!("{:.1}", aggregate.quantized_avg_latency_ms),
+            opt(aggregate.baseline_avg_latency_ms, 1),
+        ),
+        (
+            "avg first token (ms)",
+            format!("{:.1}", aggregate.quantized_avg_time_to_first_token_ms),
+            opt(aggregate.baseline_avg_time_to_first_token_ms, 1),
+        ),
+        (
+            "avg prefill tokens/s",
+            format!("{:.2}", aggregate.quantized_avg_prefill_tokens_per_s),
+            opt(aggregate.baseline_avg_prefill_tokens_per_s, 2),
+        ),
+        (
+            "avg decode tokens/s",
+            format!("{:.2}", aggregate.quantized_avg_decode_tokens_per_s),
+            opt(aggregate.baseline_avg_decode_tokens_per_s, 2),
+        ),
+        (
+            "avg tokens/s (legacy)",
+            format!("{:.2}", aggregate.quantized_avg_tokens_per_s),
+            opt(aggregate.baseline_avg_tokens_per_s, 2),
+        ),
+        (
+            "reference match rate",
+            opt(aggregate.quantized_reference_match_rate, 3),
+            opt(aggregate.baseline_reference_match_rate, 3),
+        ),
+    ];
+    for (metric, quantized, baseline) in rows {
+        let _ = write!(
+            html,
+            "<tr><td>{metric}</td><td>{quantized}</td><td>{baseline}</td></tr>"
+        );
+    }
+    html.push_str("</table><p>");
+    let _ = write!(
+        html,
+        "{sample_count} samples, {} failed · concurrency {} · wall clock {} ms · {:.2} tokens/s overall",
+        aggregate.failed_samples,
+        aggregate.concurrency,
+        aggregate.wall_clock_ms,
+        aggregate.aggregate_tokens_per_s
+    );
+    if let Some(pass_rate) = aggregate.pass_rate {
+        let _ = write!(html, " · pass rate {pass_rate:.3}");
+    }
+    if let Some(diff) = &aggregate.latency_difference {
+        let _ = write!(
+            html,
+            " · quantized − baseline latency {:+.1} ms (95% CI {:+.1} to {:+.1}{})",
+            diff.mean_difference_ms,
+            diff.ci95_low_ms,
+            diff.ci95_high_ms,
+            if diff.significant {
+                ", significant"
+            } else {
+                ""
+            }
+        );
+    }
+    html.push_str("</p><h2>Samples</h2>\n");
+    html
+}
+
+fn render_sample(idx: usize, sample: &SampleRow, max_latency: f64) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<details><summary>#{} {}",
+        idx + 1,
+        escape(&sample.prompt)
+    );
+    if let Some(error) = &sample.error {
+        let _ = writeln!(
+            html,
+            "<span class=\"badge bad\">error</span></summary><pre>{}</pre></details>",
+            escape(error)
+        );
+        return html;
+    }
+    for (label, matched) in [
+        ("quantized", sample.reference_match_quantized),
+        ("baseline", sample.reference_match_baseline),
+    ] {
+        if let Some(matched) = matched {
+            let (class, mark) = if matched {
+                ("ok", "✓")
+            } else {
+                ("bad", "✗")
+            };
+            let _ = write!(html, "<span class=\"badge {class}\">{label} {mark}</span>");
+        }
+    }
+    if let (Some(quantized), Some(baseline)) =
+        (&sample.quantized_completion, &sample.baseline_completion)
+    {
+        let (class, text) = if quantized == baseline {
+            ("ok", "agree")
+        } else {
+            ("info", "diverge")
+        };
+        let _ = write!(html, "<span class=\"badge {class}\">{text}</span>");
+    }
+    html.push_str("<div class=\"bars\">");
+    for (class, latency) in [
+        ("q", sample.quantized_latency_ms),
+        ("b", sample.baseline_latency_ms),
+    ] {
+        if let Some(ms) = latency {
+            let width = if max_latency > 0.0 {
+                (ms / max_latency * 100.0).max(1.0)
+            } else {
+                1.0
+            };
+            let _ = write!(
+                html,
+                "<div class=\"bar {class}\" style=\"width:{width:.1}%\">{ms:.0} ms</div>"
+            );
+        }
+    }
+    html.push_str("</div></summary><div class=\"cols\">");
+
+    let (quantized, baseline) = match (&sample.quantized_completion, &sample.baseline_completion) {
+        (Some(quantized), Some(baseline)) => highlight_divergence(quantized, baseline),
+        (quantized, baseline) => (
+            quantized.as_deref().map(escape).unwrap_or_default(),
+            baseline.as_deref().map(escape).unwrap_or_default(),
+        ),
+    };
+    let _ = write!(html, "<div><h4>Quantized</h4><pre>{quantized}</pre></div>");
+    if sample.baseline_completion.is_some() {
+        let _ = write!(html, "<div><h4>Baseline</h4><pre>{baseline}</pre></div>");
+    }
+    html.push_str("</div></details>\n");
+    html
+}
+
+/// Escapes both completions, wrapping the words between their common prefix
+/// and common suffix in `<mark>`.
+fn highlight_divergence(a: &str, b: &str) -> (String, String) {
+    fn words(s: &str) -> Vec<&str> {
+        s.split_inclusive(char::is_whitespace).collect()
+    }
+
+    let (a_words, b_words) = (words(a), words(b));
+    let prefix = a_words
+        .iter()
+        .zip(&b_words)
+        .take_while(|(x, y)| x == y)
+        .count();
+    let suffix = a_words[prefix..]
+        .iter()
+        .rev()
+        .zip(b_words[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let mark = |words: &[&str]| {
+        let (head, rest) = words.split_at(prefix);
+        let (middle, tail) = rest.split_at(rest.len() - suffix);
+        let middle = middle.concat();
+        let middle = if middle.is_empty() {
+            String::new()
+        } else {
+            format!("<mark>{}</mark>", escape(&middle))
+        };
+        format!(
+            "{}{middle}{}",
+            escape(&head.concat()),
+            escape(&tail.concat())
+        )
+    };
+    (mark(&a_words), mark(&b_words))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluation::PairedComparison;
+
+    fn fixture() -> (AggregateMetrics, Vec<SampleRow>) {
+        let aggregate = AggregateMetrics {
+            quantized_avg_latency_ms: 150.0,
+            quantized_avg_tokens_per_s: 20.0,
+            quantized_avg_prefill_tokens_per_s: 100.0,
+            quantized_avg_decode_tokens_per_s: 10.0,
+            quantized_avg_time_to_first_token_ms: 15.5,
+            baseline_avg_latency_ms: Some(200.0),
+            baseline_avg_tokens_per_s: Some(15.0),
+            baseline_avg_prefill_tokens_per_s: Some(80.0),
+            baseline_avg_decode_tokens_per_s: Some(7.5),
+            baseline_avg_time_to_first_token_ms: Some(25.0),
+            quantized_reference_match_rate: Some(1.0),
+            baseline_reference_match_rate: Some(0.5),
+            concurrency: 2,
+            wall_clock_ms: 400,
+            aggregate_tokens_per_s: 12.5,
+            failed_samples: 1,
+            pass_rate: None,
+            by_prompt_length: Vec::new(),
+            latency_difference: Some(PairedComparison {
+                pairs: 2,
+                mean_difference_ms: -50.0,
+                ci95_low_ms: -80.0,
+                ci95_high_ms: -20.0,
+                significant: true,
+            }),
+        };
+        let row =
+            |prompt: &str, quantized: &str, baseline: &str, latencies: (f64, f64)| SampleRow {
+                prompt: prompt.to_string(),
+                quantized_completion: Some(quantized.to_string()),
+                baseline_completion: Some(baseline.to_string()),
+                quantized_latency_ms: Some(latencies.0),
+                baseline_latency_ms: Some(latencies.1),
+                reference_match_quantized: Some(true),
+                reference_match_baseline: Some(true),
+                error: None,
+            };
+        let mut diverged = row(
+            "The capital of <France> is",
+            " Paris, of course.",
+            " Lyon, of course.",
+            (100.0, 200.0),
+        );
+        diverged.reference_match_baseline = Some(false);
+        let samples = vec![
+            row(
+                "Once upon a time",
+                " there was",
+                " there was",
+                (200.0, 200.0),
+            ),
+            diverged,
+            SampleRow {
+                prompt: "   ".into(),
+                quantized_completion: None,
+                baseline_completion: None,
+                quantized_latency_ms: None,
+                baseline_latency_ms: None,
+                reference_match_quantized: None,
+                reference_match_baseline: None,
+                error: Some("invalid request: field 'prompt' must not be empty".into()),
+            },
+        ];
+        (aggregate, samples)
+    }
+
+    async fn page(title: &str, aggregate: &AggregateMetrics, samples: Vec<SampleRow>) -> String {
+        let body = render(title, aggregate, samples).into_body();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    // Regenerate by writing the fixture's page out to the file and check the
+    // diff by hand.
+    #[tokio::test]
+    async fn page_matches_the_golden_file() {
+        let (aggregate, samples) = fixture();
+        assert_eq!(
+            page("Evaluation & report", &aggregate, samples).await,
+            include_str!("../testdata/evaluation_report.html")
+        );
+    }
+
+    #[tokio::test]
+    async fn every_sample_gets_its_own_section() {
+        let (aggregate, _) = fixture();
+        let samples: Vec<SampleRow> = (0..200).flat_map(|_| fixture().1).collect();
+        let html = page("large", &aggregate, samples).await;
+        assert_eq!(html.matches("<details>").count(), 600);
+        assert!(html.ends_with("</body></html>\n"));
+    }
+
+    #[test]
+    fn only_the_differing_words_are_marked() {
+        let (quantized, baseline) = highlight_divergence("a b c d", "a x y d");
+        assert_eq!(quantized, "a <mark>b c </mark>d");
+        assert_eq!(baseline, "a <mark>x y </mark>d");
+        let (same, _) = highlight_divergence("a <b>", "a <b>");
+        assert_eq!(same, "a &lt;b&gt;");
+    }
+}
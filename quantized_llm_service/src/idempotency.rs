@@ -0,0 +1,424 @@
+//! Generations retried with the same `Idempotency-Key` are answered from
+//! the first attempt instead of running again.
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::http::{HeaderMap, HeaderName};
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use crate::{config::AppConfig, error::ServiceError, model::GenerationResponse};
+
+pub const IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+/// Set to `true` on responses that were not generated for this request.
+pub const IDEMPOTENT_REPLAY_HEADER: HeaderName = HeaderName::from_static("idempotent-replay");
+
+const MAX_KEY_LEN: usize = 255;
+
+type Outcome = Result<GenerationResponse, ServiceError>;
+type Entries = Arc<Mutex<LruCache<RequestKey, Entry>>>;
+
+/// The `Idempotency-Key` header, if the request sent one.
+pub fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, ServiceError> {
+    let Some(value) = headers.get(&IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => Ok(Some(key.to_string())),
+        _ => Err(ServiceError::BadRequest(format!(
+            "{IDEMPOTENCY_KEY_HEADER} must be 1 to {MAX_KEY_LEN} visible ASCII characters"
+        ))),
+    }
+}
+
+/// Whose key it is and where it was sent, so neither two API keys nor two
+/// routes can see each other's responses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestKey {
+    route: &'static str,
+    api_key: Option<String>,
+    idempotency_key: String,
+}
+
+impl RequestKey {
+    pub fn new(route: &'static str, api_key: Option<String>, idempotency_key: String) -> Self {
+        Self {
+            route,
+            api_key,
+            idempotency_key,
+        }
+    }
+}
+
+enum Entry {
+    Running {
+        id: u64,
+        body: [u8; 32],
+        outcome: watch::Receiver<Option<Outcome>>,
+    },
+    Done {
+        body: [u8; 32],
+        response: Box<GenerationResponse>,
+        expires: Instant,
+    },
+}
+
+impl Entry {
+    fn body(&self) -> &[u8; 32] {
+        match self {
+            Entry::Running { body, .. } | Entry::Done { body, .. } => body,
+        }
+    }
+
+    fn is_run(&self, run: u64) -> bool {
+        matches!(self, Entry::Running { id, .. } if *id == run)
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        matches!(self, Entry::Done { expires, .. } if *expires <= now)
+    }
+}
+
+/// Drops a running entry however its work ends, including a panic, unless
+/// a response has replaced it.
+struct Forget {
+    entries: Entries,
+    key: RequestKey,
+    id: u64,
+}
+
+impl Drop for Forget {
+    fn drop(&mut self) {
+        let mut entries = self.entries.lock();
+        if entries
+            .peek(&self.key)
+            .is_some_and(|entry| entry.is_run(self.id))
+        {
+            entries.pop(&self.key);
+        }
+    }
+}
+
+/// Bounded LRU of responses by idempotency key, each kept for `ttl` after
+/// it completes. Failures are not kept, so a retry after one runs again.
+pub struct IdempotencyCache {
+    entries: Option<Entries>,
+    ttl: Duration,
+    next_id: AtomicU64,
+}
+
+impl IdempotencyCache {
+    /// A capacity of zero ignores `Idempotency-Key`.
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            entries: NonZeroUsize::new(config.idempotency_cache_size)
+                .map(|cap| Arc::new(Mutex::new(LruCache::new(cap)))),
+            ttl: config.idempotency_ttl,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs `work` for the first request with `key` and answers later ones
+    /// from it, joining it while it still runs. The flag is set when the
+    /// response came from an earlier request. Reusing a key with a
+    /// different `body` is a 422. The work runs on its own task, so a
+    /// client that disconnects and retries finds it still going.
+    pub async fn run<F>(
+        &self,
+        key: Option<RequestKey>,
+        body: &impl Serialize,
+        work: F,
+    ) -> Result<(GenerationResponse, bool), ServiceError>
+    where
+        F: Future<Output = Outcome> + Send + 'static,
+    {
+        let (Some(entries), Some(key)) = (&self.entries, key) else {
+            return work.await.map(|response| (response, false));
+        };
+        let body: [u8; 32] = Sha256::digest(
+            serde_json::to_vec(body).map_err(|err| ServiceError::Other(err.to_string()))?,
+        )
+        .into();
+
+        let (mut outcome, replayed) = {
+            let mut cached = entries.lock();
+            if cached
+                .peek(&key)
+                .is_some_and(|entry| entry.expired(Instant::now()))
+            {
+                cached.pop(&key);
+            }
+            match cached.get(&key) {
+                Some(entry) if *entry.body() != body => {
+                    return Err(ServiceError::IdempotencyConflict(key.idempotency_key));
+                }
+                Some(Entry::Done { response, .. }) => {
+                    tracing::debug!(key = %key.idempotency_key, "replaying idempotent request");
+                    return Ok((response.as_ref().clone(), true));
+                }
+                Some(Entry::Running { outcome, .. }) => {
+                    tracing::debug!(key = %key.idempotency_key, "joining idempotent request");
+                    (outcome.clone(), true)
+                }
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let (sender, outcome) = watch::channel(None);
+                    cached.put(
+                        key.clone(),
+                        Entry::Running {
+                            id,
+                            body,
+                            outcome: outcome.clone(),
+                        },
+                    );
+                    let forget = Forget {
+                        entries: entries.clone(),
+                        key,
+                        id,
+                    };
+                    let ttl = self.ttl;
+                    tokio::spawn(async move {
+                        let result = work.await;
+                        if let Ok(response) = &result {
+                            let mut cached = forget.entries.lock();
+                            // Unless it was evicted, and maybe reused, meanwhile.
+                            if cached
+                                .peek(&forget.key)
+                                .is_some_and(|entry| entry.is_run(forget.id))
+                            {
+                                cached.put(
+                                    forget.key.clone(),
+                                    Entry::Done {
+                                        body,
+                                        response: Box::new(response.clone()),
+                                        expires: Instant::now() + ttl,
+                                    },
+                                );
+                            }
+                        }
+                        drop(forget);
+                        let _ = sender.send(Some(result));
+                    });
+                    (outcome, false)
+                }
+            }
+        };
+
+        match outcome.wait_for(Option::is_some).await {
+            Ok(result) => result
+                .clone()
+                .expect("waited for a result")
+                .map(|response| (response, replayed)),
+            Err(_) => Err(ServiceError::Inference(
+                "request with this idempotency key ended without a result".into(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::model::{
+        Backend,
+        testing::{FakeModel, gpt2, greedy, next_token},
+    };
+
+    fn cache(ttl: Duration) -> IdempotencyCache {
+        IdempotencyCache::from_config(&AppConfig {
+            idempotency_cache_size: 8,
+            idempotency_ttl: ttl,
+            ..AppConfig::default()
+        })
+    }
+
+    fn key(api_key: Option<&str>, idempotency_key: &str) -> Option<RequestKey> {
+        Some(RequestKey::new(
+            "/generate",
+            api_key.map(str::to_owned),
+            idempotency_key.to_string(),
+        ))
+    }
+
+    /// Work that answers with a fixed response and counts how often it ran.
+    struct Work {
+        response: GenerationResponse,
+        runs: Arc<AtomicUsize>,
+    }
+
+    impl Work {
+        fn new() -> Self {
+            let response = FakeModel::new("fake", next_token)
+                .generate(&gpt2(), "Hello", &greedy(2), None)
+                .expect("fake generation");
+            Self {
+                response,
+                runs: Arc::default(),
+            }
+        }
+
+        /// Takes `delay` to answer.
+        fn run(&self, delay: Duration) -> impl Future<Output = Outcome> + Send + 'static {
+            let (response, runs) = (self.response.clone(), self.runs.clone());
+            async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(delay).await;
+                Ok(response)
+            }
+        }
+
+        fn runs(&self) -> usize {
+            self.runs.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_with_the_same_key_and_body_is_replayed() {
+        let cache = cache(Duration::from_secs(60));
+        let work = Work::new();
+        let body = json!({"prompt": "Hello"});
+
+        let (first, replayed) = cache
+            .run(key(None, "k"), &body, work.run(Duration::ZERO))
+            .await
+            .unwrap();
+        assert!(!replayed);
+        let (second, replayed) = cache
+            .run(key(None, "k"), &body, work.run(Duration::ZERO))
+            .await
+            .unwrap();
+        assert!(replayed);
+        assert_eq!(second.completion, first.completion);
+        assert_eq!(work.runs(), 1);
+    }
+
+    #[tokio::test]
+    async fn same_key_with_another_body_conflicts() {
+        let cache = cache(Duration::from_secs(60));
+        let work = Work::new();
+        cache
+            .run(
+                key(None, "k"),
+                &json!({"prompt": "Hello"}),
+                work.run(Duration::ZERO),
+            )
+            .await
+            .unwrap();
+
+        let err = cache
+            .run(
+                key(None, "k"),
+                &json!({"prompt": "Goodbye"}),
+                work.run(Duration::ZERO),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(&err, ServiceError::IdempotencyConflict(key) if key == "k"));
+        assert_eq!(err.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(work.runs(), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_are_scoped_to_the_api_key() {
+        let cache = cache(Duration::from_secs(60));
+        let work = Work::new();
+        let body = json!({"prompt": "Hello"});
+        for api_key in [Some("alice"), Some("bob"), None] {
+            let (_, replayed) = cache
+                .run(key(api_key, "k"), &body, work.run(Duration::ZERO))
+                .await
+                .unwrap();
+            assert!(!replayed);
+        }
+        assert_eq!(work.runs(), 3);
+    }
+
+    #[tokio::test]
+    async fn expired_responses_run_again() {
+        let cache = cache(Duration::from_millis(20));
+        let work = Work::new();
+        let body = json!({"prompt": "Hello"});
+        cache
+            .run(key(None, "k"), &body, work.run(Duration::ZERO))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        let (_, replayed) = cache
+            .run(key(None, "k"), &body, work.run(Duration::ZERO))
+            .await
+            .unwrap();
+        assert!(!replayed);
+        assert_eq!(work.runs(), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_retries_join_the_running_request() {
+        let cache = cache(Duration::from_secs(60));
+        let work = Work::new();
+        let body = json!({"prompt": "Hello"});
+        let (first, second) = tokio::join!(
+            cache.run(key(None, "k"), &body, work.run(Duration::from_millis(50))),
+            cache.run(key(None, "k"), &body, work.run(Duration::from_millis(50))),
+        );
+        assert_eq!(
+            (first.unwrap().1, second.unwrap().1),
+            (false, true),
+            "the second request joins the first"
+        );
+        assert_eq!(work.runs(), 1);
+    }
+
+    #[tokio::test]
+    async fn failures_are_not_kept() {
+        let cache = cache(Duration::from_secs(60));
+        let work = Work::new();
+        let body = json!({"prompt": "Hello"});
+        let err = cache
+            .run(key(None, "k"), &body, async {
+                Err(ServiceError::Inference("out of memory".into()))
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ServiceError::Inference(_)));
+
+        let (_, replayed) = cache
+            .run(key(None, "k"), &body, work.run(Duration::ZERO))
+            .await
+            .unwrap();
+        assert!(!replayed);
+    }
+
+    #[tokio::test]
+    async fn zero_capacity_ignores_the_key() {
+        let cache = IdempotencyCache::from_config(&AppConfig {
+            idempotency_cache_size: 0,
+            ..AppConfig::default()
+        });
+        let work = Work::new();
+        let body = json!({"prompt": "Hello"});
+        for _ in 0..2 {
+            let (_, replayed) = cache
+                .run(key(None, "k"), &body, work.run(Duration::ZERO))
+                .await
+                .unwrap();
+            assert!(!replayed);
+        }
+        assert_eq!(work.runs(), 2);
+    }
+}
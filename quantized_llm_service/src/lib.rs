@@ -1,11 +1,40 @@
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod config;
+pub mod cpu_time;
+pub mod divergence;
 pub mod error;
 pub mod evaluation;
+pub mod extract;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod html_report;
+pub mod idempotency;
+pub mod memory;
+pub mod middleware;
 pub mod model;
+pub mod offline;
+pub mod presets;
+pub mod progress;
 pub mod quantization;
+pub mod quota;
+pub mod rate_limit;
 pub mod server;
+pub mod shadow;
+pub mod slow_requests;
+pub mod store;
+pub mod telemetry;
+pub mod templates;
+#[cfg(test)]
+pub(crate) mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod version;
+pub mod websocket;
 
 pub use config::AppConfig;
-pub use evaluation::{BenchmarkSample, EvaluationReport};
+pub use evaluation::{BenchmarkSample, EvaluationReport, MatchMode};
 pub use model::{GenerationRequest, GenerationResponse, ModelRegistry};
 pub use server::build_router;
@@ -1,41 +1,315 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode, sync::Arc};
 
-use tokio::net::TcpListener;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+#[cfg(feature = "tls")]
+use std::time::Duration;
 
-use quantized_llm_service::{AppConfig, ModelRegistry, build_router};
+#[cfg(feature = "tls")]
+use axum_server::tls_rustls::RustlsConfig;
+use clap::{Args, Parser, Subcommand};
+use tokio::{net::TcpListener, sync::watch};
+
+#[cfg(feature = "tls")]
+use quantized_llm_service::tls;
+use quantized_llm_service::{
+    AppConfig, ModelRegistry,
+    audit::AuditLog,
+    build_router,
+    model::watch_models,
+    offline::{self, EvaluateArgs},
+    quantization::quantize_module,
+    store::Store,
+    telemetry::init_tracing,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Quantized LLM inference service")]
+struct Cli {
+    /// TOML config file; environment variables and flags override its values.
+    #[arg(long, env = "CONFIG_PATH", global = true)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    overrides: ConfigOverrides,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the REST server (default).
+    Serve,
+    /// Run the benchmark offline and write the report to disk.
+    Evaluate(EvaluateArgs),
+    /// Produce a dynamically quantized copy of a TorchScript module.
+    Quantize(QuantizeArgs),
+}
+
+/// Flags mirroring the environment variables read by `AppConfig`.
+#[derive(Debug, Args)]
+struct ConfigOverrides {
+    #[arg(long, env = "SERVER_ADDR", global = true)]
+    server_addr: Option<SocketAddr>,
+    #[arg(long, env = "MODEL_ID", global = true)]
+    model_id: Option<String>,
+    #[arg(long, env = "BASELINE_MODULE_PATH", global = true)]
+    baseline_module_path: Option<PathBuf>,
+    #[arg(long, env = "QUANTIZED_MODULE_PATH", global = true)]
+    quantized_module_path: Option<PathBuf>,
+    #[arg(long, env = "TOKENIZER_PATH", global = true)]
+    tokenizer_path: Option<PathBuf>,
+    #[arg(long, env = "MAX_NEW_TOKENS", global = true)]
+    max_new_tokens: Option<usize>,
+    #[arg(long, env = "TEMPERATURE", global = true)]
+    temperature: Option<f64>,
+    #[arg(long, env = "TOP_K", global = true)]
+    top_k: Option<usize>,
+    #[arg(long, env = "EVAL_CONCURRENCY", global = true)]
+    eval_concurrency: Option<usize>,
+}
+
+#[derive(Debug, Args)]
+struct QuantizeArgs {
+    /// Module to quantize; defaults to the configured baseline module.
+    #[arg(long)]
+    input: Option<PathBuf>,
+    /// Destination; defaults to the configured quantized module path.
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Python interpreter with PyTorch installed.
+    #[arg(long, env = "PYTHON", default_value = "python3")]
+    python: String,
+}
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    init_tracing();
+async fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+    let mut config = match cli.config.as_deref() {
+        Some(path) => AppConfig::load_with_file(path)?,
+        None => AppConfig::from_env()?,
+    };
+    cli.overrides.apply(&mut config);
+    // Flushes exported spans when main returns.
+    let _telemetry = init_tracing(&config)?;
+    let config = Arc::new(config);
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    if !matches!(command, Command::Quantize(_)) {
+        config.validate()?;
+    }
+    #[cfg(feature = "tch-backend")]
+    quantized_llm_service::model::tch_backend::configure_threads(&config);
+
+    match command {
+        Command::Serve => serve(config).await,
+        Command::Evaluate(args) => offline::evaluate(config, args).await,
+        Command::Quantize(args) => quantize(&config, args),
+    }
+}
+
+impl ConfigOverrides {
+    fn apply(self, config: &mut AppConfig) {
+        if let Some(addr) = self.server_addr {
+            config.listen_addr = addr;
+        }
+        if let Some(model_id) = self.model_id {
+            config.model_id = model_id;
+        }
+        if let Some(path) = self.baseline_module_path {
+            config.baseline_module_path = path;
+        }
+        if let Some(path) = self.quantized_module_path {
+            config.quantized_module_path = path;
+        }
+        if let Some(path) = self.tokenizer_path {
+            config.tokenizer_path = path;
+        }
+        if let Some(value) = self.max_new_tokens {
+            config.max_new_tokens = value;
+        }
+        if let Some(value) = self.temperature {
+            config.temperature = value;
+        }
+        if let Some(value) = self.top_k {
+            config.top_k = value;
+        }
+        if let Some(value) = self.eval_concurrency {
+            config.eval_concurrency = value;
+        }
+    }
+}
+
+async fn serve(config: Arc<AppConfig>) -> anyhow::Result<ExitCode> {
+    // Checked before the models load so a bad certificate fails fast.
+    #[cfg(feature = "tls")]
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => Some(RustlsConfig::from_config(Arc::new(
+            tls::load_server_config(cert, key)?,
+        ))),
+        _ => None,
+    };
 
-    let config = Arc::new(AppConfig::from_env()?);
     tracing::info!(?config.listen_addr, "loading model artifacts");
 
     let registry = Arc::new(ModelRegistry::initialize(config.as_ref())?);
-    let router = build_router(config.clone(), registry);
+    if config.watch_models {
+        watch_models(registry.clone(), config.clone())?;
+    }
+    let store = open_store(&config)?;
+    if let Some(store) = &store {
+        registry.usage().attach_store(store.clone()).await?;
+    }
+    let audit = AuditLog::from_config(&config)?;
+    let router = build_router(
+        config.clone(),
+        registry.clone(),
+        store.clone(),
+        audit.clone(),
+    );
 
     let listener = TcpListener::bind(config.listen_addr).await?;
     let addr = listener.local_addr()?;
-    tracing::info!(%addr, "REST server ready");
+    #[cfg(feature = "tls")]
+    let https = tls.is_some();
+    #[cfg(not(feature = "tls"))]
+    let https = false;
+    tracing::info!(%addr, https, "REST server ready");
+
+    // One signal drains every server.
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    // Peer addresses key the rate limiter when authentication is disabled.
+    let rest = async {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = tls {
+            serve_tls(
+                &config,
+                listener,
+                router,
+                tls,
+                shutdown_requested(shutdown_rx.clone()),
+            )
+            .await?;
+            tracing::info!("REST server stopped");
+            return anyhow::Ok(());
+        }
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_requested(shutdown_rx.clone()))
+        .await?;
+        tracing::info!("REST server stopped");
+        anyhow::Ok(())
+    };
+
+    #[cfg(feature = "grpc")]
+    {
+        let grpc = async {
+            if let Some(addr) = config.grpc_addr {
+                quantized_llm_service::grpc::serve(
+                    addr,
+                    config.clone(),
+                    registry,
+                    store,
+                    audit,
+                    shutdown_requested(shutdown_rx.clone()),
+                )
+                .await?;
+            }
+            anyhow::Ok(())
+        };
+        tokio::try_join!(rest, grpc)?;
+    }
+    #[cfg(not(feature = "grpc"))]
+    {
+        if config.grpc_addr.is_some() {
+            tracing::warn!("GRPC_ADDR is set but this build lacks the `grpc` feature; ignoring it");
+        }
+        rest.await?;
+    }
 
-    axum::serve(listener, router).await?;
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(feature = "tls")]
+async fn serve_tls(
+    config: &AppConfig,
+    listener: TcpListener,
+    router: axum::Router,
+    tls: RustlsConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    if config.tls_reload_secs > 0
+        && let (Some(cert), Some(key)) = (config.tls_cert_path.clone(), config.tls_key_path.clone())
+    {
+        let every = Duration::from_secs(config.tls_reload_secs);
+        let tls = tls.clone();
+        tokio::spawn(async move { tls::reload_periodically(tls, &cert, &key, every).await });
+    }
 
+    let handle = axum_server::Handle::new();
+    let stopper = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        stopper.graceful_shutdown(None);
+    });
+    axum_server::from_tcp_rustls(listener.into_std()?, tls)
+        .handle(handle)
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
     Ok(())
 }
 
-fn init_tracing() {
-    if tracing::dispatcher::has_been_set() {
-        return;
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
-    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| "info,hyper=warn,axum::rejection=trace".into());
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .with_target(false)
-        .compact();
+    tracing::info!("shutdown signal received, draining connections");
+}
+
+fn open_store(config: &AppConfig) -> anyhow::Result<Option<Store>> {
+    config
+        .database_path
+        .as_deref()
+        .map(Store::open)
+        .transpose()
+        .map_err(Into::into)
+}
 
-    tracing_subscriber::registry()
-        .with(env_filter)
-        .with(fmt_layer)
-        .init();
+fn quantize(config: &AppConfig, args: QuantizeArgs) -> anyhow::Result<ExitCode> {
+    let input = args
+        .input
+        .unwrap_or_else(|| config.baseline_module_path.clone());
+    let output = args
+        .output
+        .unwrap_or_else(|| config.quantized_module_path.clone());
+    tracing::info!(input = %input.display(), output = %output.display(), "quantizing module");
+    let summary = quantize_module(&input, &output, &args.python)?;
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    Ok(ExitCode::SUCCESS)
 }
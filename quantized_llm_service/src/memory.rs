@@ -0,0 +1,203 @@
+//! Process memory readings for comparing what the models cost to run.
+//!
+//! Linux only: every probe returns `None` where `/proc/self` is unavailable.
+//! tch exposes no CUDA allocator statistics, so GPU memory is read from the
+//! CUDA driver and covers the whole device rather than this process.
+
+use std::collections::BTreeMap;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Resident set size now and its high-water mark, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryUsage {
+    pub rss_bytes: u64,
+    pub peak_rss_bytes: u64,
+}
+
+/// Reads `VmRSS` and `VmHWM` from `/proc/self/status`.
+pub fn current() -> Option<MemoryUsage> {
+    #[cfg(target_os = "linux")]
+    {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .and_then(|rest| rest.trim().strip_suffix("kB"))
+                .and_then(|kb| kb.trim().parse::<u64>().ok())
+                .map(|kb| kb * 1024)
+        };
+        Some(MemoryUsage {
+            rss_bytes: field("VmRSS:")?,
+            peak_rss_bytes: field("VmHWM:")?,
+        })
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// How far resident memory rises above where it was when the probe started.
+///
+/// Starting a probe resets the process-wide high-water mark, so readings are
+/// approximate when several probes overlap.
+#[derive(Debug, Clone, Copy)]
+pub struct PeakProbe {
+    start_rss_bytes: u64,
+}
+
+impl PeakProbe {
+    pub fn start() -> Option<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            // "5" resets VmHWM to the current RSS without touching page state.
+            let _ = std::fs::write("/proc/self/clear_refs", "5");
+        }
+        current().map(|usage| Self {
+            start_rss_bytes: usage.rss_bytes,
+        })
+    }
+
+    pub fn peak_delta_bytes(&self) -> Option<u64> {
+        current().map(|usage| usage.peak_rss_bytes.saturating_sub(self.start_rss_bytes))
+    }
+}
+
+/// Memory of one CUDA device as the driver reports it.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeviceMemory {
+    pub device: usize,
+    /// The loaded models running on it.
+    pub models: Vec<String>,
+    /// In use on the device, by this process and any other.
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+    /// Highest `used_bytes` read since startup. Readings are only taken when
+    /// health or metadata is requested, so spikes between them are missed.
+    pub peak_used_bytes: u64,
+}
+
+static PEAK_USED_BYTES: Mutex<BTreeMap<usize, u64>> = Mutex::new(BTreeMap::new());
+
+/// Reads CUDA device `device`; `None` when no driver or device is there.
+pub fn device(device: usize, models: Vec<String>) -> Option<DeviceMemory> {
+    let (free_bytes, total_bytes) = cuda::mem_get_info(device)?;
+    let used_bytes = total_bytes.saturating_sub(free_bytes);
+    let mut peaks = PEAK_USED_BYTES.lock();
+    let peak = peaks.entry(device).or_default();
+    *peak = (*peak).max(used_bytes);
+    Some(DeviceMemory {
+        device,
+        models,
+        used_bytes,
+        total_bytes,
+        peak_used_bytes: *peak,
+    })
+}
+
+#[cfg(all(feature = "tch-backend", any(target_os = "linux", windows)))]
+mod cuda {
+    use std::{ffi::c_void, ptr, sync::LazyLock};
+
+    use libloading::Library;
+
+    #[cfg(target_os = "linux")]
+    const DRIVER: &str = "libcuda.so.1";
+    #[cfg(windows)]
+    const DRIVER: &str = "nvcuda.dll";
+    const CUDA_SUCCESS: i32 = 0;
+
+    type Context = *mut c_void;
+
+    static LIBRARY: LazyLock<Option<Library>> =
+        // SAFETY: the driver library has no load-time side effects beyond
+        // registering itself.
+        LazyLock::new(|| unsafe { Library::new(DRIVER) }.ok());
+
+    /// Free and total bytes of `device`, read inside its primary context,
+    /// which is the one libtorch allocates from.
+    pub(super) fn mem_get_info(device: usize) -> Option<(u64, u64)> {
+        let library = LIBRARY.as_ref()?;
+        let device = i32::try_from(device).ok()?;
+        // SAFETY: the signatures match the CUDA driver API, every out
+        // pointer is valid for the call, and the context pushed here is
+        // popped and released before returning.
+        unsafe {
+            let init = library
+                .get::<unsafe extern "C" fn(u32) -> i32>(b"cuInit\0")
+                .ok()?;
+            let device_get = library
+                .get::<unsafe extern "C" fn(*mut i32, i32) -> i32>(b"cuDeviceGet\0")
+                .ok()?;
+            let retain = library
+                .get::<unsafe extern "C" fn(*mut Context, i32) -> i32>(
+                    b"cuDevicePrimaryCtxRetain\0",
+                )
+                .ok()?;
+            let release = library
+                .get::<unsafe extern "C" fn(i32) -> i32>(b"cuDevicePrimaryCtxRelease_v2\0")
+                .ok()?;
+            let push = library
+                .get::<unsafe extern "C" fn(Context) -> i32>(b"cuCtxPushCurrent_v2\0")
+                .ok()?;
+            let pop = library
+                .get::<unsafe extern "C" fn(*mut Context) -> i32>(b"cuCtxPopCurrent_v2\0")
+                .ok()?;
+            let mem_get_info = library
+                .get::<unsafe extern "C" fn(*mut usize, *mut usize) -> i32>(b"cuMemGetInfo_v2\0")
+                .ok()?;
+
+            let mut handle = 0;
+            if init(0) != CUDA_SUCCESS || device_get(&mut handle, device) != CUDA_SUCCESS {
+                return None;
+            }
+            let mut context = ptr::null_mut();
+            if retain(&mut context, handle) != CUDA_SUCCESS {
+                return None;
+            }
+            let (mut free, mut total) = (0, 0);
+            let read = push(context) == CUDA_SUCCESS && {
+                let read = mem_get_info(&mut free, &mut total) == CUDA_SUCCESS;
+                pop(&mut ptr::null_mut());
+                read
+            };
+            release(handle);
+            read.then_some((free as u64, total as u64))
+        }
+    }
+}
+
+#[cfg(not(all(feature = "tch-backend", any(target_os = "linux", windows))))]
+mod cuda {
+    pub(super) fn mem_get_info(_device: usize) -> Option<(u64, u64)> {
+        None
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linux_reports_resident_memory() {
+        let usage = current().expect("/proc/self/status is readable");
+        assert!(usage.rss_bytes > 0);
+        assert!(usage.peak_rss_bytes >= usage.rss_bytes);
+    }
+
+    #[test]
+    fn a_probe_sees_memory_touched_after_it_started() {
+        let probe = PeakProbe::start().unwrap();
+        let touched = vec![1u8; 64 << 20];
+        std::hint::black_box(&touched);
+        let delta = probe.peak_delta_bytes().unwrap();
+        assert!(delta >= 32 << 20, "{delta} bytes");
+    }
+
+    #[test]
+    fn a_device_that_is_not_there_reads_as_none() {
+        assert!(device(4096, vec!["quantized".into()]).is_none());
+    }
+}
@@ -0,0 +1,354 @@
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{HeaderName, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::time::Instant;
+
+use tracing::Span;
+
+use crate::{
+    auth::ApiKeyLabel,
+    config::AppConfig,
+    error::{ErrorBody, ServiceError},
+};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Names the model that produced a generation.
+pub const SERVED_MODEL_HEADER: HeaderName = HeaderName::from_static("x-served-model");
+
+pub fn request_id(request: &Request<Body>) -> Option<&str> {
+    request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Span used by the HTTP trace layer, tagged with the request id so log lines
+/// can be correlated with error bodies.
+pub fn make_request_span(request: &Request<Body>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str)
+            .unwrap_or("-"),
+        request_id = request_id(request).unwrap_or("-"),
+        api_key = tracing::field::Empty,
+    );
+    #[cfg(feature = "otel")]
+    crate::telemetry::set_remote_parent(&span, request.headers());
+    span
+}
+
+/// Copies the request id into structured error bodies produced by
+/// `ServiceError`, so handlers don't need to thread it through themselves.
+pub async fn attach_request_id(request: Request, next: Next) -> Response {
+    let id = request_id(&request).map(str::to_owned);
+    let mut response = next.run(request).await;
+
+    let (Some(id), Some(mut body)) = (id, response.extensions_mut().remove::<ErrorBody>()) else {
+        return response;
+    };
+    body.error.request_id = Some(id);
+    rerender_error(&response, body)
+}
+
+/// Gives 503s without a retry hint of their own the configured one: always
+/// as `Retry-After`, and as `retry_after_secs` in structured error details.
+pub async fn default_retry_after(
+    State(retry_after_secs): State<u64>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if response.status() != StatusCode::SERVICE_UNAVAILABLE
+        || response.headers().contains_key(header::RETRY_AFTER)
+    {
+        return response;
+    }
+    if let Some(mut body) = response.extensions_mut().remove::<ErrorBody>() {
+        let details = body
+            .error
+            .details
+            .get_or_insert_with(|| serde_json::json!({}));
+        if let Some(details) = details.as_object_mut() {
+            details.insert("retry_after_secs".into(), retry_after_secs.into());
+        }
+        response = rerender_error(&response, body);
+    }
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    response
+}
+
+/// Renders `body` with the response's status and headers, keeping it as an
+/// extension for layers further out.
+fn rerender_error(response: &Response, body: ErrorBody) -> Response {
+    let mut rebuilt = (response.status(), axum::Json(body.clone())).into_response();
+    for (name, value) in response.headers() {
+        if name != header::CONTENT_LENGTH {
+            rebuilt.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+    rebuilt.extensions_mut().insert(body);
+    rebuilt
+}
+
+/// Replaces the plain-text 413 from the body limit (raised up front for a
+/// large `Content-Length`, or while a handler reads the body) with the
+/// structured error.
+pub async fn structured_payload_too_large(
+    State(limit_bytes): State<usize>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE
+        || response.extensions().get::<ErrorBody>().is_some()
+    {
+        return response;
+    }
+    ServiceError::PayloadTooLarge { limit_bytes }.into_response()
+}
+
+/// Access log settings; see `log_access`.
+#[derive(Debug, Clone, Copy)]
+pub struct AccessLog {
+    enabled: bool,
+    sample_rate: f64,
+}
+
+impl AccessLog {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            enabled: config.access_log,
+            sample_rate: config.access_log_sample_rate,
+        }
+    }
+}
+
+/// What a generation handler served, attached to its response for the
+/// access log.
+#[derive(Debug, Clone)]
+pub struct ServedGeneration {
+    pub model: String,
+    pub tokens_generated: usize,
+}
+
+/// Writes one `access` line per request, sampled at the configured rate.
+/// Server errors are always logged.
+pub async fn log_access(State(log): State<AccessLog>, request: Request, next: Next) -> Response {
+    if !log.enabled {
+        return next.run(request).await;
+    }
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let request_id = request_id(&request).map(str::to_owned);
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+    if !status.is_server_error() && rand::random::<f64>() >= log.sample_rate {
+        return response;
+    }
+    let api_key = response
+        .extensions()
+        .get::<ApiKeyLabel>()
+        .map(|ApiKeyLabel(label)| label.as_str());
+    let served = response.extensions().get::<ServedGeneration>();
+    tracing::info!(
+        target: "access",
+        %method,
+        path,
+        status = status.as_u16(),
+        latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+        request_id = request_id.as_deref(),
+        api_key,
+        model = served.map(|served| served.model.as_str()),
+        tokens_generated = served.map(|served| served.tokens_generated),
+        "request"
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, middleware, routing::get};
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{self, CapturedLogs, send};
+
+    /// Routes failing in each way `default_retry_after` tells apart, with a
+    /// default of 7 seconds.
+    fn router() -> Router {
+        Router::new()
+            .route(
+                "/loading",
+                get(|| async {
+                    ServiceError::ModelLoading {
+                        retry_after_secs: None,
+                    }
+                }),
+            )
+            .route(
+                "/overloaded",
+                get(|| async {
+                    ServiceError::Overloaded {
+                        message: "busy".into(),
+                        retry_after_secs: Some(2),
+                    }
+                }),
+            )
+            .route(
+                "/plain",
+                get(|| async { (StatusCode::SERVICE_UNAVAILABLE, "down") }),
+            )
+            .route(
+                "/bad",
+                get(|| async { ServiceError::BadRequest("no".into()) }),
+            )
+            .layer(middleware::from_fn_with_state(7, default_retry_after))
+    }
+
+    #[tokio::test]
+    async fn structured_503s_get_the_default_hint() {
+        let reply = send(&router(), testing::get("/loading")).await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(reply.header(header::RETRY_AFTER), Some("7"));
+        assert_eq!(reply.error_code(), "model_loading");
+        assert_eq!(
+            reply.json()["error"]["details"],
+            json!({ "retry_after_secs": 7 })
+        );
+    }
+
+    #[tokio::test]
+    async fn a_hint_of_their_own_is_kept() {
+        let reply = send(&router(), testing::get("/overloaded")).await;
+        assert_eq!(reply.header(header::RETRY_AFTER), Some("2"));
+        assert_eq!(
+            reply.json()["error"]["details"],
+            json!({ "retry_after_secs": 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn unstructured_503s_only_get_the_header() {
+        let reply = send(&router(), testing::get("/plain")).await;
+        assert_eq!(reply.header(header::RETRY_AFTER), Some("7"));
+        assert_eq!(reply.text(), "down");
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_left_alone() {
+        let reply = send(&router(), testing::get("/bad")).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert_eq!(reply.header(header::RETRY_AFTER), None);
+        assert_eq!(reply.json()["error"].get("details"), None);
+    }
+
+    #[tokio::test]
+    async fn a_client_request_id_is_echoed_and_put_in_error_bodies() {
+        let router = testing::router("middleware-request-id", |_| {});
+        let mut request = testing::post_json("/generate", json!({ "prompt": "" }));
+        request
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER, HeaderValue::from_static("trace-me-42"));
+        let reply = send(&router, request).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert_eq!(reply.header(REQUEST_ID_HEADER), Some("trace-me-42"));
+        assert_eq!(reply.json()["error"]["request_id"], "trace-me-42");
+    }
+
+    #[tokio::test]
+    async fn requests_without_one_get_a_fresh_uuid() {
+        let router = testing::router("middleware-request-uuid", |_| {});
+        let first = send(&router, testing::get("/health")).await;
+        let second = send(&router, testing::get("/health")).await;
+        let ids =
+            [&first, &second].map(|reply| reply.header(REQUEST_ID_HEADER).unwrap().to_string());
+        for id in &ids {
+            let groups: Vec<usize> = id.split('-').map(str::len).collect();
+            assert_eq!(groups, [8, 4, 4, 4, 12], "{id} is not a UUID");
+            assert!(
+                id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()),
+                "{id}"
+            );
+        }
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    /// Every `access` line logged on this thread while `run` is awaited.
+    async fn access_lines(run: impl Future<Output = ()>) -> Vec<serde_json::Value> {
+        let logs = CapturedLogs::default();
+        {
+            let _default = tracing::dispatcher::set_default(&logs.dispatch());
+            run.await;
+        }
+        logs.lines()
+            .into_iter()
+            .filter(|line| line["target"] == "access")
+            .map(|line| line["fields"].clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn generations_are_logged_with_what_they_served() {
+        let router = testing::router("middleware-access-log", |config| {
+            config.access_log = true;
+            config.api_keys = vec!["alice:user-secret".into()];
+        });
+        let lines = access_lines(async {
+            let mut request = testing::post_json(
+                "/generate",
+                json!({ "prompt": "Hello", "max_new_tokens": 3 }),
+            );
+            let headers = request.headers_mut();
+            headers.insert("x-api-key", HeaderValue::from_static("user-secret"));
+            headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("access-1"));
+            assert_eq!(send(&router, request).await.status, StatusCode::OK);
+        })
+        .await;
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        let line = &lines[0];
+        assert_eq!(line["message"], "request");
+        assert_eq!(line["method"], "POST");
+        assert_eq!(line["path"], "/generate");
+        assert_eq!(line["status"], 200);
+        assert!(line["latency_ms"].as_f64().unwrap() > 0.0);
+        assert_eq!(line["request_id"], "access-1");
+        assert_eq!(line["api_key"], "alice");
+        assert_eq!(line["model"], "quantized");
+        assert_eq!(line["tokens_generated"], 3);
+    }
+
+    #[tokio::test]
+    async fn sampled_out_or_disabled_means_no_line() {
+        let router = testing::router("middleware-access-sampled", |config| {
+            config.access_log = true;
+            config.access_log_sample_rate = 0.0;
+        });
+        let lines = access_lines(async {
+            send(&router, testing::get("/health/live")).await;
+        })
+        .await;
+        assert!(lines.is_empty(), "{lines:?}");
+
+        let router = testing::router("middleware-access-off", |_| {});
+        let lines = access_lines(async {
+            send(&router, testing::get("/health/live")).await;
+        })
+        .await;
+        assert!(lines.is_empty(), "{lines:?}");
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use utoipa::ToSchema;
+
+use crate::model::Priority;
+
+/// Lets one generation at a time onto each copy of a model, always
+/// preferring waiting interactive requests over batch ones. A batch request that has waited
+/// longer than `promote_after` goes next regardless, so a steady stream of
+/// interactive traffic can't starve it.
+pub struct AdmissionQueue {
+    state: Mutex<QueueState>,
+    promote_after: Duration,
+}
+
+struct QueueState {
+    /// Generations let on and not yet finished.
+    running: usize,
+    /// How many may run at once: one per replica.
+    capacity: usize,
+    interactive: VecDeque<Waiter>,
+    batch: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    enqueued_at: Instant,
+    admit: oneshot::Sender<()>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct QueueLengths {
+    pub interactive: usize,
+    pub batch: usize,
+}
+
+/// Holds the model's slot; dropping it admits the next waiter.
+pub struct Admission {
+    queue: Arc<AdmissionQueue>,
+}
+
+impl AdmissionQueue {
+    pub fn new(promote_after: Duration) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                running: 0,
+                capacity: 1,
+                interactive: VecDeque::new(),
+                batch: VecDeque::new(),
+            }),
+            promote_after,
+        }
+    }
+
+    pub async fn admit(self: &Arc<Self>, priority: Priority) -> Admission {
+        let admitted = {
+            let mut state = self.state.lock();
+            if state.running >= state.capacity {
+                let (admit, admitted) = oneshot::channel();
+                let waiter = Waiter {
+                    enqueued_at: Instant::now(),
+                    admit,
+                };
+                match priority {
+                    Priority::Interactive => state.interactive.push_back(waiter),
+                    Priority::Batch => state.batch.push_back(waiter),
+                }
+                Some(admitted)
+            } else {
+                state.running += 1;
+                None
+            }
+        };
+        if let Some(admitted) = admitted {
+            // `release` hands the slot over directly, so it is already
+            // counted as running on our behalf once this resolves.
+            let mut pending = Pending {
+                queue: self,
+                admitted: Some(admitted),
+            };
+            if let Some(admitted) = pending.admitted.as_mut() {
+                let _ = admitted.await;
+            }
+            pending.admitted = None;
+        }
+        Admission {
+            queue: self.clone(),
+        }
+    }
+
+    pub fn lengths(&self) -> QueueLengths {
+        let state = self.state.lock();
+        QueueLengths {
+            interactive: state.interactive.len(),
+            batch: state.batch.len(),
+        }
+    }
+
+    /// Sets how many generations may run at once, letting waiters in if it
+    /// grew. A shrink takes effect as running ones finish.
+    pub fn set_capacity(&self, capacity: usize) {
+        let admit = {
+            let mut state = self.state.lock();
+            state.capacity = capacity.max(1);
+            state.capacity.saturating_sub(state.running)
+        };
+        for _ in 0..admit {
+            // Take a free slot and pass it to the next waiter, if any.
+            self.state.lock().running += 1;
+            self.release();
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock();
+        if state.running > state.capacity {
+            state.running -= 1;
+            return;
+        }
+        loop {
+            let promote = state
+                .batch
+                .front()
+                .is_some_and(|waiter| waiter.enqueued_at.elapsed() >= self.promote_after);
+            let next = if promote {
+                state.batch.pop_front()
+            } else {
+                state
+                    .interactive
+                    .pop_front()
+                    .or_else(|| state.batch.pop_front())
+            };
+            match next {
+                Some(waiter) => {
+                    if waiter.admit.send(()).is_ok() {
+                        return;
+                    }
+                }
+                None => {
+                    state.running -= 1;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// A waiter whose future was dropped before it was admitted gives up its
+/// place, or passes the slot on if it was handed over in the meantime.
+struct Pending<'a> {
+    queue: &'a AdmissionQueue,
+    admitted: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if let Some(mut admitted) = self.admitted.take() {
+            admitted.close();
+            if admitted.try_recv().is_ok() {
+                self.queue.release();
+            }
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queues a task per arrival behind a held slot, pausing for its
+    /// duration after each, then frees the slot and returns the indices of
+    /// the arrivals in the order they were admitted.
+    async fn admission_order(
+        queue: Arc<AdmissionQueue>,
+        arrivals: &[(Priority, Duration)],
+    ) -> Vec<usize> {
+        let held = queue.admit(Priority::Interactive).await;
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for (index, &(priority, then_wait)) in arrivals.iter().enumerate() {
+            let (waiter, order) = (queue.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _admission = waiter.admit(priority).await;
+                order.lock().push(index);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }));
+            while queue.lengths().interactive + queue.lengths().batch <= index {
+                tokio::task::yield_now().await;
+            }
+            tokio::time::sleep(then_wait).await;
+        }
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        Arc::into_inner(order).unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn interactive_requests_overtake_queued_batch_ones() {
+        let queue = Arc::new(AdmissionQueue::new(Duration::from_secs(3600)));
+        let mut arrivals = vec![(Priority::Batch, Duration::ZERO); 10];
+        arrivals.push((Priority::Interactive, Duration::ZERO));
+        let order = admission_order(queue, &arrivals).await;
+        assert_eq!(order[0], 10, "{order:?}");
+        // The batch requests keep their own arrival order.
+        assert_eq!(order[1..], (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn a_batch_request_waiting_past_promote_after_goes_first() {
+        let queue = Arc::new(AdmissionQueue::new(Duration::from_millis(20)));
+        let order = admission_order(
+            queue,
+            &[
+                (Priority::Batch, Duration::from_millis(30)),
+                (Priority::Interactive, Duration::ZERO),
+                (Priority::Batch, Duration::ZERO),
+            ],
+        )
+        .await;
+        // Only the batch request that waited long enough is promoted.
+        assert_eq!(order, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn lengths_count_waiters_by_priority() {
+        let queue = Arc::new(AdmissionQueue::new(Duration::from_secs(3600)));
+        let held = queue.admit(Priority::Batch).await;
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { drop(queue.admit(Priority::Batch).await) }
+        });
+        while queue.lengths().batch == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(queue.lengths().interactive, 0);
+        drop(held);
+        waiting.await.unwrap();
+        assert_eq!(queue.lengths().batch, 0);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_waiter_gives_up_its_place() {
+        let queue = Arc::new(AdmissionQueue::new(Duration::from_secs(3600)));
+        let held = queue.admit(Priority::Interactive).await;
+        let cancelled = tokio::spawn({
+            let queue = queue.clone();
+            async move { drop(queue.admit(Priority::Interactive).await) }
+        });
+        while queue.lengths().interactive == 0 {
+            tokio::task::yield_now().await;
+        }
+        cancelled.abort();
+        let _ = cancelled.await;
+        drop(held);
+        // The slot is free again rather than handed to the abandoned waiter.
+        let admitted =
+            tokio::time::timeout(Duration::from_secs(1), queue.admit(Priority::Batch)).await;
+        assert!(admitted.is_ok());
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::model::ModelSlot;
+
+/// Follows `name` through `aliases` to the model it stands for. Model names
+/// resolve to themselves and cannot be aliased.
+pub fn resolve_alias(aliases: &BTreeMap<String, String>, name: &str) -> Result<ModelSlot, String> {
+    let mut current = name;
+    let mut seen = BTreeSet::new();
+    loop {
+        if let Some(slot) = ModelSlot::from_name(current) {
+            return Ok(slot);
+        }
+        if !seen.insert(current) {
+            return Err(format!("alias '{name}' is part of a cycle"));
+        }
+        current = match aliases.get(current) {
+            Some(target) => target,
+            None if current == name => {
+                return Err(format!(
+                    "unknown model '{name}'; expected baseline, quantized or an alias"
+                ));
+            }
+            None => {
+                return Err(format!("alias '{name}' leads to unknown model '{current}'"));
+            }
+        };
+    }
+}
+
+/// Every problem with `aliases` and `default_model`, so a bad mapping is
+/// refused as a whole.
+pub fn check_aliases(
+    aliases: &BTreeMap<String, String>,
+    default_model: Option<&str>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for alias in aliases.keys() {
+        if alias.trim().is_empty() {
+            problems.push("alias names cannot be empty".to_string());
+        } else if ModelSlot::from_name(alias).is_some() {
+            problems.push(format!("alias '{alias}' shadows a model name"));
+        } else if let Err(err) = resolve_alias(aliases, alias) {
+            problems.push(err);
+        }
+    }
+    if let Some(name) = default_model
+        && let Err(err) = resolve_alias(aliases, name)
+    {
+        problems.push(format!("default_model: {err}"));
+    }
+    problems
+}
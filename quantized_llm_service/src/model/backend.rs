@@ -0,0 +1,1322 @@
+use std::{
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(any(feature = "candle-backend", feature = "ort-backend"))]
+use rand::Rng;
+use rand::{SeedableRng, rngs::StdRng};
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+use utoipa::ToSchema;
+
+use crate::{
+    config::AppConfig,
+    error::ServiceError,
+    model::{
+        ContextStrategy, EmbedResponse, FinishReason, GenerationParams, GenerationResponse,
+        GenerationTimings, JsonConstraint, ModelMetadata, OnTimeout, Pooling, Priority,
+        ReplicaStatsSnapshot, ResponseFormat, ScoreResponse, SelfTestReport, StreamingDecoder,
+        TokenCandidate, TokenDetail, TokenVocabulary, Usage,
+    },
+};
+
+const WARMUP_PROMPT: &str = "The quick brown fox jumps over the lazy dog.";
+const WARMUP_NEW_TOKENS: usize = 8;
+const SELF_TEST_PROMPT: &str = "The capital of France is";
+const SELF_TEST_NEW_TOKENS: usize = 8;
+
+/// Invoked with each generated token id; returning `false` stops generation.
+pub type TokenCallback = Box<dyn FnMut(u32) -> bool + Send>;
+
+/// Which inference engine runs the models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// TorchScript modules on LibTorch.
+    Tch,
+    /// GPT-2 safetensors weights on candle, pure Rust.
+    Candle,
+}
+
+impl Default for BackendKind {
+    fn default() -> Self {
+        if cfg!(feature = "tch-backend") {
+            Self::Tch
+        } else {
+            Self::Candle
+        }
+    }
+}
+
+impl BackendKind {
+    pub fn is_compiled_in(self) -> bool {
+        match self {
+            Self::Tch => cfg!(feature = "tch-backend"),
+            Self::Candle => cfg!(feature = "candle-backend"),
+        }
+    }
+}
+
+/// How a model consumes the sequence it continues.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelKind {
+    /// Decoder-only: prompt and output form one growing sequence.
+    #[default]
+    Causal,
+    /// Encoder-decoder: the prompt is the encoder input and the decoder
+    /// sequence grows from a start token.
+    Seq2Seq,
+}
+
+impl ModelKind {
+    /// GPT-2's `<|endoftext|>` or T5's `</s>`.
+    pub fn default_eos_token_id(self) -> i64 {
+        match self {
+            Self::Causal => 50256,
+            Self::Seq2Seq => 1,
+        }
+    }
+}
+
+impl FromStr for ModelKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "causal" => Ok(Self::Causal),
+            "seq2seq" => Ok(Self::Seq2Seq),
+            other => Err(format!(
+                "unknown model kind {other:?}, expected causal or seq2seq"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ModelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Causal => "causal",
+            Self::Seq2Seq => "seq2seq",
+        })
+    }
+}
+
+/// Floating-point precision a float model runs in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelDtype {
+    #[default]
+    Float32,
+    Float16,
+    Bfloat16,
+}
+
+impl FromStr for ModelDtype {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "float32" | "fp32" => Ok(Self::Float32),
+            "float16" | "fp16" => Ok(Self::Float16),
+            "bfloat16" | "bf16" => Ok(Self::Bfloat16),
+            other => Err(format!(
+                "unknown dtype {other:?}, expected float32, float16 or bfloat16"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ModelDtype {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Float32 => "float32",
+            Self::Float16 => "float16",
+            Self::Bfloat16 => "bfloat16",
+        })
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "tch" => Ok(Self::Tch),
+            "candle" => Ok(Self::Candle),
+            other => Err(format!("unknown backend {other:?}, expected tch or candle")),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tch => "tch",
+            Self::Candle => "candle",
+        })
+    }
+}
+
+/// The two models the service serves side by side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelSlot {
+    Baseline,
+    Quantized,
+}
+
+impl ModelSlot {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Baseline => "baseline",
+            Self::Quantized => "quantized",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Baseline, Self::Quantized]
+            .into_iter()
+            .find(|slot| slot.name() == name)
+    }
+}
+
+/// One teacher-forced pass over a prompt, kept on the CPU for comparing two
+/// models position by position.
+#[derive(Debug, Clone)]
+pub struct PromptActivations {
+    pub tokens: usize,
+    pub vocab_size: usize,
+    /// `[tokens, vocab_size]`, row-major.
+    pub logits: Vec<f32>,
+    pub hidden_size: usize,
+    /// One `[tokens, hidden_size]` entry per layer output the trace exposes,
+    /// embeddings first; empty when it exposes none.
+    pub hidden_states: Vec<Vec<f32>>,
+}
+
+/// A loaded model on some inference engine. The registry and server only
+/// see models through this trait.
+pub trait Backend: Send + Sync {
+    /// Loads the model for `slot` from `path`; warmup happens separately.
+    fn load(config: &AppConfig, slot: ModelSlot, path: &Path) -> Result<Self, ServiceError>
+    where
+        Self: Sized;
+
+    fn metadata(&self) -> ModelMetadata;
+
+    fn generate(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: Option<&mut TokenCallback>,
+    ) -> Result<GenerationResponse, ServiceError>;
+
+    /// Whether [`Backend::generate_batch`] runs a batch in one forward pass
+    /// per step rather than one request after another.
+    fn supports_batching(&self) -> bool {
+        false
+    }
+
+    /// Generates for each `(prompt, params)` pair, returning results in the
+    /// same order.
+    fn generate_batch(
+        &self,
+        tokenizer: &Tokenizer,
+        requests: &[(String, GenerationParams)],
+    ) -> Vec<Result<GenerationResponse, ServiceError>> {
+        requests
+            .iter()
+            .map(|(prompt, params)| self.generate(tokenizer, prompt, params, None))
+            .collect()
+    }
+
+    /// Kept for `metadata`.
+    fn set_warmup_latencies(&mut self, latencies: Vec<Duration>);
+
+    /// Kept for `metadata`.
+    fn set_self_test(&mut self, report: SelfTestReport);
+
+    /// The served tokenizer's token texts, which `response_format: json`
+    /// masks the vocabulary with.
+    fn set_vocabulary(&mut self, vocabulary: Arc<TokenVocabulary>);
+
+    /// Per-copy counters; empty unless the model is replicated.
+    fn replica_stats(&self) -> Vec<ReplicaStatsSnapshot> {
+        Vec::new()
+    }
+
+    fn reset_replica_stats(&self) {}
+
+    fn score(
+        &self,
+        _tokenizer: &Tokenizer,
+        _prompt: &str,
+        _continuations: &[String],
+    ) -> Result<ScoreResponse, ServiceError> {
+        Err(ServiceError::NotImplemented(format!(
+            "the {} model's backend does not support /score",
+            self.metadata().name
+        )))
+    }
+
+    fn embed(
+        &self,
+        _tokenizer: &Tokenizer,
+        _texts: &[String],
+        _pooling: Pooling,
+    ) -> Result<EmbedResponse, ServiceError> {
+        Err(ServiceError::NotImplemented(format!(
+            "the {} model's backend does not support /embed",
+            self.metadata().name
+        )))
+    }
+
+    fn prompt_activations(
+        &self,
+        _tokenizer: &Tokenizer,
+        _prompt: &str,
+    ) -> Result<PromptActivations, ServiceError> {
+        Err(ServiceError::NotImplemented(format!(
+            "the {} model's backend does not support quantization analysis",
+            self.metadata().name
+        )))
+    }
+}
+
+/// Refuses a tokenizer that can produce ids past the end of the model's
+/// logits. A larger model vocabulary (padded for alignment) is allowed.
+pub fn check_vocab(model: &dyn Backend, tokenizer: &Tokenizer) -> Result<(), ServiceError> {
+    let metadata = model.metadata();
+    let tokenizer_vocab = tokenizer.get_vocab_size(true);
+    if tokenizer_vocab > metadata.vocab_size {
+        return Err(ServiceError::Other(format!(
+            "{} model has a vocabulary of {} but the tokenizer has {tokenizer_vocab}; \
+             check TOKENIZER_PATH",
+            metadata.name, metadata.vocab_size
+        )));
+    }
+    if tokenizer_vocab < metadata.vocab_size {
+        tracing::warn!(
+            model = %metadata.name,
+            model_vocab = metadata.vocab_size,
+            tokenizer_vocab,
+            "model vocabulary is larger than the tokenizer's; assuming padding"
+        );
+    }
+    Ok(())
+}
+
+/// Runs a few short greedy generations so lazy initialization and allocator
+/// growth happen before the first real request.
+pub fn warmup(
+    model: &mut dyn Backend,
+    tokenizer: &Tokenizer,
+    iters: usize,
+) -> Result<(), ServiceError> {
+    let params = GenerationParams {
+        max_new_tokens: WARMUP_NEW_TOKENS,
+        temperature: 0.0,
+        top_k: 1,
+        context_strategy: ContextStrategy::TruncateLeft,
+        sentinel_tokens: 0,
+        seed: None,
+        priority: Priority::Interactive,
+        add_special_tokens: true,
+        skip_special_tokens: true,
+        measure_memory: false,
+        token_details: false,
+        top_logprobs: 0,
+        timeout: None,
+        on_timeout: OnTimeout::Error,
+        response_format: ResponseFormat::Text,
+        max_lock_wait: None,
+    };
+    let name = model.metadata().name;
+    let mut latencies = Vec::with_capacity(iters);
+    for iter in 0..iters {
+        let started = Instant::now();
+        model.generate(tokenizer, WARMUP_PROMPT, &params, None)?;
+        let elapsed = started.elapsed();
+        tracing::info!(
+            model = %name,
+            iter,
+            latency_ms = as_ms(elapsed),
+            "warmup generation"
+        );
+        latencies.push(elapsed);
+    }
+    model.set_warmup_latencies(latencies);
+    Ok(())
+}
+
+/// Generates a few greedy tokens from a fixed prompt and checks that they
+/// decode to a non-empty completion. The outcome is recorded on the model
+/// and returned; a failure is never an `Err`, so the caller decides.
+pub fn self_test(model: &mut dyn Backend, tokenizer: &Tokenizer) -> SelfTestReport {
+    let params = GenerationParams {
+        max_new_tokens: SELF_TEST_NEW_TOKENS,
+        temperature: 0.0,
+        top_k: 1,
+        context_strategy: ContextStrategy::Error,
+        sentinel_tokens: 0,
+        seed: None,
+        priority: Priority::Interactive,
+        add_special_tokens: true,
+        skip_special_tokens: true,
+        measure_memory: false,
+        token_details: true,
+        top_logprobs: 0,
+        timeout: None,
+        on_timeout: OnTimeout::Error,
+        response_format: ResponseFormat::Text,
+        max_lock_wait: None,
+    };
+    let started = Instant::now();
+    let outcome = model.generate(tokenizer, SELF_TEST_PROMPT, &params, None);
+    let latency_ms = as_ms(started.elapsed());
+    let report = match outcome {
+        Ok(response) => {
+            let error = if response.tokens_generated == 0 {
+                Some("generated no tokens".to_string())
+            } else if response.completion.trim().is_empty() {
+                Some(format!(
+                    "token ids {:?} decoded to an empty completion",
+                    response.generated_token_ids.unwrap_or_default()
+                ))
+            } else {
+                None
+            };
+            SelfTestReport {
+                passed: error.is_none(),
+                latency_ms,
+                completion: response.completion,
+                first_tokens: response
+                    .token_details
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|detail| detail.text)
+                    .collect(),
+                error,
+            }
+        }
+        Err(err) => SelfTestReport {
+            passed: false,
+            latency_ms,
+            completion: String::new(),
+            first_tokens: Vec::new(),
+            error: Some(err.to_string()),
+        },
+    };
+    model.set_self_test(report.clone());
+    report
+}
+
+static GENERATION_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of generations abandoned at `generation_timeout` since startup.
+pub fn generation_timeouts() -> u64 {
+    GENERATION_TIMEOUTS.load(Ordering::Relaxed)
+}
+
+/// A sampled token id with, when token details are on, its log-probability
+/// and the likeliest alternatives.
+pub(crate) type PickedToken = (i64, Option<f32>, Vec<(i64, f32)>);
+
+/// Rejection for a request that could not have the model within
+/// `max_lock_wait_ms`, so the client can retry elsewhere.
+pub(crate) fn model_busy() -> ServiceError {
+    ServiceError::Overloaded {
+        message: "the model stayed busy past max_lock_wait_ms".into(),
+        retry_after_secs: None,
+    }
+}
+
+/// Locks `mutex`, giving up with [`model_busy`] after `max_wait`.
+#[cfg(any(feature = "tch-backend", feature = "ort-backend"))]
+pub(crate) fn lock_within<T>(
+    mutex: &parking_lot::Mutex<T>,
+    max_wait: Option<Duration>,
+) -> Result<parking_lot::MutexGuard<'_, T>, ServiceError> {
+    match max_wait {
+        Some(max_wait) => mutex.try_lock_for(max_wait).ok_or_else(model_busy),
+        None => Ok(mutex.lock()),
+    }
+}
+
+/// The autoregressive loop every backend shares, running `decoding` to the
+/// end one step at a time. `acquire` takes hold of the model once the
+/// prompt is tokenized, given the encoder input (empty for causal models);
+/// `step` then runs one forward pass over the current sequence and samples
+/// the next token from its last position, also returning its
+/// log-probability when `params.token_details` is set and the
+/// `params.top_logprobs` likeliest `(id, logprob)` pairs. With
+/// `response_format: json` it is handed the constraint to mask the logits.
+///
+/// For causal models the sequence starts as the prompt. For seq2seq models
+/// the prompt is the encoder input and the sequence is the decoder's,
+/// starting from `decoder_start_token_id`.
+#[cfg_attr(
+    not(any(
+        feature = "tch-backend",
+        feature = "candle-backend",
+        feature = "ort-backend"
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn generate_tokens<M>(
+    mut decoding: Decoding<'_>,
+    tokenizer: &Tokenizer,
+    mut on_token: Option<&mut TokenCallback>,
+    acquire: impl FnOnce(&[i64]) -> Result<M, ServiceError>,
+    mut step: impl FnMut(
+        &mut M,
+        &[i64],
+        &mut StdRng,
+        Option<&mut JsonConstraint<'_>>,
+    ) -> Result<PickedToken, ServiceError>,
+) -> Result<GenerationResponse, ServiceError> {
+    let params = decoding.params;
+    let mut held = acquire(&decoding.encoder_ids)?;
+    decoding.acquired();
+
+    // The first step runs the whole prompt; later ones extend it by a token
+    // each.
+    let mut phase =
+        Some(tracing::info_span!("prefill", prompt_tokens = decoding.prompt_token_len).entered());
+    for step_index in 0..params.max_new_tokens {
+        if !decoding.check_timeout()? {
+            break;
+        }
+        if step_index == 1 {
+            drop(phase.take());
+            phase = Some(tracing::info_span!("decode").entered());
+        }
+        let (next_token_id, logprob, top) = step(
+            &mut held,
+            &decoding.input_ids,
+            &mut decoding.rng,
+            decoding.json.as_mut(),
+        )?;
+        if !decoding.push(next_token_id, logprob, top, on_token.as_mut()) {
+            break;
+        }
+    }
+    drop(phase);
+    drop(held);
+    decoding.finish(tokenizer)
+}
+
+/// One sequence being generated: the prompt as fitted to the context, what
+/// has been generated so far and when each phase began. Shared by the
+/// one-at-a-time and batched decode loops.
+pub(crate) struct Decoding<'a> {
+    model: ModelMetadata,
+    prompt: &'a str,
+    params: &'a GenerationParams,
+    pub(crate) rng: StdRng,
+    /// What the next step runs on: the prompt and generated tokens, or for
+    /// seq2seq models the decoder's input.
+    pub(crate) input_ids: Vec<i64>,
+    pub(crate) encoder_ids: Vec<i64>,
+    /// Set for `response_format: json`; the next step masks its logits
+    /// with it.
+    pub(crate) json: Option<JsonConstraint<'a>>,
+    prompt_token_len: usize,
+    sentinels: usize,
+    /// Prompt tokens after the sentinels that a sliding window can still
+    /// evict; anything past them is generated output.
+    evictable_prompt_tokens: usize,
+    evicted_prompt_tokens: usize,
+    truncated_prompt_tokens: usize,
+    generated_ids: Vec<u32>,
+    token_details: Option<Vec<TokenDetail>>,
+    start: Instant,
+    tokenize_elapsed: Duration,
+    lock_acquired_at: Option<Instant>,
+    first_token_at: Option<Instant>,
+    finish_reason: FinishReason,
+}
+
+impl<'a> Decoding<'a> {
+    /// Validates the request, tokenizes the prompt and fits it to the
+    /// model's context window.
+    #[cfg_attr(
+        not(any(
+            feature = "tch-backend",
+            feature = "candle-backend",
+            feature = "ort-backend"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn new(
+        model: ModelMetadata,
+        tokenizer: &Tokenizer,
+        vocabulary: Option<&'a TokenVocabulary>,
+        prompt: &'a str,
+        params: &'a GenerationParams,
+    ) -> Result<Self, ServiceError> {
+        let max_new_tokens = params.max_new_tokens;
+        let max_context_tokens = model.max_context_tokens;
+        if !(params.temperature.is_finite() && params.temperature >= 0.0) {
+            return Err(ServiceError::validation(
+                "temperature",
+                "must be zero (greedy) or positive",
+            ));
+        }
+        let rng = StdRng::seed_from_u64(params.seed.unwrap_or_else(rand::random));
+        if prompt.trim().is_empty() {
+            return Err(ServiceError::validation("prompt", "must not be empty"));
+        }
+        let json = match (params.response_format, vocabulary) {
+            (ResponseFormat::Text, _) => None,
+            (ResponseFormat::Json, Some(vocabulary)) => {
+                Some(JsonConstraint::new(vocabulary, model.eos_token_id))
+            }
+            (ResponseFormat::Json, None) => {
+                return Err(ServiceError::NotImplemented(format!(
+                    "the {} model has no token vocabulary for response_format json",
+                    model.name
+                )));
+            }
+        };
+
+        let start = Instant::now();
+        let encoding = tracing::info_span!("tokenize")
+            .in_scope(|| tokenizer.encode(prompt, params.add_special_tokens))
+            .map_err(|e| ServiceError::Tokenizer(e.to_string()))?;
+        // With truncation on, the cut tokens come back as overflowing
+        // pieces, each wrapped in its own special tokens.
+        let truncated_prompt_tokens = encoding
+            .get_overflowing()
+            .iter()
+            .flat_map(|piece| piece.get_special_tokens_mask())
+            .filter(|&&special| special == 0)
+            .count();
+        let mut input_ids: Vec<i64> = encoding.get_ids().iter().map(|&id| id as i64).collect();
+        if input_ids.is_empty() {
+            input_ids.push(0);
+        }
+        let mut evicted_prompt_tokens = 0;
+        let seq2seq = model.model_kind == ModelKind::Seq2Seq;
+        let sentinels = params
+            .sentinel_tokens
+            .min(input_ids.len())
+            .min(max_context_tokens - 1);
+        if seq2seq {
+            if let Err(err) =
+                check_seq2seq_fits(input_ids.len(), max_new_tokens, max_context_tokens)
+            {
+                // The decoder's length is fixed by `max_new_tokens`, so only
+                // an overlong prompt can be truncated.
+                if params.context_strategy == ContextStrategy::Error
+                    || max_new_tokens >= max_context_tokens
+                {
+                    return Err(err);
+                }
+                evicted_prompt_tokens = input_ids.len() - max_context_tokens;
+                input_ids.drain(..evicted_prompt_tokens);
+            }
+        } else if let Err(err) =
+            check_context_fits(input_ids.len(), max_new_tokens, max_context_tokens)
+        {
+            match params.context_strategy {
+                ContextStrategy::Error => return Err(err),
+                ContextStrategy::TruncateLeft => {
+                    let budget = max_context_tokens.saturating_sub(max_new_tokens);
+                    if budget == 0 {
+                        return Err(err);
+                    }
+                    evicted_prompt_tokens = input_ids.len() - budget;
+                    input_ids.drain(..evicted_prompt_tokens);
+                }
+                ContextStrategy::SlidingWindow => {
+                    evicted_prompt_tokens =
+                        slide_window(&mut input_ids, sentinels, max_context_tokens);
+                }
+            }
+        }
+        let prompt_token_len = input_ids.len();
+        let encoder_ids = if seq2seq {
+            let start_id = model.decoder_start_token_id.unwrap_or(0);
+            std::mem::replace(&mut input_ids, vec![start_id])
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            model,
+            prompt,
+            params,
+            rng,
+            input_ids,
+            encoder_ids,
+            json,
+            prompt_token_len,
+            sentinels,
+            evictable_prompt_tokens: prompt_token_len - sentinels,
+            evicted_prompt_tokens,
+            truncated_prompt_tokens,
+            generated_ids: Vec::with_capacity(max_new_tokens),
+            token_details: params
+                .token_details
+                .then(|| Vec::with_capacity(max_new_tokens)),
+            tokenize_elapsed: start.elapsed(),
+            start,
+            lock_acquired_at: None,
+            first_token_at: None,
+            finish_reason: FinishReason::Length,
+        })
+    }
+
+    #[cfg_attr(not(feature = "tch-backend"), allow(dead_code))]
+    pub(crate) fn params(&self) -> &'a GenerationParams {
+        self.params
+    }
+
+    /// Marks the model as acquired; time before this counts as queue wait.
+    pub(crate) fn acquired(&mut self) {
+        self.lock_acquired_at = Some(Instant::now());
+    }
+
+    /// Returns whether generation goes on. Past the timeout it fails, or
+    /// with `on_timeout: partial` stops so the output so far is returned.
+    pub(crate) fn check_timeout(&mut self) -> Result<bool, ServiceError> {
+        if let Some(timeout) = self.params.timeout
+            && self.start.elapsed() >= timeout
+        {
+            GENERATION_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+            return match self.params.on_timeout {
+                OnTimeout::Error => Err(ServiceError::Timeout {
+                    elapsed_ms: self.start.elapsed().as_millis() as u64,
+                    tokens_generated: self.generated_ids.len(),
+                }),
+                OnTimeout::Partial => {
+                    self.finish_reason = FinishReason::Timeout;
+                    Ok(false)
+                }
+            };
+        }
+        Ok(true)
+    }
+
+    /// Appends a sampled token. Returns whether generation goes on: false
+    /// after end-of-sequence, `max_new_tokens`, the close of a JSON
+    /// response, or when `on_token` asks to stop.
+    pub(crate) fn push(
+        &mut self,
+        next_token_id: i64,
+        logprob: Option<f32>,
+        top: Vec<(i64, f32)>,
+        on_token: Option<&mut &mut TokenCallback>,
+    ) -> bool {
+        self.input_ids.push(next_token_id);
+        self.generated_ids.push(next_token_id as u32);
+        self.first_token_at.get_or_insert_with(Instant::now);
+        if let Some(details) = self.token_details.as_mut() {
+            details.push(TokenDetail {
+                id: next_token_id as u32,
+                text: String::new(),
+                logprob,
+                offset_ms: as_ms(self.start.elapsed()),
+                top_logprobs: (self.params.top_logprobs > 0).then(|| {
+                    top.into_iter()
+                        .map(|(id, logprob)| TokenCandidate {
+                            id: id as u32,
+                            text: String::new(),
+                            logprob,
+                        })
+                        .collect()
+                }),
+            });
+        }
+        // There is no KV cache to shift: every step re-runs the whole
+        // window, so moving it only means dropping tokens.
+        if self.model.model_kind != ModelKind::Seq2Seq
+            && self.params.context_strategy == ContextStrategy::SlidingWindow
+        {
+            let evicted = slide_window(
+                &mut self.input_ids,
+                self.sentinels,
+                self.model.max_context_tokens,
+            );
+            let from_prompt = evicted.min(self.evictable_prompt_tokens);
+            self.evictable_prompt_tokens -= from_prompt;
+            self.evicted_prompt_tokens += from_prompt;
+        }
+
+        if next_token_id == self.model.eos_token_id {
+            self.finish_reason = FinishReason::Stop;
+            return false;
+        }
+        if let Some(callback) = on_token
+            && !callback(next_token_id as u32)
+        {
+            self.finish_reason = FinishReason::Cancelled;
+            return false;
+        }
+        if let Some(json) = self.json.as_mut() {
+            json.advance(next_token_id);
+            if json.is_complete() {
+                self.finish_reason = FinishReason::Stop;
+                return false;
+            }
+        }
+        self.generated_ids.len() < self.params.max_new_tokens
+    }
+
+    /// Detokenizes the output and works out the timings.
+    pub(crate) fn finish(self, tokenizer: &Tokenizer) -> Result<GenerationResponse, ServiceError> {
+        let Self {
+            model,
+            prompt,
+            params,
+            generated_ids,
+            mut token_details,
+            prompt_token_len,
+            evicted_prompt_tokens,
+            truncated_prompt_tokens,
+            start,
+            tokenize_elapsed,
+            lock_acquired_at,
+            first_token_at,
+            finish_reason,
+            ..
+        } = self;
+        let lock_acquired_at = lock_acquired_at.unwrap_or(start);
+        let elapsed = lock_acquired_at.elapsed();
+
+        let tokens_generated = generated_ids.len();
+
+        let completion = tokenizer
+            .decode(&generated_ids, params.skip_special_tokens)
+            .map_err(|e| ServiceError::Tokenizer(e.to_string()))?;
+        let generated_token_ids =
+            (completion.is_empty() && !generated_ids.is_empty()).then(|| generated_ids.clone());
+        if let Some(details) = token_details.as_mut() {
+            fill_token_text(tokenizer, params.skip_special_tokens, details);
+        }
+
+        let total_elapsed = start.elapsed();
+        let queue_wait = lock_acquired_at
+            .duration_since(start)
+            .saturating_sub(tokenize_elapsed);
+        let time_to_first_token = first_token_at
+            .map(|at| at.duration_since(lock_acquired_at))
+            .unwrap_or_default();
+        let decode =
+            total_elapsed.saturating_sub(tokenize_elapsed + queue_wait + time_to_first_token);
+
+        let total_tokens = prompt_token_len + tokens_generated;
+        let total_time_ms = total_elapsed.as_millis();
+        let elapsed_secs = elapsed.as_secs_f64();
+        // Legacy metric: counts prompt tokens too, which overstates decode speed.
+        let tokens_per_second = if elapsed_secs > 0.0 {
+            total_tokens as f64 / elapsed_secs
+        } else {
+            total_tokens as f64
+        };
+
+        Ok(GenerationResponse {
+            prompt: prompt.to_string(),
+            raw_prompt: prompt.to_string(),
+            completion,
+            tokens_generated,
+            finish_reason,
+            total_time_ms,
+            timings: GenerationTimings {
+                tokenize_ms: as_ms(tokenize_elapsed),
+                queue_wait_ms: as_ms(queue_wait),
+                time_to_first_token_ms: as_ms(time_to_first_token),
+                decode_ms: as_ms(decode),
+                cpu_time_ms: None,
+                deadline_ms: params
+                    .timeout
+                    .filter(|_| finish_reason == FinishReason::Timeout)
+                    .map(as_ms),
+            },
+            tokens_per_second,
+            prefill_tokens_per_second: per_second(prompt_token_len, time_to_first_token),
+            decode_tokens_per_second: per_second(tokens_generated.saturating_sub(1), decode),
+            usage: Usage {
+                prompt_tokens: prompt_token_len,
+                completion_tokens: tokens_generated,
+                total_tokens,
+                evicted_prompt_tokens,
+                truncated_prompt_tokens,
+            },
+            model,
+            served_via_alias: None,
+            fallback: false,
+            prompt_truncated: truncated_prompt_tokens > 0,
+            cached: false,
+            params: params.into(),
+            generated_token_ids,
+            peak_rss_delta_bytes: None,
+            token_details,
+        })
+    }
+}
+
+/// Decodes each token's piece incrementally, so it reads as it does within
+/// the full completion. Text still held back after the last token is
+/// appended to it.
+fn fill_token_text(tokenizer: &Tokenizer, skip_special_tokens: bool, details: &mut [TokenDetail]) {
+    let mut decoder = StreamingDecoder::new(tokenizer, skip_special_tokens);
+    for detail in details.iter_mut() {
+        detail.text = decoder.push(detail.id).unwrap_or_default();
+        for candidate in detail.top_logprobs.iter_mut().flatten() {
+            candidate.text = tokenizer.decode(&[candidate.id], false).unwrap_or_default();
+        }
+    }
+    if let (Some(rest), Some(last)) = (decoder.finish(), details.last_mut()) {
+        last.text.push_str(&rest);
+    }
+}
+
+/// Natural log-probability of `id` under the softmax of `logits`.
+#[cfg(any(feature = "candle-backend", feature = "ort-backend"))]
+pub(crate) fn logprob_from_logits(logits: &[f32], id: i64) -> f32 {
+    logits[id as usize] - log_sum_exp(logits)
+}
+
+/// The `k` likeliest ids under the softmax of `logits` with their
+/// log-probabilities, best first.
+#[cfg(any(feature = "candle-backend", feature = "ort-backend"))]
+pub(crate) fn top_logprobs_from_logits(logits: &[f32], k: usize) -> Vec<(i64, f32)> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let normalizer = log_sum_exp(logits);
+    let mut ids: Vec<usize> = (0..logits.len()).collect();
+    let k = k.min(ids.len());
+    let by_logit_desc = |a: &usize, b: &usize| logits[*b].total_cmp(&logits[*a]);
+    if k < ids.len() {
+        ids.select_nth_unstable_by(k, by_logit_desc);
+        ids.truncate(k);
+    }
+    ids.sort_unstable_by(by_logit_desc);
+    ids.into_iter()
+        .map(|id| (id as i64, logits[id] - normalizer))
+        .collect()
+}
+
+#[cfg(any(feature = "candle-backend", feature = "ort-backend"))]
+fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    logits
+        .iter()
+        .map(|&logit| ((logit - max) as f64).exp())
+        .sum::<f64>()
+        .ln() as f32
+        + max
+}
+
+/// Picks the next token from last-position logits already on the CPU:
+/// argmax when greedy, otherwise a draw from the temperature-scaled top-k
+/// distribution.
+#[cfg(any(feature = "candle-backend", feature = "ort-backend"))]
+pub(crate) fn sample_from_logits(
+    logits: &[f32],
+    params: &GenerationParams,
+    rng: &mut StdRng,
+) -> Result<i64, ServiceError> {
+    let mut ranked: Vec<(usize, f32)> = logits.iter().copied().enumerate().collect();
+    if ranked.is_empty() {
+        return Err(ServiceError::Inference("empty logits".into()));
+    }
+    let by_logit = |a: &(usize, f32), b: &(usize, f32)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+    if params.temperature <= 0.0 || params.top_k == 1 {
+        let best = ranked
+            .iter()
+            .min_by(|a, b| by_logit(a, b))
+            .map(|&(id, _)| id);
+        return Ok(best.unwrap_or(0) as i64);
+    }
+
+    let k = match params.top_k {
+        0 => ranked.len(),
+        k => k.min(ranked.len()),
+    };
+    ranked.select_nth_unstable_by(k - 1, by_logit);
+    ranked.truncate(k);
+    ranked.sort_unstable_by(by_logit);
+    let max = ranked[0].1 as f64 / params.temperature;
+    let weights: Vec<f64> = ranked
+        .iter()
+        .map(|&(_, logit)| (logit as f64 / params.temperature - max).exp())
+        .collect();
+    let total: f64 = weights.iter().sum();
+
+    let mut draw = rng.r#gen::<f64>() * total;
+    for (&(id, _), weight) in ranked.iter().zip(&weights) {
+        if draw < *weight {
+            return Ok(id as i64);
+        }
+        draw -= weight;
+    }
+    // Rounding can leave a sliver of mass unassigned; fall back to the last candidate.
+    Ok(ranked[k - 1].0 as i64)
+}
+
+/// Drops the oldest tokens after the first `sentinels` until `ids` fits in
+/// `max_context_tokens`, returning how many were dropped.
+fn slide_window(ids: &mut Vec<i64>, sentinels: usize, max_context_tokens: usize) -> usize {
+    let overflow = ids.len().saturating_sub(max_context_tokens);
+    if overflow > 0 {
+        ids.drain(sentinels..sentinels + overflow);
+    }
+    overflow
+}
+
+/// Seq2seq counterpart of [`check_context_fits`]: the encoder takes the
+/// prompt and the decoder the start token plus the output, each within the
+/// context window on its own.
+pub fn check_seq2seq_fits(
+    prompt_tokens: usize,
+    max_new_tokens: usize,
+    max_context_tokens: usize,
+) -> Result<(), ServiceError> {
+    if max_new_tokens >= max_context_tokens {
+        return Err(ServiceError::validation(
+            "max_new_tokens",
+            format!(
+                "is {max_new_tokens}; with the decoder start token that exceeds the \
+                 {max_context_tokens}-token context window"
+            ),
+        ));
+    }
+    if prompt_tokens > max_context_tokens {
+        return Err(ServiceError::validation(
+            "prompt",
+            format!(
+                "is {prompt_tokens} tokens, more than the {max_context_tokens}-token encoder \
+                 window (set context_strategy to \"truncate_left\" to drop the oldest tokens)"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects requests whose prompt plus requested output would run past the
+/// model's context window.
+pub fn check_context_fits(
+    prompt_tokens: usize,
+    max_new_tokens: usize,
+    max_context_tokens: usize,
+) -> Result<(), ServiceError> {
+    if prompt_tokens + max_new_tokens <= max_context_tokens {
+        return Ok(());
+    }
+    Err(ServiceError::validation(
+        "prompt",
+        format!(
+            "is {prompt_tokens} tokens; with max_new_tokens {max_new_tokens} that exceeds \
+             the {max_context_tokens}-token context window (set context_strategy to \
+             \"truncate_left\" or \"sliding_window\" to drop the oldest tokens)"
+        ),
+    ))
+}
+
+pub(crate) fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Counts `waited`, spent before the backend's clock started, as queue wait.
+/// The total is summed again from the phases rather than bumped by whole
+/// milliseconds, which would drift from them by up to one per wait.
+pub(crate) fn add_queue_wait(response: &mut GenerationResponse, waited: Duration) {
+    let timings = &mut response.timings;
+    timings.queue_wait_ms += as_ms(waited);
+    let phases = timings.tokenize_ms
+        + timings.queue_wait_ms
+        + timings.time_to_first_token_ms
+        + timings.decode_ms;
+    response.total_time_ms = phases as u128;
+}
+
+/// `count` over `duration`, or 0 when the duration is zero.
+pub(crate) fn per_second(count: usize, duration: Duration) -> f64 {
+    let secs = duration.as_secs_f64();
+    if secs > 0.0 { count as f64 / secs } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+    use crate::model::testing::{
+        FakeModel, GPT2_EOS, GPT2_VOCAB_SIZE, assert_timings_add_up, ends_at_once, gpt2, greedy,
+        next_token,
+    };
+
+    #[test]
+    fn timings_sum_to_the_total() {
+        let tokenizer = gpt2();
+        let model = FakeModel::new("fake", next_token).with_step_delay(Duration::from_millis(2));
+        for max_new_tokens in [1, 4, 16] {
+            let response = model
+                .generate(
+                    &tokenizer,
+                    "The quick brown fox",
+                    &greedy(max_new_tokens),
+                    None,
+                )
+                .unwrap();
+            assert_eq!(response.tokens_generated, max_new_tokens);
+            assert!(response.timings.time_to_first_token_ms >= 2.0);
+            assert_timings_add_up(&response);
+        }
+    }
+
+    #[test]
+    fn lock_wait_counts_as_queue_wait() {
+        let tokenizer = gpt2();
+        let model = FakeModel::new("fake", next_token);
+        let params = greedy(4);
+        let decoding =
+            Decoding::new(model.metadata(), &tokenizer, None, "Hello there", &params).unwrap();
+        let response = generate_tokens(
+            decoding,
+            &tokenizer,
+            None,
+            |_| {
+                std::thread::sleep(Duration::from_millis(5));
+                Ok(())
+            },
+            |_, input_ids, _, _| Ok((input_ids.last().unwrap() + 1, None, Vec::new())),
+        )
+        .unwrap();
+        assert!(response.timings.queue_wait_ms >= 5.0);
+        assert_timings_add_up(&response);
+    }
+
+    #[cfg(any(feature = "tch-backend", feature = "ort-backend"))]
+    #[test]
+    fn a_held_module_lock_is_given_up_on_after_the_budget() {
+        let module = parking_lot::Mutex::new(());
+        let holder = module.lock();
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let started = Instant::now();
+                let err = lock_within(&module, Some(Duration::from_millis(50))).unwrap_err();
+                (err, started.elapsed())
+            });
+            let (err, waited) = waiter.join().unwrap();
+            assert_eq!(err.code(), "overloaded", "{err}");
+            assert!(
+                waited >= Duration::from_millis(50) && waited < Duration::from_secs(1),
+                "{waited:?}"
+            );
+        });
+        drop(holder);
+        assert!(lock_within(&module, Some(Duration::from_millis(50))).is_ok());
+    }
+
+    /// Ends a fifth of the time, otherwise picks any token.
+    fn often_ending(_input_ids: &[i64], rng: &mut StdRng) -> Vec<f32> {
+        let mut logits = vec![0.0; GPT2_VOCAB_SIZE];
+        let next = if rng.gen_bool(0.2) {
+            GPT2_EOS as usize
+        } else {
+            rng.gen_range(0..GPT2_VOCAB_SIZE - 1)
+        };
+        logits[next] = 1.0;
+        logits
+    }
+
+    fn assert_rates_sane(response: &GenerationResponse) {
+        for rate in [
+            response.prefill_tokens_per_second,
+            response.decode_tokens_per_second,
+            response.tokens_per_second,
+        ] {
+            assert!(rate.is_finite() && rate >= 0.0, "{response:?}");
+        }
+        if response.tokens_generated <= 1 {
+            assert_eq!(response.decode_tokens_per_second, 0.0, "{response:?}");
+        }
+    }
+
+    #[test]
+    fn per_second_is_finite_and_never_negative() {
+        let mut rng = StdRng::seed_from_u64(1394);
+        for _ in 0..10_000 {
+            let count = rng.gen_range(0..1_000_000);
+            let duration = match rng.gen_range(0..4) {
+                0 => Duration::ZERO,
+                1 => Duration::from_nanos(rng.gen_range(1..1_000)),
+                _ => Duration::from_micros(rng.gen_range(1..100_000_000)),
+            };
+            let rate = per_second(count, duration);
+            assert!(rate.is_finite() && rate >= 0.0, "{count} in {duration:?}");
+            if duration.is_zero() || count == 0 {
+                assert_eq!(rate, 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn generation_rates_hold_across_random_runs() {
+        let tokenizer = gpt2();
+        let model = FakeModel::new("fake", often_ending);
+        let mut rng = StdRng::seed_from_u64(1394);
+        let words = [
+            "the", "quick", "brown", "fox", "jumps", "over", "a", "lazy", "dog",
+        ];
+        for seed in 0..200 {
+            let prompt: Vec<&str> = (0..rng.gen_range(1..40))
+                .map(|_| words[rng.gen_range(0..words.len())])
+                .collect();
+            let mut params = greedy(rng.gen_range(1..24));
+            params.seed = Some(seed);
+            let response = model
+                .generate(&tokenizer, &prompt.join(" "), &params, None)
+                .unwrap();
+            assert_rates_sane(&response);
+        }
+    }
+
+    #[test]
+    fn ending_at_once_means_no_decode_rate() {
+        let tokenizer = gpt2();
+        let response = FakeModel::new("fake", ends_at_once)
+            .generate(&tokenizer, "Hello", &greedy(8), None)
+            .unwrap();
+        // The end-of-sequence token comes out of the prefill; nothing is
+        // decoded after it.
+        assert_eq!(response.finish_reason, FinishReason::Stop);
+        assert_eq!(response.completion, "");
+        assert_eq!(response.decode_tokens_per_second, 0.0);
+        assert_rates_sane(&response);
+    }
+
+    #[test]
+    fn an_end_of_text_literal_in_the_prompt_stays_one_token() {
+        let tokenizer = gpt2();
+        for add_special_tokens in [true, false] {
+            let mut params = greedy(1);
+            params.add_special_tokens = add_special_tokens;
+            let response = FakeModel::new("fake", next_token)
+                .generate(&tokenizer, "Hello<|endoftext|>", &params, None)
+                .unwrap();
+            // "Hello" and the separator, which the model continues from.
+            assert_eq!(response.usage.prompt_tokens, 2, "{add_special_tokens}");
+            assert_eq!(response.completion, "\"", "{add_special_tokens}");
+        }
+    }
+
+    #[test]
+    fn skipping_special_tokens_returns_the_raw_ids() {
+        let tokenizer = gpt2();
+        for (skip_special_tokens, completion, generated_token_ids) in [
+            (true, "", Some(vec![GPT2_EOS as u32])),
+            (false, "<|endoftext|>", None),
+        ] {
+            let mut params = greedy(8);
+            params.skip_special_tokens = skip_special_tokens;
+            let response = FakeModel::new("fake", ends_at_once)
+                .generate(&tokenizer, "Hello<|endoftext|>", &params, None)
+                .unwrap();
+            assert_eq!(response.tokens_generated, 1);
+            assert_eq!(response.completion, completion);
+            assert_eq!(response.generated_token_ids, generated_token_ids);
+        }
+    }
+
+    #[test]
+    fn token_details_follow_the_completion() {
+        let tokenizer = gpt2();
+        let model = FakeModel::new("fake", next_token).with_step_delay(Duration::from_millis(1));
+        let mut params = greedy(6);
+        params.token_details = true;
+        let response = model.generate(&tokenizer, "Hello", &params, None).unwrap();
+        let details = response.token_details.as_deref().unwrap();
+        assert_eq!(details.len(), response.tokens_generated);
+        let text: String = details.iter().map(|detail| detail.text.as_str()).collect();
+        assert_eq!(text, response.completion);
+        for pair in details.windows(2) {
+            assert_eq!(pair[1].id, pair[0].id + 1);
+            assert!(pair[1].offset_ms > pair[0].offset_ms, "{details:?}");
+        }
+        assert!(details[0].offset_ms >= 1.0);
+
+        let json = serde_json::to_value(&response).unwrap();
+        let round_trip: GenerationResponse = serde_json::from_value(json.clone()).unwrap();
+        let again = round_trip.token_details.unwrap();
+        assert_eq!(again.len(), details.len());
+        for (detail, again) in details.iter().zip(&again) {
+            assert_eq!(
+                (detail.id, &detail.text, detail.offset_ms),
+                (again.id, &again.text, again.offset_ms)
+            );
+            assert_eq!(again.logprob, None);
+        }
+        assert!(json["token_details"][0].get("logprob").is_none());
+    }
+
+    #[test]
+    fn token_details_are_left_out_unless_asked_for() {
+        let response = FakeModel::new("fake", next_token)
+            .generate(&gpt2(), "Hello", &greedy(3), None)
+            .unwrap();
+        assert!(response.token_details.is_none());
+        let json = serde_json::to_value(&response).unwrap();
+        assert!(json.get("token_details").is_none(), "{json}");
+    }
+
+    #[test]
+    fn seq2seq_decodes_from_the_start_token_until_its_own_eos() {
+        let tokenizer = gpt2();
+        let hello = i64::from(tokenizer.encode("Hello", false).unwrap().get_ids()[0]);
+        let mut metadata = FakeModel::new("t5", next_token).metadata();
+        metadata.model_kind = ModelKind::Seq2Seq;
+        metadata.decoder_start_token_id = Some(7);
+        metadata.eos_token_id = 1;
+        let mut params = greedy(8);
+        params.token_details = true;
+        let decoding = Decoding::new(metadata, &tokenizer, None, "Hello", &params).unwrap();
+        let mut decoder_inputs = Vec::new();
+        let response = generate_tokens(
+            decoding,
+            &tokenizer,
+            None,
+            |encoder_ids| Ok(encoder_ids.to_vec()),
+            |encoder_ids, decoder_ids, _, _| {
+                decoder_inputs.push(decoder_ids.to_vec());
+                // The encoder ids never grow; the decoder's end after two
+                // tokens of its own.
+                let next = match decoder_ids.len() {
+                    3 => 1,
+                    _ => decoder_ids.last().unwrap() + encoder_ids[0],
+                };
+                Ok((next, None, Vec::new()))
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            decoder_inputs,
+            [
+                vec![7],
+                vec![7, 7 + hello],
+                vec![7, 7 + hello, 7 + 2 * hello]
+            ]
+        );
+        let ids: Vec<u32> = response
+            .token_details
+            .unwrap()
+            .iter()
+            .map(|detail| detail.id)
+            .collect();
+        assert_eq!(ids, [7 + hello as u32, 7 + 2 * hello as u32, 1]);
+        assert_eq!(response.finish_reason, FinishReason::Stop);
+        assert_eq!(response.usage.prompt_tokens, 1);
+    }
+}
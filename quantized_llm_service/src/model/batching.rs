@@ -0,0 +1,330 @@
+//! Micro-batching: non-streaming generations that arrive within
+//! `batch_wait_ms` of each other run together, up to `max_batch_size`, as
+//! one forward pass per decode step.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokenizers::Tokenizer;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task,
+};
+
+use crate::{
+    error::ServiceError,
+    model::{
+        GenerationParams, GenerationResponse, Priority,
+        admission::AdmissionQueue,
+        backend::{Backend, add_queue_wait, model_busy},
+        registry::{join_error, panic_message},
+        stats::ModelStats,
+    },
+};
+
+/// Hands generations to the scheduler task; cheap to clone.
+#[derive(Clone)]
+pub struct Batcher {
+    queued: mpsc::UnboundedSender<Queued>,
+}
+
+struct Queued {
+    model: Arc<dyn Backend>,
+    tokenizer: Arc<Tokenizer>,
+    prompt: String,
+    params: GenerationParams,
+    queue: Option<Arc<AdmissionQueue>>,
+    stats: Option<Arc<ModelStats>>,
+    queued_at: Instant,
+    respond: oneshot::Sender<Result<GenerationResponse, ServiceError>>,
+}
+
+impl Batcher {
+    /// Starts the scheduler task, so this must run inside a Tokio runtime.
+    pub fn spawn(max_batch_size: usize, wait: Duration) -> Self {
+        let (queued, requests) = mpsc::unbounded_channel();
+        tokio::spawn(collect(requests, max_batch_size, wait));
+        Self { queued }
+    }
+
+    pub async fn generate(
+        &self,
+        model: Arc<dyn Backend>,
+        tokenizer: Arc<Tokenizer>,
+        prompt: String,
+        params: GenerationParams,
+        queue: Option<Arc<AdmissionQueue>>,
+        stats: Option<Arc<ModelStats>>,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let (respond, response) = oneshot::channel();
+        self.queued
+            .send(Queued {
+                model,
+                tokenizer,
+                prompt,
+                params,
+                queue,
+                stats,
+                queued_at: Instant::now(),
+                respond,
+            })
+            .map_err(|_| ServiceError::Inference("batch scheduler has stopped".into()))?;
+        response
+            .await
+            .map_err(|_| ServiceError::Inference("batched generation was dropped".into()))?
+    }
+}
+
+/// Gathers each batch from the first request to arrive plus whatever
+/// follows within `wait`, then starts it without waiting for it to finish.
+async fn collect(
+    mut requests: mpsc::UnboundedReceiver<Queued>,
+    max_batch_size: usize,
+    wait: Duration,
+) {
+    while let Some(first) = requests.recv().await {
+        let deadline = tokio::time::Instant::now() + wait;
+        let mut batch = vec![first];
+        while batch.len() < max_batch_size {
+            match tokio::time::timeout_at(deadline, requests.recv()).await {
+                Ok(Some(next)) => batch.push(next),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        // Different models, or the same one across a reload, batch apart.
+        while let Some(head) = batch.first() {
+            let model = head.model.clone();
+            let (same, rest) = batch
+                .into_iter()
+                .partition(|queued| Arc::ptr_eq(&queued.model, &model));
+            batch = rest;
+            tokio::spawn(run_batch(same));
+        }
+    }
+}
+
+async fn run_batch(batch: Vec<Queued>) {
+    let head = &batch[0];
+    let model = head.model.clone();
+    let tokenizer = head.tokenizer.clone();
+    let queue = head.queue.clone();
+    let priority = if batch
+        .iter()
+        .any(|queued| queued.params.priority == Priority::Interactive)
+    {
+        Priority::Interactive
+    } else {
+        Priority::Batch
+    };
+    let max_wait = batch
+        .iter()
+        .filter_map(|queued| {
+            let waited = queued.queued_at.elapsed();
+            queued
+                .params
+                .max_lock_wait
+                .map(|max_wait| max_wait.saturating_sub(waited))
+        })
+        .min();
+
+    // Held until the blocking task finishes so the next waiter is only let
+    // in once the model is free.
+    let _admission = match (&queue, max_wait) {
+        (Some(queue), Some(max_wait)) => {
+            match tokio::time::timeout(max_wait, queue.admit(priority)).await {
+                Ok(admission) => Some(admission),
+                Err(_) => {
+                    for queued in batch {
+                        if let Some(stats) = &queued.stats {
+                            stats.record_wait_rejection();
+                        }
+                        let _ = queued.respond.send(Err(model_busy()));
+                    }
+                    return;
+                }
+            }
+        }
+        (Some(queue), None) => Some(queue.admit(priority).await),
+        (None, _) => None,
+    };
+    let admitted_at = Instant::now();
+
+    let requests: Vec<(String, GenerationParams)> = batch
+        .iter()
+        .map(|queued| {
+            let params = GenerationParams {
+                max_lock_wait: queued
+                    .params
+                    .max_lock_wait
+                    .map(|max_wait| max_wait.saturating_sub(queued.queued_at.elapsed())),
+                ..queued.params
+            };
+            (queued.prompt.clone(), params)
+        })
+        .collect();
+    let model_name = model.metadata().name;
+    let span = tracing::info_span!("inference", model = %model_name, batch_size = batch.len());
+    let results = task::spawn_blocking(move || {
+        let _entered = span.enter();
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            model.generate_batch(&tokenizer, &requests)
+        }))
+        .map_err(|payload| {
+            let message = panic_message(payload.as_ref());
+            tracing::error!(panic = %message, "batched inference panicked");
+            ServiceError::Inference(format!("inference panicked: {message}"))
+        })
+    })
+    .await
+    .map_err(join_error)
+    .and_then(|results| results);
+
+    let results = match results {
+        Ok(results) => results,
+        Err(err) => batch.iter().map(|_| Err(err.clone())).collect(),
+    };
+    for (queued, result) in batch.into_iter().zip(results) {
+        let result = result.map(|mut response| {
+            // Time spent gathering the batch and in the admission queue.
+            add_queue_wait(&mut response, admitted_at - queued.queued_at);
+            response
+        });
+        if let Some(stats) = &queued.stats {
+            stats.record(&result);
+        }
+        let _ = queued.respond.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::join_all;
+
+    use super::*;
+    use crate::model::testing::{FakeModel, assert_timings_add_up, gpt2, greedy, next_token};
+
+    const PROMPTS: [&str; 4] = [
+        "Hello",
+        "The capital of France is",
+        "Once upon a time, in a land far away,",
+        "1 2 3",
+    ];
+
+    async fn run_all(
+        batcher: &Batcher,
+        model: &Arc<dyn Backend>,
+        tokenizer: &Arc<Tokenizer>,
+    ) -> Vec<GenerationResponse> {
+        join_all(PROMPTS.iter().map(|prompt| {
+            batcher.generate(
+                model.clone(),
+                tokenizer.clone(),
+                prompt.to_string(),
+                greedy(8),
+                None,
+                None,
+            )
+        }))
+        .await
+        .into_iter()
+        .map(|result| result.expect("batched generation"))
+        .collect()
+    }
+
+    fn assert_same_output(
+        batched: &[GenerationResponse],
+        model: &FakeModel,
+        tokenizer: &Tokenizer,
+    ) {
+        for (prompt, batched) in PROMPTS.iter().zip(batched) {
+            let alone = model.generate(tokenizer, prompt, &greedy(8), None).unwrap();
+            assert_eq!(batched.completion, alone.completion, "{prompt}");
+            assert_eq!(batched.tokens_generated, alone.tokens_generated);
+            assert_eq!(batched.finish_reason, alone.finish_reason);
+            assert_eq!(batched.usage.prompt_tokens, alone.usage.prompt_tokens);
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_output_matches_running_alone() {
+        let fake = FakeModel::new("fake", next_token);
+        let batches = fake.batches.clone();
+        let model: Arc<dyn Backend> = Arc::new(fake);
+        let tokenizer = Arc::new(gpt2());
+        let batcher = Batcher::spawn(4, Duration::from_millis(50));
+
+        let responses = run_all(&batcher, &model, &tokenizer).await;
+        assert_eq!(*batches.lock(), [4]);
+        assert_same_output(&responses, &FakeModel::new("fake", next_token), &tokenizer);
+    }
+
+    #[tokio::test]
+    async fn batch_size_one_runs_each_request_alone() {
+        let fake = FakeModel::new("fake", next_token);
+        let batches = fake.batches.clone();
+        let model: Arc<dyn Backend> = Arc::new(fake);
+        let tokenizer = Arc::new(gpt2());
+        let batcher = Batcher::spawn(1, Duration::from_millis(50));
+
+        let responses = run_all(&batcher, &model, &tokenizer).await;
+        assert_eq!(*batches.lock(), [1, 1, 1, 1]);
+        assert_same_output(&responses, &FakeModel::new("fake", next_token), &tokenizer);
+    }
+
+    #[tokio::test]
+    async fn different_models_batch_apart() {
+        let (first, second) = (
+            FakeModel::new("first", next_token),
+            FakeModel::new("second", next_token),
+        );
+        let batches = [first.batches.clone(), second.batches.clone()];
+        let models: [Arc<dyn Backend>; 2] = [Arc::new(first), Arc::new(second)];
+        let tokenizer = Arc::new(gpt2());
+        let batcher = Batcher::spawn(8, Duration::from_millis(50));
+
+        let responses = join_all((0..6).map(|n| {
+            batcher.generate(
+                models[n % 2].clone(),
+                tokenizer.clone(),
+                "Hello".into(),
+                greedy(2),
+                None,
+                None,
+            )
+        }))
+        .await;
+        for (n, response) in responses.into_iter().enumerate() {
+            let expected = if n % 2 == 0 { "first" } else { "second" };
+            assert_eq!(response.unwrap().model.name, expected);
+        }
+        assert_eq!(*batches[0].lock(), [3]);
+        assert_eq!(*batches[1].lock(), [3]);
+    }
+
+    #[tokio::test]
+    async fn gathering_the_batch_counts_as_queue_wait() {
+        let model: Arc<dyn Backend> = Arc::new(FakeModel::new("fake", next_token));
+        let batcher = Batcher::spawn(4, Duration::from_millis(30));
+        let response = batcher
+            .generate(
+                model,
+                Arc::new(gpt2()),
+                "Hello".into(),
+                greedy(2),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert!(
+            response.timings.queue_wait_ms >= 30.0,
+            "{:?}",
+            response.timings
+        );
+        assert_timings_add_up(&response);
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    num::NonZeroUsize,
+};
+
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::model::{FinishReason, GenerationParams, GenerationResponse};
+
+/// Identifies a generation by everything that influences its output,
+/// including the module file, so a reloaded model never replays answers
+/// cached from the one it replaced.
+pub(crate) fn request_key(
+    model: &str,
+    module_sha256: &str,
+    prompt: &str,
+    params: &GenerationParams,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    model.hash(&mut hasher);
+    module_sha256.hash(&mut hasher);
+    prompt.hash(&mut hasher);
+    params.max_new_tokens.hash(&mut hasher);
+    params.temperature.to_bits().hash(&mut hasher);
+    params.top_k.hash(&mut hasher);
+    params.seed.hash(&mut hasher);
+    params.context_strategy.hash(&mut hasher);
+    params.sentinel_tokens.hash(&mut hasher);
+    params.add_special_tokens.hash(&mut hasher);
+    params.skip_special_tokens.hash(&mut hasher);
+    params.token_details.hash(&mut hasher);
+    params.on_timeout.hash(&mut hasher);
+    params.response_format.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// In-memory LRU of completed responses. Only deterministic requests are
+/// stored so a cache hit never replays what should have been a fresh sample.
+pub struct ResponseCache {
+    entries: Option<Mutex<LruCache<u64, GenerationResponse>>>,
+}
+
+impl ResponseCache {
+    /// A capacity of zero disables caching.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity).map(|cap| Mutex::new(LruCache::new(cap))),
+        }
+    }
+
+    pub fn get(&self, key: u64) -> Option<GenerationResponse> {
+        let mut response = self.entries.as_ref()?.lock().get(&key)?.clone();
+        response.cached = true;
+        Some(response)
+    }
+
+    pub fn insert(&self, key: u64, params: &GenerationParams, response: &GenerationResponse) {
+        if let Some(entries) = &self.entries
+            && params.is_deterministic()
+            && response.finish_reason != FinishReason::Timeout
+        {
+            entries.lock().put(key, response.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        Backend,
+        testing::{FakeModel, gpt2, greedy, next_token},
+    };
+
+    fn response(completion: &str) -> GenerationResponse {
+        let mut response = FakeModel::new("fake", next_token)
+            .generate(&gpt2(), "Hello", &greedy(1), None)
+            .expect("fake generation");
+        response.completion = completion.to_string();
+        response
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_is_evicted() {
+        let cache = ResponseCache::new(2);
+        let params = greedy(1);
+        cache.insert(1, &params, &response("one"));
+        cache.insert(2, &params, &response("two"));
+        // Reading 1 leaves 2 as the least recently used.
+        assert_eq!(cache.get(1).unwrap().completion, "one");
+        cache.insert(3, &params, &response("three"));
+
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(1).unwrap().completion, "one");
+        assert_eq!(cache.get(3).unwrap().completion, "three");
+    }
+
+    #[test]
+    fn hits_are_marked_cached() {
+        let cache = ResponseCache::new(1);
+        cache.insert(1, &greedy(1), &response("one"));
+        assert!(cache.get(1).unwrap().cached);
+    }
+
+    #[test]
+    fn only_deterministic_complete_responses_are_stored() {
+        let cache = ResponseCache::new(4);
+        let sampled = GenerationParams {
+            temperature: 0.8,
+            top_k: 40,
+            seed: None,
+            ..greedy(1)
+        };
+        cache.insert(1, &sampled, &response("sampled"));
+        assert!(cache.get(1).is_none());
+
+        let seeded = GenerationParams {
+            seed: Some(7),
+            ..sampled
+        };
+        cache.insert(2, &seeded, &response("seeded"));
+        assert!(cache.get(2).is_some());
+
+        let mut timed_out = response("partial");
+        timed_out.finish_reason = FinishReason::Timeout;
+        cache.insert(3, &greedy(1), &timed_out);
+        assert!(cache.get(3).is_none());
+    }
+
+    #[test]
+    fn zero_capacity_disables_the_cache() {
+        let cache = ResponseCache::new(0);
+        cache.insert(1, &greedy(1), &response("one"));
+        assert!(cache.get(1).is_none());
+    }
+}
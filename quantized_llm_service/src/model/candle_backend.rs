@@ -0,0 +1,488 @@
+//! GPT-2 over safetensors weights on candle, so CPU serving works without
+//! LibTorch. Weights use the Hugging Face layout (with or without the
+//! `transformer.` prefix) and `config.json` must sit next to them.
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use candle_core::{DType, Device, IndexOp, Module, Tensor, safetensors::MmapedSafetensors};
+use candle_nn::{Embedding, LayerNorm, VarBuilder, embedding, layer_norm, ops::softmax_last_dim};
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::{
+    config::AppConfig,
+    error::ServiceError,
+    model::{
+        GenerationParams, GenerationResponse, ModelKind, ModelMetadata, SelfTestReport,
+        TokenVocabulary,
+        backend::{
+            Backend, Decoding, ModelSlot, TokenCallback, as_ms, generate_tokens,
+            logprob_from_logits, sample_from_logits, top_logprobs_from_logits,
+        },
+        loader::verify_sha256,
+    },
+};
+
+/// The subset of a Hugging Face GPT-2 `config.json` the model needs.
+#[derive(Debug, Deserialize)]
+struct Gpt2Config {
+    vocab_size: usize,
+    n_positions: usize,
+    n_embd: usize,
+    n_layer: usize,
+    n_head: usize,
+    #[serde(default = "default_layer_norm_epsilon")]
+    layer_norm_epsilon: f64,
+}
+
+fn default_layer_norm_epsilon() -> f64 {
+    1e-5
+}
+
+pub struct CandleModel {
+    name: String,
+    quantized: bool,
+    dtype: String,
+    size_bytes: u64,
+    sha256: String,
+    max_context_tokens: usize,
+    load_time: Duration,
+    num_parameters: u64,
+    vocab_size: usize,
+    eos_token_id: i64,
+    warmup_latencies: Vec<Duration>,
+    self_test: Option<SelfTestReport>,
+    vocabulary: Option<Arc<TokenVocabulary>>,
+    model: Gpt2,
+}
+
+impl Backend for CandleModel {
+    fn load(config: &AppConfig, slot: ModelSlot, path: &Path) -> Result<Self, ServiceError> {
+        if !path.exists() {
+            return Err(ServiceError::Other(format!(
+                "model artifact missing: {}",
+                path.display()
+            )));
+        }
+        let expected_sha256 = match slot {
+            ModelSlot::Baseline => config.baseline_module_sha256.as_deref(),
+            ModelSlot::Quantized => config.quantized_module_sha256.as_deref(),
+        };
+        let size_bytes = fs::metadata(path)?.len();
+        let sha256 = verify_sha256(path, expected_sha256)?;
+
+        let config_path = path.with_file_name("config.json");
+        let gpt2_config: Gpt2Config = fs::read_to_string(&config_path)
+            .map_err(ServiceError::from)
+            .and_then(|raw| {
+                serde_json::from_str(&raw).map_err(|e| ServiceError::Other(e.to_string()))
+            })
+            .map_err(|e| {
+                ServiceError::Other(format!(
+                    "failed to read GPT-2 config {}: {e}",
+                    config_path.display()
+                ))
+            })?;
+
+        let load_error = |e: candle_core::Error| {
+            ServiceError::Other(format!(
+                "failed to load {} model {}: {e}",
+                slot.name(),
+                path.display()
+            ))
+        };
+        let load_started = Instant::now();
+        // SAFETY: the file is mapped read-only and not modified while served.
+        let tensors = unsafe { MmapedSafetensors::new(path) }.map_err(load_error)?;
+        let views = tensors.tensors();
+        let num_parameters = views
+            .iter()
+            .map(|(_, view)| view.shape().iter().product::<usize>() as u64)
+            .sum();
+        let dtype = views
+            .iter()
+            .find(|(name, _)| name.ends_with("wte.weight"))
+            .map(|(_, view)| format!("{:?}", view.dtype()).to_lowercase())
+            .unwrap_or_else(|| "unknown".to_string());
+        // Weights are upcast on load; candle's CPU kernels are fastest in f32.
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[path], DType::F32, &Device::Cpu) }
+            .map_err(load_error)?;
+        let model = Gpt2::load(vb, &gpt2_config).map_err(load_error)?;
+        let load_time = load_started.elapsed();
+
+        Ok(Self {
+            name: slot.name().to_string(),
+            quantized: slot == ModelSlot::Quantized,
+            dtype,
+            size_bytes,
+            sha256,
+            max_context_tokens: config.max_context_tokens.min(gpt2_config.n_positions),
+            load_time,
+            num_parameters,
+            vocab_size: gpt2_config.vocab_size,
+            eos_token_id: config.eos_token_id(ModelKind::Causal),
+            warmup_latencies: Vec::new(),
+            self_test: None,
+            vocabulary: None,
+            model,
+        })
+    }
+
+    fn metadata(&self) -> ModelMetadata {
+        ModelMetadata {
+            name: self.name.clone(),
+            quantized: self.quantized,
+            dtype: self.dtype.clone(),
+            size_bytes: self.size_bytes,
+            sha256: self.sha256.clone(),
+            device: "cpu".to_string(),
+            device_fallback_reason: None,
+            max_context_tokens: self.max_context_tokens,
+            model_kind: ModelKind::Causal,
+            eos_token_id: self.eos_token_id,
+            decoder_start_token_id: None,
+            load_time_ms: as_ms(self.load_time),
+            num_parameters: Some(self.num_parameters),
+            vocab_size: self.vocab_size,
+            hidden_states: false,
+            warmup_latency_ms: self.warmup_latencies.iter().copied().map(as_ms).collect(),
+            self_test: self.self_test.clone(),
+            replicas: Vec::new(),
+        }
+    }
+
+    fn generate(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: Option<&mut TokenCallback>,
+    ) -> Result<GenerationResponse, ServiceError> {
+        // Candle tensors are immutable, so there is nothing to lock.
+        let decoding = Decoding::new(
+            self.metadata(),
+            tokenizer,
+            self.vocabulary.as_deref(),
+            prompt,
+            params,
+        )?;
+        generate_tokens(
+            decoding,
+            tokenizer,
+            on_token,
+            |_| Ok(()),
+            |_, input_ids, rng, json| {
+                let mut logits = self
+                    .model
+                    .last_logits(input_ids)
+                    .map_err(|e| ServiceError::Inference(e.to_string()))?;
+                if let Some(json) = json {
+                    json.mask(&mut logits)?;
+                }
+                let id = sample_from_logits(&logits, params, rng)?;
+                let logprob = params
+                    .token_details
+                    .then(|| logprob_from_logits(&logits, id));
+                let top = top_logprobs_from_logits(&logits, params.top_logprobs);
+                Ok((id, logprob, top))
+            },
+        )
+    }
+
+    fn set_warmup_latencies(&mut self, latencies: Vec<Duration>) {
+        self.warmup_latencies = latencies;
+    }
+
+    fn set_self_test(&mut self, report: SelfTestReport) {
+        self.self_test = Some(report);
+    }
+
+    fn set_vocabulary(&mut self, vocabulary: Arc<TokenVocabulary>) {
+        self.vocabulary = Some(vocabulary);
+    }
+}
+
+/// GPT-2's `Conv1D`: a linear layer with its weight stored `[in, out]`.
+struct Conv1D {
+    weight: Tensor,
+    bias: Tensor,
+}
+
+impl Conv1D {
+    fn load(vb: VarBuilder, input: usize, output: usize) -> candle_core::Result<Self> {
+        Ok(Self {
+            weight: vb.get((input, output), "weight")?,
+            bias: vb.get(output, "bias")?,
+        })
+    }
+}
+
+impl Module for Conv1D {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        xs.broadcast_matmul(&self.weight)?.broadcast_add(&self.bias)
+    }
+}
+
+struct Attention {
+    c_attn: Conv1D,
+    c_proj: Conv1D,
+    n_head: usize,
+}
+
+impl Attention {
+    fn forward(&self, xs: &Tensor, mask: &Tensor) -> candle_core::Result<Tensor> {
+        let (batch, seq_len, n_embd) = xs.dims3()?;
+        let head_dim = n_embd / self.n_head;
+        let qkv = self.c_attn.forward(xs)?;
+        let heads = |index: usize| {
+            qkv.narrow(2, index * n_embd, n_embd)?
+                .reshape((batch, seq_len, self.n_head, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let (q, k, v) = (heads(0)?, heads(1)?, heads(2)?);
+        let scores = (q.matmul(&k.t()?.contiguous()?)? / (head_dim as f64).sqrt())?;
+        let weights = softmax_last_dim(&scores.broadcast_add(mask)?)?;
+        let attended = weights
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((batch, seq_len, n_embd))?;
+        self.c_proj.forward(&attended)
+    }
+}
+
+struct Block {
+    ln_1: LayerNorm,
+    attn: Attention,
+    ln_2: LayerNorm,
+    c_fc: Conv1D,
+    mlp_proj: Conv1D,
+}
+
+impl Block {
+    fn load(vb: VarBuilder, config: &Gpt2Config) -> candle_core::Result<Self> {
+        let n_embd = config.n_embd;
+        let eps = config.layer_norm_epsilon;
+        Ok(Self {
+            ln_1: layer_norm(n_embd, eps, vb.pp("ln_1"))?,
+            attn: Attention {
+                c_attn: Conv1D::load(vb.pp("attn.c_attn"), n_embd, 3 * n_embd)?,
+                c_proj: Conv1D::load(vb.pp("attn.c_proj"), n_embd, n_embd)?,
+                n_head: config.n_head,
+            },
+            ln_2: layer_norm(n_embd, eps, vb.pp("ln_2"))?,
+            c_fc: Conv1D::load(vb.pp("mlp.c_fc"), n_embd, 4 * n_embd)?,
+            mlp_proj: Conv1D::load(vb.pp("mlp.c_proj"), 4 * n_embd, n_embd)?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, mask: &Tensor) -> candle_core::Result<Tensor> {
+        let xs = (xs + self.attn.forward(&self.ln_1.forward(xs)?, mask)?)?;
+        // GPT-2's "gelu_new" is the tanh approximation, which is candle's gelu.
+        let hidden = self.c_fc.forward(&self.ln_2.forward(&xs)?)?.gelu()?;
+        xs + self.mlp_proj.forward(&hidden)?
+    }
+}
+
+struct Gpt2 {
+    wte: Embedding,
+    wpe: Embedding,
+    blocks: Vec<Block>,
+    ln_f: LayerNorm,
+}
+
+impl Gpt2 {
+    fn load(vb: VarBuilder, config: &Gpt2Config) -> candle_core::Result<Self> {
+        let vb = if vb.contains_tensor("wte.weight") {
+            vb
+        } else {
+            vb.pp("transformer")
+        };
+        let blocks = (0..config.n_layer)
+            .map(|layer| Block::load(vb.pp(format!("h.{layer}")), config))
+            .collect::<candle_core::Result<_>>()?;
+        Ok(Self {
+            wte: embedding(config.vocab_size, config.n_embd, vb.pp("wte"))?,
+            wpe: embedding(config.n_positions, config.n_embd, vb.pp("wpe"))?,
+            blocks,
+            ln_f: layer_norm(config.n_embd, config.layer_norm_epsilon, vb.pp("ln_f"))?,
+        })
+    }
+
+    /// Runs the whole sequence and returns the logits of its last position;
+    /// the LM head is tied to the token embeddings.
+    fn last_logits(&self, input_ids: &[i64]) -> candle_core::Result<Vec<f32>> {
+        let device = Device::Cpu;
+        let seq_len = input_ids.len();
+        let ids: Vec<u32> = input_ids.iter().map(|&id| id as u32).collect();
+        let ids = Tensor::new(ids.as_slice(), &device)?.unsqueeze(0)?;
+        let positions = Tensor::arange(0u32, seq_len as u32, &device)?.unsqueeze(0)?;
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0.0 }))
+            .collect();
+        let mask = Tensor::from_vec(mask, (seq_len, seq_len), &device)?;
+
+        let mut xs = (self.wte.forward(&ids)? + self.wpe.forward(&positions)?)?;
+        for block in &self.blocks {
+            xs = block.forward(&xs, &mask)?;
+        }
+        let last = self.ln_f.forward(&xs)?.i((.., seq_len - 1, ..))?;
+        last.matmul(&self.wte.embeddings().t()?)?
+            .squeeze(0)?
+            .to_vec1()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, env, path::PathBuf, process};
+
+    use super::*;
+    use crate::model::testing::{GPT2_VOCAB_SIZE, gpt2, greedy};
+
+    const N_EMBD: usize = 4;
+    const N_POSITIONS: usize = 16;
+
+    /// Deterministic, varied weights for the embeddings.
+    fn weight(row: usize, column: usize) -> f32 {
+        (row as f32 * 0.731 + column as f32 * 1.37).sin()
+    }
+
+    /// A one-layer GPT-2 with the full vocabulary whose attention and MLP
+    /// weights are zero, so each block passes its input straight through
+    /// and the next token depends only on the last one and its position.
+    fn write_fixture(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("qls-candle-{}-{name}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let device = Device::Cpu;
+        let table = |rows: usize| {
+            let values = (0..rows)
+                .flat_map(|row| (0..N_EMBD).map(move |column| weight(row, column)))
+                .collect();
+            Tensor::from_vec(values, (rows, N_EMBD), &device).unwrap()
+        };
+        let zeros = |shape: &[usize]| Tensor::zeros(shape, DType::F32, &device).unwrap();
+        let ones = |len: usize| Tensor::ones(len, DType::F32, &device).unwrap();
+        let mut tensors = HashMap::from([
+            ("wte.weight".to_string(), table(GPT2_VOCAB_SIZE)),
+            ("wpe.weight".to_string(), table(N_POSITIONS)),
+            ("ln_f.weight".to_string(), ones(N_EMBD)),
+            ("ln_f.bias".to_string(), zeros(&[N_EMBD])),
+        ]);
+        for (name, shape) in [
+            ("ln_1.bias", vec![N_EMBD]),
+            ("ln_2.bias", vec![N_EMBD]),
+            ("attn.c_attn.weight", vec![N_EMBD, 3 * N_EMBD]),
+            ("attn.c_attn.bias", vec![3 * N_EMBD]),
+            ("attn.c_proj.weight", vec![N_EMBD, N_EMBD]),
+            ("attn.c_proj.bias", vec![N_EMBD]),
+            ("mlp.c_fc.weight", vec![N_EMBD, 4 * N_EMBD]),
+            ("mlp.c_fc.bias", vec![4 * N_EMBD]),
+            ("mlp.c_proj.weight", vec![4 * N_EMBD, N_EMBD]),
+            ("mlp.c_proj.bias", vec![N_EMBD]),
+        ] {
+            tensors.insert(format!("h.0.{name}"), zeros(&shape));
+        }
+        for name in ["ln_1.weight", "ln_2.weight"] {
+            tensors.insert(format!("h.0.{name}"), ones(N_EMBD));
+        }
+        let weights = dir.join("model.safetensors");
+        candle_core::safetensors::save(&tensors, &weights).unwrap();
+        let config = serde_json::json!({
+            "vocab_size": GPT2_VOCAB_SIZE,
+            "n_positions": N_POSITIONS,
+            "n_embd": N_EMBD,
+            "n_layer": 1,
+            "n_head": 1,
+        });
+        fs::write(dir.join("config.json"), config.to_string()).unwrap();
+        weights
+    }
+
+    /// The fixture's logits after `token` at `position`: the layer-normed
+    /// input embedding against every token embedding.
+    fn expected_logits(token: usize, position: usize) -> Vec<f32> {
+        let input: Vec<f32> = (0..N_EMBD)
+            .map(|k| weight(token, k) + weight(position, k))
+            .collect();
+        let mean = input.iter().sum::<f32>() / N_EMBD as f32;
+        let variance = input.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / N_EMBD as f32;
+        let normed: Vec<f32> = input
+            .iter()
+            .map(|x| (x - mean) / (variance + 1e-5).sqrt())
+            .collect();
+        (0..GPT2_VOCAB_SIZE)
+            .map(|v| (0..N_EMBD).map(|k| normed[k] * weight(v, k)).sum())
+            .collect()
+    }
+
+    #[test]
+    fn loads_safetensors_and_decodes_greedily() {
+        let path = write_fixture("greedy");
+        let model = CandleModel::load(&AppConfig::default(), ModelSlot::Quantized, &path).unwrap();
+        let metadata = model.metadata();
+        assert!(metadata.quantized);
+        assert_eq!(metadata.dtype, "f32");
+        assert_eq!(metadata.vocab_size, GPT2_VOCAB_SIZE);
+        assert_eq!(metadata.max_context_tokens, N_POSITIONS);
+        let embeddings = (GPT2_VOCAB_SIZE + N_POSITIONS) * N_EMBD;
+        let blocks = 2 * N_EMBD + 2 * N_EMBD + 12 * N_EMBD * N_EMBD + 9 * N_EMBD;
+        assert_eq!(
+            metadata.num_parameters,
+            Some((embeddings + blocks + 2 * N_EMBD) as u64)
+        );
+
+        let tokenizer = gpt2();
+        let prompt = tokenizer.encode("Hello", false).unwrap().get_ids().to_vec();
+        let params = GenerationParams {
+            token_details: true,
+            ..greedy(4)
+        };
+        let response = model.generate(&tokenizer, "Hello", &params, None).unwrap();
+        let generated: Vec<i64> = response
+            .token_details
+            .unwrap()
+            .iter()
+            .map(|detail| detail.id as i64)
+            .collect();
+
+        assert_eq!(generated.len(), 4);
+        // Each token must be the reference's best given the one before it,
+        // allowing for rounding between near-equal logits.
+        let mut last = *prompt.last().unwrap() as usize;
+        for (step, &token) in generated.iter().enumerate() {
+            let logits = expected_logits(last, prompt.len() - 1 + step);
+            let best = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            assert!(
+                logits[token as usize] >= best - 1e-4,
+                "step {step}: token {token} scores {}, best is {best}",
+                logits[token as usize]
+            );
+            last = token as usize;
+        }
+    }
+
+    #[test]
+    fn a_missing_config_is_named() {
+        let path = write_fixture("no-config");
+        fs::remove_file(path.with_file_name("config.json")).unwrap();
+        let err = CandleModel::load(&AppConfig::default(), ModelSlot::Baseline, &path)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("config.json"), "{err}");
+    }
+
+    #[test]
+    fn a_checksum_mismatch_is_refused() {
+        let path = write_fixture("checksum");
+        let config = AppConfig {
+            baseline_module_sha256: Some("0".repeat(64)),
+            ..AppConfig::default()
+        };
+        assert!(CandleModel::load(&config, ModelSlot::Baseline, &path).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use hf_hub::{
+    Repo, RepoType,
+    api::{
+        Progress,
+        sync::{Api, ApiBuilder},
+    },
+};
+
+use crate::{config::AppConfig, error::ServiceError};
+
+/// Returns `path` if it exists; otherwise, with auto-download enabled, fetches
+/// `remote_name` from the configured Hub repo and returns the cached copy.
+pub fn resolve_artifact(
+    config: &AppConfig,
+    path: &Path,
+    remote_name: &str,
+) -> Result<PathBuf, ServiceError> {
+    if path.exists() || !config.auto_download {
+        return Ok(path.to_path_buf());
+    }
+
+    let revision = config.revision.as_deref().unwrap_or("main");
+    tracing::info!(
+        model_id = %config.model_id,
+        revision,
+        file = remote_name,
+        missing = %path.display(),
+        "downloading artifact from the Hugging Face Hub"
+    );
+    let repo = hub(config)?.repo(Repo::with_revision(
+        config.model_id.clone(),
+        RepoType::Model,
+        revision.to_string(),
+    ));
+    // hf-hub writes to a temporary file, resumes partial downloads, and
+    // renames into the cache only once the file is complete.
+    repo.download_with_progress(remote_name, LogProgress::default())
+        .map_err(|e| {
+            ServiceError::Download(format!("{}@{revision}/{remote_name}: {e}", config.model_id))
+        })
+}
+
+/// Remote name used for a TorchScript module: the configured file name.
+pub fn module_remote_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+fn hub(config: &AppConfig) -> Result<Api, ServiceError> {
+    let mut builder = ApiBuilder::from_env().with_progress(false);
+    if let Some(dir) = &config.model_cache_dir {
+        builder = builder.with_cache_dir(dir.clone());
+    }
+    if let Ok(token) = env::var("HF_TOKEN") {
+        builder = builder.with_token(Some(token));
+    }
+    builder
+        .build()
+        .map_err(|e| ServiceError::Download(format!("failed to set up Hub client: {e}")))
+}
+
+/// Logs download progress in 10% steps instead of drawing a terminal bar.
+#[derive(Default)]
+struct LogProgress {
+    filename: String,
+    total: usize,
+    done: usize,
+    logged_tenths: usize,
+}
+
+impl Progress for LogProgress {
+    fn init(&mut self, size: usize, filename: &str) {
+        self.filename = filename.to_string();
+        self.total = size;
+        tracing::info!(file = filename, bytes = size, "download started");
+    }
+
+    fn update(&mut self, size: usize) {
+        self.done += size;
+        if self.total == 0 {
+            return;
+        }
+        let tenths = self.done * 10 / self.total;
+        if tenths > self.logged_tenths {
+            self.logged_tenths = tenths;
+            tracing::info!(
+                file = %self.filename,
+                percent = tenths * 10,
+                bytes = self.done,
+                "downloading"
+            );
+        }
+    }
+
+    fn finish(&mut self) {
+        tracing::info!(file = %self.filename, bytes = self.done, "download finished");
+    }
+}
@@ -0,0 +1,522 @@
+//! Constrained decoding: masks the logits of every token that would take
+//! the output outside a grammar, for now JSON.
+use std::time::Instant;
+
+use tokenizers::Tokenizer;
+
+use crate::error::ServiceError;
+
+/// Marks a token whose text starts with a non-ASCII character, which JSON
+/// only allows inside strings.
+const NOT_ASCII: u8 = u8::MAX;
+
+/// Deepest nesting of objects and arrays the JSON automaton tracks.
+const MAX_DEPTH: u8 = 64;
+
+/// The text each token adds to the output, worked out once per tokenizer.
+pub struct TokenVocabulary {
+    /// Empty for special tokens, which constrained output never uses.
+    texts: Vec<Box<str>>,
+    /// First character of each token when ASCII, [`NOT_ASCII`] otherwise.
+    first: Vec<u8>,
+    /// The token can go inside a JSON string as it is: no quote, backslash
+    /// or control character.
+    plain: Vec<bool>,
+}
+
+impl TokenVocabulary {
+    /// Decodes every token after a fixed one, so a piece reads as it does
+    /// inside a longer output: on its own a SentencePiece `▁word` would
+    /// lose its leading space.
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let started = Instant::now();
+        let special: std::collections::HashSet<u32> = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, _)| id)
+            .collect();
+        let anchor = tokenizer
+            .encode("a", false)
+            .ok()
+            .and_then(|encoding| encoding.get_ids().last().copied());
+        let anchor_text = anchor.and_then(|id| tokenizer.decode(&[id], false).ok());
+        let size = tokenizer.get_vocab_size(true);
+        let mut vocabulary = Self {
+            texts: Vec::with_capacity(size),
+            first: Vec::with_capacity(size),
+            plain: Vec::with_capacity(size),
+        };
+        for id in 0..size as u32 {
+            let text = if special.contains(&id) {
+                String::new()
+            } else {
+                piece_text(tokenizer, id, anchor.zip(anchor_text.as_deref()))
+            };
+            vocabulary.first.push(match text.chars().next() {
+                Some(c) if c.is_ascii() => c as u8,
+                _ => NOT_ASCII,
+            });
+            vocabulary
+                .plain
+                .push(text.chars().all(|c| c != '"' && c != '\\' && c >= ' '));
+            vocabulary.texts.push(text.into_boxed_str());
+        }
+        tracing::debug!(
+            tokens = size,
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "built token vocabulary for constrained decoding"
+        );
+        vocabulary
+    }
+
+    pub fn len(&self) -> usize {
+        self.texts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.texts.is_empty()
+    }
+}
+
+fn piece_text(tokenizer: &Tokenizer, id: u32, anchor: Option<(u32, &str)>) -> String {
+    if let Some((anchor, anchor_text)) = anchor
+        && let Ok(text) = tokenizer.decode(&[anchor, id], false)
+        && let Some(piece) = text.strip_prefix(anchor_text)
+    {
+        return piece.to_string();
+    }
+    tokenizer.decode(&[id], false).unwrap_or_default()
+}
+
+/// Keeps one generation's output valid JSON: an object or array, finished
+/// as soon as it closes.
+pub struct JsonConstraint<'a> {
+    vocabulary: &'a TokenVocabulary,
+    eos_token_id: i64,
+    state: JsonState,
+    /// Reused between steps: 0 for allowed tokens, minus infinity for the
+    /// rest.
+    bias: Vec<f32>,
+}
+
+impl<'a> JsonConstraint<'a> {
+    pub fn new(vocabulary: &'a TokenVocabulary, eos_token_id: i64) -> Self {
+        Self {
+            vocabulary,
+            eos_token_id,
+            state: JsonState::default(),
+            bias: Vec::new(),
+        }
+    }
+
+    /// The top-level value has closed.
+    pub fn is_complete(&self) -> bool {
+        self.state.mode == Mode::Done
+    }
+
+    /// Moves past a generated token.
+    pub fn advance(&mut self, id: i64) {
+        let Some(text) = usize::try_from(id)
+            .ok()
+            .and_then(|id| self.vocabulary.texts.get(id))
+        else {
+            return;
+        };
+        match self.state.feed_str(text) {
+            Some(state) => self.state = state,
+            None => tracing::warn!(id, "sampled a token the JSON constraint had masked"),
+        }
+    }
+
+    /// Sets the logits of every token that cannot come next to minus
+    /// infinity.
+    pub fn mask(&mut self, logits: &mut [f32]) -> Result<(), ServiceError> {
+        let bias = self.bias(logits.len())?;
+        logits
+            .iter_mut()
+            .zip(bias)
+            .for_each(|(logit, bias)| *logit += bias);
+        Ok(())
+    }
+
+    /// What to add to `vocab_size` logits to mask them, for backends that
+    /// keep logits on the device.
+    pub fn bias(&mut self, vocab_size: usize) -> Result<&[f32], ServiceError> {
+        self.bias.clear();
+        self.bias.resize(vocab_size, f32::NEG_INFINITY);
+        let state = self.state;
+        let in_plain_string = matches!(
+            state.mode,
+            Mode::String {
+                escape: Escape::None,
+                ..
+            }
+        );
+        // Which ASCII characters may come next settles most tokens by their
+        // first character alone.
+        let mut starts = [false; 128];
+        for (c, allowed) in starts.iter_mut().enumerate() {
+            *allowed = state.feed(c as u8 as char).is_some();
+        }
+        let vocabulary = self.vocabulary;
+        let mut allowed = 0usize;
+        for (id, bias) in self.bias.iter_mut().enumerate().take(vocabulary.len()) {
+            if id as i64 == self.eos_token_id || vocabulary.texts[id].is_empty() {
+                continue;
+            }
+            let ok = if in_plain_string && vocabulary.plain[id] {
+                true
+            } else {
+                match vocabulary.first[id] {
+                    NOT_ASCII => in_plain_string && state.feed_str(&vocabulary.texts[id]).is_some(),
+                    first => {
+                        starts[first as usize] && state.feed_str(&vocabulary.texts[id]).is_some()
+                    }
+                }
+            };
+            if ok {
+                *bias = 0.0;
+                allowed += 1;
+            }
+        }
+        if allowed == 0 {
+            return Err(ServiceError::Inference(
+                "no token in the vocabulary can continue the JSON output".into(),
+            ));
+        }
+        Ok(&self.bias)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Before the top-level value, which must be an object or array.
+    Start,
+    /// After `:` or an array's `,`.
+    Value,
+    /// Right after `[`.
+    ValueOrClose,
+    /// Right after `{`.
+    KeyOrClose,
+    /// After an object's `,`.
+    Key,
+    Colon,
+    CommaOrClose,
+    String {
+        key: bool,
+        escape: Escape,
+    },
+    Number(Number),
+    Literal {
+        word: &'static [u8],
+        next: u8,
+    },
+    /// The top-level value has closed; only whitespace may follow.
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Backslash,
+    /// Hex digits of a `\u` escape still to come.
+    Unicode(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Number {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits,
+}
+
+/// Where the output stands in the JSON grammar. Small enough to copy for
+/// every candidate token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct JsonState {
+    mode: Mode,
+    /// One bit per open container, set for objects; bit 0 is the
+    /// outermost.
+    containers: u64,
+    depth: u8,
+}
+
+impl Default for JsonState {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Start,
+            containers: 0,
+            depth: 0,
+        }
+    }
+}
+
+impl JsonState {
+    fn feed_str(mut self, text: &str) -> Option<Self> {
+        for c in text.chars() {
+            self = self.feed(c)?;
+        }
+        Some(self)
+    }
+
+    fn feed(mut self, c: char) -> Option<Self> {
+        let whitespace = matches!(c, ' ' | '\t' | '\n' | '\r');
+        match self.mode {
+            Mode::Start => match c {
+                _ if whitespace => {}
+                '{' | '[' => return self.open(c == '{'),
+                _ => return None,
+            },
+            Mode::Done => return whitespace.then_some(self),
+            Mode::Value | Mode::ValueOrClose => match c {
+                _ if whitespace => {}
+                ']' if self.mode == Mode::ValueOrClose => return Some(self.close()),
+                _ => return self.start_value(c),
+            },
+            Mode::KeyOrClose | Mode::Key => match c {
+                _ if whitespace => {}
+                '"' => {
+                    self.mode = Mode::String {
+                        key: true,
+                        escape: Escape::None,
+                    }
+                }
+                '}' if self.mode == Mode::KeyOrClose => return Some(self.close()),
+                _ => return None,
+            },
+            Mode::Colon => match c {
+                _ if whitespace => {}
+                ':' => self.mode = Mode::Value,
+                _ => return None,
+            },
+            Mode::CommaOrClose => match c {
+                _ if whitespace => {}
+                ',' => {
+                    self.mode = if self.in_object() {
+                        Mode::Key
+                    } else {
+                        Mode::Value
+                    }
+                }
+                '}' if self.in_object() => return Some(self.close()),
+                ']' if !self.in_object() => return Some(self.close()),
+                _ => return None,
+            },
+            Mode::String { key, escape } => {
+                let escape = match escape {
+                    Escape::None => match c {
+                        '"' => {
+                            self.mode = if key { Mode::Colon } else { Mode::CommaOrClose };
+                            return Some(self);
+                        }
+                        '\\' => Escape::Backslash,
+                        _ if c < ' ' => return None,
+                        _ => Escape::None,
+                    },
+                    Escape::Backslash => match c {
+                        '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't' => Escape::None,
+                        'u' => Escape::Unicode(4),
+                        _ => return None,
+                    },
+                    Escape::Unicode(left) if c.is_ascii_hexdigit() => match left {
+                        1 => Escape::None,
+                        _ => Escape::Unicode(left - 1),
+                    },
+                    Escape::Unicode(_) => return None,
+                };
+                self.mode = Mode::String { key, escape };
+            }
+            Mode::Number(number) => {
+                let next = match (number, c) {
+                    (Number::Minus, '0') => Number::Zero,
+                    (Number::Minus, '1'..='9') => Number::Int,
+                    (Number::Int, '0'..='9') => Number::Int,
+                    (Number::Zero | Number::Int, '.') => Number::Dot,
+                    (Number::Dot | Number::Fraction, '0'..='9') => Number::Fraction,
+                    (Number::Zero | Number::Int | Number::Fraction, 'e' | 'E') => Number::Exponent,
+                    (Number::Exponent, '+' | '-') => Number::ExponentSign,
+                    (
+                        Number::Exponent | Number::ExponentSign | Number::ExponentDigits,
+                        '0'..='9',
+                    ) => Number::ExponentDigits,
+                    // A complete number ends at the first character that
+                    // can't extend it.
+                    (Number::Zero | Number::Int | Number::Fraction | Number::ExponentDigits, _) => {
+                        self.mode = Mode::CommaOrClose;
+                        return self.feed(c);
+                    }
+                    _ => return None,
+                };
+                self.mode = Mode::Number(next);
+            }
+            Mode::Literal { word, next } => {
+                if !c.is_ascii() || word.get(next as usize) != Some(&(c as u8)) {
+                    return None;
+                }
+                self.mode = if next as usize + 1 == word.len() {
+                    Mode::CommaOrClose
+                } else {
+                    Mode::Literal {
+                        word,
+                        next: next + 1,
+                    }
+                };
+            }
+        }
+        Some(self)
+    }
+
+    fn start_value(mut self, c: char) -> Option<Self> {
+        self.mode = match c {
+            '{' | '[' => return self.open(c == '{'),
+            '"' => Mode::String {
+                key: false,
+                escape: Escape::None,
+            },
+            '-' => Mode::Number(Number::Minus),
+            '0' => Mode::Number(Number::Zero),
+            '1'..='9' => Mode::Number(Number::Int),
+            't' => Mode::Literal {
+                word: b"true",
+                next: 1,
+            },
+            'f' => Mode::Literal {
+                word: b"false",
+                next: 1,
+            },
+            'n' => Mode::Literal {
+                word: b"null",
+                next: 1,
+            },
+            _ => return None,
+        };
+        Some(self)
+    }
+
+    fn open(mut self, object: bool) -> Option<Self> {
+        if self.depth == MAX_DEPTH {
+            return None;
+        }
+        if object {
+            self.containers |= 1 << self.depth;
+        } else {
+            self.containers &= !(1 << self.depth);
+        }
+        self.depth += 1;
+        self.mode = if object {
+            Mode::KeyOrClose
+        } else {
+            Mode::ValueOrClose
+        };
+        Some(self)
+    }
+
+    fn close(mut self) -> Self {
+        self.depth -= 1;
+        self.mode = if self.depth == 0 {
+            Mode::Done
+        } else {
+            Mode::CommaOrClose
+        };
+        self
+    }
+
+    fn in_object(&self) -> bool {
+        self.depth > 0 && self.containers & (1 << (self.depth - 1)) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::{Rng, rngs::StdRng};
+
+    use super::*;
+    use crate::{
+        config::AppConfig,
+        model::{
+            Backend, FinishReason, GenerationParams, GenerationRequest, ResponseFormat,
+            testing::{FakeModel, GPT2_VOCAB_SIZE, Logits, gpt2},
+        },
+    };
+
+    /// Random scores, with the GPT-2 tokens `"`, `]` and `}` pulled ahead
+    /// more the longer the output gets so every generation closes.
+    fn noisy_json(input_ids: &[i64], rng: &mut StdRng) -> Vec<f32> {
+        let mut logits: Vec<f32> = (0..GPT2_VOCAB_SIZE).map(|_| rng.r#gen()).collect();
+        let pressure = input_ids.len() as f32 / 16.0;
+        for closer in [1, 60, 92] {
+            logits[closer] += pressure;
+        }
+        logits
+    }
+
+    fn json_params(seed: u64, max_new_tokens: usize) -> GenerationParams {
+        let request = GenerationRequest {
+            max_new_tokens: Some(max_new_tokens),
+            seed: Some(seed),
+            response_format: Some(ResponseFormat::Json),
+            ..GenerationRequest::default()
+        };
+        GenerationParams::resolve(&request, &AppConfig::default())
+    }
+
+    fn json_model(tokenizer: &Tokenizer, logits: Logits) -> FakeModel {
+        let mut model = FakeModel::new("fake", logits);
+        model.set_vocabulary(Arc::new(TokenVocabulary::new(tokenizer)));
+        model
+    }
+
+    #[test]
+    fn seeded_generations_all_parse() {
+        let tokenizer = gpt2();
+        let model = json_model(&tokenizer, noisy_json);
+        let mut distinct = std::collections::HashSet::new();
+        for seed in 0..100 {
+            let response = model
+                .generate(&tokenizer, "Reply in JSON:", &json_params(seed, 128), None)
+                .unwrap();
+            assert_eq!(
+                response.finish_reason,
+                FinishReason::Stop,
+                "seed {seed}: {}",
+                response.completion
+            );
+            if let Err(err) = serde_json::from_str::<serde_json::Value>(&response.completion) {
+                panic!("seed {seed}: {err} in {:?}", response.completion);
+            }
+            distinct.insert(response.completion);
+        }
+        assert!(distinct.len() > 50, "{distinct:?}");
+    }
+
+    /// Opens an object, then keeps its first key going.
+    fn never_closing(_input_ids: &[i64], _rng: &mut StdRng) -> Vec<f32> {
+        let mut logits = vec![0.0; GPT2_VOCAB_SIZE];
+        logits[90] = 1.0;
+        logits[1] = 1.0;
+        logits
+    }
+
+    #[test]
+    fn output_cut_short_is_unfinished_json() {
+        let tokenizer = gpt2();
+        let model = json_model(&tokenizer, never_closing);
+        let response = model
+            .generate(&tokenizer, "Reply in JSON:", &json_params(0, 4), None)
+            .unwrap();
+        assert_eq!(response.finish_reason, FinishReason::Length);
+        assert!(
+            serde_json::from_str::<serde_json::Value>(&response.completion).is_err(),
+            "{}",
+            response.completion
+        );
+    }
+}
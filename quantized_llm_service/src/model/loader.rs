@@ -1,197 +1,797 @@
-use std::{fs, path::Path, sync::Arc, time::Instant};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, ScopedJoinHandle},
+    time::Instant,
+};
 
-use parking_lot::Mutex;
-use tch::{Device, Tensor, no_grad};
-use tokenizers::Tokenizer;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokenizers::{
+    DecoderWrapper, ModelWrapper, PreTokenizerWrapper, Tokenizer, TruncationDirection,
+    TruncationParams,
+};
 
+#[cfg(any(feature = "tch-backend", feature = "candle-backend"))]
+use crate::model::backend::BackendKind;
+#[cfg(feature = "candle-backend")]
+use crate::model::candle_backend::CandleModel;
+#[cfg(feature = "ort-backend")]
+use crate::model::ort_backend::OrtModel;
+#[cfg(feature = "tch-backend")]
+use crate::model::tch_backend::ModelInstance;
 use crate::{
     config::AppConfig,
     error::ServiceError,
-    model::{GenerationResponse, ModelMetadata},
+    model::{
+        SpecialToken, TokenVocabulary, TokenizerMetadata,
+        backend::{Backend, ModelSlot, check_vocab, self_test, warmup},
+        download::{module_remote_name, resolve_artifact},
+        replicas::ReplicaSet,
+    },
 };
 
-pub struct ModelArtifacts {
-    pub tokenizer: Arc<Tokenizer>,
-    pub quantized: Option<Arc<ModelInstance>>,
-    pub baseline: Option<Arc<ModelInstance>>,
+static CUDA_OOM_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of CUDA out-of-memory failures observed since startup.
+pub fn cuda_oom_events() -> u64 {
+    CUDA_OOM_EVENTS.load(Ordering::Relaxed)
 }
 
-pub struct ModelInstance {
-    name: String,
-    quantized: bool,
-    dtype: String,
-    size_bytes: u64,
-    device: Device,
-    module: Mutex<tch::CModule>,
+/// Counts one more CUDA OOM and returns the new total.
+#[cfg_attr(not(feature = "tch-backend"), allow(dead_code))]
+pub(crate) fn record_cuda_oom() -> u64 {
+    CUDA_OOM_EVENTS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+pub struct ModelArtifacts {
+    pub tokenizer: Arc<Tokenizer>,
+    /// The tokenizer's token texts, for constrained decoding.
+    pub vocabulary: Arc<TokenVocabulary>,
+    pub quantized: Option<Arc<dyn Backend>>,
+    pub baseline: Option<Arc<dyn Backend>>,
+    /// Why the optional quantized module failed to load, if it did.
+    pub quantized_error: Option<String>,
+    pub tokenizer_metadata: Arc<TokenizerMetadata>,
 }
 
 impl ModelArtifacts {
     pub fn load(config: &AppConfig) -> Result<Self, ServiceError> {
-        let tokenizer = Arc::new(
-            Tokenizer::from_file(config.tokenizer_path.as_path())
-                .map_err(|e| ServiceError::Tokenizer(e.to_string()))?,
-        );
+        match config.backend {
+            #[cfg(feature = "tch-backend")]
+            BackendKind::Tch => Self::load_with::<ModelInstance>(config),
+            #[cfg(feature = "candle-backend")]
+            BackendKind::Candle => Self::load_with::<CandleModel>(config),
+            #[allow(unreachable_patterns)]
+            other => Err(ServiceError::Other(format!(
+                "the {other} backend is not compiled in"
+            ))),
+        }
+    }
+
+    /// The tokenizer and both modules are read in side by side; only the
+    /// checks that run a model need the tokenizer. Every artifact that
+    /// failed is named in the error.
+    #[cfg_attr(
+        not(any(feature = "tch-backend", feature = "candle-backend")),
+        allow(dead_code)
+    )]
+    pub(crate) fn load_with<B: Backend + 'static>(
+        config: &AppConfig,
+    ) -> Result<Self, ServiceError> {
+        let (tokenizer, baseline, quantized) = thread::scope(|scope| {
+            let baseline = scope.spawn(|| {
+                timed("baseline model", || {
+                    read_slot::<B>(config, ModelSlot::Baseline)
+                })
+            });
+            let quantized = scope.spawn(|| {
+                timed("quantized model", || {
+                    read_slot::<B>(config, ModelSlot::Quantized)
+                })
+            });
+            let tokenizer = timed("tokenizer", || load_tokenizer(config));
+            (tokenizer, joined(baseline), joined(quantized))
+        });
+        let ((tokenizer, vocabulary, tokenizer_metadata), baseline) = match (tokenizer, baseline) {
+            (Ok(tokenizer), Ok(baseline)) => (tokenizer, baseline),
+            (tokenizer, baseline) => {
+                return Err(failed_artifacts([
+                    ("tokenizer", tokenizer.err()),
+                    ("baseline model", baseline.err()),
+                    ("quantized model", quantized.err()),
+                ]));
+            }
+        };
+        let tokenizer = Arc::new(tokenizer);
+        let vocabulary = Arc::new(vocabulary);
+        let tokenizer_metadata = Arc::new(tokenizer_metadata);
+
+        // One model at a time from here, so neither's warmup latencies are
+        // skewed by the other's.
+        let baseline = prepare(config, ModelSlot::Baseline, baseline, &tokenizer)
+            .map_err(|err| failed_artifacts([("baseline model", Some(err))]))?;
+        let baseline = assemble(config, baseline, &tokenizer, &vocabulary)?;
+
+        // The quantized model is optional: dynamic quantization requires a
+        // LibTorch build with a quantization backend (fbgemm/qnnpack), so a
+        // load failure only disables it and /generate falls back to baseline.
+        let quantized = quantized
+            .and_then(|replicas| prepare(config, ModelSlot::Quantized, replicas, &tokenizer));
+        let (quantized, quantized_error) = match quantized {
+            Ok(replicas) => (Some(replicas), None),
+            Err(err) => {
+                tracing::warn!(error = %err, "quantized model unavailable, serving baseline only");
+                (None, Some(err.to_string()))
+            }
+        };
+        // Outside the fallback above: a quantized model that loads but
+        // generates garbage is reported, not silently dropped.
+        let quantized = quantized
+            .map(|replicas| assemble(config, replicas, &tokenizer, &vocabulary))
+            .transpose()?;
 
-        // Load baseline model (required)
-        let baseline = Arc::new(ModelInstance::new(
-            "baseline",
-            false,
-            "float32",
-            &config.baseline_module_path,
-            config.device,
-        )?);
-
-        // Don't load quantized model - dynamic quantization requires LibTorch
-        // with quantization backend support that may not be available
         Ok(Self {
             tokenizer,
-            quantized: None,
+            vocabulary,
+            quantized,
             baseline: Some(baseline),
+            quantized_error,
+            tokenizer_metadata,
         })
     }
+
+    /// Loads only the model in `slot`, with the same checks as at startup,
+    /// for putting back a model that was unloaded.
+    #[cfg_attr(
+        not(any(feature = "tch-backend", feature = "candle-backend")),
+        allow(unused_variables)
+    )]
+    pub fn load_model(
+        config: &AppConfig,
+        slot: ModelSlot,
+        tokenizer: &Tokenizer,
+        vocabulary: &Arc<TokenVocabulary>,
+    ) -> Result<Arc<dyn Backend>, ServiceError> {
+        match config.backend {
+            #[cfg(feature = "tch-backend")]
+            BackendKind::Tch => {
+                Self::load_model_with::<ModelInstance>(config, slot, tokenizer, vocabulary)
+            }
+            #[cfg(feature = "candle-backend")]
+            BackendKind::Candle => {
+                Self::load_model_with::<CandleModel>(config, slot, tokenizer, vocabulary)
+            }
+            #[allow(unreachable_patterns)]
+            other => Err(ServiceError::Other(format!(
+                "cannot load the {} model: the {other} backend is not compiled in",
+                slot.name()
+            ))),
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "tch-backend", feature = "candle-backend")),
+        allow(dead_code)
+    )]
+    pub(crate) fn load_model_with<B: Backend + 'static>(
+        config: &AppConfig,
+        slot: ModelSlot,
+        tokenizer: &Tokenizer,
+        vocabulary: &Arc<TokenVocabulary>,
+    ) -> Result<Arc<dyn Backend>, ServiceError> {
+        let replicas = load_slot::<B>(config, slot, tokenizer)?;
+        assemble(config, replicas, tokenizer, vocabulary)
+    }
 }
 
-impl ModelInstance {
-    pub fn new(
-        name: &str,
-        quantized: bool,
-        dtype: &str,
-        module_path: &Path,
-        device: Device,
-    ) -> Result<Self, ServiceError> {
-        if !module_path.exists() {
-            return Err(ServiceError::Other(format!(
-                "model artifact missing: {}",
-                module_path.display()
-            )));
+type LoadModel = fn(
+    &AppConfig,
+    ModelSlot,
+    &Tokenizer,
+    &Arc<TokenVocabulary>,
+) -> Result<Arc<dyn Backend>, ServiceError>;
+
+/// How a [`ModelRegistry`](crate::model::ModelRegistry) reads its models:
+/// at startup, on reload and when an unloaded model is put back.
+#[derive(Clone, Copy)]
+pub(crate) struct ArtifactLoader {
+    pub(crate) artifacts: fn(&AppConfig) -> Result<ModelArtifacts, ServiceError>,
+    pub(crate) model: LoadModel,
+}
+
+impl ArtifactLoader {
+    /// With the backend `config.backend` names.
+    pub(crate) const CONFIGURED: Self = Self {
+        artifacts: ModelArtifacts::load,
+        model: ModelArtifacts::load_model,
+    };
+
+    /// Always with `B`, whatever the configuration names.
+    #[cfg(test)]
+    pub(crate) fn with<B: Backend + 'static>() -> Self {
+        Self {
+            artifacts: ModelArtifacts::load_with::<B>,
+            model: ModelArtifacts::load_model_with::<B>,
         }
-        let size_bytes = fs::metadata(module_path)?.len();
-        let mut module = tch::CModule::load_on_device(module_path, device)
-            .map_err(|e| ServiceError::Inference(e.to_string()))?;
-        module.set_eval();
+    }
+}
 
-        Ok(Self {
-            name: name.to_string(),
-            quantized,
-            dtype: dtype.to_string(),
-            size_bytes,
-            device,
-            module: Mutex::new(module),
+/// End of the prompt the tokenizer cuts when it truncates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TruncationSide {
+    /// Keep the end of the prompt.
+    Left,
+    /// Keep the start of the prompt, as tokenizers do by default.
+    #[default]
+    Right,
+}
+
+impl FromStr for TruncationSide {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "left" => Ok(Self::Left),
+            "right" => Ok(Self::Right),
+            other => Err(format!(
+                "unknown truncation side {other:?}, expected left or right"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TruncationSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Left => "left",
+            Self::Right => "right",
         })
     }
+}
+
+/// Reads the tokenizer along with the texts of its tokens and what
+/// `/metadata` reports about it.
+fn load_tokenizer(
+    config: &AppConfig,
+) -> Result<(Tokenizer, TokenVocabulary, TokenizerMetadata), ServiceError> {
+    let path = resolve_artifact(config, &config.tokenizer_path, "tokenizer.json")?;
+    let sha256 = verify_sha256(&path, config.tokenizer_sha256.as_deref())?;
+    let mut tokenizer =
+        Tokenizer::from_file(path.as_path()).map_err(|e| ServiceError::Tokenizer(e.to_string()))?;
+    configure_tokenizer(&mut tokenizer, config)?;
+    let vocabulary = TokenVocabulary::new(&tokenizer);
+    let metadata = tokenizer_metadata(&tokenizer, config, sha256)?;
+    Ok((tokenizer, vocabulary, metadata))
+}
+
+fn tokenizer_metadata(
+    tokenizer: &Tokenizer,
+    config: &AppConfig,
+    sha256: String,
+) -> Result<TokenizerMetadata, ServiceError> {
+    let special = |id: u32| {
+        tokenizer
+            .id_to_token(id)
+            .map(|token| SpecialToken { id, token })
+    };
+    // Whatever the post-processor puts ahead of a text, if anything.
+    let encode = |add_special_tokens| {
+        tokenizer
+            .encode("a", add_special_tokens)
+            .map(|encoding| encoding.get_ids().first().copied())
+            .map_err(|e| ServiceError::Tokenizer(e.to_string()))
+    };
+    let bos = match (encode(true)?, encode(false)?) {
+        (Some(first), plain) if plain != Some(first) => special(first),
+        _ => None,
+    };
+    let eos = u32::try_from(config.eos_token_id(config.model_kind(ModelSlot::Baseline)))
+        .ok()
+        .and_then(special);
+    let pad = tokenizer.get_padding().map(|padding| SpecialToken {
+        id: padding.pad_id,
+        token: padding.pad_token.clone(),
+    });
+    let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)))
+        || matches!(
+            tokenizer.get_pre_tokenizer(),
+            Some(PreTokenizerWrapper::ByteLevel(_))
+        );
+    Ok(TokenizerMetadata {
+        sha256,
+        vocab_size: tokenizer.get_vocab_size(true),
+        byte_level_bpe: byte_level && matches!(tokenizer.get_model(), ModelWrapper::BPE(_)),
+        bos,
+        eos,
+        pad,
+    })
+}
+
+/// Runs `load` and logs how long it took.
+fn timed<T>(
+    artifact: &str,
+    load: impl FnOnce() -> Result<T, ServiceError>,
+) -> Result<T, ServiceError> {
+    let start = Instant::now();
+    let result = load();
+    tracing::info!(
+        artifact,
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        loaded = result.is_ok(),
+        "artifact load finished"
+    );
+    result
+}
+
+fn joined<T>(handle: ScopedJoinHandle<'_, Result<T, ServiceError>>) -> Result<T, ServiceError> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(ServiceError::Other("loading panicked".into())))
+}
+
+/// One error naming each artifact that has one.
+fn failed_artifacts<const N: usize>(artifacts: [(&str, Option<ServiceError>); N]) -> ServiceError {
+    let failures: Vec<String> = artifacts
+        .into_iter()
+        .filter_map(|(artifact, err)| err.map(|err| format!("{artifact}: {err}")))
+        .collect();
+    ServiceError::Other(format!("failed to load {}", failures.join("; ")))
+}
+
+/// Replaces the truncation and padding baked into tokenizer.json with the
+/// configured ones, so an export that truncates to 512 tokens does not
+/// silently cut prompts.
+fn configure_tokenizer(tokenizer: &mut Tokenizer, config: &AppConfig) -> Result<(), ServiceError> {
+    let baked_truncation = tokenizer.get_truncation().map(|params| params.max_length);
+    let baked_padding = tokenizer.get_padding().is_some();
+    let truncation = config
+        .tokenizer_max_length
+        .map(|max_length| TruncationParams {
+            max_length,
+            direction: match config.tokenizer_truncation_side {
+                TruncationSide::Left => TruncationDirection::Left,
+                TruncationSide::Right => TruncationDirection::Right,
+            },
+            ..TruncationParams::default()
+        });
+    tokenizer
+        .with_truncation(truncation)
+        .map_err(|e| ServiceError::Tokenizer(e.to_string()))?;
+    if !config.tokenizer_padding {
+        tokenizer.with_padding(None);
+    }
+    tracing::info!(
+        truncation = ?config.tokenizer_max_length,
+        truncation_side = %config.tokenizer_truncation_side,
+        padding = tokenizer.get_padding().is_some(),
+        file_truncation = ?baked_truncation,
+        file_padding = baked_padding,
+        "tokenizer settings"
+    );
+    Ok(())
+}
+
+/// Loads, checks and warms up the configured copies of `slot`.
+#[cfg_attr(
+    not(any(feature = "tch-backend", feature = "candle-backend")),
+    allow(dead_code)
+)]
+fn load_slot<B: Backend + 'static>(
+    config: &AppConfig,
+    slot: ModelSlot,
+    tokenizer: &Tokenizer,
+) -> Result<Vec<Box<dyn Backend>>, ServiceError> {
+    let replicas = read_slot::<B>(config, slot)?;
+    prepare(config, slot, replicas, tokenizer)
+}
+
+/// Loads the configured copies of `slot` onto their devices.
+fn read_slot<B: Backend + 'static>(
+    config: &AppConfig,
+    slot: ModelSlot,
+) -> Result<Vec<Box<dyn Backend>>, ServiceError> {
+    if let (ModelSlot::Quantized, Some(path)) = (slot, &config.quantized_onnx_path) {
+        return Ok(vec![load_onnx(config, path)?]);
+    }
+    let module_path = match slot {
+        ModelSlot::Baseline => &config.baseline_module_path,
+        ModelSlot::Quantized => &config.quantized_module_path,
+    };
+    let path = resolve_artifact(config, module_path, module_remote_name(module_path))?;
+    let copies = config
+        .replica_configs(slot)
+        .into_iter()
+        .map(|replica_config| {
+            B::load(&replica_config, slot, &path).map(|model| Box::new(model) as Box<dyn Backend>)
+        });
+    keep_loaded(config, slot, copies)
+}
 
-    pub fn metadata(&self) -> ModelMetadata {
-        ModelMetadata {
-            name: self.name.clone(),
-            quantized: self.quantized,
-            dtype: self.dtype.clone(),
-            size_bytes: self.size_bytes,
+/// Checks each copy against the tokenizer and warms it up.
+fn prepare(
+    config: &AppConfig,
+    slot: ModelSlot,
+    replicas: Vec<Box<dyn Backend>>,
+    tokenizer: &Tokenizer,
+) -> Result<Vec<Box<dyn Backend>>, ServiceError> {
+    let checked = replicas.into_iter().map(|mut replica| {
+        check_vocab(&*replica, tokenizer)?;
+        warmup(&mut *replica, tokenizer, config.warmup_iters)?;
+        Ok(replica)
+    });
+    keep_loaded(config, slot, checked)
+}
+
+/// Self-tests each copy and serves them together.
+fn assemble(
+    config: &AppConfig,
+    mut replicas: Vec<Box<dyn Backend>>,
+    tokenizer: &Tokenizer,
+    vocabulary: &Arc<TokenVocabulary>,
+) -> Result<Arc<dyn Backend>, ServiceError> {
+    for replica in &mut replicas {
+        replica.set_vocabulary(vocabulary.clone());
+        run_self_test(config, &mut **replica, tokenizer)?;
+    }
+    Ok(Arc::new(ReplicaSet::new(replicas, config.stats_window)))
+}
+
+/// Collects the copies of `slot` that made it. A copy that fails is left
+/// out with a warning, unless `strict_devices` is set or it was the last
+/// one.
+fn keep_loaded(
+    config: &AppConfig,
+    slot: ModelSlot,
+    copies: impl Iterator<Item = Result<Box<dyn Backend>, ServiceError>>,
+) -> Result<Vec<Box<dyn Backend>>, ServiceError> {
+    let mut replicas = Vec::new();
+    let mut first_error = None;
+    for (index, replica) in copies.enumerate() {
+        match replica {
+            Ok(replica) => replicas.push(replica),
+            Err(err) if config.strict_devices => return Err(err),
+            Err(err) => {
+                tracing::warn!(
+                    model = slot.name(),
+                    replica = index,
+                    error = %err,
+                    "replica failed to load, serving from the others"
+                );
+                first_error.get_or_insert(err);
+            }
         }
     }
+    match first_error {
+        Some(err) if replicas.is_empty() => Err(err),
+        _ => Ok(replicas),
+    }
+}
 
-    pub fn generate(
-        &self,
-        tokenizer: &Tokenizer,
-        prompt: &str,
-        max_new_tokens: usize,
-        _temperature: f64,
-        _top_k: usize,
-    ) -> Result<GenerationResponse, ServiceError> {
-        if prompt.trim().is_empty() {
-            return Err(ServiceError::BadRequest("prompt must not be empty".into()));
+/// Self-tests `model` when enabled. A failure marks the service not ready,
+/// or with `self_test_strict` aborts startup.
+fn run_self_test(
+    config: &AppConfig,
+    model: &mut dyn Backend,
+    tokenizer: &Tokenizer,
+) -> Result<(), ServiceError> {
+    if !config.self_test {
+        return Ok(());
+    }
+    let report = self_test(model, tokenizer);
+    let name = model.metadata().name;
+    match &report.error {
+        None => tracing::info!(
+            model = %name,
+            latency_ms = report.latency_ms,
+            completion = ?report.completion,
+            "self-test passed"
+        ),
+        Some(err) if config.self_test_strict => {
+            return Err(ServiceError::Other(format!(
+                "{name} model failed its startup self-test: {err}"
+            )));
         }
+        Some(err) => tracing::error!(model = %name, error = %err, "self-test failed"),
+    }
+    Ok(())
+}
 
-        let encoding = tokenizer
-            .encode(prompt, true)
-            .map_err(|e| ServiceError::Tokenizer(e.to_string()))?;
-        let mut input_ids: Vec<i64> = encoding.get_ids().iter().map(|&id| id as i64).collect();
-        if input_ids.is_empty() {
-            input_ids.push(0);
+#[cfg(feature = "ort-backend")]
+fn load_onnx(config: &AppConfig, path: &Path) -> Result<Box<dyn Backend>, ServiceError> {
+    Ok(Box::new(OrtModel::load(
+        config,
+        ModelSlot::Quantized,
+        path,
+    )?))
+}
+
+#[cfg(not(feature = "ort-backend"))]
+fn load_onnx(_config: &AppConfig, _path: &Path) -> Result<Box<dyn Backend>, ServiceError> {
+    Err(ServiceError::Other(
+        "quantized_onnx_path requires the ort-backend feature".to_string(),
+    ))
+}
+
+/// Hashes `path` in chunks and, when a digest is expected, refuses a file
+/// that doesn't match it. Returns the computed hex digest either way.
+pub(crate) fn verify_sha256(path: &Path, expected: Option<&str>) -> Result<String, ServiceError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
         }
-        let prompt_token_len = input_ids.len();
-
-        let start = Instant::now();
-
-        // Autoregressive generation loop using the traced forward pass
-        no_grad(|| {
-            let module = self.module.lock();
-            
-            for _ in 0..max_new_tokens {
-                // Create input tensor from current sequence
-                let input_tensor = Tensor::from_slice(&input_ids)
-                    .reshape([1, input_ids.len() as i64])
-                    .to(self.device);
-
-                // Run forward pass - traced GPT-2 model
-                // The model may return either a tensor or tuple with (logits, past)
-                let output = module
-                    .forward_is(&[tch::IValue::Tensor(input_tensor)])
-                    .map_err(|e| ServiceError::Inference(e.to_string()))?;
-                
-                // Extract logits from output (handle both tensor and tuple cases)
-                let logits = match output {
-                    tch::IValue::Tensor(t) => t,
-                    tch::IValue::Tuple(ref tuple) if !tuple.is_empty() => {
-                        match &tuple[0] {
-                            tch::IValue::Tensor(t) => t.shallow_clone(),
-                            _ => return Err(ServiceError::Inference(
-                                "Expected tensor as first tuple element".into()
-                            )),
-                        }
-                    }
-                    _ => return Err(ServiceError::Inference(
-                        "Unexpected model output format".into()
-                    )),
-                };
-
-                // Get logits for the last token: shape [1, seq_len, vocab_size]
-                let last_logits = logits
-                    .select(1, -1)  // Select last position in sequence
-                    .squeeze();      // Remove batch dimension
-
-                // Greedy sampling: take argmax (for simplicity, ignoring temperature/top_k)
-                let next_token_id = last_logits.argmax(0, false).int64_value(&[]);
-                
-                // Append to sequence
-                input_ids.push(next_token_id);
-
-                // Stop if we hit EOS token (50256 for GPT-2)
-                if next_token_id == 50256 {
-                    break;
-                }
-            }
+    }
+    let actual: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    if let Some(expected) = expected
+        && !expected.trim().eq_ignore_ascii_case(&actual)
+    {
+        return Err(ServiceError::Other(format!(
+            "checksum mismatch for {}: expected sha256 {}, got {actual}",
+            path.display(),
+            expected.trim()
+        )));
+    }
+    Ok(actual)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::model::{
+        ModelRegistry,
+        testing::{FakeModel, GPT2_TOKENIZER, greedy},
+    };
+
+    /// A file under the temp dir unique to this test, absent unless
+    /// `contents` is given.
+    fn fixture(name: &str, contents: Option<&str>) -> PathBuf {
+        let path = env::temp_dir().join(format!("qls-loader-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        if let Some(contents) = contents {
+            fs::write(&path, contents).unwrap();
+        }
+        path
+    }
+
+    fn config(tokenizer: PathBuf, baseline: PathBuf, quantized: PathBuf) -> AppConfig {
+        AppConfig {
+            tokenizer_path: tokenizer,
+            baseline_module_path: baseline,
+            quantized_module_path: quantized,
+            warmup_iters: 1,
+            ..AppConfig::default()
+        }
+    }
+
+    #[test]
+    fn missing_quantized_module_leaves_the_baseline_serving() {
+        let baseline = fixture("baseline.ts", Some("baseline"));
+        let quantized = fixture("missing_quantized.ts", None);
+        let config = config(GPT2_TOKENIZER.into(), baseline.clone(), quantized.clone());
+
+        let artifacts = ModelArtifacts::load_with::<FakeModel>(&config).unwrap();
+        let baseline_model = artifacts.baseline.expect("baseline loads");
+        assert_eq!(baseline_model.metadata().name, "baseline");
+        assert_eq!(baseline_model.metadata().warmup_latency_ms.len(), 1);
+        assert!(artifacts.quantized.is_none());
+        let error = artifacts.quantized_error.expect("quantized error is kept");
+        assert!(error.contains(&quantized.display().to_string()), "{error}");
+        let _ = fs::remove_file(baseline);
+    }
+
+    #[test]
+    fn a_failed_self_test_marks_only_that_model_unhealthy() {
+        let baseline = fixture("self-test-baseline.ts", Some("baseline"));
+        let quantized = fixture("self-test-broken.ts", Some("eos"));
+        let mut config = config(GPT2_TOKENIZER.into(), baseline, quantized);
+
+        let registry =
+            ModelRegistry::with_loader(&config, ArtifactLoader::with::<FakeModel>()).unwrap();
+        let readiness = registry.readiness();
+        assert!(!readiness.ready);
+        assert_eq!(
+            readiness.problems,
+            [
+                "quantized model failed its startup self-test: token ids [50256] decoded to an \
+              empty completion"
+            ]
+        );
+        let report = readiness.quantized.unwrap().self_test.unwrap();
+        assert!(!report.passed);
+        assert_eq!(report.first_tokens, [""]);
+        assert!(readiness.baseline.unwrap().self_test.unwrap().passed);
+
+        config.self_test_strict = true;
+        let err = ModelRegistry::with_loader(&config, ArtifactLoader::with::<FakeModel>())
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .contains("quantized model failed its startup self-test"),
+            "{err}"
+        );
 
-            Ok::<(), ServiceError>(())
-        })?;
-
-        let elapsed = start.elapsed();
-
-        // Extract only the generated tokens
-        let generated_ids: Vec<u32> = input_ids[prompt_token_len..]
-            .iter()
-            .map(|&id| id as u32)
-            .collect();
-        let tokens_generated = generated_ids.len();
-        
-        let completion = tokenizer
-            .decode(&generated_ids, true)
-            .map_err(|e| ServiceError::Tokenizer(e.to_string()))?;
-
-        let total_tokens = prompt_token_len + tokens_generated;
-        let total_time_ms = elapsed.as_millis();
-        let tokens_per_second = if elapsed.as_secs_f64() > 0.0 {
-            total_tokens as f64 / elapsed.as_secs_f64()
-        } else {
-            total_tokens as f64
+        config.self_test = false;
+        let registry =
+            ModelRegistry::with_loader(&config, ArtifactLoader::with::<FakeModel>()).unwrap();
+        assert!(registry.readiness().ready);
+    }
+
+    #[test]
+    fn every_failed_artifact_is_named() {
+        let tokenizer = fixture("missing_tokenizer.json", None);
+        let baseline = fixture("missing_baseline.ts", None);
+        let quantized = fixture("quantized.ts", Some("quantized"));
+        let config = config(tokenizer, baseline.clone(), quantized.clone());
+
+        let Err(err) = ModelArtifacts::load_with::<FakeModel>(&config) else {
+            panic!("loading without a tokenizer or baseline succeeded");
         };
+        let message = err.to_string();
+        assert!(message.contains("tokenizer: "), "{message}");
+        assert!(message.contains("baseline model: "), "{message}");
+        assert!(
+            message.contains(&baseline.display().to_string()),
+            "{message}"
+        );
+        assert!(!message.contains("quantized model"), "{message}");
+        let _ = fs::remove_file(quantized);
+    }
 
-        Ok(GenerationResponse {
-            prompt: prompt.to_string(),
-            completion,
-            tokens_generated,
-            total_time_ms,
-            tokens_per_second,
-            model: self.metadata(),
-        })
+    #[test]
+    fn a_tokenizer_larger_than_the_model_is_refused_with_both_sizes() {
+        let mut tokenizer: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(GPT2_TOKENIZER).unwrap()).unwrap();
+        tokenizer["added_tokens"]
+            .as_array_mut()
+            .unwrap()
+            .extend((0..3).map(|i| {
+                json!({
+                    "id": 50257 + i,
+                    "content": format!("<|extra_{i}|>"),
+                    "single_word": false,
+                    "lstrip": false,
+                    "rstrip": false,
+                    "normalized": false,
+                    "special": true,
+                })
+            }));
+        let tokenizer = fixture("extended_tokenizer.json", Some(&tokenizer.to_string()));
+        let baseline = fixture("vocab_baseline.ts", Some("baseline"));
+        let quantized = fixture("vocab_quantized.ts", None);
+        let config = config(tokenizer.clone(), baseline.clone(), quantized);
+
+        let Err(err) = ModelArtifacts::load_with::<FakeModel>(&config) else {
+            panic!("a tokenizer with ids past the model's logits was accepted");
+        };
+        let message = err.to_string();
+        assert!(
+            message
+                .contains("baseline model has a vocabulary of 50257 but the tokenizer has 50260"),
+            "{message}"
+        );
+        let _ = fs::remove_file(tokenizer);
+        let _ = fs::remove_file(baseline);
+    }
+
+    #[test]
+    fn truncation_and_padding_baked_into_the_tokenizer_are_overridden() {
+        let mut tokenizer: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(GPT2_TOKENIZER).unwrap()).unwrap();
+        tokenizer["truncation"] = json!({
+            "direction": "Right",
+            "max_length": 4,
+            "strategy": "LongestFirst",
+            "stride": 0,
+        });
+        tokenizer["padding"] = json!({
+            "strategy": {"Fixed": 16},
+            "direction": "Right",
+            "pad_to_multiple_of": null,
+            "pad_id": 50256,
+            "pad_type_id": 0,
+            "pad_token": "<|endoftext|>",
+        });
+        let tokenizer = fixture("baked_tokenizer.json", Some(&tokenizer.to_string()));
+        let baseline = fixture("baked_baseline.ts", Some("baseline"));
+        let quantized = fixture("baked_quantized.ts", None);
+        let mut config = config(tokenizer.clone(), baseline.clone(), quantized);
+        let baked = Tokenizer::from_file(&tokenizer).unwrap();
+        assert_eq!(baked.get_truncation().unwrap().max_length, 4);
+        assert!(baked.get_padding().is_some());
+        // Nine tokens.
+        let prompt = "The quick brown fox jumps over the lazy dog";
+
+        let artifacts = ModelArtifacts::load_with::<FakeModel>(&config).unwrap();
+        assert!(artifacts.tokenizer.get_truncation().is_none());
+        assert!(artifacts.tokenizer.get_padding().is_none());
+        let encoding = artifacts.tokenizer.encode(prompt, false).unwrap();
+        assert_eq!(encoding.get_ids().len(), 9);
+
+        config.tokenizer_max_length = Some(3);
+        config.tokenizer_truncation_side = TruncationSide::Left;
+        let artifacts = ModelArtifacts::load_with::<FakeModel>(&config).unwrap();
+        let response = artifacts
+            .baseline
+            .unwrap()
+            .generate(&artifacts.tokenizer, prompt, &greedy(1), None)
+            .unwrap();
+        assert!(response.prompt_truncated);
+        assert_eq!(response.usage.prompt_tokens, 3);
+        assert_eq!(response.usage.truncated_prompt_tokens, 6);
+        // The fake model continues with the token after the last one kept.
+        let dog = artifacts.tokenizer.token_to_id("Ġdog").unwrap();
+        let after_dog = artifacts.tokenizer.decode(&[dog + 1], false).unwrap();
+        assert_eq!(response.completion, after_dog);
+        let _ = fs::remove_file(tokenizer);
+        let _ = fs::remove_file(baseline);
+    }
+
+    fn gpt2_metadata(config: AppConfig) -> serde_json::Value {
+        let config = AppConfig {
+            tokenizer_path: GPT2_TOKENIZER.into(),
+            ..config
+        };
+        let (_, _, metadata) = load_tokenizer(&config).unwrap();
+        serde_json::to_value(metadata).unwrap()
+    }
+
+    #[test]
+    fn tokenizer_metadata_shape() {
+        let mut metadata = gpt2_metadata(AppConfig::default());
+        let sha256 = metadata["sha256"].as_str().unwrap();
+        assert!(
+            sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()),
+            "{sha256}"
+        );
+        metadata["sha256"] = json!("<sha256>");
+        assert_eq!(
+            metadata,
+            json!({
+                "sha256": "<sha256>",
+                "vocab_size": 50257,
+                "byte_level_bpe": true,
+                "bos": null,
+                "eos": {"id": 50256, "token": "<|endoftext|>"},
+                "pad": null,
+            })
+        );
+    }
+
+    #[test]
+    fn tokenizer_metadata_eos_follows_the_configured_id() {
+        let config = |eos_token_id| AppConfig {
+            eos_token_id: Some(eos_token_id),
+            ..AppConfig::default()
+        };
+        assert_eq!(
+            gpt2_metadata(config(198))["eos"],
+            json!({"id": 198, "token": "Ċ"})
+        );
+        // Not in the vocabulary, so there is no token to name.
+        assert_eq!(gpt2_metadata(config(60_000))["eos"], json!(null));
     }
 }
@@ -1,10 +1,45 @@
+mod admission;
+mod aliases;
+mod backend;
+mod batching;
+mod cache;
+mod download;
+mod grammar;
 mod loader;
 mod registry;
+mod replicas;
+mod single_flight;
+mod stats;
+mod streaming;
+#[cfg(test)]
+pub(crate) mod testing;
 mod types;
+mod watcher;
 
+#[cfg(feature = "candle-backend")]
+mod candle_backend;
+#[cfg(feature = "ort-backend")]
+mod ort_backend;
 #[cfg(feature = "tch-backend")]
 pub mod tch_backend;
 
-pub use loader::ModelArtifacts;
-pub use registry::ModelRegistry;
-pub use types::{GenerationRequest, GenerationResponse, ModelMetadata};
+pub use admission::QueueLengths;
+pub use aliases::{check_aliases, resolve_alias};
+pub use backend::{
+    Backend, BackendKind, ModelDtype, ModelKind, ModelSlot, PromptActivations, TokenCallback,
+    generation_timeouts,
+};
+pub use cache::ResponseCache;
+pub use grammar::{JsonConstraint, TokenVocabulary};
+pub use loader::{ModelArtifacts, TruncationSide, cuda_oom_events};
+pub use registry::{ModelRegistry, ModelRoute};
+pub use stats::{ModelStatsSnapshot, ReplicaStatsSnapshot, WaitBucket};
+pub use streaming::StreamingDecoder;
+pub use types::{
+    ClientFrame, ContextStrategy, ContinuationScore, EffectiveParams, EmbedRequest, EmbedResponse,
+    FinishReason, GenerationParams, GenerationRequest, GenerationResponse, GenerationTimings,
+    HistoryTrim, ModelMetadata, ModelState, OnTimeout, Pooling, Priority, ReadinessReport,
+    ReplicaMetadata, ResponseFormat, ScoreRequest, ScoreResponse, SelfTestReport, ServerFrame,
+    SpecialToken, StreamParams, TokenCandidate, TokenDetail, TokenizerMetadata, Usage,
+};
+pub use watcher::watch_models;
@@ -0,0 +1,577 @@
+//! ONNX models (typically int8 from the quantization pipeline) on ONNX
+//! Runtime's CPU execution provider.
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ort::{
+    session::{Session, SessionInputValue, builder::GraphOptimizationLevel},
+    value::Tensor,
+};
+use parking_lot::Mutex;
+use prost::Message;
+use tokenizers::Tokenizer;
+
+use crate::{
+    config::AppConfig,
+    error::ServiceError,
+    model::{
+        GenerationParams, GenerationResponse, ModelKind, ModelMetadata, SelfTestReport,
+        TokenVocabulary,
+        backend::{
+            Backend, Decoding, ModelSlot, TokenCallback, as_ms, generate_tokens, lock_within,
+            logprob_from_logits, sample_from_logits, top_logprobs_from_logits,
+        },
+        loader::verify_sha256,
+    },
+};
+
+/// Graph inputs the generate loop knows how to fill.
+#[derive(Debug, Clone, Copy)]
+enum InputKind {
+    InputIds,
+    AttentionMask,
+    PositionIds,
+}
+
+pub struct OrtModel {
+    name: String,
+    quantized: bool,
+    dtype: String,
+    size_bytes: u64,
+    sha256: String,
+    max_context_tokens: usize,
+    load_time: Duration,
+    num_parameters: Option<u64>,
+    vocab_size: usize,
+    eos_token_id: i64,
+    warmup_latencies: Vec<Duration>,
+    self_test: Option<SelfTestReport>,
+    vocabulary: Option<Arc<TokenVocabulary>>,
+    inputs: Vec<(String, InputKind)>,
+    logits_output: String,
+    // `Session::run` takes `&mut self`.
+    session: Mutex<Session>,
+}
+
+impl Backend for OrtModel {
+    fn load(config: &AppConfig, slot: ModelSlot, path: &Path) -> Result<Self, ServiceError> {
+        if !path.exists() {
+            return Err(ServiceError::Other(format!(
+                "model artifact missing: {}",
+                path.display()
+            )));
+        }
+        let expected_sha256 = match slot {
+            ModelSlot::Baseline => config.baseline_module_sha256.as_deref(),
+            ModelSlot::Quantized => config.quantized_module_sha256.as_deref(),
+        };
+        let size_bytes = fs::metadata(path)?.len();
+        let sha256 = verify_sha256(path, expected_sha256)?;
+        let (dtype, num_parameters) = weight_types(path)?;
+
+        let load_error = |e: ort::Error| {
+            ServiceError::Other(format!(
+                "failed to load {} model {}: {e}",
+                slot.name(),
+                path.display()
+            ))
+        };
+        let load_started = Instant::now();
+        let mut builder = Session::builder()
+            .and_then(|builder| builder.with_optimization_level(GraphOptimizationLevel::Level3))
+            .map_err(load_error)?;
+        if let Some(threads) = config.ort_intra_threads {
+            builder = builder.with_intra_threads(threads).map_err(load_error)?;
+        }
+        let session = builder.commit_from_file(path).map_err(load_error)?;
+        let load_time = load_started.elapsed();
+
+        let inputs = session
+            .inputs
+            .iter()
+            .map(|input| {
+                let kind = match input.name.as_str() {
+                    "input_ids" => InputKind::InputIds,
+                    "attention_mask" => InputKind::AttentionMask,
+                    "position_ids" => InputKind::PositionIds,
+                    other => {
+                        return Err(ServiceError::Other(format!(
+                            "{} model has an unsupported input {other:?}; export it without \
+                             past key values",
+                            slot.name()
+                        )));
+                    }
+                };
+                Ok((input.name.clone(), kind))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let logits_output = session
+            .outputs
+            .iter()
+            .find(|output| output.name == "logits")
+            .or_else(|| session.outputs.first())
+            .map(|output| output.name.clone())
+            .ok_or_else(|| ServiceError::Other(format!("{} model has no outputs", slot.name())))?;
+
+        let mut model = Self {
+            name: slot.name().to_string(),
+            quantized: slot == ModelSlot::Quantized,
+            dtype,
+            size_bytes,
+            sha256,
+            max_context_tokens: config.max_context_tokens,
+            load_time,
+            num_parameters,
+            vocab_size: 0,
+            eos_token_id: config.eos_token_id(ModelKind::Causal),
+            warmup_latencies: Vec::new(),
+            self_test: None,
+            vocabulary: None,
+            inputs,
+            logits_output,
+            session: Mutex::new(session),
+        };
+        model.vocab_size = model.last_logits(&mut model.session.lock(), &[0])?.len();
+        Ok(model)
+    }
+
+    fn metadata(&self) -> ModelMetadata {
+        ModelMetadata {
+            name: self.name.clone(),
+            quantized: self.quantized,
+            dtype: self.dtype.clone(),
+            size_bytes: self.size_bytes,
+            sha256: self.sha256.clone(),
+            device: "cpu".to_string(),
+            device_fallback_reason: None,
+            max_context_tokens: self.max_context_tokens,
+            model_kind: ModelKind::Causal,
+            eos_token_id: self.eos_token_id,
+            decoder_start_token_id: None,
+            load_time_ms: as_ms(self.load_time),
+            num_parameters: self.num_parameters,
+            vocab_size: self.vocab_size,
+            hidden_states: false,
+            warmup_latency_ms: self.warmup_latencies.iter().copied().map(as_ms).collect(),
+            self_test: self.self_test.clone(),
+            replicas: Vec::new(),
+        }
+    }
+
+    fn generate(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: Option<&mut TokenCallback>,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let decoding = Decoding::new(
+            self.metadata(),
+            tokenizer,
+            self.vocabulary.as_deref(),
+            prompt,
+            params,
+        )?;
+        generate_tokens(
+            decoding,
+            tokenizer,
+            on_token,
+            |_| lock_within(&self.session, params.max_lock_wait),
+            |session, input_ids, rng, json| {
+                let mut logits = self.last_logits(session, input_ids)?;
+                if let Some(json) = json {
+                    json.mask(&mut logits)?;
+                }
+                let id = sample_from_logits(&logits, params, rng)?;
+                let logprob = params
+                    .token_details
+                    .then(|| logprob_from_logits(&logits, id));
+                let top = top_logprobs_from_logits(&logits, params.top_logprobs);
+                Ok((id, logprob, top))
+            },
+        )
+    }
+
+    fn set_warmup_latencies(&mut self, latencies: Vec<Duration>) {
+        self.warmup_latencies = latencies;
+    }
+
+    fn set_self_test(&mut self, report: SelfTestReport) {
+        self.self_test = Some(report);
+    }
+
+    fn set_vocabulary(&mut self, vocabulary: Arc<TokenVocabulary>) {
+        self.vocabulary = Some(vocabulary);
+    }
+}
+
+impl OrtModel {
+    /// Runs the whole sequence and returns the logits of its last position.
+    fn last_logits(
+        &self,
+        session: &mut Session,
+        input_ids: &[i64],
+    ) -> Result<Vec<f32>, ServiceError> {
+        let to_inference = |e: ort::Error| ServiceError::Inference(e.to_string());
+        let seq_len = input_ids.len();
+        let mut inputs: Vec<(Cow<'_, str>, SessionInputValue<'_>)> =
+            Vec::with_capacity(self.inputs.len());
+        for (name, kind) in &self.inputs {
+            let values: Vec<i64> = match kind {
+                InputKind::InputIds => input_ids.to_vec(),
+                // No padding within a single sequence, so every position attends.
+                InputKind::AttentionMask => vec![1; seq_len],
+                InputKind::PositionIds => (0..seq_len as i64).collect(),
+            };
+            let tensor = Tensor::from_array(([1, seq_len], values)).map_err(to_inference)?;
+            inputs.push((Cow::from(name.as_str()), tensor.into()));
+        }
+        let outputs = session.run(inputs).map_err(to_inference)?;
+        let (shape, logits) = outputs[self.logits_output.as_str()]
+            .try_extract_tensor::<f32>()
+            .map_err(to_inference)?;
+        // [1, seq_len, vocab]
+        let vocab = shape.last().copied().unwrap_or(0) as usize;
+        if vocab == 0 || logits.len() < vocab {
+            return Err(ServiceError::Inference(format!(
+                "unexpected logits shape {shape:?}"
+            )));
+        }
+        Ok(logits[logits.len() - vocab..].to_vec())
+    }
+}
+
+/// The parts of `onnx.proto` needed to read initializer types; every other
+/// field is skipped while decoding.
+#[derive(Clone, PartialEq, Message)]
+struct OnnxModel {
+    #[prost(message, optional, tag = "7")]
+    graph: Option<OnnxGraph>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct OnnxGraph {
+    #[prost(message, repeated, tag = "5")]
+    initializer: Vec<OnnxTensor>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct OnnxTensor {
+    #[prost(int64, repeated, tag = "1")]
+    dims: Vec<i64>,
+    #[prost(int32, tag = "2")]
+    data_type: i32,
+}
+
+/// Describes the graph's weights by element type, largest share first
+/// (e.g. `int8+float32` for a dynamically quantized model), and counts them.
+fn weight_types(path: &Path) -> Result<(String, Option<u64>), ServiceError> {
+    let model = OnnxModel::decode(fs::read(path)?.as_slice()).map_err(|e| {
+        ServiceError::Other(format!(
+            "failed to parse ONNX model {}: {e}",
+            path.display()
+        ))
+    })?;
+    let mut by_type: BTreeMap<&'static str, u64> = BTreeMap::new();
+    for tensor in model.graph.iter().flat_map(|graph| &graph.initializer) {
+        let count = tensor
+            .dims
+            .iter()
+            .map(|&dim| dim.max(0) as u64)
+            .product::<u64>();
+        *by_type.entry(onnx_type_name(tensor.data_type)).or_default() += count;
+    }
+    if by_type.is_empty() {
+        return Ok(("unknown".to_string(), None));
+    }
+    let total = by_type.values().sum();
+    let mut types: Vec<_> = by_type.into_iter().collect();
+    types.sort_by_key(|&(_, count)| Reverse(count));
+    let dtype = types
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join("+");
+    Ok((dtype, Some(total)))
+}
+
+fn onnx_type_name(data_type: i32) -> &'static str {
+    match data_type {
+        1 => "float32",
+        2 => "uint8",
+        3 => "int8",
+        4 => "uint16",
+        5 => "int16",
+        6 => "int32",
+        7 => "int64",
+        9 => "bool",
+        10 => "float16",
+        11 => "float64",
+        12 => "uint32",
+        13 => "uint64",
+        16 => "bfloat16",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use super::*;
+    use crate::model::testing::{GPT2_VOCAB_SIZE, gpt2, greedy};
+
+    // Just enough of `onnx.proto` to write a model.
+    #[derive(Clone, PartialEq, Message)]
+    struct ModelProto {
+        #[prost(int64, tag = "1")]
+        ir_version: i64,
+        #[prost(message, optional, tag = "7")]
+        graph: Option<GraphProto>,
+        #[prost(message, repeated, tag = "8")]
+        opset_import: Vec<OperatorSetIdProto>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct OperatorSetIdProto {
+        #[prost(string, tag = "1")]
+        domain: String,
+        #[prost(int64, tag = "2")]
+        version: i64,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct GraphProto {
+        #[prost(message, repeated, tag = "1")]
+        node: Vec<NodeProto>,
+        #[prost(string, tag = "2")]
+        name: String,
+        #[prost(message, repeated, tag = "5")]
+        initializer: Vec<TensorProto>,
+        #[prost(message, repeated, tag = "11")]
+        input: Vec<ValueInfoProto>,
+        #[prost(message, repeated, tag = "12")]
+        output: Vec<ValueInfoProto>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct NodeProto {
+        #[prost(string, repeated, tag = "1")]
+        input: Vec<String>,
+        #[prost(string, repeated, tag = "2")]
+        output: Vec<String>,
+        #[prost(string, tag = "4")]
+        op_type: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct TensorProto {
+        #[prost(int64, repeated, tag = "1")]
+        dims: Vec<i64>,
+        #[prost(int32, tag = "2")]
+        data_type: i32,
+        #[prost(float, repeated, tag = "4")]
+        float_data: Vec<f32>,
+        #[prost(int32, repeated, tag = "5")]
+        int32_data: Vec<i32>,
+        #[prost(int64, repeated, tag = "7")]
+        int64_data: Vec<i64>,
+        #[prost(string, tag = "8")]
+        name: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct ValueInfoProto {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(message, optional, tag = "2")]
+        r#type: Option<TypeProto>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct TypeProto {
+        #[prost(message, optional, tag = "1")]
+        tensor_type: Option<TensorTypeProto>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct TensorTypeProto {
+        #[prost(int32, tag = "1")]
+        elem_type: i32,
+        #[prost(message, optional, tag = "2")]
+        shape: Option<TensorShapeProto>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct TensorShapeProto {
+        #[prost(message, repeated, tag = "1")]
+        dim: Vec<Dimension>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Dimension {
+        #[prost(string, tag = "2")]
+        dim_param: String,
+    }
+
+    const FLOAT: i32 = 1;
+    const INT8: i32 = 3;
+    const INT64: i32 = 7;
+
+    fn node(op_type: &str, input: &[&str], output: &str) -> NodeProto {
+        NodeProto {
+            input: input.iter().map(|name| name.to_string()).collect(),
+            output: vec![output.to_string()],
+            op_type: op_type.to_string(),
+        }
+    }
+
+    fn scalar(name: &str, data_type: i32) -> TensorProto {
+        TensorProto {
+            name: name.to_string(),
+            data_type,
+            ..TensorProto::default()
+        }
+    }
+
+    fn value_info(name: &str, elem_type: i32, dims: &[&str]) -> ValueInfoProto {
+        let dim = dims
+            .iter()
+            .map(|dim| Dimension {
+                dim_param: dim.to_string(),
+            })
+            .collect();
+        ValueInfoProto {
+            name: name.to_string(),
+            r#type: Some(TypeProto {
+                tensor_type: Some(TensorTypeProto {
+                    elem_type,
+                    shape: Some(TensorShapeProto { dim }),
+                }),
+            }),
+        }
+    }
+
+    /// A graph over the GPT-2 vocabulary whose logits one-hot the token
+    /// after each input id, with its on/off values stored as int8 and
+    /// dequantized like a quantized export's weights.
+    fn write_fixture(name: &str) -> PathBuf {
+        let initializer = vec![
+            TensorProto {
+                dims: vec![2],
+                int32_data: vec![0, 100],
+                ..scalar("values_q", INT8)
+            },
+            TensorProto {
+                float_data: vec![0.01],
+                ..scalar("scale", FLOAT)
+            },
+            TensorProto {
+                int32_data: vec![0],
+                ..scalar("zero_point", INT8)
+            },
+            TensorProto {
+                int64_data: vec![GPT2_VOCAB_SIZE as i64],
+                ..scalar("depth", INT64)
+            },
+            TensorProto {
+                int64_data: vec![1],
+                ..scalar("one", INT64)
+            },
+        ];
+        let model = ModelProto {
+            ir_version: 7,
+            opset_import: vec![OperatorSetIdProto {
+                domain: String::new(),
+                version: 13,
+            }],
+            graph: Some(GraphProto {
+                name: "next_token".to_string(),
+                node: vec![
+                    node("Add", &["input_ids", "one"], "next_ids"),
+                    node(
+                        "DequantizeLinear",
+                        &["values_q", "scale", "zero_point"],
+                        "values",
+                    ),
+                    node("OneHot", &["next_ids", "depth", "values"], "logits"),
+                ],
+                initializer,
+                input: vec![value_info("input_ids", INT64, &["batch", "sequence"])],
+                output: vec![value_info("logits", FLOAT, &["batch", "sequence", "vocab"])],
+            }),
+        };
+        let dir = env::temp_dir().join(format!("qls-ort-{}-{name}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.onnx");
+        fs::write(&path, model.encode_to_vec()).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_an_onnx_model_and_decodes_greedily() {
+        let path = write_fixture("greedy");
+        let config = AppConfig {
+            ort_intra_threads: Some(1),
+            ..AppConfig::default()
+        };
+        let model = OrtModel::load(&config, ModelSlot::Quantized, &path).unwrap();
+        let metadata = model.metadata();
+        assert!(metadata.quantized);
+        assert_eq!(metadata.vocab_size, GPT2_VOCAB_SIZE);
+        // Three int8 values, two int64 and one float32.
+        assert_eq!(metadata.dtype, "int8+int64+float32");
+        assert_eq!(metadata.num_parameters, Some(6));
+
+        let tokenizer = gpt2();
+        let prompt = tokenizer.encode("Hello", false).unwrap().get_ids().to_vec();
+        let params = GenerationParams {
+            token_details: true,
+            ..greedy(3)
+        };
+        let response = model.generate(&tokenizer, "Hello", &params, None).unwrap();
+        let generated: Vec<u32> = response
+            .token_details
+            .unwrap()
+            .iter()
+            .map(|detail| detail.id)
+            .collect();
+        let last = *prompt.last().unwrap();
+        assert_eq!(generated, [last + 1, last + 2, last + 3]);
+    }
+
+    #[test]
+    fn unsupported_inputs_are_refused() {
+        let path = write_fixture("past");
+        let mut model = ModelProto::decode(fs::read(&path).unwrap().as_slice()).unwrap();
+        let graph = model.graph.as_mut().unwrap();
+        graph
+            .input
+            .push(value_info("past_key_values", FLOAT, &["batch"]));
+        fs::write(&path, model.encode_to_vec()).unwrap();
+
+        let err = OrtModel::load(&AppConfig::default(), ModelSlot::Quantized, &path)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("past_key_values"), "{err}");
+    }
+
+    #[test]
+    fn a_file_that_is_not_onnx_is_named() {
+        let dir = env::temp_dir().join(format!("qls-ort-{}-garbage", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.onnx");
+        fs::write(&path, [0xff; 16]).unwrap();
+        let err = OrtModel::load(&AppConfig::default(), ModelSlot::Quantized, &path)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("model.onnx"), "{err}");
+    }
+}
@@ -1,44 +1,613 @@
-use std::sync::Arc;
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Once, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-use tokio::task;
+use parking_lot::{Mutex, RwLock};
+use tokenizers::Tokenizer;
+use tokio::{sync::mpsc, task};
 
 use crate::{
     config::AppConfig,
+    cpu_time::ThreadCpuTimer,
     error::ServiceError,
+    memory::{self, DeviceMemory, PeakProbe},
     model::{
-        GenerationRequest, GenerationResponse, ModelMetadata, loader::ModelArtifacts,
-        loader::ModelInstance,
+        EmbedRequest, EmbedResponse, GenerationParams, GenerationRequest, GenerationResponse,
+        ModelKind, ModelMetadata, ModelSlot, ModelState, Priority, PromptActivations,
+        ReadinessReport, ResponseCache, ScoreRequest, ScoreResponse, SelfTestReport,
+        StreamingDecoder, TokenizerMetadata,
+        admission::{Admission, AdmissionQueue},
+        backend::{
+            Backend, TokenCallback, add_queue_wait, as_ms, check_context_fits, check_seq2seq_fits,
+            model_busy,
+        },
+        batching::Batcher,
+        cache::request_key,
+        check_aliases,
+        loader::{ArtifactLoader, ModelArtifacts},
+        resolve_alias,
+        single_flight::SingleFlight,
+        stats::{ModelStats, ModelStatsSnapshot},
     },
+    quota::{ANONYMOUS, UsageLedger},
+    telemetry::ContentLogging,
 };
 
+/// Least time between two warnings about serving the baseline in place of
+/// the quantized model.
+const FALLBACK_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct ModelRegistry {
-    artifacts: Arc<ModelArtifacts>,
+    /// Swapped whole by [`ModelRegistry::reload`]; requests hold on to the
+    /// snapshot they started with.
+    artifacts: RwLock<Arc<ModelArtifacts>>,
+    reloading: tokio::sync::Mutex<()>,
+    last_reload_error: RwLock<Option<String>>,
+    /// Models taken out by [`ModelRegistry::unload`], by slot name, until
+    /// loaded again. The handle only tells whether requests still hold them.
+    unloaded: Mutex<BTreeMap<&'static str, Weak<dyn Backend>>>,
+    /// When falling back to the baseline was last logged.
+    fallback_warned: Mutex<Option<Instant>>,
+    cache: Arc<ResponseCache>,
+    in_flight: SingleFlight,
+    stats: BTreeMap<String, Arc<ModelStats>>,
+    queues: BTreeMap<String, Arc<AdmissionQueue>>,
+    aliases: RwLock<BTreeMap<String, String>>,
+    default_model: Option<String>,
+    /// Set when `max_batch_size` is above 1.
+    batcher: Option<Batcher>,
+    usage: Arc<UsageLedger>,
+    loader: ArtifactLoader,
+}
+
+/// Where a request's `model` led.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelRoute {
+    pub slot: ModelSlot,
+    /// The alias that was followed, when the request did not name the
+    /// model itself.
+    pub alias: Option<String>,
 }
 
 impl ModelRegistry {
     pub fn initialize(config: &AppConfig) -> Result<Self, ServiceError> {
-        let artifacts = ModelArtifacts::load(config)?;
-        Ok(Self {
-            artifacts: Arc::new(artifacts),
-        })
+        Self::with_loader(config, ArtifactLoader::CONFIGURED)
+    }
+
+    /// Reads the models with `loader`, now and on every reload.
+    pub(crate) fn with_loader(
+        config: &AppConfig,
+        loader: ArtifactLoader,
+    ) -> Result<Self, ServiceError> {
+        install_panic_hook();
+        let artifacts = (loader.artifacts)(config)?;
+        // Kept for both slots, since a reload may bring in a quantized model
+        // that failed to load at startup.
+        let names = [ModelSlot::Baseline, ModelSlot::Quantized].map(ModelSlot::name);
+        let stats = names
+            .iter()
+            .map(|name| {
+                let stats = Arc::new(ModelStats::new(config.stats_window));
+                (name.to_string(), stats)
+            })
+            .collect();
+        let queues = names
+            .iter()
+            .map(|name| {
+                let queue = Arc::new(AdmissionQueue::new(config.batch_promote_after));
+                (name.to_string(), queue)
+            })
+            .collect();
+        let registry = Self {
+            artifacts: RwLock::new(Arc::new(artifacts)),
+            reloading: tokio::sync::Mutex::new(()),
+            last_reload_error: RwLock::new(None),
+            unloaded: Mutex::new(BTreeMap::new()),
+            fallback_warned: Mutex::new(None),
+            cache: Arc::new(ResponseCache::new(config.response_cache_size)),
+            in_flight: SingleFlight::default(),
+            stats,
+            queues,
+            aliases: RwLock::new(config.aliases.clone()),
+            default_model: config.default_model.clone(),
+            batcher: (config.max_batch_size > 1).then(|| {
+                Batcher::spawn(
+                    config.max_batch_size,
+                    Duration::from_millis(config.batch_wait_ms),
+                )
+            }),
+            usage: Arc::new(UsageLedger::from_config(config)),
+            loader,
+        };
+        registry.size_queues();
+        Ok(registry)
+    }
+
+    /// Lets as many generations onto each model at once as it has replicas.
+    fn size_queues(&self) {
+        let artifacts = self.artifacts();
+        for model in [&artifacts.baseline, &artifacts.quantized]
+            .into_iter()
+            .flatten()
+        {
+            let metadata = model.metadata();
+            if let Some(queue) = self.queues.get(&metadata.name) {
+                queue.set_capacity(metadata.replicas.len());
+            }
+        }
+    }
+
+    pub fn aliases(&self) -> BTreeMap<String, String> {
+        self.aliases.read().clone()
+    }
+
+    pub fn default_model(&self) -> Option<&str> {
+        self.default_model.as_deref()
+    }
+
+    /// Replaces the alias map, unless it has a cycle, a dangling alias or
+    /// would leave `default_model` unresolvable.
+    pub fn set_aliases(&self, aliases: BTreeMap<String, String>) -> Result<(), ServiceError> {
+        let problems = check_aliases(&aliases, self.default_model.as_deref());
+        if !problems.is_empty() {
+            return Err(ServiceError::validation("aliases", problems.join("; ")));
+        }
+        *self.aliases.write() = aliases;
+        Ok(())
+    }
+
+    /// Resolves `requested`, or `default_model` when that is `None`, to a
+    /// loaded model. `Ok(None)` means neither names one.
+    pub fn route(&self, requested: Option<&str>) -> Result<Option<ModelRoute>, ServiceError> {
+        let Some(name) = requested.or(self.default_model.as_deref()) else {
+            return Ok(None);
+        };
+        let slot = resolve_alias(&self.aliases.read(), name)
+            .map_err(|err| ServiceError::validation("model", err))?;
+        let loaded = match slot {
+            ModelSlot::Baseline => self.has_baseline(),
+            ModelSlot::Quantized => self.has_quantized(),
+        };
+        if !loaded {
+            return Err(ServiceError::validation(
+                "model",
+                format!(
+                    "'{name}' resolves to the {} model, which is not loaded",
+                    slot.name()
+                ),
+            ));
+        }
+        Ok(Some(ModelRoute {
+            slot,
+            alias: (name != slot.name()).then(|| name.to_string()),
+        }))
+    }
+
+    fn artifacts(&self) -> Arc<ModelArtifacts> {
+        self.artifacts.read().clone()
     }
 
     pub fn metadata(&self) -> (Option<ModelMetadata>, Option<ModelMetadata>) {
-        let quantized = self.artifacts.quantized.as_ref().map(|m| m.metadata());
-        let baseline = self
-            .artifacts
-            .baseline
-            .as_ref()
-            .map(|model| model.metadata());
+        let artifacts = self.artifacts();
+        let quantized = artifacts.quantized.as_ref().map(|m| m.metadata());
+        let baseline = artifacts.baseline.as_ref().map(|model| model.metadata());
         (quantized, baseline)
     }
 
+    /// Ready once the baseline model is loaded, no loaded model failed its
+    /// self-test, and the tokenizer can encode and decode a short string. A
+    /// missing quantized model is reported but does not block readiness
+    /// since `/generate` falls back to baseline, and neither does a baseline
+    /// unloaded on purpose.
+    pub fn readiness(&self) -> ReadinessReport {
+        let artifacts = self.artifacts();
+        let (quantized, baseline) = self.metadata();
+        let loaded: Vec<String> = baseline
+            .iter()
+            .chain(quantized.iter())
+            .map(|model| model.name.clone())
+            .collect();
+        let mut problems = Vec::new();
+        let mut ready = true;
+
+        if baseline.is_none() && !self.was_unloaded(ModelSlot::Baseline) {
+            ready = false;
+            problems.push("baseline model not loaded".to_string());
+        }
+        if let Some(err) = &artifacts.quantized_error {
+            problems.push(format!("quantized model failed to load: {err}"));
+        }
+        for model in baseline.iter().chain(quantized.iter()) {
+            if let Some(SelfTestReport {
+                passed: false,
+                error,
+                ..
+            }) = &model.self_test
+            {
+                ready = false;
+                problems.push(format!(
+                    "{} model failed its startup self-test: {}",
+                    model.name,
+                    error.as_deref().unwrap_or("unknown error")
+                ));
+            }
+        }
+
+        let tokenizer = &artifacts.tokenizer;
+        let round_trip = tokenizer
+            .encode("ready", false)
+            .and_then(|encoding| tokenizer.decode(encoding.get_ids(), true));
+        match round_trip {
+            Ok(text) if text.trim() == "ready" => {}
+            Ok(text) => {
+                ready = false;
+                problems.push(format!("tokenizer round-trip returned {text:?}"));
+            }
+            Err(err) => {
+                ready = false;
+                problems.push(format!("tokenizer failure: {err}"));
+            }
+        }
+
+        ReadinessReport {
+            ready,
+            baseline,
+            quantized,
+            problems,
+            queues: self
+                .queues
+                .iter()
+                .filter(|(name, _)| loaded.contains(name))
+                .map(|(name, queue)| (name.clone(), queue.lengths()))
+                .collect(),
+            gpu_memory: self.gpu_memory(),
+        }
+    }
+
+    /// Memory of each CUDA device a loaded model runs on, read now.
+    pub fn gpu_memory(&self) -> Vec<DeviceMemory> {
+        let (quantized, baseline) = self.metadata();
+        let mut devices: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for model in baseline.iter().chain(quantized.iter()) {
+            let labels: Vec<&str> = if model.replicas.is_empty() {
+                vec![&model.device]
+            } else {
+                model
+                    .replicas
+                    .iter()
+                    .map(|replica| replica.device.as_str())
+                    .collect()
+            };
+            for label in labels {
+                if let Some(device) = label
+                    .strip_prefix("cuda:")
+                    .and_then(|index| index.parse().ok())
+                {
+                    devices.entry(device).or_default().push(model.name.clone());
+                }
+            }
+        }
+        devices
+            .into_iter()
+            .filter_map(|(device, models)| memory::device(device, models))
+            .collect()
+    }
+
+    /// Tokenizes `prompt` and checks it leaves room for `max_new_tokens`
+    /// in every loaded model's context window.
+    pub fn check_prompt_fits(
+        &self,
+        prompt: &str,
+        max_new_tokens: usize,
+    ) -> Result<(), ServiceError> {
+        let prompt_tokens = self
+            .artifacts()
+            .tokenizer
+            .encode(prompt, true)
+            .map_err(|e| ServiceError::Tokenizer(e.to_string()))?
+            .len()
+            .max(1);
+        let (quantized, baseline) = self.metadata();
+        for model in quantized.iter().chain(baseline.iter()) {
+            match model.model_kind {
+                ModelKind::Causal => {
+                    check_context_fits(prompt_tokens, max_new_tokens, model.max_context_tokens)?
+                }
+                ModelKind::Seq2Seq => {
+                    check_seq2seq_fits(prompt_tokens, max_new_tokens, model.max_context_tokens)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Most prompt tokens every loaded model accepts alongside
+    /// `max_new_tokens` new ones.
+    pub fn prompt_budget(&self, max_new_tokens: usize) -> usize {
+        let (quantized, baseline) = self.metadata();
+        quantized
+            .iter()
+            .chain(baseline.iter())
+            .map(|model| match model.model_kind {
+                ModelKind::Causal => model.max_context_tokens.saturating_sub(max_new_tokens),
+                ModelKind::Seq2Seq => model.max_context_tokens,
+            })
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Per-model counters keyed by model name.
+    pub fn stats(&self) -> BTreeMap<String, ModelStatsSnapshot> {
+        let artifacts = self.artifacts();
+        [&artifacts.baseline, &artifacts.quantized]
+            .into_iter()
+            .flatten()
+            .filter_map(|model| {
+                let name = model.metadata().name;
+                let mut snapshot = self.stats.get(&name)?.snapshot();
+                snapshot.replicas = model.replica_stats();
+                Some((name, snapshot))
+            })
+            .collect()
+    }
+
+    /// Records the outcome of re-running a response from `model` on the
+    /// baseline.
+    pub fn record_shadow(&self, model: &str, matched: bool) {
+        if let Some(stats) = self.stats.get(model) {
+            stats.record_shadow(matched);
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.values().for_each(|stats| stats.reset());
+        let artifacts = self.artifacts();
+        [&artifacts.baseline, &artifacts.quantized]
+            .into_iter()
+            .flatten()
+            .for_each(|model| model.reset_replica_stats());
+    }
+
+    /// Token ids and their string forms for `text`.
+    pub fn tokenize(
+        &self,
+        text: &str,
+        add_special_tokens: bool,
+    ) -> Result<(Vec<u32>, Vec<String>), ServiceError> {
+        let encoding = self
+            .artifacts()
+            .tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(|e| ServiceError::Tokenizer(e.to_string()))?;
+        Ok((encoding.get_ids().to_vec(), encoding.get_tokens().to_vec()))
+    }
+
+    /// Tokens charged to each API key.
+    pub fn usage(&self) -> &UsageLedger {
+        &self.usage
+    }
+
+    pub fn tokenizer_sha256(&self) -> String {
+        self.artifacts().tokenizer_metadata.sha256.clone()
+    }
+
+    pub fn tokenizer_metadata(&self) -> TokenizerMetadata {
+        self.artifacts().tokenizer_metadata.as_ref().clone()
+    }
+
     pub fn has_baseline(&self) -> bool {
-        self.artifacts.baseline.is_some()
+        self.artifacts().baseline.is_some()
     }
 
     pub fn has_quantized(&self) -> bool {
-        self.artifacts.quantized.is_some()
+        self.artifacts().quantized.is_some()
+    }
+
+    /// The model for a request that names none: the quantized one, else the
+    /// baseline when `fallback_to_baseline` allows, flagged as a fallback.
+    pub fn default_slot(&self, config: &AppConfig) -> Result<(ModelSlot, bool), ServiceError> {
+        if self.has_quantized() {
+            return Ok((ModelSlot::Quantized, false));
+        }
+        if !config.fallback_to_baseline {
+            return Err(ServiceError::ModelUnavailable(
+                "quantized model unavailable and fallback_to_baseline is off".into(),
+            ));
+        }
+        if !self.has_baseline() {
+            return Err(ServiceError::model_loading());
+        }
+        let mut warned = self.fallback_warned.lock();
+        if warned.is_none_or(|at| at.elapsed() >= FALLBACK_WARNING_INTERVAL) {
+            *warned = Some(Instant::now());
+            tracing::warn!("quantized model unavailable, serving the baseline instead");
+        }
+        Ok((ModelSlot::Baseline, true))
+    }
+
+    /// Whether each model is serving, keyed by model name.
+    pub fn model_states(&self) -> BTreeMap<String, ModelState> {
+        let artifacts = self.artifacts();
+        let unloaded = self.unloaded.lock();
+        [
+            (ModelSlot::Baseline, &artifacts.baseline),
+            (ModelSlot::Quantized, &artifacts.quantized),
+        ]
+        .into_iter()
+        .map(|(slot, model)| {
+            let state = match (model, unloaded.get(slot.name())) {
+                (Some(_), _) => ModelState::Loaded,
+                (None, Some(model)) if model.strong_count() > 0 => ModelState::Draining,
+                (None, _) => ModelState::Unloaded,
+            };
+            (slot.name().to_string(), state)
+        })
+        .collect()
+    }
+
+    fn was_unloaded(&self, slot: ModelSlot) -> bool {
+        self.unloaded.lock().contains_key(slot.name())
+    }
+
+    /// The error for a request needing the model in `slot`, which is gone:
+    /// a client error when it was unloaded on purpose, otherwise the model
+    /// is taken to still be loading.
+    fn missing(&self, slot: ModelSlot) -> ServiceError {
+        if self.was_unloaded(slot) {
+            ServiceError::validation("model", format!("the {} model is not loaded", slot.name()))
+        } else {
+            ServiceError::model_loading()
+        }
+    }
+
+    /// Takes the model in `slot` out of service. Requests already running
+    /// finish on it and its memory is freed once the last one does; the
+    /// returned state says whether that has happened yet. The last loaded
+    /// model cannot be unloaded.
+    pub async fn unload(&self, slot: ModelSlot) -> Result<ModelState, ServiceError> {
+        let _reloading = self.reloading.lock().await;
+        let artifacts = self.artifacts();
+        let (model, other) = match slot {
+            ModelSlot::Baseline => (&artifacts.baseline, &artifacts.quantized),
+            ModelSlot::Quantized => (&artifacts.quantized, &artifacts.baseline),
+        };
+        let Some(model) = model else {
+            return Err(ServiceError::validation(
+                "model",
+                format!("the {} model is not loaded", slot.name()),
+            ));
+        };
+        if other.is_none() {
+            return Err(ServiceError::validation(
+                "model",
+                format!("the {} model is the only one loaded", slot.name()),
+            ));
+        }
+        let handle = Arc::downgrade(model);
+        let (baseline, quantized) = match slot {
+            ModelSlot::Baseline => (None, artifacts.quantized.clone()),
+            ModelSlot::Quantized => (artifacts.baseline.clone(), None),
+        };
+        *self.artifacts.write() = Arc::new(ModelArtifacts {
+            tokenizer: artifacts.tokenizer.clone(),
+            vocabulary: artifacts.vocabulary.clone(),
+            quantized,
+            baseline,
+            quantized_error: artifacts.quantized_error.clone(),
+            tokenizer_metadata: artifacts.tokenizer_metadata.clone(),
+        });
+        drop(artifacts);
+        self.unloaded.lock().insert(slot.name(), handle.clone());
+        tracing::info!(model = slot.name(), "unloaded model");
+        Ok(if handle.strong_count() > 0 {
+            ModelState::Draining
+        } else {
+            ModelState::Unloaded
+        })
+    }
+
+    /// Loads the model in `slot` again from its configured path, with the
+    /// same checks as at startup.
+    pub async fn load(
+        &self,
+        slot: ModelSlot,
+        config: Arc<AppConfig>,
+    ) -> Result<ModelMetadata, ServiceError> {
+        let _reloading = self.reloading.lock().await;
+        let artifacts = self.artifacts();
+        let loaded = match slot {
+            ModelSlot::Baseline => &artifacts.baseline,
+            ModelSlot::Quantized => &artifacts.quantized,
+        };
+        if loaded.is_some() {
+            return Err(ServiceError::validation(
+                "model",
+                format!("the {} model is already loaded", slot.name()),
+            ));
+        }
+        let tokenizer = artifacts.tokenizer.clone();
+        let vocabulary = artifacts.vocabulary.clone();
+        let load_model = self.loader.model;
+        let model =
+            task::spawn_blocking(move || load_model(&config, slot, &tokenizer, &vocabulary))
+                .await
+                .map_err(|err| ServiceError::Other(format!("load task failed: {err}")))??;
+        let metadata = model.metadata();
+        let (baseline, quantized, quantized_error) = match slot {
+            ModelSlot::Baseline => (
+                Some(model),
+                artifacts.quantized.clone(),
+                artifacts.quantized_error.clone(),
+            ),
+            ModelSlot::Quantized => (artifacts.baseline.clone(), Some(model), None),
+        };
+        *self.artifacts.write() = Arc::new(ModelArtifacts {
+            tokenizer: artifacts.tokenizer.clone(),
+            vocabulary: artifacts.vocabulary.clone(),
+            quantized,
+            baseline,
+            quantized_error,
+            tokenizer_metadata: artifacts.tokenizer_metadata.clone(),
+        });
+        self.unloaded.lock().remove(slot.name());
+        self.size_queues();
+        tracing::info!(model = slot.name(), "loaded model");
+        Ok(metadata)
+    }
+
+    /// Why the last reload failed; cleared by a successful one.
+    pub fn last_reload_error(&self) -> Option<String> {
+        self.last_reload_error.read().clone()
+    }
+
+    /// Loads the configured artifacts afresh, with the same checks as at
+    /// startup, and swaps them in, unloaded models included. Requests
+    /// already running finish on the models they started with. On failure, including a quantized model
+    /// that loaded before and no longer does, the current models keep
+    /// serving.
+    pub async fn reload(&self, config: Arc<AppConfig>) -> Result<(), ServiceError> {
+        let _reloading = self.reloading.lock().await;
+        let load = self.loader.artifacts;
+        let result = task::spawn_blocking(move || load(&config))
+            .await
+            .map_err(|err| ServiceError::Other(format!("reload task failed: {err}")))
+            .and_then(|loaded| {
+                let artifacts = loaded?;
+                if artifacts.quantized.is_none() && self.has_quantized() {
+                    return Err(ServiceError::Other(format!(
+                        "quantized model failed to load: {}",
+                        artifacts
+                            .quantized_error
+                            .as_deref()
+                            .unwrap_or("unknown error")
+                    )));
+                }
+                Ok(artifacts)
+            });
+        match result {
+            Ok(artifacts) => {
+                *self.artifacts.write() = Arc::new(artifacts);
+                self.unloaded.lock().clear();
+                self.size_queues();
+                *self.last_reload_error.write() = None;
+                tracing::info!("reloaded models");
+                Ok(())
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "model reload failed, keeping current models");
+                *self.last_reload_error.write() = Some(err.to_string());
+                Err(err)
+            }
+        }
     }
 
     pub async fn generate_quantized(
@@ -46,14 +615,14 @@ impl ModelRegistry {
         request: GenerationRequest,
         config: &AppConfig,
     ) -> Result<GenerationResponse, ServiceError> {
-        let model = self
-            .artifacts
+        let artifacts = self.artifacts();
+        let model = artifacts
             .quantized
             .clone()
-            .ok_or_else(|| {
-                ServiceError::Other("Quantized model not available".to_string())
-            })?;
-        self.spawn_inference(model, request, config).await
+            .ok_or_else(|| self.missing(ModelSlot::Quantized))?;
+        let tokenizer = artifacts.tokenizer.clone();
+        self.spawn_inference(model, tokenizer, request, config, None)
+            .await
     }
 
     pub async fn generate_baseline(
@@ -61,30 +630,833 @@ impl ModelRegistry {
         request: GenerationRequest,
         config: &AppConfig,
     ) -> Result<GenerationResponse, ServiceError> {
-        let model = self
-            .artifacts
+        let artifacts = self.artifacts();
+        let model = artifacts
+            .baseline
+            .clone()
+            .ok_or_else(|| self.missing(ModelSlot::Baseline))?;
+        let tokenizer = artifacts.tokenizer.clone();
+        self.spawn_inference(model, tokenizer, request, config, None)
+            .await
+    }
+
+    /// Runs `request` on the baseline outside the cache and statistics, for
+    /// comparing against a response already served by the quantized model.
+    pub async fn generate_shadow(
+        &self,
+        request: GenerationRequest,
+        config: &AppConfig,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let artifacts = self.artifacts();
+        let model = artifacts
             .baseline
             .clone()
-            .ok_or_else(|| ServiceError::ModelLoading)?;
-        self.spawn_inference(model, request, config).await
+            .ok_or_else(|| self.missing(ModelSlot::Baseline))?;
+        // Shadow runs are background work and must never delay real traffic.
+        let params = GenerationParams {
+            priority: Priority::Batch,
+            ..GenerationParams::resolve(&request, config)
+        };
+        let tokenizer = artifacts.tokenizer.clone();
+        let queue = self.queues.get(&model.metadata().name).cloned();
+        run_inference(model, tokenizer, request.prompt, params, None, queue, None).await
     }
 
+    /// Runs `prompt` on the model in `slot` with `params` exactly as given,
+    /// outside the cache and statistics, for debugging tools.
+    pub async fn generate_with_params(
+        &self,
+        slot: ModelSlot,
+        prompt: String,
+        params: GenerationParams,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let artifacts = self.artifacts();
+        let model = match slot {
+            ModelSlot::Baseline => artifacts.baseline.clone(),
+            ModelSlot::Quantized => artifacts.quantized.clone(),
+        }
+        .ok_or_else(|| self.missing(slot))?;
+        let tokenizer = artifacts.tokenizer.clone();
+        let queue = self.queues.get(&model.metadata().name).cloned();
+        run_inference(model, tokenizer, prompt, params, None, queue, None).await
+    }
+
+    /// Generates with the model the request names, or else the one
+    /// `/generate` would use, sending each token's text to `tokens` as it
+    /// is produced. Setting `cancel` stops generation
+    /// after the current step and returns what was produced so far.
+    pub async fn generate_stream(
+        &self,
+        request: GenerationRequest,
+        config: &AppConfig,
+        tokens: mpsc::UnboundedSender<String>,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let route = self.route(request.model.as_deref())?;
+        let (slot, fallback) = match &route {
+            Some(route) => (route.slot, false),
+            None => self.default_slot(config)?,
+        };
+        let artifacts = self.artifacts();
+        let model = match slot {
+            ModelSlot::Baseline => artifacts.baseline.clone(),
+            ModelSlot::Quantized => artifacts.quantized.clone(),
+        }
+        .ok_or_else(|| self.missing(slot))?;
+        let decoder = Arc::new(Mutex::new(StreamingDecoder::new(
+            artifacts.tokenizer.clone(),
+            request.skip_special_tokens.unwrap_or(true),
+        )));
+        let stream_decoder = decoder.clone();
+        let stream_tokens = tokens.clone();
+        let on_token: TokenCallback = Box::new(move |id| {
+            if let Some(text) = stream_decoder.lock().push(id)
+                && stream_tokens.send(text).is_err()
+            {
+                return false;
+            }
+            !cancel.load(Ordering::Relaxed)
+        });
+        let result = self
+            .spawn_inference(
+                model,
+                artifacts.tokenizer.clone(),
+                request,
+                config,
+                Some(on_token),
+            )
+            .await;
+        if let Some(text) = decoder.lock().finish() {
+            let _ = tokens.send(text);
+        }
+        result.map(|mut response| {
+            response.served_via_alias = route.and_then(|route| route.alias);
+            response.fallback = fallback;
+            response
+        })
+    }
+
+    /// Scores continuations with the model `/generate` would use, queueing
+    /// for it like a generation.
+    pub async fn score(
+        &self,
+        request: ScoreRequest,
+        config: &AppConfig,
+    ) -> Result<ScoreResponse, ServiceError> {
+        let (model, tokenizer) = self.unnamed_model(config)?;
+        let _admission = self.admit(&model, config).await?;
+        spawn_guarded("scoring", move || {
+            model.score(&tokenizer, &request.prompt, &request.continuations)
+        })
+        .await
+    }
+
+    /// Embeds texts with the model `/generate` would use, queueing for it
+    /// like a generation.
+    pub async fn embed(
+        &self,
+        request: EmbedRequest,
+        config: &AppConfig,
+    ) -> Result<EmbedResponse, ServiceError> {
+        if request.texts.len() > config.embed_max_batch {
+            return Err(ServiceError::validation(
+                "texts",
+                format!(
+                    "has {} entries, more than the limit of {}",
+                    request.texts.len(),
+                    config.embed_max_batch
+                ),
+            ));
+        }
+        let (model, tokenizer) = self.unnamed_model(config)?;
+        let _admission = self.admit(&model, config).await?;
+        spawn_guarded("embedding", move || {
+            model.embed(&tokenizer, &request.texts, request.pooling)
+        })
+        .await
+    }
+
+    /// The model `/generate` serves a request without `model` from:
+    /// `default_model`, or else the quantized model, falling back to the
+    /// baseline only when allowed.
+    fn unnamed_model(
+        &self,
+        config: &AppConfig,
+    ) -> Result<(Arc<dyn Backend>, Arc<Tokenizer>), ServiceError> {
+        let slot = match self.route(None)? {
+            Some(route) => route.slot,
+            None => self.default_slot(config)?.0,
+        };
+        let artifacts = self.artifacts();
+        let model = match slot {
+            ModelSlot::Baseline => artifacts.baseline.clone(),
+            ModelSlot::Quantized => artifacts.quantized.clone(),
+        }
+        .ok_or_else(|| self.missing(slot))?;
+        Ok((model, artifacts.tokenizer.clone()))
+    }
+
+    /// A turn on `model` as an interactive request, within
+    /// `max_lock_wait_ms`.
+    async fn admit(
+        &self,
+        model: &Arc<dyn Backend>,
+        config: &AppConfig,
+    ) -> Result<Option<Admission>, ServiceError> {
+        let name = model.metadata().name;
+        admit(
+            self.queues.get(&name),
+            Priority::Interactive,
+            config.max_lock_wait(),
+            self.stats.get(&name),
+        )
+        .await
+    }
+
+    /// Logits and hidden states of the model in `slot` over `prompt`.
+    pub async fn prompt_activations(
+        &self,
+        slot: ModelSlot,
+        prompt: String,
+    ) -> Result<PromptActivations, ServiceError> {
+        let artifacts = self.artifacts();
+        let model = match slot {
+            ModelSlot::Baseline => artifacts.baseline.clone(),
+            ModelSlot::Quantized => artifacts.quantized.clone(),
+        }
+        .ok_or_else(|| self.missing(slot))?;
+        let tokenizer = artifacts.tokenizer.clone();
+        spawn_guarded("activation capture", move || {
+            model.prompt_activations(&tokenizer, &prompt)
+        })
+        .await
+    }
+
+    /// Runs `request` and charges its tokens to the request's API key, and
+    /// with `log_prompts` on logs what went in and came out at debug level,
+    /// under the caller's request span.
+    ///
+    /// A key with a quota must have room for the whole prompt plus
+    /// `max_new_tokens` before the generation starts; what it actually used
+    /// is charged once it ends, and nothing when it fails.
     async fn spawn_inference(
         &self,
-        model: Arc<ModelInstance>,
+        model: Arc<dyn Backend>,
+        tokenizer: Arc<Tokenizer>,
         request: GenerationRequest,
         config: &AppConfig,
+        on_token: Option<TokenCallback>,
     ) -> Result<GenerationResponse, ServiceError> {
-        let tokenizer = self.artifacts.tokenizer.clone();
+        let key = request.api_key.as_deref().unwrap_or(ANONYMOUS);
+        let needed = match self.usage.quota(key) {
+            Some(_) => {
+                let params = GenerationParams::resolve(&request, config);
+                let prompt_tokens = tokenizer
+                    .encode(request.prompt.as_str(), params.add_special_tokens)
+                    .map_err(|e| ServiceError::Tokenizer(e.to_string()))?
+                    .len()
+                    .min(config.max_context_tokens);
+                (prompt_tokens + params.max_new_tokens) as u64
+            }
+            None => 0,
+        };
+        let reservation = self.usage.reserve(key, needed)?;
+        let result = self
+            .dispatch_inference(model, tokenizer, request, config, on_token)
+            .await;
+        if let Ok(response) = &result {
+            reservation.settle(
+                response.usage.prompt_tokens as u64,
+                response.usage.completion_tokens as u64,
+            );
+        }
+        let logging = ContentLogging::from_config(config);
+        if logging.enabled()
+            && let Ok(response) = &result
+        {
+            tracing::debug!(
+                model = %response.model.name,
+                cached = response.cached,
+                prompt = %logging.text(&response.prompt),
+                completion = %logging.text(&response.completion),
+                params = ?response.params,
+                "generation content"
+            );
+        }
+        result
+    }
+
+    async fn dispatch_inference(
+        &self,
+        model: Arc<dyn Backend>,
+        tokenizer: Arc<Tokenizer>,
+        request: GenerationRequest,
+        config: &AppConfig,
+        on_token: Option<TokenCallback>,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let params = GenerationParams::resolve(&request, config);
         let prompt = request.prompt;
-        let max_new_tokens = request.max_new_tokens.unwrap_or(config.max_new_tokens);
-        let temperature = request.temperature.unwrap_or(config.temperature);
-        let top_k = request.top_k.unwrap_or(config.top_k);
+        let metadata = model.metadata();
+        let model_name = metadata.name;
+        let stats = self.stats.get(&model_name).cloned();
+        let queue = self.queues.get(&model_name).cloned();
+
+        // Streaming callers need tokens as they are produced, so they always
+        // run on their own and skip the cache.
+        if on_token.is_some() {
+            return run_inference(model, tokenizer, prompt, params, on_token, queue, stats).await;
+        }
+
+        let key = request_key(&model_name, &metadata.sha256, &prompt, &params);
+        if let Some(hit) = self.cache.get(key) {
+            return Ok(hit);
+        }
+        // Sampled requests must each get a fresh draw, so only deterministic
+        // ones are coalesced.
+        let batcher = self.batcher.clone();
+        if !params.is_deterministic() {
+            return run_unstreamed(model, tokenizer, prompt, params, queue, stats, batcher).await;
+        }
+
+        let cache = self.cache.clone();
+        self.in_flight
+            .run(key, async move {
+                let response =
+                    run_unstreamed(model, tokenizer, prompt, params, queue, stats, batcher).await?;
+                cache.insert(key, &params, &response);
+                Ok(response)
+            })
+            .await
+    }
+}
+
+async fn run_inference(
+    model: Arc<dyn Backend>,
+    tokenizer: Arc<Tokenizer>,
+    prompt: String,
+    params: GenerationParams,
+    mut on_token: Option<TokenCallback>,
+    queue: Option<Arc<AdmissionQueue>>,
+    stats: Option<Arc<ModelStats>>,
+) -> Result<GenerationResponse, ServiceError> {
+    let model_name = model.metadata().name;
+    let waiting = Instant::now();
+    // Held until the blocking task finishes so the next waiter is only let
+    // in once the model is free.
+    let _admission = admit(
+        queue.as_ref(),
+        params.priority,
+        params.max_lock_wait,
+        stats.as_ref(),
+    )
+    .await?;
+    let admission_wait = waiting.elapsed();
+    // The module lock gets what is left of the budget.
+    let params = GenerationParams {
+        max_lock_wait: params
+            .max_lock_wait
+            .map(|max_wait| max_wait.saturating_sub(admission_wait)),
+        ..params
+    };
+    let span = tracing::info_span!(
+        "inference",
+        model = %model_name,
+        prompt_tokens = tracing::field::Empty,
+        generated_tokens = tracing::field::Empty,
+    );
+    let result = task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let probe = params.measure_memory.then(PeakProbe::start).flatten();
+        let cpu_timer = ThreadCpuTimer::start();
+        let mut result = panic::catch_unwind(AssertUnwindSafe(|| {
+            model.generate(&tokenizer, &prompt, &params, on_token.as_mut())
+        }));
+        if let Ok(Ok(response)) = &mut result {
+            response.timings.cpu_time_ms = cpu_timer.and_then(|timer| timer.elapsed()).map(as_ms);
+        }
+        if let (Some(probe), Ok(Ok(response))) = (probe, &mut result) {
+            response.peak_rss_delta_bytes = probe.peak_delta_bytes();
+        }
+        if let Ok(Ok(response)) = &result {
+            span.record("prompt_tokens", response.usage.prompt_tokens);
+            span.record("generated_tokens", response.tokens_generated);
+        }
+        result.unwrap_or_else(|payload| {
+            let message = panic_message(payload.as_ref());
+            let backtrace = PANIC_BACKTRACE
+                .with(|slot| slot.borrow_mut().take())
+                .unwrap_or_else(|| "<backtrace unavailable>".to_string());
+            tracing::error!(
+                model = %model_name,
+                prompt_chars = prompt.chars().count(),
+                max_new_tokens = params.max_new_tokens,
+                temperature = params.temperature,
+                top_k = params.top_k,
+                panic = %message,
+                %backtrace,
+                "inference panicked"
+            );
+            Err(ServiceError::Inference(format!(
+                "inference panicked: {message}"
+            )))
+        })
+    })
+    .await
+    .map_err(join_error)
+    .and_then(|result| result)
+    .map(|mut response| {
+        // The backend's clock started after admission; the wait counts
+        // toward the total like any other queueing.
+        add_queue_wait(&mut response, admission_wait);
+        response
+    });
+    if let Some(stats) = stats {
+        stats.record(&result);
+    }
+    result
+}
 
-        task::spawn_blocking(move || {
-            model.generate(&tokenizer, &prompt, max_new_tokens, temperature, top_k)
+/// Waits for a turn on the model `queue` belongs to, giving up with
+/// [`model_busy`] after `max_wait`.
+async fn admit(
+    queue: Option<&Arc<AdmissionQueue>>,
+    priority: Priority,
+    max_wait: Option<Duration>,
+    stats: Option<&Arc<ModelStats>>,
+) -> Result<Option<Admission>, ServiceError> {
+    let Some(queue) = queue else {
+        return Ok(None);
+    };
+    let Some(max_wait) = max_wait else {
+        return Ok(Some(queue.admit(priority).await));
+    };
+    match tokio::time::timeout(max_wait, queue.admit(priority)).await {
+        Ok(admission) => Ok(Some(admission)),
+        Err(_) => {
+            if let Some(stats) = stats {
+                stats.record_wait_rejection();
+            }
+            Err(model_busy())
+        }
+    }
+}
+
+/// Runs `work` on the blocking pool, turning a panic into an inference
+/// error that says what panicked.
+async fn spawn_guarded<T: Send + 'static>(
+    what: &'static str,
+    work: impl FnOnce() -> Result<T, ServiceError> + Send + 'static,
+) -> Result<T, ServiceError> {
+    task::spawn_blocking(move || {
+        panic::catch_unwind(AssertUnwindSafe(work)).unwrap_or_else(|payload| {
+            let message = panic_message(payload.as_ref());
+            tracing::error!(task = what, panic = %message, "blocking task panicked");
+            Err(ServiceError::Inference(format!(
+                "{what} panicked: {message}"
+            )))
         })
+    })
+    .await
+    .map_err(join_error)?
+}
+
+/// Runs a generation nobody streams, through the batcher when the model
+/// takes part in batching.
+async fn run_unstreamed(
+    model: Arc<dyn Backend>,
+    tokenizer: Arc<Tokenizer>,
+    prompt: String,
+    params: GenerationParams,
+    queue: Option<Arc<AdmissionQueue>>,
+    stats: Option<Arc<ModelStats>>,
+    batcher: Option<Batcher>,
+) -> Result<GenerationResponse, ServiceError> {
+    match batcher {
+        Some(batcher) if model.supports_batching() => {
+            batcher
+                .generate(model, tokenizer, prompt, params, queue, stats)
+                .await
+        }
+        _ => run_inference(model, tokenizer, prompt, params, None, queue, stats).await,
+    }
+}
+
+pub(super) fn join_error(err: task::JoinError) -> ServiceError {
+    if err.is_cancelled() {
+        ServiceError::Inference("inference task was cancelled".into())
+    } else {
+        ServiceError::Inference(format!("inference task failed: {err}"))
+    }
+}
+
+thread_local! {
+    static PANIC_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Chains onto the existing panic hook so the backtrace of a panic inside a
+/// blocking inference task is still available after `catch_unwind` returns.
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::force_capture().to_string();
+            PANIC_BACKTRACE.with(|slot| *slot.borrow_mut() = Some(backtrace));
+            previous(info);
+        }));
+    });
+}
+
+pub(super) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+
+    use super::*;
+    use crate::model::{
+        Pooling,
+        testing::{FakeModel, fake_config, fake_registry, gpt2, greedy},
+    };
+
+    fn scoring(prompt: &str, continuations: &[&str]) -> ScoreRequest {
+        ScoreRequest {
+            prompt: prompt.to_string(),
+            continuations: continuations.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Takes the only turn on `model` until dropped.
+    async fn occupy(registry: &ModelRegistry, model: &str) -> Admission {
+        registry.queues[model].admit(Priority::Interactive).await
+    }
+
+    #[tokio::test]
+    async fn score_uses_the_quantized_model() {
+        let config = fake_config("registry-score", true);
+        let registry = fake_registry(&config);
+        // The fake model always predicts the token after the last one:
+        // "B" follows "A".
+        let response = registry
+            .score(scoring("A", &["B", "C"]), &config)
+            .await
+            .unwrap();
+        assert_eq!(response.model.name, "quantized");
+        assert_eq!(response.prompt_tokens, 1);
+        let [likely, unlikely] = &response.scores[..] else {
+            panic!("{:?}", response.scores);
+        };
+        assert_eq!((likely.tokens, unlikely.tokens), (1, 1));
+        assert!(likely.sum_logprob > unlikely.sum_logprob, "{response:?}");
+        assert_eq!(likely.mean_logprob, likely.sum_logprob);
+    }
+
+    #[tokio::test]
+    async fn score_falls_back_only_when_allowed() {
+        let mut config = fake_config("registry-score-fallback", false);
+        let registry = fake_registry(&config);
+        let response = registry.score(scoring("A", &["B"]), &config).await.unwrap();
+        assert_eq!(response.model.name, "baseline");
+
+        config.fallback_to_baseline = false;
+        let err = registry
+            .score(scoring("A", &["B"]), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "model_unavailable", "{err}");
+    }
+
+    #[tokio::test]
+    async fn score_waits_its_turn_within_max_lock_wait() {
+        let mut config = fake_config("registry-score-busy", true);
+        config.max_lock_wait_ms = 50;
+        let registry = fake_registry(&config);
+
+        let turn = occupy(&registry, "quantized").await;
+        let started = Instant::now();
+        let err = registry
+            .score(scoring("A", &["B"]), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "overloaded", "{err}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(registry.stats()["quantized"].queue_wait_rejections, 1);
+
+        drop(turn);
+        assert!(registry.score(scoring("A", &["B"]), &config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn an_unloaded_model_drains_until_its_last_request_lets_go() {
+        let config = fake_config("registry-unload", true);
+        let registry = fake_registry(&config);
+        let running = registry.artifacts().baseline.clone().unwrap();
+        assert_eq!(
+            registry.unload(ModelSlot::Baseline).await.unwrap(),
+            ModelState::Draining
+        );
+        assert_eq!(registry.model_states()["baseline"], ModelState::Draining);
+        assert!(!registry.has_baseline());
+
+        drop(running);
+        assert_eq!(registry.model_states()["baseline"], ModelState::Unloaded);
+    }
+
+    #[tokio::test]
+    async fn streams_without_a_key_are_charged_to_anonymous() {
+        let mut config = fake_config("registry-quota-stream", true);
+        config.token_quota = Some(5);
+        let registry = fake_registry(&config);
+        // A stream stops once nobody is listening.
+        let (tokens, _listening) = mpsc::unbounded_channel();
+        let stream = || {
+            let request = GenerationRequest {
+                prompt: "Hello".to_string(),
+                max_new_tokens: Some(3),
+                ..GenerationRequest::default()
+            };
+            let cancel = Arc::new(AtomicBool::new(false));
+            registry.generate_stream(request, &config, tokens.clone(), cancel)
+        };
+
+        stream().await.unwrap();
+        let usage = registry.usage().snapshot();
+        let [anonymous] = &usage.keys[..] else {
+            panic!("{usage:?}");
+        };
+        assert_eq!(anonymous.key, ANONYMOUS);
+        assert_eq!(anonymous.total_tokens, 4);
+        assert_eq!(anonymous.remaining, Some(1));
+
+        let err = stream().await.unwrap_err();
+        assert_eq!(err.code(), "quota_exceeded", "{err}");
+    }
+
+    #[tokio::test]
+    async fn generation_fails_fast_while_the_model_is_held() {
+        let mut config = fake_config("registry-generate-busy", true);
+        config.max_lock_wait_ms = 50;
+        let registry = fake_registry(&config);
+        let request = || GenerationRequest {
+            prompt: "Hello".to_string(),
+            max_new_tokens: Some(2),
+            ..GenerationRequest::default()
+        };
+
+        let turn = occupy(&registry, "quantized").await;
+        let started = Instant::now();
+        let err = registry
+            .generate_quantized(request(), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "overloaded", "{err}");
+        let waited = started.elapsed();
+        assert!(
+            waited >= Duration::from_millis(50) && waited < Duration::from_secs(1),
+            "{waited:?}"
+        );
+        assert_eq!(registry.stats()["quantized"].queue_wait_rejections, 1);
+
+        // Let go within the budget: the waiter is served and the wait shows.
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(turn);
+        });
+        let response = registry
+            .generate_quantized(request(), &config)
+            .await
+            .unwrap();
+        release.await.unwrap();
+        assert!(
+            response.timings.queue_wait_ms >= 15.0,
+            "{:?}",
+            response.timings
+        );
+        let stats = &registry.stats()["quantized"];
+        let waits: u64 = stats
+            .queue_wait_histogram
+            .iter()
+            .filter(|bucket| bucket.le_ms.is_none_or(|le_ms| le_ms > 5.0))
+            .map(|bucket| bucket.count)
+            .sum();
+        assert_eq!(waits, 1, "{:?}", stats.queue_wait_histogram);
+        assert_eq!(stats.queue_wait_rejections, 1);
+    }
+
+    fn embedding(texts: &[&str], pooling: Pooling) -> EmbedRequest {
+        EmbedRequest {
+            texts: texts.iter().map(|text| text.to_string()).collect(),
+            pooling,
+        }
+    }
+
+    #[tokio::test]
+    async fn embed_pools_per_text_on_the_quantized_model() {
+        let config = fake_config("registry-embed", true);
+        let registry = fake_registry(&config);
+        // "QZ" is tokens 48 and 57; each hidden state is `[id, 1]`.
+        let mean = registry
+            .embed(embedding(&["QZ", "A"], Pooling::Mean), &config)
+            .await
+            .unwrap();
+        assert_eq!(mean.model.name, "quantized");
+        assert_eq!(mean.embeddings, [vec![52.5, 1.0], vec![32.0, 1.0]]);
+        assert_eq!(mean.tokens, [2, 1]);
+        assert_eq!(mean.dimensions, 2);
+
+        let last = registry
+            .embed(embedding(&["QZ"], Pooling::Last), &config)
+            .await
+            .unwrap();
+        assert_eq!(last.embeddings, [vec![57.0, 1.0]]);
+    }
+
+    #[tokio::test]
+    async fn embed_falls_back_only_when_allowed() {
+        let mut config = fake_config("registry-embed-fallback", false);
+        let registry = fake_registry(&config);
+        let response = registry
+            .embed(embedding(&["A"], Pooling::Mean), &config)
+            .await
+            .unwrap();
+        assert_eq!(response.model.name, "baseline");
+
+        config.fallback_to_baseline = false;
+        let err = registry
+            .embed(embedding(&["A"], Pooling::Mean), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "model_unavailable", "{err}");
+    }
+
+    #[tokio::test]
+    async fn embed_waits_its_turn_within_max_lock_wait() {
+        let mut config = fake_config("registry-embed-busy", true);
+        config.max_lock_wait_ms = 50;
+        let registry = fake_registry(&config);
+
+        let turn = occupy(&registry, "quantized").await;
+        let err = registry
+            .embed(embedding(&["A"], Pooling::Mean), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "overloaded", "{err}");
+
+        drop(turn);
+        assert!(
+            registry
+                .embed(embedding(&["A"], Pooling::Mean), &config)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn embed_caps_the_batch() {
+        let mut config = fake_config("registry-embed-cap", true);
+        config.embed_max_batch = 2;
+        let registry = fake_registry(&config);
+        let err = registry
+            .embed(embedding(&["A", "B", "C"], Pooling::Mean), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid request: field 'texts' has 3 entries, more than the limit of 2"
+        );
+    }
+
+    async fn dispatch(
+        registry: &ModelRegistry,
+        config: &AppConfig,
+        request: GenerationRequest,
+    ) -> GenerationResponse {
+        let (model, tokenizer) = registry.unnamed_model(config).unwrap();
+        registry
+            .dispatch_inference(model, tokenizer, request, config, None)
+            .await
+            .unwrap()
+    }
+
+    fn sampled(seed: Option<u64>) -> GenerationRequest {
+        GenerationRequest {
+            prompt: "Hello".to_string(),
+            max_new_tokens: Some(2),
+            temperature: Some(0.8),
+            top_k: Some(40),
+            seed,
+            ..GenerationRequest::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn unseeded_samples_are_never_served_from_the_cache() {
+        let mut config = fake_config("registry-cache-sampled", true);
+        config.response_cache_size = 8;
+        let registry = fake_registry(&config);
+        for _ in 0..2 {
+            assert!(!dispatch(&registry, &config, sampled(None)).await.cached);
+        }
+    }
+
+    #[tokio::test]
+    async fn seeded_samples_are_served_from_the_cache() {
+        let mut config = fake_config("registry-cache-seeded", true);
+        config.response_cache_size = 8;
+        let registry = fake_registry(&config);
+        let first = dispatch(&registry, &config, sampled(Some(7))).await;
+        let second = dispatch(&registry, &config, sampled(Some(7))).await;
+        assert!(!first.cached);
+        assert!(second.cached);
+        assert_eq!(second.completion, first.completion);
+    }
+
+    fn shape_mismatch(_: &[i64], _: &mut StdRng) -> Vec<f32> {
+        panic!("shape mismatch: [1, 7] vs [1, 8]")
+    }
+
+    #[tokio::test]
+    async fn a_panicking_model_is_a_500_naming_the_panic() {
+        let model: Arc<dyn Backend> = Arc::new(FakeModel::new("broken", shape_mismatch));
+        let stats = Arc::new(ModelStats::new(8));
+        let err = run_inference(
+            model,
+            Arc::new(gpt2()),
+            "Hello".to_string(),
+            greedy(2),
+            None,
+            None,
+            Some(stats.clone()),
+        )
         .await
-        .map_err(|err| ServiceError::Inference(format!("inference task failed: {err}")))?
+        .unwrap_err();
+        assert_eq!(err.status(), axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            err.to_body().error.message,
+            "model execution failed: inference panicked: shape mismatch: [1, 7] vs [1, 8]"
+        );
+        assert_eq!(stats.snapshot().failures, 1);
+    }
+
+    #[tokio::test]
+    async fn cancellation_and_panics_are_told_apart() {
+        let cancelled = tokio::spawn(std::future::pending::<()>());
+        cancelled.abort();
+        assert_eq!(
+            join_error(cancelled.await.unwrap_err()).to_string(),
+            "model execution failed: inference task was cancelled"
+        );
+        let panicked = tokio::spawn(async { panic!("boom") }).await.unwrap_err();
+        let message = join_error(panicked).to_string();
+        assert!(message.contains("inference task failed"), "{message}");
     }
 }
@@ -0,0 +1,226 @@
+//! One model loaded on several devices, served as a single backend.
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokenizers::Tokenizer;
+
+use crate::{
+    config::AppConfig,
+    error::ServiceError,
+    model::{
+        EmbedResponse, GenerationParams, GenerationResponse, ModelMetadata, ModelSlot, Pooling,
+        ReplicaMetadata, ReplicaStatsSnapshot, ScoreResponse, SelfTestReport, TokenVocabulary,
+        backend::{Backend, PromptActivations, TokenCallback},
+        stats::ModelStats,
+    },
+};
+
+/// Copies of a model, each behind its own module lock. Every call goes to
+/// the copy with the fewest calls outstanding, so a slow device ends up
+/// with less of the traffic.
+pub struct ReplicaSet {
+    replicas: Vec<Replica>,
+    /// Where the search for the least busy copy starts, so idle ones take
+    /// turns.
+    next: AtomicUsize,
+}
+
+struct Replica {
+    model: Box<dyn Backend>,
+    outstanding: AtomicUsize,
+    stats: ModelStats,
+}
+
+/// Counts a call against its replica until dropped.
+struct Claim<'a> {
+    replica: &'a Replica,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.replica.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ReplicaSet {
+    /// `models` must not be empty.
+    pub fn new(models: Vec<Box<dyn Backend>>, stats_window: usize) -> Self {
+        assert!(!models.is_empty(), "a replica set needs at least one model");
+        Self {
+            replicas: models
+                .into_iter()
+                .map(|model| Replica {
+                    model,
+                    outstanding: AtomicUsize::new(0),
+                    stats: ModelStats::new(stats_window),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn claim(&self) -> Claim<'_> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.replicas.len();
+        let replica = (0..count)
+            .map(|offset| &self.replicas[(start + offset) % count])
+            .min_by_key(|replica| replica.outstanding.load(Ordering::Relaxed))
+            .expect("a replica set is never empty");
+        replica.outstanding.fetch_add(1, Ordering::Relaxed);
+        Claim { replica }
+    }
+}
+
+impl Backend for ReplicaSet {
+    /// Sets are assembled by the loader from copies it has loaded, checked
+    /// and warmed up one by one.
+    fn load(_config: &AppConfig, slot: ModelSlot, _path: &Path) -> Result<Self, ServiceError> {
+        Err(ServiceError::Other(format!(
+            "the {} model's replicas are loaded one by one",
+            slot.name()
+        )))
+    }
+
+    fn metadata(&self) -> ModelMetadata {
+        let mut metadata = self.replicas[0].model.metadata();
+        if self.replicas.len() > 1 {
+            let copies: Vec<ModelMetadata> = self
+                .replicas
+                .iter()
+                .map(|replica| replica.model.metadata())
+                .collect();
+            // One copy failing its self-test is enough to report.
+            if let Some(failed) = copies
+                .iter()
+                .find_map(|copy| copy.self_test.clone().filter(|report| !report.passed))
+            {
+                metadata.self_test = Some(failed);
+            }
+            metadata.replicas = copies
+                .into_iter()
+                .map(|copy| ReplicaMetadata {
+                    device: copy.device,
+                    size_bytes: copy.size_bytes,
+                    load_time_ms: copy.load_time_ms,
+                })
+                .collect();
+        }
+        metadata
+    }
+
+    fn generate(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: Option<&mut TokenCallback>,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let claim = self.claim();
+        let result = claim
+            .replica
+            .model
+            .generate(tokenizer, prompt, params, on_token);
+        claim.replica.stats.record(&result);
+        result
+    }
+
+    fn supports_batching(&self) -> bool {
+        self.replicas[0].model.supports_batching()
+    }
+
+    fn generate_batch(
+        &self,
+        tokenizer: &Tokenizer,
+        requests: &[(String, GenerationParams)],
+    ) -> Vec<Result<GenerationResponse, ServiceError>> {
+        let claim = self.claim();
+        let results = claim.replica.model.generate_batch(tokenizer, requests);
+        results
+            .iter()
+            .for_each(|result| claim.replica.stats.record(result));
+        results
+    }
+
+    fn set_warmup_latencies(&mut self, latencies: Vec<Duration>) {
+        for replica in &mut self.replicas {
+            replica.model.set_warmup_latencies(latencies.clone());
+        }
+    }
+
+    fn set_self_test(&mut self, report: SelfTestReport) {
+        for replica in &mut self.replicas {
+            replica.model.set_self_test(report.clone());
+        }
+    }
+
+    fn set_vocabulary(&mut self, vocabulary: Arc<TokenVocabulary>) {
+        for replica in &mut self.replicas {
+            replica.model.set_vocabulary(vocabulary.clone());
+        }
+    }
+
+    fn replica_stats(&self) -> Vec<ReplicaStatsSnapshot> {
+        if self.replicas.len() < 2 {
+            return Vec::new();
+        }
+        self.replicas
+            .iter()
+            .map(|replica| {
+                let stats = replica.stats.snapshot();
+                ReplicaStatsSnapshot {
+                    device: replica.model.metadata().device,
+                    outstanding: replica.outstanding.load(Ordering::Relaxed),
+                    requests: stats.requests,
+                    failures: stats.failures,
+                    tokens_generated: stats.tokens_generated,
+                    avg_latency_ms: stats.avg_latency_ms,
+                    avg_decode_tokens_per_second: stats.avg_decode_tokens_per_second,
+                }
+            })
+            .collect()
+    }
+
+    fn reset_replica_stats(&self) {
+        self.replicas
+            .iter()
+            .for_each(|replica| replica.stats.reset());
+    }
+
+    fn score(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        continuations: &[String],
+    ) -> Result<ScoreResponse, ServiceError> {
+        self.claim()
+            .replica
+            .model
+            .score(tokenizer, prompt, continuations)
+    }
+
+    fn embed(
+        &self,
+        tokenizer: &Tokenizer,
+        texts: &[String],
+        pooling: Pooling,
+    ) -> Result<EmbedResponse, ServiceError> {
+        self.claim().replica.model.embed(tokenizer, texts, pooling)
+    }
+
+    fn prompt_activations(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+    ) -> Result<PromptActivations, ServiceError> {
+        self.claim()
+            .replica
+            .model
+            .prompt_activations(tokenizer, prompt)
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use parking_lot::Mutex;
+use tokio::sync::watch;
+use tracing::Instrument;
+
+use crate::{error::ServiceError, model::GenerationResponse};
+
+type Outcome = Result<GenerationResponse, ServiceError>;
+type Calls = Arc<Mutex<HashMap<u64, watch::Receiver<Option<Outcome>>>>>;
+
+/// Coalesces identical concurrent generations: the first caller for a key
+/// starts the work, later callers wait on the same result.
+#[derive(Default)]
+pub(crate) struct SingleFlight {
+    calls: Calls,
+}
+
+/// Removes the entry however the work ends, including a panic.
+struct Forget {
+    calls: Calls,
+    key: u64,
+}
+
+impl Drop for Forget {
+    fn drop(&mut self) {
+        self.calls.lock().remove(&self.key);
+    }
+}
+
+impl SingleFlight {
+    /// The work runs on its own task, so callers going away doesn't strand
+    /// the others waiting on it.
+    pub fn run<F>(&self, key: u64, work: F) -> impl Future<Output = Outcome> + use<F>
+    where
+        F: Future<Output = Outcome> + Send + 'static,
+    {
+        let mut receiver = {
+            let mut calls = self.calls.lock();
+            match calls.get(&key) {
+                Some(receiver) => {
+                    tracing::debug!(key, "joining in-flight generation");
+                    receiver.clone()
+                }
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    calls.insert(key, receiver.clone());
+                    let forget = Forget {
+                        calls: self.calls.clone(),
+                        key,
+                    };
+                    // Under the first caller's span, so its trace covers the
+                    // generation.
+                    tokio::spawn(
+                        async move {
+                            let outcome = work.await;
+                            drop(forget);
+                            let _ = sender.send(Some(outcome));
+                        }
+                        .in_current_span(),
+                    );
+                    receiver
+                }
+            }
+        };
+
+        async move {
+            match receiver.wait_for(Option::is_some).await {
+                Ok(outcome) => outcome.clone().expect("waited for a result"),
+                Err(_) => Err(ServiceError::Inference(
+                    "in-flight generation ended without a result".into(),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::model::{
+        Backend,
+        testing::{FakeModel, gpt2, greedy, next_token},
+    };
+
+    /// A generation that counts its runs and takes long enough for the
+    /// other callers to join it.
+    fn work(runs: &Arc<AtomicUsize>) -> impl Future<Output = Outcome> + Send + 'static {
+        let runs = runs.clone();
+        async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            FakeModel::new("fake", next_token).generate(&gpt2(), "Hello", &greedy(2), None)
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_for_a_key_share_one_run() {
+        let flight = SingleFlight::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let calls: Vec<_> = (0..5).map(|_| flight.run(1, work(&runs))).collect();
+        let outcomes = futures::future::join_all(calls).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let completions: Vec<_> = outcomes
+            .into_iter()
+            .map(|outcome| outcome.unwrap().completion)
+            .collect();
+        assert!(completions.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[tokio::test]
+    async fn other_keys_and_later_calls_run_again() {
+        let flight = SingleFlight::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (first, second) = tokio::join!(flight.run(1, work(&runs)), flight.run(2, work(&runs)));
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        flight.run(1, work(&runs)).await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn errors_are_shared_and_forgotten() {
+        let flight = SingleFlight::default();
+        let failing = async { Err(ServiceError::Inference("boom".into())) };
+        let (first, second) = tokio::join!(
+            flight.run(1, failing),
+            flight.run(1, async { unreachable!("joins the failing run") }),
+        );
+        for outcome in [first, second] {
+            assert_eq!(
+                outcome.unwrap_err().to_string(),
+                "model execution failed: boom"
+            );
+        }
+        assert!(flight.calls.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_panicking_run_is_an_error() {
+        let flight = SingleFlight::default();
+        let err = flight
+            .run(1, async { panic!("generation blew up") })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), "inference");
+        assert!(flight.calls.lock().is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{error::ServiceError, model::GenerationResponse};
+
+/// Upper bounds of the queue wait histogram buckets; a last, unbounded
+/// bucket takes longer waits.
+const QUEUE_WAIT_BOUNDS_MS: [f64; 8] = [1.0, 5.0, 25.0, 100.0, 250.0, 1000.0, 5000.0, 30000.0];
+
+/// Running counters for one model since startup (or the last reset), plus
+/// averages over its most recent requests.
+pub struct ModelStats {
+    requests: AtomicU64,
+    failures: AtomicU64,
+    tokens_generated: AtomicU64,
+    cpu_time_us: AtomicU64,
+    queue_waits: [AtomicU64; QUEUE_WAIT_BOUNDS_MS.len() + 1],
+    queue_wait_rejections: AtomicU64,
+    shadow_runs: AtomicU64,
+    shadow_mismatches: AtomicU64,
+    window: usize,
+    recent: Mutex<VecDeque<Sample>>,
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    latency_ms: f64,
+    decode_tokens_per_second: f64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ModelStatsSnapshot {
+    pub requests: u64,
+    pub failures: u64,
+    pub tokens_generated: u64,
+    /// CPU time of the generations counted in `requests`; stays 0 where it
+    /// cannot be measured.
+    pub cpu_time_ms: f64,
+    /// How long successful generations waited for the model, one count
+    /// per bucket (not cumulative).
+    pub queue_wait_histogram: Vec<WaitBucket>,
+    /// Generations turned away after waiting `max_lock_wait_ms`.
+    pub queue_wait_rejections: u64,
+    /// Sampled responses re-run on the baseline model for comparison.
+    pub shadow_runs: u64,
+    /// Shadow runs whose baseline completion differed from this model's.
+    pub shadow_mismatches: u64,
+    /// Requests the averages below are taken over.
+    pub window: usize,
+    pub avg_latency_ms: Option<f64>,
+    pub avg_decode_tokens_per_second: Option<f64>,
+    /// Each copy's share when the model is replicated across devices.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<ReplicaStatsSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ReplicaStatsSnapshot {
+    pub device: String,
+    /// Generations running on or waiting for this copy right now.
+    pub outstanding: usize,
+    pub requests: u64,
+    pub failures: u64,
+    pub tokens_generated: u64,
+    pub avg_latency_ms: Option<f64>,
+    pub avg_decode_tokens_per_second: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WaitBucket {
+    /// Inclusive upper bound; absent for the last bucket.
+    pub le_ms: Option<f64>,
+    pub count: u64,
+}
+
+impl ModelStats {
+    pub fn new(window: usize) -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            tokens_generated: AtomicU64::new(0),
+            cpu_time_us: AtomicU64::new(0),
+            queue_waits: Default::default(),
+            queue_wait_rejections: AtomicU64::new(0),
+            shadow_runs: AtomicU64::new(0),
+            shadow_mismatches: AtomicU64::new(0),
+            window: window.max(1),
+            recent: Mutex::new(VecDeque::with_capacity(window.max(1))),
+        }
+    }
+
+    /// Counts a finished generation. Rejected requests never reached the
+    /// model, so only server-side failures count against it.
+    pub fn record(&self, result: &Result<GenerationResponse, ServiceError>) {
+        match result {
+            Ok(response) => self.record_success(response),
+            Err(ServiceError::Overloaded { .. }) => self.record_wait_rejection(),
+            Err(err) if err.status().is_server_error() => self.record_failure(),
+            Err(_) => {}
+        }
+    }
+
+    pub fn record_success(&self, response: &GenerationResponse) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.tokens_generated
+            .fetch_add(response.tokens_generated as u64, Ordering::Relaxed);
+        if let Some(cpu_time_ms) = response.timings.cpu_time_ms {
+            self.cpu_time_us
+                .fetch_add((cpu_time_ms * 1000.0) as u64, Ordering::Relaxed);
+        }
+        let bucket = QUEUE_WAIT_BOUNDS_MS
+            .iter()
+            .position(|&bound| response.timings.queue_wait_ms <= bound)
+            .unwrap_or(QUEUE_WAIT_BOUNDS_MS.len());
+        self.queue_waits[bucket].fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent.lock();
+        if recent.len() == self.window {
+            recent.pop_front();
+        }
+        recent.push_back(Sample {
+            latency_ms: response.total_time_ms as f64,
+            decode_tokens_per_second: response.decode_tokens_per_second,
+        });
+    }
+
+    pub fn record_failure(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_wait_rejection(&self) {
+        self.queue_wait_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_shadow(&self, matched: bool) {
+        self.shadow_runs.fetch_add(1, Ordering::Relaxed);
+        if !matched {
+            self.shadow_mismatches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn reset(&self) {
+        self.requests.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+        self.tokens_generated.store(0, Ordering::Relaxed);
+        self.cpu_time_us.store(0, Ordering::Relaxed);
+        self.queue_waits
+            .iter()
+            .for_each(|count| count.store(0, Ordering::Relaxed));
+        self.queue_wait_rejections.store(0, Ordering::Relaxed);
+        self.shadow_runs.store(0, Ordering::Relaxed);
+        self.shadow_mismatches.store(0, Ordering::Relaxed);
+        self.recent.lock().clear();
+    }
+
+    pub fn snapshot(&self) -> ModelStatsSnapshot {
+        let recent: Vec<Sample> = self.recent.lock().iter().copied().collect();
+        let average = |value: fn(&Sample) -> f64| {
+            (!recent.is_empty())
+                .then(|| recent.iter().map(value).sum::<f64>() / recent.len() as f64)
+        };
+        ModelStatsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            tokens_generated: self.tokens_generated.load(Ordering::Relaxed),
+            cpu_time_ms: self.cpu_time_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            queue_wait_histogram: self
+                .queue_waits
+                .iter()
+                .enumerate()
+                .map(|(i, count)| WaitBucket {
+                    le_ms: QUEUE_WAIT_BOUNDS_MS.get(i).copied(),
+                    count: count.load(Ordering::Relaxed),
+                })
+                .collect(),
+            queue_wait_rejections: self.queue_wait_rejections.load(Ordering::Relaxed),
+            shadow_runs: self.shadow_runs.load(Ordering::Relaxed),
+            shadow_mismatches: self.shadow_mismatches.load(Ordering::Relaxed),
+            window: recent.len(),
+            avg_latency_ms: average(|sample| sample.latency_ms),
+            avg_decode_tokens_per_second: average(|sample| sample.decode_tokens_per_second),
+            replicas: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        Backend,
+        testing::{FakeModel, gpt2, greedy, next_token},
+    };
+
+    fn response(total_time_ms: u128, decode_tokens_per_second: f64) -> GenerationResponse {
+        let mut response = FakeModel::new("fake", next_token)
+            .generate(&gpt2(), "Hello", &greedy(2), None)
+            .expect("fake generation");
+        response.total_time_ms = total_time_ms;
+        response.decode_tokens_per_second = decode_tokens_per_second;
+        response
+    }
+
+    #[test]
+    fn averages_cover_only_the_window() {
+        let stats = ModelStats::new(2);
+        for (latency, rate) in [(100, 10.0), (20, 30.0), (40, 50.0)] {
+            stats.record(&Ok(response(latency, rate)));
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests, 3);
+        assert_eq!(snapshot.tokens_generated, 6);
+        assert_eq!(snapshot.window, 2);
+        assert_eq!(snapshot.avg_latency_ms, Some(30.0));
+        assert_eq!(snapshot.avg_decode_tokens_per_second, Some(40.0));
+    }
+
+    #[test]
+    fn only_server_side_failures_count_against_the_model() {
+        let stats = ModelStats::new(4);
+        stats.record(&Err(ServiceError::Inference("boom".into())));
+        stats.record(&Err(ServiceError::BadRequest("bad".into())));
+        stats.record(&Err(ServiceError::Overloaded {
+            message: "busy".into(),
+            retry_after_secs: None,
+        }));
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.requests, snapshot.failures), (1, 1));
+        assert_eq!(snapshot.queue_wait_rejections, 1);
+        assert_eq!(snapshot.avg_latency_ms, None);
+    }
+
+    #[test]
+    fn reset_clears_counters_and_window() {
+        let stats = ModelStats::new(4);
+        stats.record(&Ok(response(10, 1.0)));
+        stats.record_failure();
+        stats.record_shadow(false);
+        stats.reset();
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            (snapshot.requests, snapshot.failures, snapshot.shadow_runs),
+            (0, 0, 0)
+        );
+        assert_eq!(snapshot.window, 0);
+        assert!(
+            snapshot
+                .queue_wait_histogram
+                .iter()
+                .all(|bucket| bucket.count == 0)
+        );
+    }
+}
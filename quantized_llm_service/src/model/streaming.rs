@@ -0,0 +1,135 @@
+use std::{ops::Deref, sync::Arc};
+
+use tokenizers::Tokenizer;
+
+/// Turns a stream of token ids into text deltas without re-decoding the whole
+/// sequence each step.
+///
+/// Only the tokens since the last emitted boundary are decoded, together with
+/// the piece before them so context-dependent rules (GPT-2's leading space,
+/// byte-level merges) come out the same as a full decode. When the tail ends
+/// in an incomplete UTF-8 sequence the text is held back until the next
+/// token completes it.
+pub struct StreamingDecoder<T = Arc<Tokenizer>> {
+    tokenizer: T,
+    skip_special_tokens: bool,
+    ids: Vec<u32>,
+    prefix_offset: usize,
+    read_offset: usize,
+}
+
+impl<T: Deref<Target = Tokenizer>> StreamingDecoder<T> {
+    pub fn new(tokenizer: T, skip_special_tokens: bool) -> Self {
+        Self {
+            tokenizer,
+            skip_special_tokens,
+            ids: Vec::new(),
+            prefix_offset: 0,
+            read_offset: 0,
+        }
+    }
+
+    /// Adds one token and returns the newly completed text, if any.
+    pub fn push(&mut self, id: u32) -> Option<String> {
+        self.ids.push(id);
+        let delta = self.pending_text()?;
+        if delta.ends_with(char::REPLACEMENT_CHARACTER) {
+            return None;
+        }
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.ids.len();
+        Some(delta)
+    }
+
+    /// Returns whatever is still held back, even if it is not valid text yet.
+    pub fn finish(&mut self) -> Option<String> {
+        let delta = self.pending_text()?;
+        self.prefix_offset = self.ids.len();
+        self.read_offset = self.ids.len();
+        Some(delta)
+    }
+
+    fn pending_text(&self) -> Option<String> {
+        let prefix = self
+            .tokenizer
+            .decode(
+                &self.ids[self.prefix_offset..self.read_offset],
+                self.skip_special_tokens,
+            )
+            .ok()?;
+        let full = self
+            .tokenizer
+            .decode(&self.ids[self.prefix_offset..], self.skip_special_tokens)
+            .ok()?;
+        if full.len() <= prefix.len() {
+            return None;
+        }
+        full.get(prefix.len()..).map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::testing::gpt2;
+
+    /// Streams `text` token by token, returning each delta.
+    fn stream(tokenizer: &Tokenizer, text: &str) -> Vec<String> {
+        let ids = tokenizer.encode(text, false).unwrap().get_ids().to_vec();
+        let mut decoder = StreamingDecoder::new(tokenizer, true);
+        let mut deltas: Vec<String> = ids.iter().filter_map(|&id| decoder.push(id)).collect();
+        deltas.extend(decoder.finish());
+        deltas
+    }
+
+    fn assert_streams_intact(tokenizer: &Tokenizer, text: &str) -> Vec<String> {
+        let deltas = stream(tokenizer, text);
+        assert_eq!(deltas.concat(), text);
+        for delta in &deltas {
+            assert!(!delta.contains(char::REPLACEMENT_CHARACTER), "{deltas:?}");
+        }
+        deltas
+    }
+
+    #[test]
+    fn emoji_split_across_tokens_is_held_back() {
+        let tokenizer = gpt2();
+        let text = "Thanks 🤗 and 👍🏽!";
+        // Each emoji takes several byte-level tokens.
+        assert!(tokenizer.encode(text, false).unwrap().len() > text.split(' ').count() + 2);
+        let deltas = assert_streams_intact(&tokenizer, text);
+        assert!(deltas.iter().any(|delta| delta.contains('🤗')));
+    }
+
+    #[test]
+    fn accented_characters_come_out_whole() {
+        let tokenizer = gpt2();
+        let text = "Crème brûlée à São Paulo, señor Dvořák";
+        // At least one character is split between two tokens.
+        let ids = tokenizer.encode(text, false).unwrap().get_ids().to_vec();
+        assert!(ids.iter().any(|&id| {
+            tokenizer
+                .decode(&[id], true)
+                .unwrap()
+                .contains(char::REPLACEMENT_CHARACTER)
+        }));
+        assert_streams_intact(&tokenizer, text);
+    }
+
+    #[test]
+    fn leading_spaces_stay_with_their_word() {
+        let tokenizer = gpt2();
+        let deltas = assert_streams_intact(&tokenizer, "Hello world, how are you");
+        assert_eq!(deltas, ["Hello", " world", ",", " how", " are", " you"]);
+    }
+
+    #[test]
+    fn finish_flushes_an_incomplete_character() {
+        let tokenizer = gpt2();
+        let ids = tokenizer.encode("🤗", false).unwrap().get_ids().to_vec();
+        let mut decoder = StreamingDecoder::new(&tokenizer, true);
+        assert_eq!(decoder.push(ids[0]), None);
+        assert!(decoder.finish().is_some());
+        assert_eq!(decoder.finish(), None);
+    }
+}
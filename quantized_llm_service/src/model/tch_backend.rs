@@ -1,3 +1,1459 @@
-//! Tch backend specific utilities live here.
-//! The current implementation relies directly on TorchScript modules exported via
-//! `scripts/prepare_model.py`, so no additional helpers are required yet.
+//! TorchScript modules exported by `scripts/prepare_model.py`, run on
+//! LibTorch through tch.
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rand::{Rng, rngs::StdRng};
+use tch::{Device, Kind, Tensor, no_grad};
+use tokenizers::Tokenizer;
+
+use crate::{
+    config::AppConfig,
+    error::ServiceError,
+    model::{
+        ContinuationScore, EmbedResponse, GenerationParams, GenerationResponse, JsonConstraint,
+        ModelDtype, ModelKind, ModelMetadata, Pooling, PromptActivations, ScoreResponse,
+        SelfTestReport, TokenVocabulary,
+        backend::{
+            Backend, Decoding, ModelSlot, PickedToken, TokenCallback, as_ms, check_context_fits,
+            generate_tokens, lock_within,
+        },
+        loader::{record_cuda_oom, verify_sha256},
+    },
+};
+
+pub struct ModelInstance {
+    name: String,
+    quantized: bool,
+    dtype: String,
+    size_bytes: u64,
+    sha256: String,
+    device: Device,
+    max_context_tokens: usize,
+    device_fallback_reason: Option<String>,
+    load_time: Duration,
+    num_parameters: Option<u64>,
+    /// Size of the logits' last dimension, found by a probe forward pass.
+    vocab_size: usize,
+    hidden_states: bool,
+    model_kind: ModelKind,
+    eos_token_id: i64,
+    decoder_start_token_id: i64,
+    signature: ForwardSignature,
+    layout: OutputLayout,
+    warmup_latencies: Vec<Duration>,
+    self_test: Option<SelfTestReport>,
+    vocabulary: Option<Arc<TokenVocabulary>>,
+    module: Mutex<tch::CModule>,
+}
+
+/// Placement and validation settings for loading one module.
+/// Applies the configured LibTorch thread pool sizes. Must run before any
+/// model is loaded: LibTorch fixes its pools on first use.
+pub fn configure_threads(config: &AppConfig) {
+    if let Some(threads) = config.torch_num_threads {
+        tch::set_num_threads(threads as i32);
+    }
+    if let Some(threads) = config.torch_num_interop_threads {
+        tch::set_num_interop_threads(threads as i32);
+    }
+    tracing::info!(
+        intra_op = tch::get_num_threads(),
+        inter_op = tch::get_num_interop_threads(),
+        "LibTorch thread pools configured"
+    );
+}
+
+pub struct LoadOptions<'a> {
+    pub device: Device,
+    /// Retry on CPU when loading on a CUDA device fails.
+    pub allow_device_fallback: bool,
+    /// Set when `device` is already a CPU substitute for an unavailable GPU.
+    pub device_fallback_reason: Option<String>,
+    pub max_context_tokens: usize,
+    pub expected_sha256: Option<&'a str>,
+    pub model_kind: ModelKind,
+    pub eos_token_id: i64,
+    pub decoder_start_token_id: i64,
+    /// Precision to convert a float module to; ignored for quantized ones.
+    pub dtype: ModelDtype,
+}
+
+/// How a traced module's `forward` expects to be called. GPT-2 traces are
+/// exported either with `input_ids` alone or with an attention mask too;
+/// seq2seq traces (e.g. T5) take the encoder and decoder ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ForwardSignature {
+    InputIds,
+    InputIdsAndMask,
+    EncoderDecoder,
+}
+
+impl ForwardSignature {
+    fn candidates(kind: ModelKind) -> &'static [Self] {
+        match kind {
+            ModelKind::Causal => &[Self::InputIds, Self::InputIdsAndMask],
+            ModelKind::Seq2Seq => &[Self::EncoderDecoder],
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Self::InputIds => "forward(input_ids)",
+            Self::InputIdsAndMask => "forward(input_ids, attention_mask)",
+            Self::EncoderDecoder => "forward(input_ids, decoder_input_ids)",
+        }
+    }
+}
+
+/// Where a trace puts the logits in its output, found by the load-time probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputLayout {
+    /// `forward` returns the logits tensor itself.
+    Tensor,
+    /// `(logits, ...)`, e.g. followed by past key values or hidden states.
+    Tuple,
+    /// A dict with a `"logits"` entry, as traced with `return_dict=True`.
+    Dict,
+}
+
+impl OutputLayout {
+    /// Classifies the probe's output, describing the structure it found when
+    /// no `[batch, seq_len, vocab]` logits tensor is where one is expected.
+    fn classify(output: &tch::IValue) -> Result<Self, String> {
+        let (layout, logits) = match output {
+            tch::IValue::Tensor(t) => (Self::Tensor, Some(t)),
+            tch::IValue::Tuple(items) => (Self::Tuple, first_tensor(items)),
+            tch::IValue::GenericDict(entries) => (Self::Dict, dict_logits(entries)),
+            _ => return Err(describe_ivalue(output)),
+        };
+        match logits {
+            Some(logits) if logits.dim() == 3 => Ok(layout),
+            _ => Err(describe_ivalue(output)),
+        }
+    }
+
+    /// The logits of an output this layout was detected on.
+    fn logits(self, output: &tch::IValue) -> Result<Tensor, ServiceError> {
+        let logits = match (self, output) {
+            (Self::Tensor, tch::IValue::Tensor(t)) => Some(t),
+            (Self::Tuple, tch::IValue::Tuple(items)) => first_tensor(items),
+            (Self::Dict, tch::IValue::GenericDict(entries)) => dict_logits(entries),
+            _ => None,
+        };
+        logits.map(Tensor::shallow_clone).ok_or_else(|| {
+            ServiceError::Inference(format!(
+                "model output changed shape since load: expected {self:?} logits, got {}",
+                describe_ivalue(output)
+            ))
+        })
+    }
+}
+
+fn first_tensor(items: &[tch::IValue]) -> Option<&Tensor> {
+    match items.first()? {
+        tch::IValue::Tensor(t) => Some(t),
+        _ => None,
+    }
+}
+
+fn dict_logits(entries: &[(tch::IValue, tch::IValue)]) -> Option<&Tensor> {
+    entries.iter().find_map(|(key, value)| match (key, value) {
+        (tch::IValue::String(key), tch::IValue::Tensor(t)) if key == "logits" => Some(t),
+        _ => None,
+    })
+}
+
+/// A compact rendering of an output's structure for load errors, e.g.
+/// `Tuple(Tensor[1, 1, 50257], Tuple(Tensor[..], ..))`.
+fn describe_ivalue(value: &tch::IValue) -> String {
+    let list = |items: &[tch::IValue]| {
+        let shown: Vec<String> = items.iter().take(4).map(describe_ivalue).collect();
+        let more = if items.len() > 4 { ", .." } else { "" };
+        format!("{}{more}", shown.join(", "))
+    };
+    match value {
+        tch::IValue::Tensor(t) => format!("Tensor{:?}", t.size()),
+        tch::IValue::Tuple(items) => format!("Tuple({})", list(items)),
+        tch::IValue::GenericList(items) => format!("List({})", list(items)),
+        tch::IValue::TensorList(tensors) => format!("TensorList(len {})", tensors.len()),
+        tch::IValue::GenericDict(entries) => {
+            let shown: Vec<String> = entries
+                .iter()
+                .take(4)
+                .map(|(key, value)| format!("{}: {}", describe_ivalue(key), describe_ivalue(value)))
+                .collect();
+            let more = if entries.len() > 4 { ", .." } else { "" };
+            format!("Dict{{{}{more}}}", shown.join(", "))
+        }
+        tch::IValue::String(s) => format!("{s:?}"),
+        tch::IValue::None => "None".to_string(),
+        tch::IValue::Int(i) => format!("Int({i})"),
+        tch::IValue::Double(d) => format!("Double({d})"),
+        tch::IValue::Bool(b) => format!("Bool({b})"),
+        _ => "an unsupported value".to_string(),
+    }
+}
+
+/// Finds the calling convention by trying each with a one-token input, and
+/// returns the probe's raw output alongside it.
+fn detect_signature(
+    module: &tch::CModule,
+    name: &str,
+    device: Device,
+    kind: ModelKind,
+) -> Result<(ForwardSignature, tch::IValue), ServiceError> {
+    let mut tried = Vec::new();
+    for &signature in ForwardSignature::candidates(kind) {
+        let probe = no_grad(|| {
+            let output = match signature {
+                ForwardSignature::EncoderDecoder => {
+                    let encoder_ids = Tensor::from_slice(&[0i64]).reshape([1, 1]).to(device);
+                    run_seq2seq(module, name, device, &encoder_ids, &[0])?
+                }
+                _ => run_forward(module, name, device, signature, &[0])?,
+            };
+            Ok::<_, ServiceError>(output)
+        });
+        match probe {
+            // The trace accepted the inputs, so the signature is right even
+            // if the output turns out to be unusable.
+            Ok(output) => {
+                tracing::debug!(
+                    model = name,
+                    signature = signature.describe(),
+                    "detected forward signature"
+                );
+                return Ok((signature, output));
+            }
+            Err(err) => tried.push(format!("{}: {err}", signature.describe())),
+        }
+    }
+    Err(ServiceError::Other(format!(
+        "{name} model has an unrecognized forward signature; tried {}",
+        tried.join("; ")
+    )))
+}
+
+/// Runs one forward pass over `input_ids` and returns the logits, shaped
+/// `[1, seq_len, vocab_size]`.
+fn forward_logits(
+    module: &tch::CModule,
+    name: &str,
+    device: Device,
+    signature: ForwardSignature,
+    layout: OutputLayout,
+    input_ids: &[i64],
+) -> Result<Tensor, ServiceError> {
+    let output = run_forward(module, name, device, signature, input_ids)?;
+    layout.logits(&output)
+}
+
+fn run_forward(
+    module: &tch::CModule,
+    name: &str,
+    device: Device,
+    signature: ForwardSignature,
+    input_ids: &[i64],
+) -> Result<tch::IValue, ServiceError> {
+    let shape = [1, input_ids.len() as i64];
+    let input_tensor = Tensor::from_slice(input_ids).reshape(shape).to(device);
+    // No padding within a single sequence, so every position attends.
+    let attention_mask = Tensor::ones(shape, (Kind::Int64, device));
+    run_forward_batch(
+        module,
+        name,
+        device,
+        signature,
+        input_tensor,
+        attention_mask,
+    )
+}
+
+/// Runs `forward` over `[batch, seq_len]` ids, passing the mask only when
+/// the trace takes one.
+fn run_forward_batch(
+    module: &tch::CModule,
+    name: &str,
+    device: Device,
+    signature: ForwardSignature,
+    input_ids: Tensor,
+    attention_mask: Tensor,
+) -> Result<tch::IValue, ServiceError> {
+    let sequence_length = input_ids.size().last().copied().unwrap_or(0) as usize;
+    let inputs = match signature {
+        ForwardSignature::InputIds => vec![tch::IValue::Tensor(input_ids)],
+        ForwardSignature::InputIdsAndMask => vec![
+            tch::IValue::Tensor(input_ids),
+            tch::IValue::Tensor(attention_mask),
+        ],
+        ForwardSignature::EncoderDecoder => {
+            return Err(ServiceError::Inference(format!(
+                "the {name} model is seq2seq and needs decoder input ids"
+            )));
+        }
+    };
+    module
+        .forward_is(&inputs)
+        .map_err(|e| classify_tch_error(name, device, e, Some(sequence_length)))
+}
+
+/// Runs a seq2seq trace over the encoder input `[1, prompt_len]` and the
+/// decoder ids so far. The trace has no separate encoder entry point, so the
+/// encoder runs again on every call.
+fn run_seq2seq(
+    module: &tch::CModule,
+    name: &str,
+    device: Device,
+    encoder_ids: &Tensor,
+    decoder_ids: &[i64],
+) -> Result<tch::IValue, ServiceError> {
+    let decoder_tensor = Tensor::from_slice(decoder_ids)
+        .reshape([1, decoder_ids.len() as i64])
+        .to(device);
+    let sequence_length = encoder_ids.size().last().copied().unwrap_or(0) as usize;
+    module
+        .forward_is(&[
+            tch::IValue::Tensor(encoder_ids.shallow_clone()),
+            tch::IValue::Tensor(decoder_tensor),
+        ])
+        .map_err(|e| {
+            classify_tch_error(
+                name,
+                device,
+                e,
+                Some(sequence_length.max(decoder_ids.len())),
+            )
+        })
+}
+
+/// Final-layer hidden states, `[batch, seq_len, hidden]`, from a trace
+/// exported with `output_hidden_states=True`: the last element of the output
+/// tuple, either that tensor itself or a tuple of per-layer tensors.
+fn output_hidden_states(output: &tch::IValue) -> Option<Tensor> {
+    let tch::IValue::Tuple(tuple) = output else {
+        return None;
+    };
+    if tuple.len() < 2 {
+        return None;
+    }
+    let last = match tuple.last()? {
+        tch::IValue::Tuple(layers) | tch::IValue::GenericList(layers) => layers.last()?,
+        other => other,
+    };
+    match last {
+        tch::IValue::Tensor(t) if t.dim() == 3 => Some(t.shallow_clone()),
+        _ => None,
+    }
+}
+
+/// Every layer's hidden states when the trace outputs the full tuple, or
+/// just the final layer's when that is all it outputs.
+fn output_layer_states(output: &tch::IValue) -> Vec<Tensor> {
+    let Some(tch::IValue::Tuple(layers) | tch::IValue::GenericList(layers)) = (match output {
+        tch::IValue::Tuple(tuple) if tuple.len() >= 2 => tuple.last(),
+        _ => None,
+    }) else {
+        return output_hidden_states(output).into_iter().collect();
+    };
+    layers
+        .iter()
+        .filter_map(|layer| match layer {
+            tch::IValue::Tensor(t) if t.dim() == 3 => Some(t.shallow_clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Dynamically quantized linears keep their weights in packed params that
+/// aren't exposed as named parameters, so the count can be partial or absent.
+fn count_parameters(name: &str, module: &tch::CModule) -> Option<u64> {
+    match module.named_parameters() {
+        Ok(params) if !params.is_empty() => {
+            Some(params.iter().map(|(_, tensor)| tensor.numel() as u64).sum())
+        }
+        Ok(_) => {
+            tracing::warn!(
+                model = name,
+                "module exposes no named parameters, skipping count"
+            );
+            None
+        }
+        Err(err) => {
+            tracing::warn!(model = name, error = %err, "could not enumerate module parameters");
+            None
+        }
+    }
+}
+
+/// Picks the next token from last-position logits: argmax when greedy,
+/// otherwise a draw from the temperature-scaled top-k distribution.
+/// Samples the next token from last-position logits, with its log-probability
+/// and the likeliest alternatives when `token_details` asks for them.
+fn pick_token(
+    last_logits: &Tensor,
+    params: &GenerationParams,
+    rng: &mut StdRng,
+    json: Option<&mut JsonConstraint<'_>>,
+) -> Result<PickedToken, ServiceError> {
+    let masked;
+    let last_logits = match json {
+        Some(json) => {
+            let bias = json.bias(last_logits.size()[0] as usize)?;
+            masked = last_logits.to_kind(Kind::Float)
+                + Tensor::from_slice(bias).to(last_logits.device());
+            &masked
+        }
+        None => last_logits,
+    };
+    let id = sample_next_token(last_logits, params, rng)?;
+    if !params.token_details {
+        return Ok((id, None, Vec::new()));
+    }
+    let logprobs = last_logits.to_kind(Kind::Float).log_softmax(0, Kind::Float);
+    let logprob = logprobs.double_value(&[id]) as f32;
+    let top = if params.top_logprobs > 0 {
+        let k = (params.top_logprobs as i64).min(logprobs.size()[0]);
+        let (values, indices) = logprobs.topk(k, 0, true, true);
+        let to_inference = |e: tch::TchError| ServiceError::Inference(e.to_string());
+        let values = Vec::<f32>::try_from(values.to(Device::Cpu)).map_err(to_inference)?;
+        let indices = Vec::<i64>::try_from(indices.to(Device::Cpu)).map_err(to_inference)?;
+        indices.into_iter().zip(values).collect()
+    } else {
+        Vec::new()
+    };
+    Ok((id, Some(logprob), top))
+}
+
+fn sample_next_token(
+    logits: &Tensor,
+    params: &GenerationParams,
+    rng: &mut StdRng,
+) -> Result<i64, ServiceError> {
+    if params.temperature <= 0.0 || params.top_k == 1 {
+        return Ok(logits.argmax(0, false).int64_value(&[]));
+    }
+
+    let vocab = logits.size().first().copied().unwrap_or(0);
+    let k = match params.top_k {
+        0 => vocab,
+        k => (k as i64).min(vocab),
+    };
+    let (values, indices) =
+        (logits.to_kind(Kind::Float) / params.temperature).topk(k, 0, true, true);
+    let to_inference = |e: tch::TchError| ServiceError::Inference(e.to_string());
+    let probs = Vec::<f32>::try_from(values.softmax(0, Kind::Float).to(Device::Cpu))
+        .map_err(to_inference)?;
+    let ids = Vec::<i64>::try_from(indices.to(Device::Cpu)).map_err(to_inference)?;
+
+    let mut draw: f32 = rng.r#gen();
+    for (&p, &id) in probs.iter().zip(&ids) {
+        if draw < p {
+            return Ok(id);
+        }
+        draw -= p;
+    }
+    // Rounding can leave a sliver of mass unassigned; fall back to the last candidate.
+    ids.last()
+        .copied()
+        .ok_or_else(|| ServiceError::Inference("empty logits".into()))
+}
+
+fn dtype_kind(dtype: ModelDtype) -> Kind {
+    match dtype {
+        ModelDtype::Float32 => Kind::Float,
+        ModelDtype::Float16 => Kind::Half,
+        ModelDtype::Bfloat16 => Kind::BFloat16,
+    }
+}
+
+/// Returns the device a model will actually run on, refusing to silently
+/// swap CUDA for CPU unless fallback was explicitly allowed.
+/// The second value explains a fallback when one happened.
+fn resolve_device(
+    model: &str,
+    requested: Device,
+    allow_fallback: bool,
+) -> Result<(Device, Option<String>), ServiceError> {
+    let Device::Cuda(idx) = requested else {
+        return Ok((requested, None));
+    };
+    let available = tch::Cuda::is_available() && (idx as i64) < tch::Cuda::device_count();
+    if available {
+        return Ok((requested, None));
+    }
+    if allow_fallback {
+        tracing::warn!(
+            model,
+            requested = %device_label(requested),
+            "CUDA device unavailable, FALLING BACK TO CPU; benchmark numbers will not reflect GPU performance"
+        );
+        Ok((
+            Device::Cpu,
+            Some(format!("{} is not available", device_label(requested))),
+        ))
+    } else {
+        Err(ServiceError::Other(format!(
+            "{model} model requested {} but it is not available \
+             (set ALLOW_DEVICE_FALLBACK=true to run on CPU instead)",
+            device_label(requested)
+        )))
+    }
+}
+
+fn load_error(name: &str, path: &Path, device: Device, err: tch::TchError) -> ServiceError {
+    if is_cuda_oom(&err.to_string()) {
+        return classify_tch_error(name, device, err, None);
+    }
+    let hint = if matches!(device, Device::Cuda(_)) {
+        " (set ALLOW_DEVICE_FALLBACK=true to retry on CPU)"
+    } else {
+        ""
+    };
+    ServiceError::Other(format!(
+        "failed to load {name} model {} on {}: {err}{hint}",
+        path.display(),
+        device_label(device)
+    ))
+}
+
+pub fn device_label(device: Device) -> String {
+    match device {
+        Device::Cpu => "cpu".to_string(),
+        Device::Cuda(idx) => format!("cuda:{idx}"),
+        other => format!("{other:?}").to_lowercase(),
+    }
+}
+
+impl ModelInstance {
+    pub fn new(
+        name: &str,
+        quantized: bool,
+        module_path: &Path,
+        options: LoadOptions<'_>,
+    ) -> Result<Self, ServiceError> {
+        let LoadOptions {
+            mut device,
+            allow_device_fallback,
+            mut device_fallback_reason,
+            max_context_tokens,
+            expected_sha256,
+            model_kind,
+            eos_token_id,
+            decoder_start_token_id,
+            mut dtype,
+        } = options;
+        if !module_path.exists() {
+            return Err(ServiceError::Other(format!(
+                "model artifact missing: {}",
+                module_path.display()
+            )));
+        }
+        let size_bytes = fs::metadata(module_path)?.len();
+        let sha256 = verify_sha256(module_path, expected_sha256)?;
+        let load_started = Instant::now();
+        let mut module = match tch::CModule::load_on_device(module_path, device) {
+            Ok(module) => module,
+            Err(err) if matches!(device, Device::Cuda(_)) && allow_device_fallback => {
+                let reason = format!("loading on {} failed: {err}", device_label(device));
+                tracing::warn!(
+                    model = name,
+                    artifact = %module_path.display(),
+                    %reason,
+                    "CUDA load failed, FALLING BACK TO CPU; benchmark numbers will not reflect GPU performance"
+                );
+                device = Device::Cpu;
+                device_fallback_reason = Some(reason);
+                tch::CModule::load_on_device(module_path, device)
+                    .map_err(|e| load_error(name, module_path, device, e))?
+            }
+            Err(err) => return Err(load_error(name, module_path, device, err)),
+        };
+        if !quantized && dtype != ModelDtype::Float32 {
+            if dtype == ModelDtype::Float16 && device == Device::Cpu {
+                // Only reachable after a CUDA fallback; validation refuses
+                // float16 on a configured CPU.
+                tracing::warn!(
+                    model = name,
+                    "float16 is poorly supported on CPU; keeping float32 after the device fallback"
+                );
+                dtype = ModelDtype::Float32;
+            } else {
+                module.to(device, dtype_kind(dtype), false);
+            }
+        }
+        let load_time = load_started.elapsed();
+        module.set_eval();
+        let num_parameters = count_parameters(name, &module);
+        let (signature, probe) =
+            detect_signature(&module, name, device, model_kind).map_err(|err| match dtype {
+                ModelDtype::Float32 => err,
+                _ => ServiceError::Other(format!(
+                    "{name} model does not run in {dtype} on {}: {err}",
+                    device_label(device)
+                )),
+            })?;
+        let layout = OutputLayout::classify(&probe).map_err(|observed| {
+            ServiceError::Other(format!(
+                "{name} model {} returns an unusable output from {}: expected a \
+                 [batch, seq_len, vocab] logits tensor, a tuple starting with one, or a dict \
+                 with a \"logits\" entry; got {observed}",
+                module_path.display(),
+                signature.describe()
+            ))
+        })?;
+        tracing::debug!(model = name, ?layout, "detected output layout");
+        let vocab_size = layout.logits(&probe)?.size().last().copied().unwrap_or(0) as usize;
+        // A seq2seq tuple's trailing tensor is the encoder's, not usable by /embed.
+        let hidden_states =
+            model_kind == ModelKind::Causal && output_hidden_states(&probe).is_some();
+
+        Ok(Self {
+            name: name.to_string(),
+            quantized,
+            dtype: if quantized {
+                "qint8".to_string()
+            } else {
+                dtype.to_string()
+            },
+            size_bytes,
+            sha256,
+            device,
+            max_context_tokens,
+            device_fallback_reason,
+            load_time,
+            num_parameters,
+            vocab_size,
+            hidden_states,
+            model_kind,
+            eos_token_id,
+            decoder_start_token_id,
+            signature,
+            layout,
+            warmup_latencies: Vec::new(),
+            self_test: None,
+            vocabulary: None,
+            module: Mutex::new(module),
+        })
+    }
+
+    /// One forward pass over the unfinished sequences of a batch, right-padded
+    /// to the longest, picking each one's next token.
+    fn batch_step(
+        &self,
+        module: &tch::CModule,
+        rows: &mut [(usize, Decoding<'_>)],
+    ) -> Result<Vec<PickedToken>, ServiceError> {
+        let width = rows
+            .iter()
+            .map(|(_, decoding)| decoding.input_ids.len())
+            .max()
+            .unwrap_or(0);
+        let mut ids = Vec::with_capacity(rows.len() * width);
+        let mut mask = Vec::with_capacity(rows.len() * width);
+        for (_, decoding) in rows.iter() {
+            let padding = width - decoding.input_ids.len();
+            ids.extend_from_slice(&decoding.input_ids);
+            ids.extend(std::iter::repeat_n(0i64, padding));
+            mask.extend(std::iter::repeat_n(1i64, decoding.input_ids.len()));
+            mask.extend(std::iter::repeat_n(0i64, padding));
+        }
+        let shape = [rows.len() as i64, width as i64];
+        let ids = Tensor::from_slice(&ids).reshape(shape).to(self.device);
+        let mask = Tensor::from_slice(&mask).reshape(shape).to(self.device);
+        let output = run_forward_batch(module, &self.name, self.device, self.signature, ids, mask)?;
+        let logits = self.layout.logits(&output)?;
+        rows.iter_mut()
+            .enumerate()
+            .map(|(row, (_, decoding))| {
+                let last = decoding.input_ids.len() as i64 - 1;
+                let params = decoding.params();
+                pick_token(
+                    &logits.select(0, row as i64).select(0, last),
+                    params,
+                    &mut decoding.rng,
+                    decoding.json.as_mut(),
+                )
+            })
+            .collect()
+    }
+}
+
+impl Backend for ModelInstance {
+    fn load(config: &AppConfig, slot: ModelSlot, path: &Path) -> Result<Self, ServiceError> {
+        let (requested, dtype, expected_sha256) = match slot {
+            ModelSlot::Baseline => (
+                config.baseline_device,
+                config.baseline_dtype,
+                config.baseline_module_sha256.as_deref(),
+            ),
+            ModelSlot::Quantized => (
+                config.quantized_device,
+                ModelDtype::Float32,
+                config.quantized_module_sha256.as_deref(),
+            ),
+        };
+        let (device, device_fallback_reason) =
+            resolve_device(slot.name(), requested, config.allow_device_fallback)?;
+        Self::new(
+            slot.name(),
+            slot == ModelSlot::Quantized,
+            path,
+            LoadOptions {
+                device,
+                allow_device_fallback: config.allow_device_fallback,
+                device_fallback_reason,
+                max_context_tokens: config.max_context_tokens,
+                expected_sha256,
+                model_kind: config.model_kind(slot),
+                eos_token_id: config.eos_token_id(config.model_kind(slot)),
+                decoder_start_token_id: config.decoder_start_token_id,
+                dtype,
+            },
+        )
+    }
+
+    fn metadata(&self) -> ModelMetadata {
+        ModelMetadata {
+            name: self.name.clone(),
+            quantized: self.quantized,
+            dtype: self.dtype.clone(),
+            size_bytes: self.size_bytes,
+            sha256: self.sha256.clone(),
+            device: device_label(self.device),
+            device_fallback_reason: self.device_fallback_reason.clone(),
+            max_context_tokens: self.max_context_tokens,
+            model_kind: self.model_kind,
+            eos_token_id: self.eos_token_id,
+            decoder_start_token_id: (self.model_kind == ModelKind::Seq2Seq)
+                .then_some(self.decoder_start_token_id),
+            load_time_ms: as_ms(self.load_time),
+            num_parameters: self.num_parameters,
+            vocab_size: self.vocab_size,
+            hidden_states: self.hidden_states,
+            warmup_latency_ms: self.warmup_latencies.iter().copied().map(as_ms).collect(),
+            self_test: self.self_test.clone(),
+            replicas: Vec::new(),
+        }
+    }
+
+    fn generate(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: Option<&mut TokenCallback>,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let decoding = Decoding::new(
+            self.metadata(),
+            tokenizer,
+            self.vocabulary.as_deref(),
+            prompt,
+            params,
+        )?;
+        generate_tokens(
+            decoding,
+            tokenizer,
+            on_token,
+            |encoder_ids| {
+                let encoder_ids = (self.model_kind == ModelKind::Seq2Seq).then(|| {
+                    Tensor::from_slice(encoder_ids)
+                        .reshape([1, encoder_ids.len() as i64])
+                        .to(self.device)
+                });
+                let module = lock_within(&self.module, params.max_lock_wait)?;
+                Ok((tch::no_grad_guard(), module, encoder_ids))
+            },
+            |(_, module, encoder_ids), input_ids, rng, json| {
+                let logits = match encoder_ids {
+                    Some(encoder_ids) => self.layout.logits(&run_seq2seq(
+                        module,
+                        &self.name,
+                        self.device,
+                        encoder_ids,
+                        input_ids,
+                    )?)?,
+                    None => forward_logits(
+                        module,
+                        &self.name,
+                        self.device,
+                        self.signature,
+                        self.layout,
+                        input_ids,
+                    )?,
+                };
+                // Logits for the last position: [1, seq_len, vocab] -> [vocab]
+                pick_token(&logits.select(1, -1).squeeze(), params, rng, json)
+            },
+        )
+    }
+
+    fn supports_batching(&self) -> bool {
+        self.model_kind == ModelKind::Causal
+    }
+
+    /// Decodes the batch in lockstep, one forward pass over all unfinished
+    /// sequences per step. Sequences are right-padded: under causal
+    /// attention a token never sees the padding after it, and its position
+    /// is the same as when run alone, so each sequence gets the logits it
+    /// would get unbatched. Finished sequences leave the batch.
+    fn generate_batch(
+        &self,
+        tokenizer: &Tokenizer,
+        requests: &[(String, GenerationParams)],
+    ) -> Vec<Result<GenerationResponse, ServiceError>> {
+        let mut results: Vec<Option<Result<GenerationResponse, ServiceError>>> =
+            requests.iter().map(|_| None).collect();
+        let mut active = Vec::with_capacity(requests.len());
+        for (index, (prompt, params)) in requests.iter().enumerate() {
+            match Decoding::new(
+                self.metadata(),
+                tokenizer,
+                self.vocabulary.as_deref(),
+                prompt,
+                params,
+            ) {
+                Ok(decoding) if params.max_new_tokens > 0 => active.push((index, decoding)),
+                Ok(decoding) => results[index] = Some(decoding.finish(tokenizer)),
+                Err(err) => results[index] = Some(Err(err)),
+            }
+        }
+
+        let max_wait = active
+            .iter()
+            .filter_map(|(_, decoding)| decoding.params().max_lock_wait)
+            .min();
+        match lock_within(&self.module, max_wait) {
+            Ok(module) => {
+                let _no_grad = tch::no_grad_guard();
+                let _span =
+                    tracing::info_span!("batch_decode", batch_size = active.len()).entered();
+                active
+                    .iter_mut()
+                    .for_each(|(_, decoding)| decoding.acquired());
+                while !active.is_empty() {
+                    let mut running = Vec::with_capacity(active.len());
+                    for (index, mut decoding) in active {
+                        match decoding.check_timeout() {
+                            Ok(true) => running.push((index, decoding)),
+                            Ok(false) => results[index] = Some(decoding.finish(tokenizer)),
+                            Err(err) => results[index] = Some(Err(err)),
+                        }
+                    }
+                    active = running;
+                    if active.is_empty() {
+                        break;
+                    }
+                    let picks = match self.batch_step(&module, &mut active) {
+                        Ok(picks) => picks,
+                        Err(err) => {
+                            for (index, _) in active.drain(..) {
+                                results[index] = Some(Err(err.clone()));
+                            }
+                            break;
+                        }
+                    };
+                    let mut still_running = Vec::with_capacity(active.len());
+                    for ((index, mut decoding), (id, logprob, top)) in active.into_iter().zip(picks)
+                    {
+                        if decoding.push(id, logprob, top, None) {
+                            still_running.push((index, decoding));
+                        } else {
+                            results[index] = Some(decoding.finish(tokenizer));
+                        }
+                    }
+                    active = still_running;
+                }
+            }
+            Err(err) => {
+                for (index, _) in active {
+                    results[index] = Some(Err(err.clone()));
+                }
+            }
+        }
+        results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    Err(ServiceError::Inference(
+                        "batched generation lost a request".into(),
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    fn set_warmup_latencies(&mut self, latencies: Vec<Duration>) {
+        self.warmup_latencies = latencies;
+    }
+
+    fn set_self_test(&mut self, report: SelfTestReport) {
+        self.self_test = Some(report);
+    }
+
+    fn set_vocabulary(&mut self, vocabulary: Arc<TokenVocabulary>) {
+        self.vocabulary = Some(vocabulary);
+    }
+
+    /// Scores each continuation with one teacher-forced forward pass over
+    /// prompt + continuation, summing the log-probabilities the model assigns
+    /// to the continuation's tokens.
+    fn score(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        continuations: &[String],
+    ) -> Result<ScoreResponse, ServiceError> {
+        if self.model_kind == ModelKind::Seq2Seq {
+            return Err(ServiceError::NotImplemented(format!(
+                "the {} model is seq2seq; /score supports causal models only",
+                self.name
+            )));
+        }
+        if prompt.trim().is_empty() {
+            return Err(ServiceError::validation("prompt", "must not be empty"));
+        }
+        if continuations.is_empty() {
+            return Err(ServiceError::validation(
+                "continuations",
+                "must contain at least one entry",
+            ));
+        }
+
+        let start = Instant::now();
+        let prompt_ids: Vec<i64> = tokenizer
+            .encode(prompt, true)
+            .map_err(|e| ServiceError::Tokenizer(e.to_string()))?
+            .get_ids()
+            .iter()
+            .map(|&id| id as i64)
+            .collect();
+        if prompt_ids.is_empty() {
+            return Err(ServiceError::validation("prompt", "encodes to no tokens"));
+        }
+
+        let mut scores = Vec::with_capacity(continuations.len());
+        for (idx, continuation) in continuations.iter().enumerate() {
+            let targets: Vec<i64> = tokenizer
+                .encode(continuation.as_str(), false)
+                .map_err(|e| ServiceError::Tokenizer(e.to_string()))?
+                .get_ids()
+                .iter()
+                .map(|&id| id as i64)
+                .collect();
+            if targets.is_empty() {
+                return Err(ServiceError::validation(
+                    "continuations",
+                    format!("entry {idx} encodes to no tokens"),
+                ));
+            }
+            check_context_fits(prompt_ids.len(), targets.len(), self.max_context_tokens).map_err(
+                |_| {
+                    ServiceError::validation(
+                        "continuations",
+                        format!(
+                            "entry {idx}: prompt ({}) plus continuation ({}) tokens exceed the \
+                             {}-token context window",
+                            prompt_ids.len(),
+                            targets.len(),
+                            self.max_context_tokens
+                        ),
+                    )
+                },
+            )?;
+
+            let mut input_ids = prompt_ids.clone();
+            input_ids.extend_from_slice(&targets);
+            let logprobs = no_grad(|| {
+                let module = self.module.lock();
+                let logits = forward_logits(
+                    &module,
+                    &self.name,
+                    self.device,
+                    self.signature,
+                    self.layout,
+                    &input_ids,
+                )?;
+                // The logits at position i predict token i + 1, so the
+                // continuation is scored from the last prompt position on.
+                let target_tensor = Tensor::from_slice(&targets).to(self.device).unsqueeze(1);
+                let gathered = logits
+                    .log_softmax(-1, Kind::Float)
+                    .select(0, 0)
+                    .narrow(0, prompt_ids.len() as i64 - 1, targets.len() as i64)
+                    .gather(1, &target_tensor, false)
+                    .squeeze_dim(1)
+                    .to(Device::Cpu);
+                Vec::<f32>::try_from(gathered).map_err(|e| {
+                    classify_tch_error(&self.name, self.device, e, Some(input_ids.len()))
+                })
+            })?;
+
+            let sum_logprob: f64 = logprobs.iter().map(|&lp| lp as f64).sum();
+            scores.push(ContinuationScore {
+                continuation: continuation.clone(),
+                tokens: targets.len(),
+                sum_logprob,
+                mean_logprob: sum_logprob / targets.len() as f64,
+            });
+        }
+
+        Ok(ScoreResponse {
+            prompt_tokens: prompt_ids.len(),
+            scores,
+            total_time_ms: start.elapsed().as_millis(),
+            model: self.metadata(),
+        })
+    }
+
+    /// Pools the final hidden layer into one vector per text, running all
+    /// texts as a single right-padded batch.
+    fn embed(
+        &self,
+        tokenizer: &Tokenizer,
+        texts: &[String],
+        pooling: Pooling,
+    ) -> Result<EmbedResponse, ServiceError> {
+        if !self.hidden_states {
+            return Err(ServiceError::NotImplemented(format!(
+                "the {} model's trace does not output hidden states; re-export it with \
+                 output_hidden_states=True to use /embed",
+                self.name
+            )));
+        }
+        if texts.is_empty() {
+            return Err(ServiceError::validation(
+                "texts",
+                "must contain at least one entry",
+            ));
+        }
+
+        let start = Instant::now();
+        let mut sequences = Vec::with_capacity(texts.len());
+        for (idx, text) in texts.iter().enumerate() {
+            let ids: Vec<i64> = tokenizer
+                .encode(text.as_str(), true)
+                .map_err(|e| ServiceError::Tokenizer(e.to_string()))?
+                .get_ids()
+                .iter()
+                .map(|&id| id as i64)
+                .collect();
+            if ids.is_empty() {
+                return Err(ServiceError::validation(
+                    "texts",
+                    format!("entry {idx} encodes to no tokens"),
+                ));
+            }
+            if ids.len() > self.max_context_tokens {
+                return Err(ServiceError::validation(
+                    "texts",
+                    format!(
+                        "entry {idx} is {} tokens, more than the {}-token context window",
+                        ids.len(),
+                        self.max_context_tokens
+                    ),
+                ));
+            }
+            sequences.push(ids);
+        }
+
+        let batch = sequences.len();
+        let max_len = sequences.iter().map(Vec::len).max().unwrap_or(0);
+        let lengths: Vec<usize> = sequences.iter().map(Vec::len).collect();
+        let mut ids = Vec::with_capacity(batch * max_len);
+        let mut mask = Vec::with_capacity(batch * max_len);
+        for sequence in &sequences {
+            ids.extend_from_slice(sequence);
+            ids.resize(ids.len() + max_len - sequence.len(), 0);
+            mask.extend(std::iter::repeat_n(1i64, sequence.len()));
+            mask.resize(mask.len() + max_len - sequence.len(), 0);
+        }
+        let shape = [batch as i64, max_len as i64];
+
+        let (flat, dimensions) = no_grad(|| {
+            let input_ids = Tensor::from_slice(&ids).reshape(shape).to(self.device);
+            let attention_mask = Tensor::from_slice(&mask).reshape(shape).to(self.device);
+            let module = self.module.lock();
+            // Padding sits after each text, so with a causal model it never
+            // changes the hidden states of the real tokens even when the trace
+            // takes no mask.
+            let output = run_forward_batch(
+                &module,
+                &self.name,
+                self.device,
+                self.signature,
+                input_ids,
+                attention_mask.shallow_clone(),
+            )?;
+            drop(module);
+            let hidden = output_hidden_states(&output)
+                .ok_or_else(|| {
+                    ServiceError::Inference("model output is missing hidden states".into())
+                })?
+                .to_kind(Kind::Float);
+            let dimensions = hidden.size().last().copied().unwrap_or(0);
+
+            let pooled = match pooling {
+                Pooling::Mean => {
+                    let weights = attention_mask.to_kind(Kind::Float).unsqueeze(1);
+                    let counts: Vec<f32> = lengths.iter().map(|&len| len as f32).collect();
+                    let counts = Tensor::from_slice(&counts)
+                        .reshape([batch as i64, 1])
+                        .to(self.device);
+                    weights.matmul(&hidden).squeeze_dim(1) / counts
+                }
+                Pooling::Last => {
+                    let last: Vec<i64> = lengths.iter().map(|&len| len as i64 - 1).collect();
+                    let index = Tensor::from_slice(&last)
+                        .reshape([batch as i64, 1, 1])
+                        .to(self.device)
+                        .expand([batch as i64, 1, dimensions], false);
+                    hidden.gather(1, &index, false).squeeze_dim(1)
+                }
+            };
+            let flat = Vec::<f32>::try_from(pooled.to(Device::Cpu).reshape([-1]))
+                .map_err(|e| classify_tch_error(&self.name, self.device, e, Some(max_len)))?;
+            Ok::<_, ServiceError>((flat, dimensions as usize))
+        })?;
+
+        let embeddings = if dimensions == 0 {
+            vec![Vec::new(); batch]
+        } else {
+            flat.chunks(dimensions).map(<[f32]>::to_vec).collect()
+        };
+        Ok(EmbedResponse {
+            embeddings,
+            dimensions,
+            pooling,
+            tokens: lengths,
+            total_time_ms: start.elapsed().as_millis(),
+            model: self.metadata(),
+        })
+    }
+
+    fn prompt_activations(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+    ) -> Result<PromptActivations, ServiceError> {
+        if self.model_kind == ModelKind::Seq2Seq {
+            return Err(ServiceError::NotImplemented(format!(
+                "the {} model is seq2seq; quantization analysis supports causal models only",
+                self.name
+            )));
+        }
+        let input_ids: Vec<i64> = tokenizer
+            .encode(prompt, true)
+            .map_err(|e| ServiceError::Tokenizer(e.to_string()))?
+            .get_ids()
+            .iter()
+            .map(|&id| id as i64)
+            .collect();
+        if input_ids.is_empty() {
+            return Err(ServiceError::validation(
+                "prompts",
+                "a prompt encodes to no tokens",
+            ));
+        }
+        if input_ids.len() > self.max_context_tokens {
+            return Err(ServiceError::validation(
+                "prompts",
+                format!(
+                    "a prompt is {} tokens, more than the {}-token context window",
+                    input_ids.len(),
+                    self.max_context_tokens
+                ),
+            ));
+        }
+
+        no_grad(|| {
+            let module = self.module.lock();
+            let output = run_forward(&module, &self.name, self.device, self.signature, &input_ids)?;
+            drop(module);
+            let to_vec = |tensor: Tensor| {
+                Vec::<f32>::try_from(tensor.to_kind(Kind::Float).to(Device::Cpu).reshape([-1]))
+                    .map_err(|e| {
+                        classify_tch_error(&self.name, self.device, e, Some(input_ids.len()))
+                    })
+            };
+            let logits = self.layout.logits(&output)?.select(0, 0);
+            let vocab_size = logits.size().last().copied().unwrap_or(0) as usize;
+            let layers = output_layer_states(&output);
+            let hidden_size = layers
+                .first()
+                .and_then(|layer| layer.size().last().copied())
+                .unwrap_or(0) as usize;
+            Ok(PromptActivations {
+                tokens: input_ids.len(),
+                vocab_size,
+                logits: to_vec(logits)?,
+                hidden_size,
+                hidden_states: layers
+                    .into_iter()
+                    .map(|layer| to_vec(layer.select(0, 0)))
+                    .collect::<Result<_, _>>()?,
+            })
+        })
+    }
+}
+
+fn is_cuda_oom(message: &str) -> bool {
+    let lower = message.to_lowercase();
+    lower.contains("cuda out of memory")
+        || lower.contains("cuda error: out of memory")
+        || lower.contains("cublas_status_alloc_failed")
+        || (lower.contains("out of memory") && lower.contains("cuda"))
+}
+
+/// Maps a LibTorch failure onto a service error, singling out CUDA OOM so
+/// callers get a retryable 503 instead of a generic 500.
+fn classify_tch_error(
+    model: &str,
+    device: Device,
+    err: tch::TchError,
+    sequence_length: Option<usize>,
+) -> ServiceError {
+    let message = err.to_string();
+    if !is_cuda_oom(&message) {
+        return ServiceError::Inference(message);
+    }
+
+    let total = record_cuda_oom();
+    tracing::warn!(
+        cuda_oom = true,
+        model,
+        ?device,
+        sequence_length,
+        total,
+        "CUDA out of memory"
+    );
+
+    // Intermediate tensors are scoped to the failed forward pass and have
+    // already been dropped by the time we get here; tch has no binding for
+    // `empty_cache`, so synchronizing lets the caching allocator reclaim the
+    // freed blocks before the next request arrives.
+    if let Device::Cuda(idx) = device {
+        tch::Cuda::synchronize(idx as i64);
+    }
+
+    ServiceError::ResourceExhausted {
+        message: format!("CUDA out of memory while running {model}"),
+        sequence_length,
+        retry_after_secs: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use super::*;
+    use crate::model::testing::{GPT2_VOCAB_SIZE, gpt2, greedy};
+
+    type Forward = fn(&[Tensor]) -> Tensor;
+
+    /// Traces `forward` over `inputs` example id tensors and saves it as a
+    /// TorchScript module, like an exported GPT-2 with that signature.
+    fn trace(name: &str, inputs: usize, forward: Forward) -> PathBuf {
+        let example: Vec<Tensor> = (0..inputs)
+            .map(|_| Tensor::ones([1, 3], (Kind::Int64, Device::Cpu)))
+            .collect();
+        let module =
+            tch::CModule::create_by_tracing("fixture", "forward", &example, &mut |inputs| {
+                vec![forward(inputs)]
+            })
+            .unwrap();
+        let path = env::temp_dir().join(format!("qls-tch-{}-{name}.pt", process::id()));
+        module.save(&path).unwrap();
+        path
+    }
+
+    /// Logits picking the token after each input id.
+    fn next_token(input_ids: &Tensor) -> Tensor {
+        (input_ids + 1)
+            .one_hot(GPT2_VOCAB_SIZE as i64)
+            .to_kind(Kind::Float)
+    }
+
+    fn ids_only(inputs: &[Tensor]) -> Tensor {
+        next_token(&inputs[0])
+    }
+
+    fn ids_and_mask(inputs: &[Tensor]) -> Tensor {
+        next_token(&inputs[0]) * inputs[1].unsqueeze(-1).to_kind(Kind::Float)
+    }
+
+    fn three_inputs(inputs: &[Tensor]) -> Tensor {
+        next_token(&(&inputs[0] + &inputs[1] + &inputs[2]))
+    }
+
+    fn generated_ids(model: &ModelInstance) -> Vec<u32> {
+        let params = GenerationParams {
+            token_details: true,
+            ..greedy(3)
+        };
+        let response = model.generate(&gpt2(), "Hello", &params, None).unwrap();
+        response
+            .token_details
+            .unwrap()
+            .iter()
+            .map(|detail| detail.id)
+            .collect()
+    }
+
+    #[test]
+    fn both_gpt2_signatures_are_detected_and_generate() {
+        let hello = gpt2().encode("Hello", false).unwrap().get_ids()[0];
+        let fixtures: [(&str, usize, Forward, ForwardSignature); 2] = [
+            ("ids", 1, ids_only, ForwardSignature::InputIds),
+            ("mask", 2, ids_and_mask, ForwardSignature::InputIdsAndMask),
+        ];
+        for (name, inputs, forward, signature) in fixtures {
+            let path = trace(name, inputs, forward);
+            let model =
+                ModelInstance::load(&AppConfig::default(), ModelSlot::Baseline, &path).unwrap();
+            assert_eq!(model.signature, signature, "{name}");
+            assert_eq!(model.metadata().vocab_size, GPT2_VOCAB_SIZE, "{name}");
+            assert_eq!(
+                generated_ids(&model),
+                [hello + 1, hello + 2, hello + 3],
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn an_unrecognized_signature_lists_what_was_tried() {
+        let path = trace("three", 3, three_inputs);
+        let err = ModelInstance::load(&AppConfig::default(), ModelSlot::Baseline, &path)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("unrecognized forward signature"), "{err}");
+        assert!(err.contains("forward(input_ids): "), "{err}");
+        assert!(
+            err.contains("forward(input_ids, attention_mask): "),
+            "{err}"
+        );
+    }
+
+    /// A T5-shaped trace: each decoder position picks the token after its
+    /// id plus the first encoder id.
+    fn encoder_decoder(inputs: &[Tensor]) -> Tensor {
+        next_token(&(&inputs[1] + &inputs[0].narrow(1, 0, 1)))
+    }
+
+    #[test]
+    fn seq2seq_traces_decode_greedily_from_the_start_token() {
+        let hello = i64::from(gpt2().encode("Hello", false).unwrap().get_ids()[0]);
+        let path = trace("seq2seq", 2, encoder_decoder);
+        let mut config = AppConfig {
+            baseline_model_kind: ModelKind::Seq2Seq,
+            decoder_start_token_id: 5,
+            ..AppConfig::default()
+        };
+        let model = ModelInstance::load(&config, ModelSlot::Baseline, &path).unwrap();
+        assert_eq!(model.signature, ForwardSignature::EncoderDecoder);
+        let metadata = model.metadata();
+        assert_eq!(metadata.model_kind, ModelKind::Seq2Seq);
+        assert_eq!(metadata.decoder_start_token_id, Some(5));
+        let golden = [6 + hello, 7 + 2 * hello, 8 + 3 * hello].map(|id| id as u32);
+        assert_eq!(generated_ids(&model), golden);
+
+        // Generation stops at the model's own end-of-sequence id.
+        config.eos_token_id = Some(7 + 2 * hello);
+        let model = ModelInstance::load(&config, ModelSlot::Baseline, &path).unwrap();
+        assert_eq!(generated_ids(&model), golden[..2]);
+    }
+
+    fn logits(shape: &[i64]) -> tch::IValue {
+        tch::IValue::Tensor(Tensor::zeros(shape, (Kind::Float, Device::Cpu)))
+    }
+
+    fn dict(entries: Vec<(&str, tch::IValue)>) -> tch::IValue {
+        tch::IValue::GenericDict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (tch::IValue::String(key.into()), value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn outputs_are_classified_once_by_where_the_logits_are() {
+        let past = tch::IValue::Tuple(vec![logits(&[1, 2, 1, 4]), logits(&[1, 2, 1, 4])]);
+        let cases = [
+            (logits(&[1, 1, 50]), OutputLayout::Tensor),
+            (
+                tch::IValue::Tuple(vec![logits(&[1, 1, 50]), past]),
+                OutputLayout::Tuple,
+            ),
+            (
+                dict(vec![
+                    ("past_key_values", tch::IValue::None),
+                    ("logits", logits(&[1, 1, 50])),
+                ]),
+                OutputLayout::Dict,
+            ),
+        ];
+        for (output, layout) in cases {
+            assert_eq!(OutputLayout::classify(&output), Ok(layout));
+            assert_eq!(layout.logits(&output).unwrap().size(), [1, 1, 50]);
+        }
+    }
+
+    #[test]
+    fn a_dict_without_logits_is_described() {
+        let output = dict(vec![
+            ("scores", logits(&[1, 1, 50])),
+            ("hidden", tch::IValue::Int(3)),
+        ]);
+        assert_eq!(
+            OutputLayout::classify(&output),
+            Err(r#"Dict{"scores": Tensor[1, 1, 50], "hidden": Int(3)}"#.to_string())
+        );
+        // Logits of the wrong rank are no better than none.
+        let output = dict(vec![("logits", logits(&[1, 50]))]);
+        assert_eq!(
+            OutputLayout::classify(&output),
+            Err(r#"Dict{"logits": Tensor[1, 50]}"#.to_string())
+        );
+    }
+
+    fn last_position_only(inputs: &[Tensor]) -> Tensor {
+        next_token(&inputs[0]).select(1, -1)
+    }
+
+    #[test]
+    fn an_unusable_output_fails_the_load_with_its_structure() {
+        let path = trace("last", 1, last_position_only);
+        let err = ModelInstance::load(&AppConfig::default(), ModelSlot::Baseline, &path)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("returns an unusable output"), "{err}");
+        assert!(err.contains("got Tensor[1, 50257]"), "{err}");
+    }
+
+    #[test]
+    fn a_bfloat16_baseline_reports_its_dtype_and_still_generates() {
+        let hello = gpt2().encode("Hello", false).unwrap().get_ids()[0];
+        let path = trace("bf16", 1, ids_only);
+        let config = AppConfig {
+            baseline_dtype: ModelDtype::Bfloat16,
+            ..AppConfig::default()
+        };
+        let model = ModelInstance::load(&config, ModelSlot::Baseline, &path).unwrap();
+        assert_eq!(model.metadata().dtype, "bfloat16");
+        assert_eq!(generated_ids(&model), [hello + 1, hello + 2, hello + 3]);
+
+        // The dtype setting leaves a quantized module as it was exported.
+        let model = ModelInstance::load(&config, ModelSlot::Quantized, &path).unwrap();
+        assert_eq!(model.metadata().dtype, "qint8");
+    }
+}
@@ -0,0 +1,349 @@
+//! Fixtures for the model tests: the GPT-2 tokenizer shipped in `models/`
+//! and a stand-in backend that runs the shared decode loop over logits
+//! computed on the CPU.
+
+use std::{
+    env, fs,
+    path::Path,
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rand::{SeedableRng, rngs::StdRng};
+use tokenizers::Tokenizer;
+
+use crate::{
+    config::AppConfig,
+    error::ServiceError,
+    model::{
+        ContinuationScore, EmbedResponse, GenerationParams, GenerationRequest, GenerationResponse,
+        ModelKind, ModelMetadata, ModelRegistry, ModelSlot, Pooling, ScoreResponse, SelfTestReport,
+        TokenCallback, TokenVocabulary,
+        backend::{Backend, Decoding, as_ms, generate_tokens},
+        loader::ArtifactLoader,
+    },
+};
+
+pub(crate) const GPT2_TOKENIZER: &str =
+    concat!(env!("CARGO_MANIFEST_DIR"), "/models/tokenizer.json");
+pub(crate) const GPT2_VOCAB_SIZE: usize = 50257;
+pub(crate) const GPT2_EOS: i64 = 50256;
+
+pub(crate) fn gpt2() -> Tokenizer {
+    Tokenizer::from_file(GPT2_TOKENIZER).expect("models/tokenizer.json loads")
+}
+
+/// Greedy settings for `max_new_tokens` tokens, otherwise the defaults.
+pub(crate) fn greedy(max_new_tokens: usize) -> GenerationParams {
+    let request = GenerationRequest {
+        max_new_tokens: Some(max_new_tokens),
+        temperature: Some(0.0),
+        ..GenerationRequest::default()
+    };
+    GenerationParams::resolve(&request, &AppConfig::default())
+}
+
+/// The phases in `timings` make up `total_time_ms`, give or take the
+/// millisecond it is rounded down to.
+pub(crate) fn assert_timings_add_up(response: &GenerationResponse) {
+    let timings = &response.timings;
+    let sum = timings.tokenize_ms
+        + timings.queue_wait_ms
+        + timings.time_to_first_token_ms
+        + timings.decode_ms;
+    assert!(
+        (sum - response.total_time_ms as f64).abs() <= 1.0,
+        "{timings:?} sum to {sum} ms, total is {} ms",
+        response.total_time_ms
+    );
+}
+
+/// Serves fake module files for both slots, written to a scratch directory
+/// unique to `name`, with the GPT-2 tokenizer. Without `quantized` its file
+/// is missing, so only the baseline loads.
+pub(crate) fn fake_config(name: &str, quantized: bool) -> AppConfig {
+    let dir = env::temp_dir().join(format!("qls-{}-{name}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let baseline_module_path = dir.join("baseline.pt");
+    let quantized_module_path = dir.join("quantized.pt");
+    fs::write(&baseline_module_path, "").unwrap();
+    if quantized {
+        fs::write(&quantized_module_path, "").unwrap();
+    } else {
+        let _ = fs::remove_file(&quantized_module_path);
+    }
+    AppConfig {
+        tokenizer_path: GPT2_TOKENIZER.into(),
+        baseline_module_path,
+        quantized_module_path,
+        warmup_iters: 1,
+        ..AppConfig::default()
+    }
+}
+
+/// A registry serving [`FakeModel`]s from `config`'s module paths, on
+/// reload as well.
+pub(crate) fn fake_registry(config: &AppConfig) -> ModelRegistry {
+    ModelRegistry::with_loader(config, ArtifactLoader::with::<FakeModel>())
+        .expect("fake models load")
+}
+
+/// Scores every token given the sequence so far and the generation's rng.
+pub(crate) type Logits = fn(&[i64], &mut StdRng) -> Vec<f32>;
+
+/// Always continues with the token after the last one, never ending early.
+pub(crate) fn next_token(input_ids: &[i64], _rng: &mut StdRng) -> Vec<f32> {
+    let mut logits = vec![0.0; GPT2_VOCAB_SIZE];
+    let last = input_ids.last().copied().unwrap_or(0) as usize;
+    logits[(last + 1) % (GPT2_VOCAB_SIZE - 1)] = 1.0;
+    logits
+}
+
+/// Ends every generation with its first token.
+pub(crate) fn ends_at_once(_input_ids: &[i64], _rng: &mut StdRng) -> Vec<f32> {
+    let mut logits = vec![0.0; GPT2_VOCAB_SIZE];
+    logits[GPT2_EOS as usize] = 1.0;
+    logits
+}
+
+/// A causal model over the GPT-2 vocabulary that picks the best of
+/// `logits` at each step.
+pub(crate) struct FakeModel {
+    name: String,
+    logits: Logits,
+    step_delay: Duration,
+    size_bytes: u64,
+    vocabulary: Option<Arc<TokenVocabulary>>,
+    warmup_latencies: Vec<Duration>,
+    self_test: Option<SelfTestReport>,
+    /// Size of each batch `generate_batch` was handed.
+    pub(crate) batches: Arc<Mutex<Vec<usize>>>,
+}
+
+impl FakeModel {
+    pub(crate) fn new(name: &str, logits: Logits) -> Self {
+        Self {
+            name: name.to_string(),
+            logits,
+            step_delay: Duration::ZERO,
+            size_bytes: 0,
+            vocabulary: None,
+            warmup_latencies: Vec::new(),
+            self_test: None,
+            batches: Arc::default(),
+        }
+    }
+
+    /// Sleeps this long in every step, standing in for a forward pass.
+    pub(crate) fn with_step_delay(mut self, step_delay: Duration) -> Self {
+        self.step_delay = step_delay;
+        self
+    }
+}
+
+impl Backend for FakeModel {
+    /// Fails like a real backend, naming `path`, when the file is missing,
+    /// and reports the file's size as its own. A file holding a number
+    /// makes each step sleep that many milliseconds; one holding `eos` ends
+    /// every generation at once, like a broken export.
+    fn load(_config: &AppConfig, slot: ModelSlot, path: &Path) -> Result<Self, ServiceError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| ServiceError::Other(format!("{}: {err}", path.display())))?;
+        let model = if contents.trim() == "eos" {
+            Self::new(slot.name(), ends_at_once)
+        } else {
+            let step_delay = contents
+                .trim()
+                .parse()
+                .map_or(Duration::ZERO, Duration::from_millis);
+            Self::new(slot.name(), next_token).with_step_delay(step_delay)
+        };
+        Ok(Self {
+            size_bytes: contents.len() as u64,
+            ..model
+        })
+    }
+
+    fn metadata(&self) -> ModelMetadata {
+        ModelMetadata {
+            name: self.name.clone(),
+            quantized: false,
+            dtype: "float32".into(),
+            size_bytes: self.size_bytes,
+            sha256: String::new(),
+            device: "cpu".into(),
+            device_fallback_reason: None,
+            max_context_tokens: 1024,
+            model_kind: ModelKind::Causal,
+            eos_token_id: GPT2_EOS,
+            decoder_start_token_id: None,
+            load_time_ms: 0.0,
+            num_parameters: None,
+            vocab_size: GPT2_VOCAB_SIZE,
+            hidden_states: false,
+            warmup_latency_ms: self.warmup_latencies.iter().copied().map(as_ms).collect(),
+            self_test: self.self_test.clone(),
+            replicas: Vec::new(),
+        }
+    }
+
+    fn generate(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        params: &GenerationParams,
+        on_token: Option<&mut TokenCallback>,
+    ) -> Result<GenerationResponse, ServiceError> {
+        let decoding = Decoding::new(
+            self.metadata(),
+            tokenizer,
+            self.vocabulary.as_deref(),
+            prompt,
+            params,
+        )?;
+        generate_tokens(
+            decoding,
+            tokenizer,
+            on_token,
+            |_| Ok(()),
+            |_, input_ids, rng, json| {
+                thread::sleep(self.step_delay);
+                let mut logits = (self.logits)(input_ids, rng);
+                if let Some(json) = json {
+                    json.mask(&mut logits)?;
+                }
+                let best = (0..logits.len())
+                    .max_by(|&a, &b| logits[a].total_cmp(&logits[b]).then(b.cmp(&a)))
+                    .unwrap_or(0);
+                Ok((best as i64, None, Vec::new()))
+            },
+        )
+    }
+
+    fn supports_batching(&self) -> bool {
+        true
+    }
+
+    /// Teacher-forces each continuation through `logits`, one step per
+    /// token.
+    fn score(
+        &self,
+        tokenizer: &Tokenizer,
+        prompt: &str,
+        continuations: &[String],
+    ) -> Result<ScoreResponse, ServiceError> {
+        let start = Instant::now();
+        let prompt_ids = encode(tokenizer, prompt, true)?;
+        let mut rng = StdRng::seed_from_u64(0);
+        let scores = continuations
+            .iter()
+            .map(|continuation| {
+                let targets = encode(tokenizer, continuation, false)?;
+                let mut input_ids = prompt_ids.clone();
+                let mut sum_logprob = 0.0;
+                for &target in &targets {
+                    thread::sleep(self.step_delay);
+                    let logits = (self.logits)(&input_ids, &mut rng);
+                    sum_logprob += log_softmax(&logits)[target as usize];
+                    input_ids.push(target);
+                }
+                Ok(ContinuationScore {
+                    continuation: continuation.clone(),
+                    tokens: targets.len(),
+                    sum_logprob,
+                    mean_logprob: sum_logprob / targets.len().max(1) as f64,
+                })
+            })
+            .collect::<Result<_, ServiceError>>()?;
+        Ok(ScoreResponse {
+            prompt_tokens: prompt_ids.len(),
+            scores,
+            total_time_ms: start.elapsed().as_millis(),
+            model: self.metadata(),
+        })
+    }
+
+    /// Each token's hidden state is `[id, 1.0]`.
+    fn embed(
+        &self,
+        tokenizer: &Tokenizer,
+        texts: &[String],
+        pooling: Pooling,
+    ) -> Result<EmbedResponse, ServiceError> {
+        let start = Instant::now();
+        thread::sleep(self.step_delay);
+        let ids = texts
+            .iter()
+            .map(|text| encode(tokenizer, text, true))
+            .collect::<Result<Vec<_>, _>>()?;
+        let embeddings = ids
+            .iter()
+            .map(|ids| {
+                let pooled = match pooling {
+                    Pooling::Mean => ids.iter().sum::<i64>() as f32 / ids.len().max(1) as f32,
+                    Pooling::Last => ids.last().copied().unwrap_or(0) as f32,
+                };
+                vec![pooled, 1.0]
+            })
+            .collect();
+        Ok(EmbedResponse {
+            embeddings,
+            dimensions: 2,
+            pooling,
+            tokens: ids.iter().map(Vec::len).collect(),
+            total_time_ms: start.elapsed().as_millis(),
+            model: self.metadata(),
+        })
+    }
+
+    fn generate_batch(
+        &self,
+        tokenizer: &Tokenizer,
+        requests: &[(String, GenerationParams)],
+    ) -> Vec<Result<GenerationResponse, ServiceError>> {
+        self.batches.lock().push(requests.len());
+        requests
+            .iter()
+            .map(|(prompt, params)| self.generate(tokenizer, prompt, params, None))
+            .collect()
+    }
+
+    fn set_warmup_latencies(&mut self, latencies: Vec<Duration>) {
+        self.warmup_latencies = latencies;
+    }
+
+    fn set_self_test(&mut self, report: SelfTestReport) {
+        self.self_test = Some(report);
+    }
+
+    fn set_vocabulary(&mut self, vocabulary: Arc<TokenVocabulary>) {
+        self.vocabulary = Some(vocabulary);
+    }
+}
+
+fn encode(
+    tokenizer: &Tokenizer,
+    text: &str,
+    add_special_tokens: bool,
+) -> Result<Vec<i64>, ServiceError> {
+    let encoding = tokenizer
+        .encode(text, add_special_tokens)
+        .map_err(|e| ServiceError::Tokenizer(e.to_string()))?;
+    Ok(encoding.get_ids().iter().map(|&id| i64::from(id)).collect())
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f64> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let log_sum = logits
+        .iter()
+        .map(|&logit| (logit as f64 - max).exp())
+        .sum::<f64>()
+        .ln();
+    logits
+        .iter()
+        .map(|&logit| logit as f64 - max - log_sum)
+        .collect()
+}
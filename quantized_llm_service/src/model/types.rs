@@ -1,27 +1,685 @@
+use std::{collections::BTreeMap, fmt, time::Duration};
+
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Deserialize)]
+use crate::{
+    config::AppConfig,
+    error::ErrorPayload,
+    memory::DeviceMemory,
+    model::{ModelKind, admission::QueueLengths},
+    telemetry::LoggedText,
+};
+
+// Debug is written out below so prompt text never reaches a log by way of
+// `{:?}`.
+#[derive(Clone, Default, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
 pub struct GenerationRequest {
     pub prompt: String,
+    /// `baseline`, `quantized` or a configured alias; defaults to
+    /// `default_model`, then to the route's usual choice.
+    pub model: Option<String>,
     pub max_new_tokens: Option<usize>,
     pub temperature: Option<f64>,
     pub top_k: Option<usize>,
+    /// What to do when the prompt plus `max_new_tokens` exceeds the context
+    /// window; defaults to rejecting the request.
+    pub context_strategy: Option<ContextStrategy>,
+    /// Shorthand for `context_strategy: "truncate_left"`, kept for older
+    /// clients.
+    #[serde(default)]
+    pub truncate_prompt: bool,
+    /// Seeds sampling so the same request reproduces the same completion.
+    pub seed: Option<u64>,
+    /// Defaults to interactive.
+    pub priority: Option<Priority>,
+    /// Name of a configured prompt template to wrap `prompt` in.
+    pub template: Option<String>,
+    /// Fills the template's `{system}` placeholder.
+    pub system: Option<String>,
+    /// Name of a configured preset supplying the settings this request
+    /// leaves unset.
+    pub preset: Option<String>,
+    /// Let the tokenizer's post-processor add its special tokens to the
+    /// prompt; defaults to true.
+    pub add_special_tokens: Option<bool>,
+    /// Drop special tokens (e.g. `<|endoftext|>`) from the completion;
+    /// defaults to true.
+    pub skip_special_tokens: Option<bool>,
+    /// Include a `token_details` entry per generated token in the response.
+    pub return_token_details: Option<bool>,
+    /// What to return when the generation runs past `generation_timeout`;
+    /// defaults to an error.
+    pub on_timeout: Option<OnTimeout>,
+    /// `json` restricts the completion to a JSON object or array and ends
+    /// it once the value closes; defaults to `text`, and any other value is
+    /// a 422. A completion cut short by `max_new_tokens` or the timeout is
+    /// unfinished JSON, with `finish_reason` `length` or `timeout`.
+    pub response_format: Option<ResponseFormat>,
+    /// Label of the API key the request came in with, which its tokens are
+    /// charged to. Set by the server, never read from the body.
+    #[serde(skip)]
+    pub api_key: Option<String>,
+}
+
+impl GenerationRequest {
+    /// A request for `prompt` with every setting left to the server defaults.
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn with_max_new_tokens(mut self, max_new_tokens: usize) -> Self {
+        self.max_new_tokens = Some(max_new_tokens);
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f64) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = Some(top_k);
+        self
+    }
+
+    pub fn with_context_strategy(mut self, context_strategy: ContextStrategy) -> Self {
+        self.context_strategy = Some(context_strategy);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn with_template(mut self, template: impl Into<String>) -> Self {
+        self.template = Some(template.into());
+        self
+    }
+
+    pub fn with_system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    pub fn with_preset(mut self, preset: impl Into<String>) -> Self {
+        self.preset = Some(preset.into());
+        self
+    }
+
+    pub fn with_add_special_tokens(mut self, add_special_tokens: bool) -> Self {
+        self.add_special_tokens = Some(add_special_tokens);
+        self
+    }
+
+    pub fn with_skip_special_tokens(mut self, skip_special_tokens: bool) -> Self {
+        self.skip_special_tokens = Some(skip_special_tokens);
+        self
+    }
+
+    pub fn with_return_token_details(mut self, return_token_details: bool) -> Self {
+        self.return_token_details = Some(return_token_details);
+        self
+    }
+
+    pub fn with_on_timeout(mut self, on_timeout: OnTimeout) -> Self {
+        self.on_timeout = Some(on_timeout);
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.response_format = Some(response_format);
+        self
+    }
+}
+
+impl fmt::Debug for GenerationRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GenerationRequest")
+            .field("prompt", &LoggedText::redacted(&self.prompt))
+            .field("model", &self.model)
+            .field("max_new_tokens", &self.max_new_tokens)
+            .field("temperature", &self.temperature)
+            .field("top_k", &self.top_k)
+            .field("context_strategy", &self.context_strategy)
+            .field("truncate_prompt", &self.truncate_prompt)
+            .field("seed", &self.seed)
+            .field("priority", &self.priority)
+            .field("template", &self.template)
+            .field("system", &self.system.as_deref().map(LoggedText::redacted))
+            .field("preset", &self.preset)
+            .field("add_special_tokens", &self.add_special_tokens)
+            .field("skip_special_tokens", &self.skip_special_tokens)
+            .field("return_token_details", &self.return_token_details)
+            .field("on_timeout", &self.on_timeout)
+            .field("response_format", &self.response_format)
+            .field("api_key", &self.api_key)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+    /// Reject the request with a 400.
+    #[default]
+    Error,
+    /// Drop the oldest prompt tokens up front so the whole generation fits.
+    TruncateLeft,
+    /// Generate as asked, dropping the oldest tokens after the configured
+    /// sentinel prefix whenever the sequence outgrows the window.
+    SlidingWindow,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    /// Only tokens that keep the output valid JSON are sampled.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OnTimeout {
+    /// Fail with a 504.
+    #[default]
+    Error,
+    /// Return what was generated so far, with `finish_reason: "timeout"`.
+    Partial,
+}
+
+/// Why generation stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model produced its end-of-sequence token.
+    #[default]
+    Stop,
+    /// `max_new_tokens` were generated.
+    Length,
+    /// The caller stopped it, e.g. a streaming client went away.
+    Cancelled,
+    /// It ran past `generation_timeout`; the completion is partial.
+    Timeout,
+}
+
+/// Which admission queue a generation waits in; interactive requests are
+/// always let onto a model before batch ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Batch,
+}
+
+/// A request's settings with config defaults filled in.
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationParams {
+    pub max_new_tokens: usize,
+    pub temperature: f64,
+    pub top_k: usize,
+    pub context_strategy: ContextStrategy,
+    /// Leading prompt tokens a sliding window never evicts.
+    pub sentinel_tokens: usize,
+    pub seed: Option<u64>,
+    pub priority: Priority,
+    pub add_special_tokens: bool,
+    pub skip_special_tokens: bool,
+    pub measure_memory: bool,
+    pub token_details: bool,
+    /// Most likely alternatives listed per entry of `token_details`; 0
+    /// lists none.
+    pub top_logprobs: usize,
+    /// Abandon the generation once it has run this long.
+    pub timeout: Option<Duration>,
+    pub on_timeout: OnTimeout,
+    pub response_format: ResponseFormat,
+    /// Fail with `Overloaded` rather than wait longer than this for the
+    /// model behind other requests.
+    pub max_lock_wait: Option<Duration>,
+}
+
+impl GenerationParams {
+    pub fn resolve(request: &GenerationRequest, config: &AppConfig) -> Self {
+        Self {
+            max_new_tokens: request.max_new_tokens.unwrap_or(config.max_new_tokens),
+            temperature: request.temperature.unwrap_or(config.temperature),
+            top_k: request.top_k.unwrap_or(config.top_k),
+            context_strategy: request
+                .context_strategy
+                .unwrap_or(if request.truncate_prompt {
+                    ContextStrategy::TruncateLeft
+                } else {
+                    ContextStrategy::Error
+                }),
+            sentinel_tokens: config.context_sentinel_tokens,
+            seed: request.seed,
+            priority: request.priority.unwrap_or_default(),
+            add_special_tokens: request.add_special_tokens.unwrap_or(true),
+            skip_special_tokens: request.skip_special_tokens.unwrap_or(true),
+            measure_memory: config.measure_memory,
+            token_details: request.return_token_details.unwrap_or(false),
+            top_logprobs: 0,
+            timeout: (!config.generation_timeout.is_zero()).then_some(config.generation_timeout),
+            on_timeout: request.on_timeout.unwrap_or_default(),
+            response_format: request.response_format.unwrap_or_default(),
+            max_lock_wait: config.max_lock_wait(),
+        }
+    }
+
+    /// Greedy decoding and seeded sampling always produce the same output.
+    pub fn is_deterministic(&self) -> bool {
+        self.temperature <= 0.0 || self.top_k == 1 || self.seed.is_some()
+    }
+}
+
+// Debug is written out below, redacting the prompt and completion.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub struct GenerationResponse {
+    /// What the model was given, after any prompt template was applied.
     pub prompt: String,
+    /// The prompt as the client sent it.
+    pub raw_prompt: String,
     pub completion: String,
     pub tokens_generated: usize,
+    #[serde(default)]
+    pub finish_reason: FinishReason,
+    /// Sum of the phases in `timings`.
     pub total_time_ms: u128,
+    pub timings: GenerationTimings,
+    /// Deprecated: prompt plus generated tokens over the time since the
+    /// model was acquired, which mixes both phases. Use
+    /// `prefill_tokens_per_second` and `decode_tokens_per_second`.
+    #[schema(deprecated)]
     pub tokens_per_second: f64,
+    /// Prompt tokens over `timings.time_to_first_token_ms`; 0 when that is
+    /// too short to measure.
+    #[serde(default)]
+    pub prefill_tokens_per_second: f64,
+    /// Tokens sampled after the first over `timings.decode_ms`, the first
+    /// coming out of the prefill; 0 when at most one token was generated.
+    pub decode_tokens_per_second: f64,
+    pub usage: Usage,
+    /// Always the model that ran, even when the request named an alias.
     pub model: ModelMetadata,
+    /// The alias the request was resolved through, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub served_via_alias: Option<String>,
+    /// Served by the baseline because the quantized model the request would
+    /// have gone to is not loaded.
+    #[serde(default)]
+    pub fallback: bool,
+    /// The tokenizer cut the prompt; `usage.truncated_prompt_tokens` says
+    /// by how much.
+    #[serde(default)]
+    pub prompt_truncated: bool,
+    /// Served from the response cache; timings are those of the original run.
+    pub cached: bool,
+    /// The settings generation actually ran with, after request values were
+    /// merged with the server defaults.
+    pub params: EffectiveParams,
+    /// The generated ids, included only when tokens were generated but
+    /// decoding left the completion empty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generated_token_ids: Option<Vec<u32>>,
+    /// How far the process's resident memory rose above its level at the
+    /// start of the generation; set when memory measurement is on (Linux).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_rss_delta_bytes: Option<u64>,
+    /// One entry per generated token; set when `return_token_details` was
+    /// requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_details: Option<Vec<TokenDetail>>,
+}
+
+impl fmt::Debug for GenerationResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GenerationResponse")
+            .field("prompt", &LoggedText::redacted(&self.prompt))
+            .field("raw_prompt", &LoggedText::redacted(&self.raw_prompt))
+            .field("completion", &LoggedText::redacted(&self.completion))
+            .field("tokens_generated", &self.tokens_generated)
+            .field("finish_reason", &self.finish_reason)
+            .field("total_time_ms", &self.total_time_ms)
+            .field("timings", &self.timings)
+            .field("usage", &self.usage)
+            .field("model", &self.model.name)
+            .field("served_via_alias", &self.served_via_alias)
+            .field("fallback", &self.fallback)
+            .field("prompt_truncated", &self.prompt_truncated)
+            .field("cached", &self.cached)
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TokenDetail {
+    pub id: u32,
+    /// The token's text in context. Empty when it is a skipped special token
+    /// or the start of a character that a later token's text completes.
+    pub text: String,
+    /// Natural log-probability under the model's untempered distribution.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprob: Option<f32>,
+    /// When the token was sampled, measured from the start of the request.
+    pub offset_ms: f64,
+    /// The most likely tokens at this position, best first; set when the
+    /// generation asked for them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<Vec<TokenCandidate>>,
+}
+
+/// A token the model could have picked, with its log-probability under the
+/// untempered distribution.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TokenCandidate {
+    pub id: u32,
+    /// The token decoded on its own.
+    pub text: String,
+    pub logprob: f32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct EffectiveParams {
+    pub max_new_tokens: usize,
+    pub temperature: f64,
+    pub top_k: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl From<&GenerationParams> for EffectiveParams {
+    fn from(params: &GenerationParams) -> Self {
+        Self {
+            max_new_tokens: params.max_new_tokens,
+            temperature: params.temperature,
+            top_k: params.top_k,
+            seed: params.seed,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// Where the time of a single generation went, in milliseconds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct GenerationTimings {
+    pub tokenize_ms: f64,
+    /// Waiting for the model behind other requests, in its admission queue
+    /// and for its module lock.
+    pub queue_wait_ms: f64,
+    /// From acquiring the module lock until the first token is sampled
+    /// (the prefill forward pass).
+    pub time_to_first_token_ms: f64,
+    /// Remaining decode steps plus detokenization.
+    pub decode_ms: f64,
+    /// CPU time of the thread that ran the generation, which unlike the
+    /// phases above leaves out time spent blocked. Absent where it cannot
+    /// be measured (outside Linux).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_time_ms: Option<f64>,
+    /// The generation timeout, set when the generation ran into it and
+    /// returned partial output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    /// Prompt tokens dropped to fit the context window, up front or as a
+    /// sliding window moved.
+    pub evicted_prompt_tokens: usize,
+    /// Prompt tokens the tokenizer cut at `tokenizer_max_length`.
+    #[serde(default)]
+    pub truncated_prompt_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ModelMetadata {
     pub name: String,
     pub quantized: bool,
     pub dtype: String,
     pub size_bytes: u64,
+    /// Hex SHA-256 of the module file that was loaded.
+    pub sha256: String,
+    pub device: String,
+    /// Why the model runs on CPU instead of the configured GPU, if it does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_fallback_reason: Option<String>,
+    pub max_context_tokens: usize,
+    pub model_kind: ModelKind,
+    /// Token that ends a generation.
+    pub eos_token_id: i64,
+    /// First decoder input; set for seq2seq models.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoder_start_token_id: Option<i64>,
+    /// Time spent in `CModule::load_on_device`.
+    pub load_time_ms: f64,
+    /// Sum of the module's named parameter sizes, when it exposes them.
+    pub num_parameters: Option<u64>,
+    pub vocab_size: usize,
+    /// Whether the traced output includes hidden states, which `/embed` needs.
+    pub hidden_states: bool,
+    /// Latency of each startup warmup generation; empty when warmup is off.
+    pub warmup_latency_ms: Vec<f64>,
+    /// Outcome of the startup self-test; absent when `self_test` is off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_test: Option<SelfTestReport>,
+    /// Each copy of the model when it is replicated across devices; the
+    /// fields above describe the first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<ReplicaMetadata>,
+}
+
+/// The tokenizer requests are encoded with, so clients can count tokens
+/// the way the server does.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct TokenizerMetadata {
+    /// Hex SHA-256 of tokenizer.json.
+    pub sha256: String,
+    /// Added tokens included.
+    pub vocab_size: usize,
+    /// A BPE over bytes, as GPT-2's: any text encodes without unknown
+    /// tokens.
+    pub byte_level_bpe: bool,
+    /// Put in front of the prompt by `add_special_tokens`; absent when
+    /// nothing is.
+    pub bos: Option<SpecialToken>,
+    /// The token the baseline model's generations stop at, `eos_token_id`
+    /// for its model kind; absent when that id is not in the vocabulary.
+    /// Each model reports its own as `eos_token_id`.
+    pub eos: Option<SpecialToken>,
+    /// Fills out batched encodings; absent while `tokenizer_padding` is off
+    /// or tokenizer.json sets no padding.
+    pub pad: Option<SpecialToken>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct SpecialToken {
+    pub id: u32,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ReplicaMetadata {
+    pub device: String,
+    pub size_bytes: u64,
+    pub load_time_ms: f64,
+}
+
+/// One short greedy generation run right after loading, so a wrong
+/// tokenizer or broken trace shows up before the first real request.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub latency_ms: f64,
+    pub completion: String,
+    /// Text of each generated token, in order.
+    pub first_tokens: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of `/health/ready`; `problems` lists what keeps the service from
+/// being ready.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub baseline: Option<ModelMetadata>,
+    pub quantized: Option<ModelMetadata>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+    /// Generations waiting for each model, by priority.
+    pub queues: BTreeMap<String, QueueLengths>,
+    /// The CUDA devices models run on; absent on CPU.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gpu_memory: Vec<DeviceMemory>,
+}
+
+/// Whether a model is serving, as changed by `/admin/models/{name}/unload`
+/// and `/load`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelState {
+    Loaded,
+    /// Unloaded, but requests that started before are still running on it;
+    /// its memory is freed when the last one finishes.
+    Draining,
+    Unloaded,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ScoreRequest {
+    pub prompt: String,
+    pub continuations: Vec<String>,
+}
+
+/// Log-probability of one continuation given the prompt, in nats.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ContinuationScore {
+    pub continuation: String,
+    pub tokens: usize,
+    pub sum_logprob: f64,
+    pub mean_logprob: f64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ScoreResponse {
+    pub prompt_tokens: usize,
+    pub scores: Vec<ContinuationScore>,
+    pub total_time_ms: u128,
+    pub model: ModelMetadata,
+}
+
+/// How per-token hidden states are reduced to one vector per text.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    /// Average over the text's tokens, ignoring padding.
+    #[default]
+    Mean,
+    /// The hidden state of the text's final token.
+    Last,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct EmbedRequest {
+    pub texts: Vec<String>,
+    #[serde(default)]
+    pub pooling: Pooling,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EmbedResponse {
+    pub embeddings: Vec<Vec<f32>>,
+    pub dimensions: usize,
+    pub pooling: Pooling,
+    /// Token count of each text, in request order.
+    pub tokens: Vec<usize>,
+    pub total_time_ms: u128,
+    pub model: ModelMetadata,
+}
+
+/// Sampling overrides carried by a WebSocket `generate` frame.
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamParams {
+    pub max_new_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub context_strategy: Option<ContextStrategy>,
+    #[serde(default)]
+    pub truncate_prompt: bool,
+    pub seed: Option<u64>,
+    pub add_special_tokens: Option<bool>,
+    pub skip_special_tokens: Option<bool>,
+    pub return_token_details: Option<bool>,
+    pub on_timeout: Option<OnTimeout>,
+    pub response_format: Option<ResponseFormat>,
+}
+
+/// Frames accepted on `/ws/generate`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Generate {
+        prompt: String,
+        #[serde(default)]
+        params: StreamParams,
+        /// Prepend earlier turns of this connection to the prompt.
+        #[serde(default)]
+        keep_history: bool,
+    },
+    Cancel,
+}
+
+/// Frames sent on `/ws/generate`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Token {
+        text: String,
+    },
+    Done {
+        response: GenerationResponse,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        history_trimmed: Option<HistoryTrim>,
+    },
+    Cancelled {
+        response: GenerationResponse,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        history_trimmed: Option<HistoryTrim>,
+    },
+    Error {
+        error: ErrorPayload,
+    },
+}
+
+/// Earlier turns left out of a `keep_history` prompt to fit the context
+/// window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HistoryTrim {
+    pub turns: usize,
+    pub tokens: usize,
 }
@@ -0,0 +1,170 @@
+//! Reloads the models when their files change on disk, for `watch_models`.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{config::AppConfig, error::ServiceError, model::ModelRegistry};
+
+/// Starts watching the configured tokenizer and module files. A change
+/// triggers [`ModelRegistry::reload`] once the files have stopped changing
+/// for `watch_settle_ms`, so a half-written export is never loaded.
+pub fn watch_models(
+    registry: Arc<ModelRegistry>,
+    config: Arc<AppConfig>,
+) -> Result<(), ServiceError> {
+    let files = watched_files(&config);
+    let (changes_tx, changes) = mpsc::unbounded_channel();
+    let watched = files.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event)
+                if !matches!(event.kind, EventKind::Access(_))
+                    && event.paths.iter().any(|path| watched.contains(path)) =>
+            {
+                let _ = changes_tx.send(());
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!(error = %err, "model file watcher error"),
+        })
+        .map_err(|err| ServiceError::Other(format!("cannot watch model files: {err}")))?;
+    // Directories rather than the files themselves, so a file replaced by
+    // a rename is still seen.
+    let dirs: BTreeSet<PathBuf> = files
+        .iter()
+        .filter_map(|file| file.parent().map(PathBuf::from))
+        .collect();
+    for dir in &dirs {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|err| ServiceError::Other(format!("cannot watch {}: {err}", dir.display())))?;
+    }
+    tracing::info!(?files, "watching model files for changes");
+
+    let settle = Duration::from_millis(config.watch_settle_ms);
+    tokio::spawn(reload_on_change(
+        registry, config, watcher, changes, files, settle,
+    ));
+    Ok(())
+}
+
+/// The files the loader reads, with their directories made absolute to
+/// match the paths in watcher events. Files in missing directories, such as
+/// ones still to be downloaded, are left out.
+fn watched_files(config: &AppConfig) -> Vec<PathBuf> {
+    let quantized = config
+        .quantized_onnx_path
+        .as_ref()
+        .unwrap_or(&config.quantized_module_path);
+    [
+        &config.tokenizer_path,
+        &config.baseline_module_path,
+        quantized,
+    ]
+    .into_iter()
+    .filter_map(|path| {
+        let name = path.file_name()?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => ".".as_ref(),
+        };
+        match fs::canonicalize(dir) {
+            Ok(dir) => Some(dir.join(name)),
+            Err(err) => {
+                tracing::warn!(path = %path.display(), error = %err, "not watching model file");
+                None
+            }
+        }
+    })
+    .collect()
+}
+
+async fn reload_on_change(
+    registry: Arc<ModelRegistry>,
+    config: Arc<AppConfig>,
+    // Dropping the watcher would stop the events.
+    _watcher: RecommendedWatcher,
+    mut changes: mpsc::UnboundedReceiver<()>,
+    files: Vec<PathBuf>,
+    settle: Duration,
+) {
+    while changes.recv().await.is_some() {
+        loop {
+            let before = fingerprints(&files);
+            tokio::time::sleep(settle).await;
+            let mut changed = false;
+            while changes.try_recv().is_ok() {
+                changed = true;
+            }
+            if !changed && fingerprints(&files) == before {
+                break;
+            }
+        }
+        tracing::info!("model files changed, reloading");
+        // Failures are logged and kept as `last_reload_error` by the registry.
+        let _ = registry.reload(config.clone()).await;
+    }
+}
+
+/// Size and modification time of each file, `None` while it is missing.
+fn fingerprints(files: &[PathBuf]) -> Vec<Option<(u64, Option<SystemTime>)>> {
+    files
+        .iter()
+        .map(|file| {
+            let metadata = fs::metadata(file).ok()?;
+            Some((metadata.len(), metadata.modified().ok()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::model::testing::{fake_config, fake_registry};
+
+    /// Polls `done` until it holds, for at most five seconds.
+    async fn eventually(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting until {what}");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_rewritten_module_is_reloaded_and_a_broken_one_is_not() {
+        let mut config = fake_config("watcher", true);
+        config.watch_settle_ms = 50;
+        let registry = Arc::new(fake_registry(&config));
+        let quantized_size = || registry.metadata().0.map(|model| model.size_bytes);
+        assert_eq!(quantized_size(), Some(0));
+        watch_models(registry.clone(), Arc::new(config.clone())).unwrap();
+
+        fs::write(&config.quantized_module_path, "x".repeat(64)).unwrap();
+        eventually("the new module is served", || quantized_size() == Some(64)).await;
+        assert_eq!(registry.last_reload_error(), None);
+
+        fs::remove_file(&config.baseline_module_path).unwrap();
+        eventually("the reload fails", || {
+            registry.last_reload_error().is_some()
+        })
+        .await;
+        let err = registry.last_reload_error().unwrap();
+        assert!(
+            err.contains(&config.baseline_module_path.display().to_string()),
+            "{err}"
+        );
+        // The models loaded before keep serving.
+        assert_eq!(quantized_size(), Some(64));
+        assert!(registry.readiness().ready);
+    }
+}
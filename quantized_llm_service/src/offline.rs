@@ -0,0 +1,132 @@
+//! Benchmark runs without the HTTP server, shared by the `evaluate`
+//! subcommand and the standalone `evaluate` binary.
+use std::{fs, path::PathBuf, process::ExitCode, sync::Arc};
+
+use clap::Args;
+
+use crate::{
+    config::AppConfig,
+    evaluation::{
+        EvaluationMode, EvaluationReport, MARKDOWN_PROMPT_CHARS, fallback_samples,
+        load_samples_with_references, run_benchmark_until,
+    },
+    model::ModelRegistry,
+    store::Store,
+};
+
+#[derive(Debug, Args)]
+pub struct EvaluateArgs {
+    /// Benchmark samples (JSON array, JSONL or CSV by extension); defaults to EVAL_PROMPTS_PATH or the built-in set.
+    #[arg(long, env = "EVAL_PROMPTS_PATH")]
+    pub prompts: Option<PathBuf>,
+    /// Reference data merged into the samples by index or prompt; defaults to EVAL_REFERENCE_PATH.
+    #[arg(long, env = "EVAL_REFERENCE_PATH")]
+    pub references: Option<PathBuf>,
+    /// Where to write the full JSON report.
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+    /// Fail when the quantized reference match rate falls below this value.
+    #[arg(long, env = "EVAL_MIN_MATCH_RATE")]
+    pub min_match_rate: Option<f64>,
+    /// Fail when the quantized average latency exceeds this many milliseconds.
+    #[arg(long, env = "EVAL_MAX_LATENCY_MS")]
+    pub max_latency_ms: Option<f64>,
+    /// Decode greedily with a fixed seed and check each sample's expected completion.
+    #[arg(long = "assert")]
+    pub assert_outputs: bool,
+    /// With --assert, fail when the pass rate falls below this value.
+    #[arg(long, env = "EVAL_MIN_PASS_RATE", default_value_t = 1.0)]
+    pub min_pass_rate: f64,
+}
+
+/// Loads the models, runs the benchmark and writes the report, exiting with
+/// failure when a threshold is violated. Ctrl-C stops the run early; the
+/// samples finished so far are still reported and written.
+pub async fn evaluate(config: Arc<AppConfig>, args: EvaluateArgs) -> anyhow::Result<ExitCode> {
+    let registry = Arc::new(ModelRegistry::initialize(config.as_ref())?);
+    let samples = match args.prompts.as_deref() {
+        Some(path) => load_samples_with_references(
+            path,
+            args.references
+                .as_deref()
+                .or(config.eval_reference_path.as_deref()),
+        )?,
+        None => fallback_samples(),
+    };
+
+    let mode = if args.assert_outputs {
+        EvaluationMode::Assert
+    } else {
+        EvaluationMode::Benchmark
+    };
+    tracing::info!(count = samples.len(), ?mode, "running offline evaluation");
+    let stop = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    let report = run_benchmark_until(registry, &config, samples, mode, stop).await?;
+    print!("{}", report.to_markdown(MARKDOWN_PROMPT_CHARS));
+
+    if let Some(path) = config.database_path.as_deref() {
+        let id = Store::open(path)?.save_evaluation(&report).await?;
+        tracing::info!(id, "saved evaluation report");
+    }
+
+    if let Some(path) = args.output.as_deref() {
+        fs::write(path, serde_json::to_vec_pretty(&report)?)?;
+        tracing::info!(path = %path.display(), "wrote evaluation report");
+    }
+
+    if report.interrupted {
+        eprintln!(
+            "interrupted after {} samples; the report is partial",
+            report.samples.len()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+    let violations = threshold_violations(&report, &args, mode);
+    for violation in &violations {
+        eprintln!("{violation}");
+    }
+    Ok(if violations.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn threshold_violations(
+    report: &EvaluationReport,
+    args: &EvaluateArgs,
+    mode: EvaluationMode,
+) -> Vec<String> {
+    let aggregate = &report.aggregate;
+    let mut violations = Vec::new();
+    if let Some(threshold) = args.min_match_rate {
+        let rate = aggregate.quantized_reference_match_rate.unwrap_or(0.0);
+        if rate < threshold {
+            violations.push(format!(
+                "reference match rate {rate:.3} is below the required {threshold:.3}"
+            ));
+        }
+    }
+    if let Some(limit) = args.max_latency_ms {
+        let latency = aggregate.quantized_avg_latency_ms;
+        if latency > limit {
+            violations.push(format!(
+                "average latency {latency:.1} ms exceeds the allowed {limit:.1} ms"
+            ));
+        }
+    }
+    if mode == EvaluationMode::Assert {
+        match aggregate.pass_rate {
+            None => violations
+                .push("no benchmark sample has an expected_completion to assert".to_string()),
+            Some(rate) if rate < args.min_pass_rate => violations.push(format!(
+                "golden output pass rate {rate:.3} is below the required {:.3}",
+                args.min_pass_rate
+            )),
+            Some(_) => {}
+        }
+    }
+    violations
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    config::AppConfig,
+    error::ServiceError,
+    model::{ContextStrategy, GenerationRequest},
+};
+
+/// A named set of generation settings a request selects with `preset`.
+/// Settings left out fall through to the server defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GenerationPreset {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_new_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_strategy: Option<ContextStrategy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add_special_tokens: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_special_tokens: Option<bool>,
+}
+
+/// Fills the settings the request left unset from the preset it names, so
+/// request fields win over the preset and the preset over config defaults.
+pub fn apply_preset(
+    request: &mut GenerationRequest,
+    config: &AppConfig,
+) -> Result<(), ServiceError> {
+    let Some(name) = request.preset.take() else {
+        return Ok(());
+    };
+    let preset = config.presets.get(&name).ok_or_else(|| {
+        let available = if config.presets.is_empty() {
+            "none are configured".to_string()
+        } else {
+            let names: Vec<&str> = config.presets.keys().map(String::as_str).collect();
+            format!("available: {}", names.join(", "))
+        };
+        ServiceError::validation("preset", format!("unknown preset '{name}'; {available}"))
+    })?;
+    request.max_new_tokens = request.max_new_tokens.or(preset.max_new_tokens);
+    request.temperature = request.temperature.or(preset.temperature);
+    request.top_k = request.top_k.or(preset.top_k);
+    // `truncate_prompt` is the request asking for a strategy too.
+    if !request.truncate_prompt {
+        request.context_strategy = request.context_strategy.or(preset.context_strategy);
+    }
+    request.add_special_tokens = request.add_special_tokens.or(preset.add_special_tokens);
+    request.skip_special_tokens = request.skip_special_tokens.or(preset.skip_special_tokens);
+    Ok(())
+}
+
+/// Config validation: presets are held to the same ranges as the defaults.
+pub(crate) fn check_presets(config: &AppConfig, problems: &mut Vec<String>) {
+    for (name, preset) in &config.presets {
+        if let Some(temperature) = preset.temperature
+            && !(temperature.is_finite() && temperature >= 0.0)
+        {
+            problems.push(format!(
+                "preset '{name}': temperature must be zero (greedy) or positive, got {temperature}"
+            ));
+        }
+        if preset.top_k == Some(0) {
+            problems.push(format!("preset '{name}': top_k must be at least 1"));
+        }
+        if preset.max_new_tokens == Some(0) {
+            problems.push(format!(
+                "preset '{name}': max_new_tokens must be at least 1"
+            ));
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Live progress of the running evaluation, streamed by
+//! `/evaluate/progress`.
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+use crate::evaluation::SampleReport;
+
+/// Events buffered for slow subscribers before they start missing some.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Least time between two aggregate snapshots.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One server-sent event; `event` names the SSE event type.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// Running totals. Sent first to every subscriber, then at most once a
+    /// second and after the last sample.
+    Snapshot(ProgressSnapshot),
+    /// A sample finished, successfully or not.
+    Sample {
+        index: usize,
+        /// Quantized model latency; absent when the sample failed.
+        latency_ms: Option<u128>,
+        reference_match: Option<bool>,
+        /// Set in assert mode for samples with an `expected_completion`.
+        passed: Option<bool>,
+        error: Option<String>,
+    },
+    /// The run is over. `report_id` is set when the report was stored;
+    /// `error` when the run failed or was abandoned.
+    Finished {
+        report_id: Option<i64>,
+        error: Option<String>,
+    },
+}
+
+impl ProgressEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgressEvent::Snapshot(_) => "snapshot",
+            ProgressEvent::Sample { .. } => "sample",
+            ProgressEvent::Finished { .. } => "finished",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ProgressSnapshot {
+    pub finished: usize,
+    pub total: usize,
+    pub failed: usize,
+    pub elapsed_ms: u128,
+    /// Over the samples finished so far.
+    pub quantized_avg_latency_ms: Option<f64>,
+    pub quantized_reference_match_rate: Option<f64>,
+}
+
+#[derive(Debug)]
+struct RunState {
+    total: usize,
+    finished: usize,
+    failed: usize,
+    latency_sum_ms: f64,
+    latency_count: usize,
+    matched: usize,
+    checked: usize,
+    started: Instant,
+    last_snapshot: Instant,
+}
+
+impl RunState {
+    fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            finished: self.finished,
+            total: self.total,
+            failed: self.failed,
+            elapsed_ms: self.started.elapsed().as_millis(),
+            quantized_avg_latency_ms: (self.latency_count > 0)
+                .then(|| self.latency_sum_ms / self.latency_count as f64),
+            quantized_reference_match_rate: (self.checked > 0)
+                .then(|| self.matched as f64 / self.checked as f64),
+        }
+    }
+}
+
+/// Tracks the one evaluation whose progress is published. A run started
+/// while another is active goes unreported.
+pub struct EvaluationProgress {
+    sender: broadcast::Sender<ProgressEvent>,
+    run: Mutex<Option<RunState>>,
+}
+
+impl Default for EvaluationProgress {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+            run: Mutex::new(None),
+        }
+    }
+}
+
+impl EvaluationProgress {
+    /// Starts publishing a run of `total` samples, unless one is already
+    /// being published. Dropping the returned handle without calling
+    /// [`ProgressRun::finish`] reports the run as abandoned.
+    pub fn start(&self, total: usize) -> Option<ProgressRun<'_>> {
+        let mut run = self.run.lock();
+        if run.is_some() {
+            return None;
+        }
+        let now = Instant::now();
+        *run = Some(RunState {
+            total,
+            finished: 0,
+            failed: 0,
+            latency_sum_ms: 0.0,
+            latency_count: 0,
+            matched: 0,
+            checked: 0,
+            started: now,
+            last_snapshot: now,
+        });
+        Some(ProgressRun {
+            progress: self,
+            finished: false,
+        })
+    }
+
+    /// Subscribes to the active run. The snapshot is taken after
+    /// subscribing, so no event falls between the two; `None` when idle.
+    pub fn subscribe(&self) -> Option<(ProgressSnapshot, broadcast::Receiver<ProgressEvent>)> {
+        let run = self.run.lock();
+        let receiver = self.sender.subscribe();
+        run.as_ref().map(|state| (state.snapshot(), receiver))
+    }
+}
+
+/// Handle of the run being published.
+pub struct ProgressRun<'a> {
+    progress: &'a EvaluationProgress,
+    finished: bool,
+}
+
+impl ProgressRun<'_> {
+    pub fn sample(&self, index: usize, report: &SampleReport) {
+        let mut run = self.progress.run.lock();
+        let Some(state) = run.as_mut() else {
+            return;
+        };
+        state.finished += 1;
+        let latency_ms = report.quantized.as_ref().map(|q| q.total_time_ms);
+        if report.error.is_some() {
+            state.failed += 1;
+        }
+        if let Some(latency_ms) = latency_ms {
+            state.latency_sum_ms += latency_ms as f64;
+            state.latency_count += 1;
+        }
+        if let Some(matched) = report.reference_match_quantized {
+            state.checked += 1;
+            state.matched += usize::from(matched);
+        }
+        let _ = self.progress.sender.send(ProgressEvent::Sample {
+            index,
+            latency_ms,
+            reference_match: report.reference_match_quantized,
+            passed: report.passed,
+            error: report.error.clone(),
+        });
+        if state.finished == state.total || state.last_snapshot.elapsed() >= SNAPSHOT_INTERVAL {
+            state.last_snapshot = Instant::now();
+            let _ = self
+                .progress
+                .sender
+                .send(ProgressEvent::Snapshot(state.snapshot()));
+        }
+    }
+
+    pub fn finish(mut self, report_id: Option<i64>, error: Option<String>) {
+        self.finished = true;
+        self.end(ProgressEvent::Finished { report_id, error });
+    }
+
+    fn end(&self, event: ProgressEvent) {
+        // Cleared before sending so a subscriber woken by the event sees
+        // the tracker idle.
+        self.progress.run.lock().take();
+        let _ = self.progress.sender.send(event);
+    }
+}
+
+impl Drop for ProgressRun<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.end(ProgressEvent::Finished {
+                report_id: None,
+                error: Some("evaluation abandoned".into()),
+            });
+        }
+    }
+}
@@ -1,8 +1,32 @@
-use serde::Serialize;
+use std::{fs, path::Path, process::Command, time::Instant};
 
-use crate::model::ModelMetadata;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize)]
+use crate::{
+    error::ServiceError,
+    model::{ModelMetadata, ModelRegistry, ModelSlot, PromptActivations},
+};
+
+/// Most prompts one analysis runs; each costs a forward pass per model.
+pub const MAX_CALIBRATION_PROMPTS: usize = 64;
+
+/// Dynamic int8 quantization of a scripted module. LibTorch's C++ API has no
+/// equivalent of `quantize_dynamic_jit`, so this runs under the Python
+/// interpreter that produced the baseline export.
+const QUANTIZE_SCRIPT: &str = r#"
+import sys
+import torch
+
+module = torch.jit.load(sys.argv[1], map_location="cpu")
+module.eval()
+quantized = torch.quantization.quantize_dynamic_jit(
+    module, {"": torch.quantization.default_dynamic_qconfig}
+)
+torch.jit.save(quantized, sys.argv[2])
+"#;
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct QuantizationSummary {
     pub baseline_size_bytes: Option<u64>,
     pub quantized_size_bytes: u64,
@@ -14,20 +38,325 @@ impl QuantizationSummary {
         quantized: &ModelMetadata,
         baseline: Option<&ModelMetadata>,
     ) -> QuantizationSummary {
-        let baseline_size = baseline.map(|m| m.size_bytes);
+        Self::from_sizes(quantized.size_bytes, baseline.map(|m| m.size_bytes))
+    }
+
+    pub fn from_sizes(quantized_size: u64, baseline_size: Option<u64>) -> QuantizationSummary {
         let reduction = baseline_size.map(|baseline| {
             if baseline == 0 {
                 0.0
             } else {
-                let diff = baseline.saturating_sub(quantized.size_bytes) as f64;
+                let diff = baseline.saturating_sub(quantized_size) as f64;
                 (diff / baseline as f64) * 100.0
             }
         });
 
         QuantizationSummary {
             baseline_size_bytes: baseline_size,
-            quantized_size_bytes: quantized.size_bytes,
+            quantized_size_bytes: quantized_size,
             size_reduction_percent: reduction,
         }
     }
 }
+
+pub fn quantize_module(
+    input: &Path,
+    output: &Path,
+    python: &str,
+) -> Result<QuantizationSummary, ServiceError> {
+    if !input.exists() {
+        return Err(ServiceError::Quantization(format!(
+            "input module missing: {}",
+            input.display()
+        )));
+    }
+
+    let result = Command::new(python)
+        .arg("-c")
+        .arg(QUANTIZE_SCRIPT)
+        .arg(input)
+        .arg(output)
+        .output()
+        .map_err(|e| ServiceError::Quantization(format!("failed to launch {python}: {e}")))?;
+
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(ServiceError::Quantization(format!(
+            "{python} exited with {}: {}",
+            result.status,
+            stderr.trim()
+        )));
+    }
+
+    let baseline_size = fs::metadata(input)?.len();
+    let quantized_size = fs::metadata(output)?.len();
+    Ok(QuantizationSummary::from_sizes(
+        quantized_size,
+        Some(baseline_size),
+    ))
+}
+
+/// Where the quantized model's outputs drift from the baseline's, from
+/// teacher-forced passes over a calibration prompt set.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct QuantizationAnalysis {
+    pub prompts: usize,
+    /// Token positions compared, summed over the prompts.
+    pub positions: usize,
+    /// Over every compared position.
+    pub logits: LogitsStats,
+    /// Entry `i` covers token `i` of every prompt at least `i + 1` tokens long.
+    pub by_position: Vec<PositionStats>,
+    /// One entry per hidden-state output, embeddings first; empty unless both
+    /// traces output hidden states.
+    pub layers: Vec<LayerStats>,
+    pub total_time_ms: u128,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LogitsStats {
+    /// Absolute logit difference, averaged over the vocabulary and positions.
+    pub mean_abs_diff: f64,
+    pub max_abs_diff: f64,
+    /// Share of positions where both models rank the same token first.
+    pub top1_agreement: f64,
+    /// KL(baseline ‖ quantized) of the next-token distributions, in nats.
+    pub mean_kl_divergence: f64,
+    pub max_kl_divergence: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PositionStats {
+    pub position: usize,
+    /// Prompts long enough to reach this position.
+    pub prompts: usize,
+    pub mean_abs_diff: f64,
+    pub top1_agreement: f64,
+    pub mean_kl_divergence: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LayerStats {
+    pub layer: usize,
+    pub mean_abs_diff: f64,
+    /// ‖baseline − quantized‖ / ‖baseline‖ over all positions.
+    pub relative_error: f64,
+    /// Per-position cosine similarity of the hidden vectors, averaged.
+    pub mean_cosine_similarity: f64,
+}
+
+/// Runs each calibration prompt through both models and compares their
+/// outputs. Both models must be loaded.
+pub async fn analyze(
+    registry: &ModelRegistry,
+    prompts: &[String],
+) -> Result<QuantizationAnalysis, ServiceError> {
+    if !registry.has_quantized() {
+        return Err(ServiceError::BadRequest(
+            "quantization analysis needs the quantized model, which is not loaded".into(),
+        ));
+    }
+    if !registry.has_baseline() {
+        return Err(ServiceError::BadRequest(
+            "quantization analysis compares against the baseline model, which is not loaded".into(),
+        ));
+    }
+    if prompts.is_empty() || prompts.len() > MAX_CALIBRATION_PROMPTS {
+        return Err(ServiceError::validation(
+            "prompts",
+            format!("must contain between 1 and {MAX_CALIBRATION_PROMPTS} entries"),
+        ));
+    }
+
+    let start = Instant::now();
+    let mut comparison = Comparison::default();
+    for prompt in prompts {
+        let baseline = registry
+            .prompt_activations(ModelSlot::Baseline, prompt.clone())
+            .await?;
+        let quantized = registry
+            .prompt_activations(ModelSlot::Quantized, prompt.clone())
+            .await?;
+        comparison.add(&baseline, &quantized);
+    }
+    Ok(comparison.finish(prompts.len(), start.elapsed().as_millis()))
+}
+
+#[derive(Default)]
+struct Totals {
+    count: usize,
+    abs_diff: f64,
+    agreements: usize,
+    kl: f64,
+}
+
+#[derive(Default)]
+struct LayerTotals {
+    elements: usize,
+    abs_diff: f64,
+    squared_error: f64,
+    squared_baseline: f64,
+    positions: usize,
+    cosine: f64,
+}
+
+#[derive(Default)]
+struct Comparison {
+    overall: Totals,
+    max_abs_diff: f64,
+    max_kl: f64,
+    by_position: Vec<Totals>,
+    layers: Vec<LayerTotals>,
+    /// Set once either model lacks hidden states for some prompt.
+    skip_layers: bool,
+}
+
+impl Comparison {
+    fn add(&mut self, baseline: &PromptActivations, quantized: &PromptActivations) {
+        // A vocabulary padded for alignment differs only past the real ids.
+        let vocab = baseline.vocab_size.min(quantized.vocab_size);
+        let positions = baseline.tokens.min(quantized.tokens);
+        if self.by_position.len() < positions {
+            self.by_position.resize_with(positions, Totals::default);
+        }
+        for position in 0..positions {
+            let b = &baseline.logits[position * baseline.vocab_size..][..vocab];
+            let q = &quantized.logits[position * quantized.vocab_size..][..vocab];
+            let abs_diff = mean_abs_diff(b, q);
+            let agreed = argmax(b) == argmax(q);
+            let kl = kl_divergence(b, q);
+            self.max_abs_diff = self.max_abs_diff.max(max_abs_diff(b, q));
+            self.max_kl = self.max_kl.max(kl);
+            for totals in [&mut self.overall, &mut self.by_position[position]] {
+                totals.count += 1;
+                totals.abs_diff += abs_diff;
+                totals.agreements += usize::from(agreed);
+                totals.kl += kl;
+            }
+        }
+
+        let has_layers = !baseline.hidden_states.is_empty()
+            && baseline.hidden_states.len() == quantized.hidden_states.len()
+            && baseline.hidden_size == quantized.hidden_size;
+        self.skip_layers |= !has_layers;
+        if self.skip_layers {
+            self.layers.clear();
+            return;
+        }
+        let hidden = baseline.hidden_size;
+        if self.layers.is_empty() {
+            self.layers
+                .resize_with(baseline.hidden_states.len(), LayerTotals::default);
+        }
+        for (totals, (b, q)) in self
+            .layers
+            .iter_mut()
+            .zip(baseline.hidden_states.iter().zip(&quantized.hidden_states))
+        {
+            for position in 0..positions {
+                let b = &b[position * hidden..][..hidden];
+                let q = &q[position * hidden..][..hidden];
+                let (mut dot, mut norm_b, mut norm_q) = (0.0, 0.0, 0.0);
+                for (&x, &y) in b.iter().zip(q) {
+                    let (x, y) = (x as f64, y as f64);
+                    totals.abs_diff += (x - y).abs();
+                    totals.squared_error += (x - y) * (x - y);
+                    totals.squared_baseline += x * x;
+                    dot += x * y;
+                    norm_b += x * x;
+                    norm_q += y * y;
+                }
+                totals.elements += hidden;
+                totals.positions += 1;
+                let norms = (norm_b * norm_q).sqrt();
+                totals.cosine += if norms > 0.0 { dot / norms } else { 1.0 };
+            }
+        }
+    }
+
+    fn finish(self, prompts: usize, total_time_ms: u128) -> QuantizationAnalysis {
+        let ratio = |sum: f64, count: usize| if count == 0 { 0.0 } else { sum / count as f64 };
+        let overall = &self.overall;
+        QuantizationAnalysis {
+            prompts,
+            positions: overall.count,
+            logits: LogitsStats {
+                mean_abs_diff: ratio(overall.abs_diff, overall.count),
+                max_abs_diff: self.max_abs_diff,
+                top1_agreement: ratio(overall.agreements as f64, overall.count),
+                mean_kl_divergence: ratio(overall.kl, overall.count),
+                max_kl_divergence: self.max_kl,
+            },
+            by_position: self
+                .by_position
+                .iter()
+                .enumerate()
+                .map(|(position, totals)| PositionStats {
+                    position,
+                    prompts: totals.count,
+                    mean_abs_diff: ratio(totals.abs_diff, totals.count),
+                    top1_agreement: ratio(totals.agreements as f64, totals.count),
+                    mean_kl_divergence: ratio(totals.kl, totals.count),
+                })
+                .collect(),
+            layers: self
+                .layers
+                .iter()
+                .enumerate()
+                .map(|(layer, totals)| LayerStats {
+                    layer,
+                    mean_abs_diff: ratio(totals.abs_diff, totals.elements),
+                    relative_error: if totals.squared_baseline > 0.0 {
+                        (totals.squared_error / totals.squared_baseline).sqrt()
+                    } else {
+                        0.0
+                    },
+                    mean_cosine_similarity: ratio(totals.cosine, totals.positions),
+                })
+                .collect(),
+            total_time_ms,
+        }
+    }
+}
+
+fn mean_abs_diff(a: &[f32], b: &[f32]) -> f64 {
+    let sum: f64 = a
+        .iter()
+        .zip(b)
+        .map(|(&x, &y)| (x as f64 - y as f64).abs())
+        .sum();
+    sum / a.len().max(1) as f64
+}
+
+fn max_abs_diff(a: &[f32], b: &[f32]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(&x, &y)| (x as f64 - y as f64).abs())
+        .fold(0.0, f64::max)
+}
+
+fn argmax(logits: &[f32]) -> Option<usize> {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(idx, _)| idx)
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f64> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let sum: f64 = logits.iter().map(|&x| (x as f64 - max).exp()).sum();
+    let log_sum = max + sum.ln();
+    logits.iter().map(|&x| x as f64 - log_sum).collect()
+}
+
+/// KL(p ‖ q) between the softmax distributions of two logit rows.
+fn kl_divergence(p_logits: &[f32], q_logits: &[f32]) -> f64 {
+    let p = log_softmax(p_logits);
+    let q = log_softmax(q_logits);
+    p.iter()
+        .zip(&q)
+        .map(|(&lp, &lq)| lp.exp() * (lp - lq))
+        .sum::<f64>()
+        .max(0.0)
+}
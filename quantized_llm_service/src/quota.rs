@@ -0,0 +1,315 @@
+//! Token quotas per API key and the usage counted against them.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, OnceLock},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{config::AppConfig, error::ServiceError, store::Store};
+
+/// Bucket for requests that carry no API key, e.g. with authentication
+/// disabled or over gRPC.
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Tokens a key has been charged for since its last reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct KeyUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub requests: u64,
+}
+
+impl KeyUsage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// One key's entry in `GET /admin/usage`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct KeyUsageReport {
+    /// API key label, or `anonymous`.
+    pub key: String,
+    #[serde(flatten)]
+    pub usage: KeyUsage,
+    pub total_tokens: u64,
+    /// Tokens held by generations still running.
+    pub reserved_tokens: u64,
+    /// Absent when the key is unlimited.
+    pub quota: Option<u64>,
+    /// Left for new generations once the reserved tokens are counted.
+    pub remaining: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UsageSnapshot {
+    /// Applies to keys without a quota of their own; absent when unlimited.
+    pub default_quota: Option<u64>,
+    pub keys: Vec<KeyUsageReport>,
+}
+
+#[derive(Debug, Default)]
+struct Account {
+    usage: KeyUsage,
+    reserved: u64,
+}
+
+/// Usage per API key, checked against its quota before a generation runs
+/// and charged once it has finished. Totals survive restarts when a store
+/// is attached.
+pub struct UsageLedger {
+    default_quota: Option<u64>,
+    quotas: BTreeMap<String, u64>,
+    accounts: Mutex<HashMap<String, Account>>,
+    store: OnceLock<Store>,
+}
+
+impl UsageLedger {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            default_quota: config.token_quota,
+            quotas: config.token_quotas.clone(),
+            accounts: Mutex::new(HashMap::new()),
+            store: OnceLock::new(),
+        }
+    }
+
+    /// `None` when `key` may use any number of tokens.
+    pub fn quota(&self, key: &str) -> Option<u64> {
+        self.quotas.get(key).copied().or(self.default_quota)
+    }
+
+    /// Loads the totals kept in `store` and persists every charge from now
+    /// on. Only the first store attached is used.
+    pub async fn attach_store(&self, store: Store) -> Result<(), ServiceError> {
+        let saved = store.load_usage().await?;
+        {
+            let mut accounts = self.accounts.lock();
+            for (key, usage) in saved {
+                let account = accounts.entry(key).or_default();
+                account.usage.prompt_tokens += usage.prompt_tokens;
+                account.usage.completion_tokens += usage.completion_tokens;
+                account.usage.requests += usage.requests;
+            }
+        }
+        let _ = self.store.set(store);
+        Ok(())
+    }
+
+    /// Holds `tokens` of `key`'s remaining quota for a generation about to
+    /// run, or refuses it when they would take the key over its quota.
+    /// Dropping the reservation without settling it charges nothing.
+    pub fn reserve(self: &Arc<Self>, key: &str, tokens: u64) -> Result<Reservation, ServiceError> {
+        let quota = self.quota(key);
+        let mut accounts = self.accounts.lock();
+        let account = accounts.entry(key.to_string()).or_default();
+        if let Some(quota) = quota {
+            let remaining = quota.saturating_sub(account.usage.total_tokens() + account.reserved);
+            if tokens > remaining {
+                return Err(ServiceError::QuotaExceeded {
+                    key: key.to_string(),
+                    quota,
+                    remaining,
+                    requested: tokens,
+                });
+            }
+        }
+        account.reserved += tokens;
+        Ok(Reservation {
+            ledger: self.clone(),
+            key: key.to_string(),
+            tokens,
+        })
+    }
+
+    /// Every key with usage or a quota of its own, by label.
+    pub fn snapshot(&self) -> UsageSnapshot {
+        let accounts = self.accounts.lock();
+        let mut keys: Vec<&str> = accounts
+            .keys()
+            .map(String::as_str)
+            .chain(self.quotas.keys().map(String::as_str))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        UsageSnapshot {
+            default_quota: self.default_quota,
+            keys: keys
+                .into_iter()
+                .map(|key| self.report(key, accounts.get(key)))
+                .collect(),
+        }
+    }
+
+    /// Clears `key`'s usage; generations still running are charged when
+    /// they finish.
+    pub async fn reset(&self, key: &str) -> Result<KeyUsageReport, ServiceError> {
+        let report = {
+            let mut accounts = self.accounts.lock();
+            match accounts.get_mut(key) {
+                Some(account) => account.usage = KeyUsage::default(),
+                None if self.quotas.contains_key(key) => {}
+                None => return Err(ServiceError::NotFound(format!("no usage for key {key:?}"))),
+            }
+            self.report(key, accounts.get(key))
+        };
+        if let Some(store) = self.store.get() {
+            store.reset_usage(key.to_string()).await?;
+        }
+        tracing::info!(key, "usage reset");
+        Ok(report)
+    }
+
+    fn report(&self, key: &str, account: Option<&Account>) -> KeyUsageReport {
+        let usage = account.map(|account| account.usage).unwrap_or_default();
+        let reserved_tokens = account.map_or(0, |account| account.reserved);
+        let quota = self.quota(key);
+        KeyUsageReport {
+            key: key.to_string(),
+            usage,
+            total_tokens: usage.total_tokens(),
+            reserved_tokens,
+            quota,
+            remaining: quota
+                .map(|quota| quota.saturating_sub(usage.total_tokens() + reserved_tokens)),
+        }
+    }
+}
+
+/// Quota held for one generation until it is settled or dropped.
+pub struct Reservation {
+    ledger: Arc<UsageLedger>,
+    key: String,
+    tokens: u64,
+}
+
+impl Reservation {
+    /// Releases the hold and charges what the generation actually used.
+    pub fn settle(mut self, prompt_tokens: u64, completion_tokens: u64) {
+        {
+            let mut accounts = self.ledger.accounts.lock();
+            let account = accounts.entry(self.key.clone()).or_default();
+            account.reserved = account.reserved.saturating_sub(self.tokens);
+            account.usage.prompt_tokens += prompt_tokens;
+            account.usage.completion_tokens += completion_tokens;
+            account.usage.requests += 1;
+        }
+        self.tokens = 0;
+        let Some(store) = self.ledger.store.get().cloned() else {
+            return;
+        };
+        let key = self.key.clone();
+        tokio::spawn(async move {
+            if let Err(err) = store
+                .record_usage(key, prompt_tokens, completion_tokens)
+                .await
+            {
+                tracing::warn!(error = %err, "failed to record key usage");
+            }
+        });
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.tokens == 0 {
+            return;
+        }
+        if let Some(account) = self.ledger.accounts.lock().get_mut(&self.key) {
+            account.reserved = account.reserved.saturating_sub(self.tokens);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use super::*;
+
+    fn ledger(default_quota: Option<u64>, quotas: &[(&str, u64)]) -> Arc<UsageLedger> {
+        let config = AppConfig {
+            token_quota: default_quota,
+            token_quotas: quotas
+                .iter()
+                .map(|&(key, quota)| (key.to_string(), quota))
+                .collect(),
+            ..AppConfig::default()
+        };
+        Arc::new(UsageLedger::from_config(&config))
+    }
+
+    #[test]
+    fn a_reservation_over_the_quota_is_refused() {
+        let ledger = ledger(Some(100), &[]);
+        let held = ledger.reserve("alice", 60).unwrap();
+        let err = ledger.reserve("alice", 50).err().unwrap();
+        assert_eq!(err.status(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            err.details(),
+            Some(serde_json::json!({ "quota": 100, "remaining": 40, "requested": 50 }))
+        );
+        // Exactly what is left still fits.
+        drop(ledger.reserve("alice", 40).unwrap());
+        drop(held);
+    }
+
+    #[test]
+    fn settling_charges_what_was_used_and_frees_the_rest() {
+        let ledger = ledger(Some(100), &[]);
+        ledger.reserve("alice", 80).unwrap().settle(10, 20);
+        let report = &ledger.snapshot().keys[0];
+        assert_eq!(report.total_tokens, 30);
+        assert_eq!(report.reserved_tokens, 0);
+        assert_eq!(report.remaining, Some(70));
+        assert_eq!(report.usage.requests, 1);
+    }
+
+    #[test]
+    fn a_dropped_reservation_charges_nothing() {
+        let ledger = ledger(Some(100), &[]);
+        drop(ledger.reserve("alice", 100).unwrap());
+        assert!(ledger.reserve("alice", 100).is_ok());
+    }
+
+    #[test]
+    fn per_key_quotas_override_the_default() {
+        let ledger = ledger(None, &[("capped", 10)]);
+        assert!(ledger.reserve("capped", 11).is_err());
+        assert!(ledger.reserve(ANONYMOUS, u64::MAX).is_ok());
+        assert_eq!(ledger.quota("other"), None);
+    }
+
+    #[tokio::test]
+    async fn reset_clears_usage_and_unknown_keys_are_404() {
+        let ledger = ledger(Some(100), &[]);
+        ledger.reserve("alice", 50).unwrap().settle(50, 0);
+        assert_eq!(ledger.reset("alice").await.unwrap().remaining, Some(100));
+        let err = ledger.reset("nobody").await.unwrap_err();
+        assert_eq!(err.code(), "not_found");
+    }
+
+    #[tokio::test]
+    async fn stored_usage_outlives_the_ledger() {
+        let store = Store::open(Path::new(":memory:")).unwrap();
+        let running = ledger(Some(100), &[]);
+        running.attach_store(store.clone()).await.unwrap();
+        running.reserve("alice", 50).unwrap().settle(10, 20);
+        // Charges are written in the background.
+        while store.load_usage().await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let restarted = ledger(Some(100), &[]);
+        restarted.attach_store(store.clone()).await.unwrap();
+        let report = &restarted.snapshot().keys[0];
+        assert_eq!((report.key.as_str(), report.total_tokens), ("alice", 30));
+        assert_eq!(report.remaining, Some(70));
+
+        restarted.reset("alice").await.unwrap();
+        assert!(store.load_usage().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{auth::ApiKeyLabel, config::AppConfig, error::ServiceError};
+
+/// Full buckets are dropped once the table grows past this many clients.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    allowed: u64,
+    limited: u64,
+}
+
+/// Token bucket per client: `rps` tokens are added per second up to `burst`,
+/// and each request spends one.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rps: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClientUsage {
+    pub client: String,
+    pub tokens_available: f64,
+    pub allowed: u64,
+    pub limited: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RateLimitSnapshot {
+    pub enabled: bool,
+    pub requests_per_second: f64,
+    pub burst: u32,
+    pub clients: Vec<ClientUsage>,
+}
+
+impl RateLimiter {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            rps: config.rate_limit_rps,
+            burst: f64::from(config.rate_limit_burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rps > 0.0
+    }
+
+    /// Spends one token for `client`, or returns how long until one is
+    /// available.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(client) {
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            refilled_at: now,
+            allowed: 0,
+            limited: 0,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.allowed += 1;
+            Ok(())
+        } else {
+            bucket.limited += 1;
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rps))
+        }
+    }
+
+    pub fn snapshot(&self) -> RateLimitSnapshot {
+        let now = Instant::now();
+        let buckets = self.buckets.lock();
+        let mut clients: Vec<ClientUsage> = buckets
+            .iter()
+            .map(|(client, bucket)| ClientUsage {
+                client: client.clone(),
+                tokens_available: self.refilled(bucket, now),
+                allowed: bucket.allowed,
+                limited: bucket.limited,
+            })
+            .collect();
+        clients.sort_by(|a, b| b.limited.cmp(&a.limited).then(b.allowed.cmp(&a.allowed)));
+
+        RateLimitSnapshot {
+            enabled: self.is_enabled(),
+            requests_per_second: self.rps,
+            burst: self.burst as u32,
+            clients,
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.rps).min(self.burst)
+    }
+}
+
+fn is_exempt(path: &str) -> bool {
+    path == "/health" || path.starts_with("/health/") || path == "/metrics"
+}
+
+/// Keys clients by the authenticated API key label, falling back to the
+/// peer address when authentication is disabled.
+fn client_key(request: &Request) -> String {
+    if let Some(ApiKeyLabel(label)) = request.extensions().get::<ApiKeyLabel>() {
+        return format!("key:{label}");
+    }
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+pub async fn enforce_rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !limiter.is_enabled() || is_exempt(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let client = client_key(&request);
+    match limiter.check(&client) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            tracing::debug!(%client, ?wait, "rate limit exceeded");
+            ServiceError::RateLimited {
+                retry_after_secs: wait.as_secs_f64().ceil().max(1.0) as u64,
+            }
+            .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode, header},
+    };
+
+    use super::*;
+    use crate::testing::{get, router, send};
+
+    const BURST: u32 = 3;
+
+    fn limited(config: &mut AppConfig) {
+        config.rate_limit_rps = 0.5;
+        config.rate_limit_burst = BURST;
+    }
+
+    #[tokio::test]
+    async fn a_burst_past_the_limit_is_a_429_with_retry_after() {
+        let router = router("rate-limit", limited);
+        for _ in 0..BURST {
+            assert_eq!(send(&router, get("/version")).await.status, StatusCode::OK);
+        }
+        let reply = send(&router, get("/version")).await;
+        assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(reply.error_code(), "rate_limited");
+        let retry_after: u64 = reply
+            .header(header::RETRY_AFTER)
+            .expect("Retry-After is set")
+            .parse()
+            .unwrap();
+        // One token at half a token per second.
+        assert!((1..=2).contains(&retry_after), "{retry_after}");
+        assert_eq!(
+            reply.json()["error"]["details"]["retry_after_secs"],
+            retry_after
+        );
+    }
+
+    #[tokio::test]
+    async fn health_is_never_throttled() {
+        let router = router("rate-limit-health", limited);
+        for _ in 0..3 * BURST {
+            for uri in ["/health", "/health/live", "/health/ready"] {
+                assert_eq!(
+                    send(&router, get(uri)).await.status,
+                    StatusCode::OK,
+                    "{uri}"
+                );
+            }
+        }
+        assert_eq!(send(&router, get("/version")).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn each_api_key_has_its_own_bucket() {
+        let router = router("rate-limit-keys", |config| {
+            limited(config);
+            config.api_keys = vec!["alice:a-secret".into(), "bob:b-secret".into()];
+        });
+        let as_key = |secret: &str| {
+            Request::get("/version")
+                .header("x-api-key", secret)
+                .body(Body::empty())
+                .unwrap()
+        };
+        for _ in 0..BURST {
+            assert_eq!(
+                send(&router, as_key("a-secret")).await.status,
+                StatusCode::OK
+            );
+        }
+        let reply = send(&router, as_key("a-secret")).await;
+        assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            send(&router, as_key("b-secret")).await.status,
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn usage_is_reported_noisiest_first() {
+        let limiter = RateLimiter::from_config(&AppConfig {
+            rate_limit_rps: 0.5,
+            rate_limit_burst: BURST,
+            ..AppConfig::default()
+        });
+        assert!(limiter.check("key:bob").is_ok());
+        for _ in 0..BURST + 2 {
+            let _ = limiter.check("key:alice");
+        }
+        let snapshot = limiter.snapshot();
+        assert!(snapshot.enabled);
+        let clients: Vec<(&str, u64, u64)> = snapshot
+            .clients
+            .iter()
+            .map(|usage| (usage.client.as_str(), usage.allowed, usage.limited))
+            .collect();
+        assert_eq!(
+            clients,
+            [("key:alice", u64::from(BURST), 2), ("key:bob", 1, 0)]
+        );
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let limiter = RateLimiter::from_config(&AppConfig::default());
+        assert!(!limiter.is_enabled());
+    }
+}
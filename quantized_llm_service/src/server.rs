@@ -1,124 +1,2328 @@
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    time::Instant,
+};
 
 use axum::{
-    Json, Router,
-    extract::State,
-    routing::{get, post},
+    Extension, Json, Router,
+    extract::{
+        DefaultBodyLimit, Multipart, Path, State,
+        multipart::{MultipartError, MultipartRejection},
+    },
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri, header},
+    response::{
+        Html, IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, post, put},
 };
+use futures::{StreamExt, future, stream};
 use parking_lot::RwLock;
-use serde::Serialize;
-use tower_http::trace::TraceLayer;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tower_http::{
+    LatencyUnit,
+    compression::{CompressionLayer, DefaultPredicate, Predicate},
+    cors::{AllowOrigin, CorsLayer},
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+};
+use tracing::{Level, info};
+use utoipa::{
+    IntoParams, Modify, OpenApi, ToSchema,
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+use utoipa_swagger_ui::{Config, SwaggerUi};
 
 use crate::{
+    audit::AuditLog,
+    auth::{ApiKeyLabel, ApiKeys, require_api_key},
     config::AppConfig,
-    error::ServiceError,
-    evaluation::{EvaluationReport, fallback_samples, load_samples_from_path, run_benchmark},
-    model::{GenerationRequest, ModelRegistry},
-    quantization::QuantizationSummary,
+    divergence::{self, CompareRequest, DivergenceReport},
+    error::{ErrorBody, ServiceError},
+    evaluation::{
+        BenchmarkSample, EvaluationMode, EvaluationReport, MARKDOWN_PROMPT_CHARS, ReportComparison,
+        ReportFormat, SampleFormat, compare_reports, fallback_samples,
+        load_samples_with_references, parse_samples, run_benchmark_with_progress,
+    },
+    extract::{ApiJson, ApiQuery},
+    html_report,
+    idempotency::{
+        IDEMPOTENCY_KEY_HEADER, IDEMPOTENT_REPLAY_HEADER, IdempotencyCache, RequestKey,
+        idempotency_key,
+    },
+    memory::DeviceMemory,
+    middleware::{
+        AccessLog, REQUEST_ID_HEADER, SERVED_MODEL_HEADER, ServedGeneration, attach_request_id,
+        default_retry_after, log_access, make_request_span, structured_payload_too_large,
+    },
+    model::{
+        EmbedRequest, EmbedResponse, GenerationParams, GenerationRequest, GenerationResponse,
+        ModelMetadata, ModelRegistry, ModelSlot, ModelState, ModelStatsSnapshot, ReadinessReport,
+        ScoreRequest, ScoreResponse, TokenizerMetadata, cuda_oom_events, generation_timeouts,
+        resolve_alias,
+    },
+    presets::{GenerationPreset, apply_preset},
+    progress::{EvaluationProgress, ProgressEvent},
+    quantization::{self, QuantizationAnalysis, QuantizationSummary},
+    quota::{KeyUsage, KeyUsageReport, UsageSnapshot},
+    rate_limit::{RateLimitSnapshot, RateLimiter, enforce_rate_limit},
+    shadow::{ShadowCompare, ShadowDiff},
+    slow_requests::{SlowRequest, SlowRequests},
+    store::{Store, StoredEvaluation},
+    templates::apply_template,
+    version::ServiceInfo,
+    websocket::ws_generate,
 };
 
+const PLAYGROUND_HTML: &str = include_str!("../static/playground.html");
+
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<AppConfig>,
     pub registry: Arc<ModelRegistry>,
     pub evaluation: Arc<RwLock<Option<EvaluationReport>>>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub shadow: Arc<ShadowCompare>,
+    pub slow_requests: Arc<SlowRequests>,
+    pub idempotency: Arc<IdempotencyCache>,
+    /// Set when `audit_log_path` is configured.
+    pub audit: Option<AuditLog>,
+    /// Share of `/generate` traffic sent to the quantized model.
+    pub canary_quantized_percent: Arc<RwLock<f64>>,
+    /// Set when `database_path` is configured.
+    pub store: Option<Store>,
+    pub progress: Arc<EvaluationProgress>,
+    pub quantization_analysis: Arc<RwLock<Option<QuantizationAnalysis>>>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct HistoryQuery {
+    /// Most runs returned; defaults to 20.
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+}
+
+fn default_history_limit() -> usize {
+    20
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct EvaluateQuery {
+    /// `assert` decodes greedily with a fixed seed and checks expected completions.
+    #[serde(default)]
+    mode: EvaluationMode,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct ReportQuery {
+    #[serde(default)]
+    format: ReportFormat,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct CompareQuery {
+    /// Id of the run to compare against, usually the older one.
+    base: i64,
+    candidate: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct Canary {
+    percent: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ModelList {
+    models: Vec<ModelMetadata>,
+    aliases: Vec<ModelAlias>,
+    /// Used when a request names no model.
+    default_model: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+struct ModelAlias {
+    alias: String,
+    /// What the alias points at, possibly another alias.
+    target: String,
+    /// The model it finally resolves to.
+    model: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct AliasUpdate {
+    /// Replaces the whole map; send every alias that should remain.
+    aliases: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct AnalyzeRequest {
+    /// Calibration prompts, run through both models.
+    prompts: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
 struct MetadataResponse {
-    quantized: Option<crate::model::ModelMetadata>,
-    baseline: Option<crate::model::ModelMetadata>,
+    service: ServiceInfo,
+    quantized: Option<ModelMetadata>,
+    baseline: Option<ModelMetadata>,
+    /// Same as `tokenizer.sha256`.
+    tokenizer_sha256: String,
+    tokenizer: TokenizerMetadata,
     quantization: Option<QuantizationSummary>,
     evaluation: Option<EvaluationReport>,
+    cuda_oom_events: u64,
+    /// Audit records dropped because the writer fell behind.
+    audit_records_dropped: u64,
+    /// Generations abandoned at `generation_timeout_secs` since startup.
+    generation_timeouts: u64,
+    stats: BTreeMap<String, ModelStatsSnapshot>,
+    /// Why the last model reload failed; cleared by a successful one.
+    last_reload_error: Option<String>,
+    /// The CUDA devices models run on; absent on CPU.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    gpu_memory: Vec<DeviceMemory>,
+    /// Whether each model is serving, keyed by model name.
+    model_states: BTreeMap<String, ModelState>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReloadResponse {
+    reload_time_ms: u128,
+    quantized: Option<ModelMetadata>,
+    baseline: Option<ModelMetadata>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UnloadResponse {
+    model: String,
+    /// `draining` while requests that started before are still running.
+    state: ModelState,
+}
+
+#[derive(Serialize, ToSchema)]
+struct LoadResponse {
+    load_time_ms: u128,
+    model: ModelMetadata,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Quantized LLM Service"),
+    paths(
+        playground,
+        liveness,
+        readiness,
+        generate_quantized,
+        generate_baseline,
+        score,
+        embed,
+        metadata,
+        version,
+        run_evaluation,
+        evaluation_progress,
+        evaluation_history,
+        compare_evaluations,
+        latest_report,
+        latest_report_html,
+        stored_report_html,
+        crate::websocket::ws_generate,
+        stats,
+        rate_limits,
+        usage,
+        reset_usage,
+        reset_stats,
+        shadow_diffs,
+        slow_requests,
+        canary,
+        set_canary,
+        presets,
+        models,
+        set_aliases,
+        analyze_quantization,
+        quantization_analysis,
+        reload_models,
+        unload_model,
+        load_model,
+        debug_compare,
+        openapi_json,
+    ),
+    components(schemas(
+        ErrorBody,
+        crate::error::ErrorPayload,
+        GenerationRequest,
+        GenerationResponse,
+        GenerationPreset,
+        crate::model::ContextStrategy,
+        crate::model::Priority,
+        crate::model::OnTimeout,
+        crate::model::ResponseFormat,
+        crate::model::FinishReason,
+        crate::model::GenerationTimings,
+        crate::model::Usage,
+        crate::model::EffectiveParams,
+        crate::model::ModelKind,
+        crate::model::TokenDetail,
+        ModelMetadata,
+        TokenizerMetadata,
+        crate::model::SpecialToken,
+        crate::model::SelfTestReport,
+        ReadinessReport,
+        DeviceMemory,
+        crate::model::QueueLengths,
+        ScoreRequest,
+        ScoreResponse,
+        crate::model::ContinuationScore,
+        EmbedRequest,
+        EmbedResponse,
+        crate::model::Pooling,
+        MetadataResponse,
+        ServiceInfo,
+        QuantizationSummary,
+        ModelStatsSnapshot,
+        crate::model::WaitBucket,
+        crate::model::ReplicaStatsSnapshot,
+        crate::model::ReplicaMetadata,
+        EvaluationReport,
+        crate::evaluation::SampleReport,
+        crate::evaluation::AggregateMetrics,
+        crate::evaluation::MemoryReport,
+        crate::evaluation::LengthBucket,
+        crate::evaluation::PairedComparison,
+        EvaluationMode,
+        ReportFormat,
+        StoredEvaluation,
+        ReportComparison,
+        crate::evaluation::MetricDelta,
+        crate::evaluation::MatchFlip,
+        crate::evaluation::UnmatchedSamples,
+        ProgressEvent,
+        crate::progress::ProgressSnapshot,
+        RateLimitSnapshot,
+        UsageSnapshot,
+        KeyUsageReport,
+        KeyUsage,
+        UsageResetRequest,
+        crate::rate_limit::ClientUsage,
+        ShadowDiff,
+        SlowRequest,
+        Canary,
+        ModelList,
+        ModelAlias,
+        AliasUpdate,
+        AnalyzeRequest,
+        QuantizationAnalysis,
+        crate::quantization::LogitsStats,
+        crate::quantization::PositionStats,
+        crate::quantization::LayerStats,
+        ReloadResponse,
+        ModelState,
+        UnloadResponse,
+        LoadResponse,
+        CompareRequest,
+        DivergenceReport,
+        crate::divergence::ComparedCompletion,
+        crate::divergence::Candidate,
+        crate::model::TokenCandidate,
+    )),
+    modifiers(&Aliases, &Security),
+    security(("bearer" = []), ("api_key" = []))
+)]
+pub struct ApiDoc;
+
+/// Routes served by the same handler as a documented one.
+struct Aliases;
+
+impl Modify for Aliases {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let paths = &mut openapi.paths.paths;
+        for (alias, target) in [("/health", "/health/ready"), ("/", "/playground")] {
+            if let Some(item) = paths.get(target).cloned() {
+                paths.insert(alias.to_string(), item);
+            }
+        }
+    }
 }
 
-pub fn build_router(config: Arc<AppConfig>, registry: Arc<ModelRegistry>) -> Router {
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-api-key"))),
+        );
+    }
+}
+
+pub fn build_router(
+    config: Arc<AppConfig>,
+    registry: Arc<ModelRegistry>,
+    store: Option<Store>,
+    audit: Option<AuditLog>,
+) -> Router {
+    let api_keys = Arc::new(ApiKeys::from_config(&config));
+    let rate_limiter = Arc::new(RateLimiter::from_config(&config));
+    let playground_enabled = config.playground_enabled();
+    let cors = cors_layer(&config);
+    let max_request_bytes = config.max_request_bytes;
+    let retry_after_secs = config.retry_after_secs;
+    let access_log = AccessLog::from_config(&config);
+    let swagger_ui_enabled = config.swagger_ui_enabled;
     let state = AppState {
         evaluation: Arc::new(RwLock::new(None)),
+        rate_limiter: rate_limiter.clone(),
+        shadow: Arc::new(ShadowCompare::from_config(&config)),
+        slow_requests: Arc::new(SlowRequests::from_config(&config)),
+        idempotency: Arc::new(IdempotencyCache::from_config(&config)),
+        canary_quantized_percent: Arc::new(RwLock::new(config.canary_quantized_percent)),
+        store,
+        progress: Arc::default(),
+        quantization_analysis: Arc::new(RwLock::new(None)),
+        audit,
         registry,
         config,
     };
 
-    Router::new()
-        .route("/health", get(health))
+    let mut router = Router::new();
+    if playground_enabled {
+        router = router
+            .route("/", get(playground))
+            .route("/playground", get(playground));
+    }
+    if swagger_ui_enabled {
+        router = router.merge(SwaggerUi::new("/docs").config(Config::from("/openapi.json")));
+    }
+
+    let router = router
+        // `/health` predates the split and stays an alias for readiness.
+        .route("/health", get(readiness))
+        .route("/health/live", get(liveness))
+        .route("/health/ready", get(readiness))
         .route("/generate", post(generate_quantized))
         .route("/generate/baseline", post(generate_baseline))
+        .route("/score", post(score))
+        .route("/embed", post(embed))
         .route("/metadata", get(metadata))
+        .route("/models", get(models))
+        .route("/presets", get(presets))
+        .route("/version", get(version))
+        .route("/openapi.json", get(openapi_json))
         .route("/evaluate", post(run_evaluation))
+        .route("/evaluate/progress", get(evaluation_progress))
+        .route("/evaluate/history", get(evaluation_history))
+        .route("/evaluate/compare", get(compare_evaluations))
+        .route("/evaluate/report", get(latest_report))
+        .route("/evaluate/report.html", get(latest_report_html))
+        // `{id}.html`; the static routes above take precedence.
+        .route("/evaluate/:page", get(stored_report_html))
+        .route("/ws/generate", get(ws_generate))
+        .route("/stats", get(stats))
+        .route("/admin/rate-limits", get(rate_limits))
+        .route("/admin/usage", get(usage))
+        .route("/admin/usage/reset", post(reset_usage))
+        .route("/admin/stats/reset", post(reset_stats))
+        .route("/admin/shadow/diffs", get(shadow_diffs))
+        .route("/admin/slow-requests", get(slow_requests))
+        .route("/admin/canary", get(canary).put(set_canary))
+        .route("/admin/aliases", put(set_aliases))
+        .route("/admin/reload", post(reload_models))
+        .route("/admin/models/:name/unload", post(unload_model))
+        .route("/admin/models/:name/load", post(load_model))
+        .route("/admin/quantization/analyze", post(analyze_quantization))
+        .route("/admin/quantization/analysis", get(quantization_analysis))
+        .route("/debug/compare", post(debug_compare))
+        .method_not_allowed_fallback(method_not_allowed)
+        .fallback(unknown_route)
         .with_state(state)
-        .layer(TraceLayer::new_for_http())
+        // Runs after authentication so limits can be keyed by API key.
+        .layer(axum::middleware::from_fn_with_state(
+            rate_limiter,
+            enforce_rate_limit,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            api_keys,
+            require_api_key,
+        ))
+        // Replaces axum's fixed 2 MB extractor limit with the configured one.
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(max_request_bytes))
+        .layer(axum::middleware::from_fn_with_state(
+            max_request_bytes,
+            structured_payload_too_large,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            retry_after_secs,
+            default_retry_after,
+        ))
+        .layer(axum::middleware::from_fn(attach_request_id))
+        // The default predicate already leaves event streams alone; upgraded
+        // WebSocket connections must not be wrapped either.
+        .layer(
+            CompressionLayer::new().compress_when(DefaultPredicate::new().and(
+                |status: StatusCode, _, _: &HeaderMap, _: &_| {
+                    status != StatusCode::SWITCHING_PROTOCOLS
+                },
+            )),
+        )
+        .layer(axum::middleware::from_fn_with_state(access_log, log_access))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(make_request_span)
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::INFO)
+                        .latency_unit(LatencyUnit::Millis),
+                ),
+        )
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+    // Outermost, so preflights are answered before authentication and rate
+    // limiting see them.
+    match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
+
+async fn unknown_route(method: Method, uri: Uri) -> ServiceError {
+    ServiceError::NotFound(format!("no route for {method} {}", uri.path()))
+}
+
+async fn method_not_allowed(method: Method, uri: Uri) -> ServiceError {
+    ServiceError::MethodNotAllowed(format!("{} does not accept {method}", uri.path()))
+}
+
+/// `None` when no origins are configured, leaving responses without CORS
+/// headers.
+fn cors_layer(config: &AppConfig) -> Option<CorsLayer> {
+    if config.cors_allowed_origins.is_empty() {
+        return None;
+    }
+    let origins = if config
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
+    {
+        AllowOrigin::any()
+    } else {
+        // Validated at startup.
+        AllowOrigin::list(
+            config
+                .cors_allowed_origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            // PUT for the admin settings (`/admin/canary`, `/admin/aliases`).
+            .allow_methods([Method::GET, Method::POST, Method::PUT])
+            .allow_headers([
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                HeaderName::from_static("x-api-key"),
+                IDEMPOTENCY_KEY_HEADER,
+            ])
+            .expose_headers([
+                REQUEST_ID_HEADER,
+                SERVED_MODEL_HEADER,
+                IDEMPOTENT_REPLAY_HEADER,
+            ])
+            .max_age(config.cors_max_age),
+    )
 }
 
-async fn health() -> &'static str {
+#[utoipa::path(
+    get,
+    path = "/playground",
+    tag = "health",
+    responses((status = 200, description = "Browser playground", content_type = "text/html", body = String))
+)]
+async fn playground() -> Html<&'static str> {
+    Html(PLAYGROUND_HTML)
+}
+
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    tag = "health",
+    responses((status = 200, description = "The process is up", content_type = "text/plain", body = String))
+)]
+async fn liveness() -> &'static str {
     "ok"
 }
 
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "health",
+    responses(
+        (status = 200, description = "Ready to serve", body = ReadinessReport),
+        (status = 503, description = "Not ready; `problems` says why", body = ReadinessReport)
+    )
+)]
+async fn readiness(State(state): State<AppState>) -> (StatusCode, Json<ReadinessReport>) {
+    let report = state.registry.readiness();
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+#[utoipa::path(
+    post,
+    path = "/generate",
+    tag = "generation",
+    request_body = GenerationRequest,
+    responses(
+        (status = 200, description = "Completion from the model the request or default_model names; otherwise the quantized model, or the baseline when it is unavailable or the canary routes there; `x-served-model` names the model, and `idempotent-replay: true` marks a response replayed for a retried Idempotency-Key", body = GenerationResponse),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Body does not match the schema, e.g. an unknown field, or the Idempotency-Key was already used with a different body", body = ErrorBody),
+        (status = 413, description = "Request body too large", body = ErrorBody),
+        (status = 429, description = "Rate limited, or the API key's token quota is used up", body = ErrorBody),
+        (status = 503, description = "Model loading or overloaded, or the quantized model is unavailable with fallback_to_baseline off", body = ErrorBody),
+        (status = 500, description = "Inference failed", body = ErrorBody)
+    )
+)]
 async fn generate_quantized(
     State(state): State<AppState>,
-    Json(request): Json<GenerationRequest>,
-) -> Result<Json<crate::model::GenerationResponse>, ServiceError> {
-    // Use quantized model if available, otherwise fallback to baseline
-    let response = if state.registry.has_quantized() {
-        state
+    api_key: Option<Extension<ApiKeyLabel>>,
+    headers: HeaderMap,
+    ApiJson(mut request): ApiJson<GenerationRequest>,
+) -> Result<Response, ServiceError> {
+    request.api_key = api_key.map(|Extension(ApiKeyLabel(label))| label);
+    let key = idempotency_key(&headers)?
+        .map(|key| RequestKey::new("/generate", request.api_key.clone(), key));
+    let request_id = request_id(&headers).map(str::to_string);
+    let (response, replayed) = state
+        .idempotency
+        .run(
+            key,
+            &request,
+            serve_quantized(state.clone(), request_id, request.clone()),
+        )
+        .await?;
+    Ok(idempotent_reply(response, replayed))
+}
+
+async fn serve_quantized(
+    state: AppState,
+    request_id: Option<String>,
+    mut request: GenerationRequest,
+) -> Result<GenerationResponse, ServiceError> {
+    let request_id = request_id.as_deref();
+    apply_preset(&mut request, &state.config)?;
+    let raw_prompt = apply_template(&mut request, &state.config)?;
+    let route = state.registry.route(request.model.as_deref())?;
+    // A named model wins; otherwise use quantized if available, falling back
+    // to baseline when allowed
+    let (use_quantized, fallback) = match &route {
+        Some(route) => (route.slot == ModelSlot::Quantized, false),
+        None => match state.registry.default_slot(&state.config)? {
+            (ModelSlot::Quantized, _) => (
+                !state.registry.has_baseline()
+                    || canary_selects_quantized(*state.canary_quantized_percent.read(), request_id),
+                false,
+            ),
+            (ModelSlot::Baseline, fallback) => (false, fallback),
+        },
+    };
+    let mut response = if use_quantized {
+        let shadowed = state.registry.has_baseline() && state.shadow.should_sample();
+        if shadowed {
+            state.shadow.prepare(&mut request, &state.config);
+        }
+        let params = GenerationParams::resolve(&request, &state.config);
+        let response = state
             .registry
             .generate_quantized(request, &state.config)
-            .await?
+            .await?;
+        if shadowed {
+            state.shadow.spawn(
+                state.registry.clone(),
+                state.config.clone(),
+                params,
+                response.clone(),
+            );
+        }
+        response
     } else {
         state
             .registry
             .generate_baseline(request, &state.config)
             .await?
     };
-    Ok(Json(response))
+    response.raw_prompt = raw_prompt;
+    response.served_via_alias = route.and_then(|route| route.alias);
+    response.fallback = fallback;
+    record_request_metrics(&state, &response);
+    state.slow_requests.observe(request_id, &response);
+    if let Some(audit) = &state.audit {
+        audit.record(request_id, &response);
+    }
+    Ok(response)
 }
 
+fn request_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Marks responses handed over from an earlier request with the same
+/// idempotency key.
+fn idempotent_reply(response: GenerationResponse, replayed: bool) -> Response {
+    let mut reply = generation_reply(response);
+    if replayed {
+        reply
+            .headers_mut()
+            .insert(IDEMPOTENT_REPLAY_HEADER, HeaderValue::from_static("true"));
+    }
+    reply
+}
+
+fn generation_reply(response: GenerationResponse) -> Response {
+    let served = ServedGeneration {
+        model: response.model.name.clone(),
+        tokens_generated: response.tokens_generated,
+    };
+    let model = HeaderValue::from_str(&served.model);
+    let mut reply = Json(response).into_response();
+    if let Ok(model) = model {
+        reply.headers_mut().insert(SERVED_MODEL_HEADER, model);
+    }
+    reply.extensions_mut().insert(served);
+    reply
+}
+
+/// Cache hits are left out so the stored latencies reflect real inference.
+fn record_request_metrics(state: &AppState, response: &GenerationResponse) {
+    let Some(store) = state.store.clone() else {
+        return;
+    };
+    if response.cached {
+        return;
+    }
+    let model = response.model.name.clone();
+    let latency_ms = response.total_time_ms as f64;
+    let tokens_generated = response.tokens_generated;
+    tokio::spawn(async move {
+        if let Err(err) = store
+            .record_request(model, latency_ms, tokens_generated)
+            .await
+        {
+            tracing::warn!(error = %err, "failed to record request metrics");
+        }
+    });
+}
+
+/// Buckets requests by a hash of their id so a retry that reuses its
+/// `x-request-id` is served by the same model.
+fn canary_selects_quantized(percent: f64, request_id: Option<&str>) -> bool {
+    if percent >= 100.0 {
+        return true;
+    }
+    if percent <= 0.0 {
+        return false;
+    }
+    let bucket = match request_id {
+        Some(id) => {
+            let mut hasher = DefaultHasher::new();
+            id.hash(&mut hasher);
+            hasher.finish() % 10_000
+        }
+        None => rand::random::<u64>() % 10_000,
+    };
+    (bucket as f64) < percent * 100.0
+}
+
+#[utoipa::path(
+    post,
+    path = "/generate/baseline",
+    tag = "generation",
+    request_body = GenerationRequest,
+    responses(
+        (status = 200, description = "Completion from the baseline model; `idempotent-replay: true` marks a response replayed for a retried Idempotency-Key", body = GenerationResponse),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Body does not match the schema, e.g. an unknown field, or the Idempotency-Key was already used with a different body", body = ErrorBody),
+        (status = 413, description = "Request body too large", body = ErrorBody),
+        (status = 429, description = "Rate limited, or the API key's token quota is used up", body = ErrorBody),
+        (status = 503, description = "Model loading or overloaded", body = ErrorBody),
+        (status = 500, description = "Inference failed", body = ErrorBody)
+    )
+)]
 async fn generate_baseline(
     State(state): State<AppState>,
-    Json(request): Json<GenerationRequest>,
-) -> Result<Json<crate::model::GenerationResponse>, ServiceError> {
+    api_key: Option<Extension<ApiKeyLabel>>,
+    headers: HeaderMap,
+    ApiJson(mut request): ApiJson<GenerationRequest>,
+) -> Result<Response, ServiceError> {
+    request.api_key = api_key.map(|Extension(ApiKeyLabel(label))| label);
+    let key = idempotency_key(&headers)?
+        .map(|key| RequestKey::new("/generate/baseline", request.api_key.clone(), key));
+    let request_id = request_id(&headers).map(str::to_string);
+    let (response, replayed) = state
+        .idempotency
+        .run(
+            key,
+            &request,
+            serve_baseline(state.clone(), request_id, request.clone()),
+        )
+        .await?;
+    Ok(idempotent_reply(response, replayed))
+}
+
+async fn serve_baseline(
+    state: AppState,
+    request_id: Option<String>,
+    mut request: GenerationRequest,
+) -> Result<GenerationResponse, ServiceError> {
     if !state.registry.has_baseline() {
-        return Err(ServiceError::BadRequest(
-            "baseline model not available".into(),
+        return Err(ServiceError::validation(
+            "model",
+            "the baseline model is not loaded",
         ));
     }
-    let response = state
+    // The route names its model, so only an explicit `model` is checked.
+    let alias = match request.model.as_deref() {
+        Some(name) => match state.registry.route(Some(name))? {
+            Some(route) if route.slot == ModelSlot::Baseline => route.alias,
+            _ => {
+                return Err(ServiceError::validation(
+                    "model",
+                    format!("'{name}' is not the baseline model; send it to /generate"),
+                ));
+            }
+        },
+        None => None,
+    };
+    apply_preset(&mut request, &state.config)?;
+    let raw_prompt = apply_template(&mut request, &state.config)?;
+    let mut response = state
         .registry
         .generate_baseline(request, &state.config)
         .await?;
-    Ok(Json(response))
+    response.raw_prompt = raw_prompt;
+    response.served_via_alias = alias;
+    let request_id = request_id.as_deref();
+    record_request_metrics(&state, &response);
+    state.slow_requests.observe(request_id, &response);
+    if let Some(audit) = &state.audit {
+        audit.record(request_id, &response);
+    }
+    Ok(response)
 }
 
+#[utoipa::path(
+    post,
+    path = "/score",
+    tag = "generation",
+    request_body = ScoreRequest,
+    responses(
+        (status = 200, description = "Log-probability of each continuation", body = ScoreResponse),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Body does not match the schema, e.g. an unknown field", body = ErrorBody),
+        (status = 501, description = "Not supported by the backend", body = ErrorBody),
+        (status = 503, description = "Model loading or overloaded, or the quantized model is unavailable with fallback_to_baseline off", body = ErrorBody)
+    )
+)]
+async fn score(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<ScoreRequest>,
+) -> Result<Json<ScoreResponse>, ServiceError> {
+    Ok(Json(state.registry.score(request, &state.config).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/embed",
+    tag = "generation",
+    request_body = EmbedRequest,
+    responses(
+        (status = 200, description = "One pooled vector per text", body = EmbedResponse),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Body does not match the schema, e.g. an unknown field", body = ErrorBody),
+        (status = 501, description = "Not supported by the backend", body = ErrorBody),
+        (status = 503, description = "Model loading or overloaded, or the quantized model is unavailable with fallback_to_baseline off", body = ErrorBody)
+    )
+)]
+async fn embed(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<EmbedRequest>,
+) -> Result<Json<EmbedResponse>, ServiceError> {
+    Ok(Json(state.registry.embed(request, &state.config).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/metadata",
+    tag = "metadata",
+    responses((status = 200, description = "Loaded models, build and latest evaluation", body = MetadataResponse))
+)]
 async fn metadata(State(state): State<AppState>) -> Json<MetadataResponse> {
     let (quantized, baseline) = state.registry.metadata();
-    let summarised = if let Some(ref q) = quantized {
-        Some(QuantizationSummary::from_metadata(q, baseline.as_ref()))
-    } else {
-        None
-    };
+    let summarised = quantized
+        .as_ref()
+        .map(|q| QuantizationSummary::from_metadata(q, baseline.as_ref()));
     let evaluation = state.evaluation.read().clone();
 
     Json(MetadataResponse {
+        service: ServiceInfo::new(&state.config),
         quantized,
         baseline,
+        tokenizer_sha256: state.registry.tokenizer_sha256(),
+        tokenizer: state.registry.tokenizer_metadata(),
         quantization: summarised,
         evaluation,
+        cuda_oom_events: cuda_oom_events(),
+        audit_records_dropped: state.audit.as_ref().map_or(0, AuditLog::dropped),
+        generation_timeouts: generation_timeouts(),
+        stats: state.registry.stats(),
+        last_reload_error: state.registry.last_reload_error(),
+        gpu_memory: state.registry.gpu_memory(),
+        model_states: state.registry.model_states(),
     })
 }
 
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "metadata",
+    responses((status = 200, description = "This document", content_type = "application/json"))
+)]
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "metadata",
+    responses((status = 200, description = "Build information", body = ServiceInfo))
+)]
+async fn version(State(state): State<AppState>) -> Json<ServiceInfo> {
+    Json(ServiceInfo::new(&state.config))
+}
+
+#[utoipa::path(
+    post,
+    path = "/evaluate",
+    tag = "evaluation",
+    params(EvaluateQuery),
+    request_body(
+        content = String,
+        content_type = "multipart/form-data",
+        description = "Optional. A `samples` file part (JSON, JSONL or CSV, by file name) replaces the configured benchmark; `max_new_tokens` and `temperature` fields override the configured values for this run."
+    ),
+    responses(
+        (status = 200, description = "Benchmark of the quantized model against the baseline", body = EvaluationReport),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 413, description = "Upload exceeds max_request_bytes", body = ErrorBody),
+        (status = 503, description = "Model loading or overloaded", body = ErrorBody)
+    )
+)]
 async fn run_evaluation(
     State(state): State<AppState>,
+    ApiQuery(query): ApiQuery<EvaluateQuery>,
+    headers: HeaderMap,
+    multipart: Result<Multipart, MultipartRejection>,
 ) -> Result<Json<EvaluationReport>, ServiceError> {
-    let samples = if let Some(path) = state.config.eval_prompts_path.as_ref() {
-        load_samples_from_path(path)?
-    } else {
-        fallback_samples()
+    // Anything other than a form keeps the original behavior, body or not.
+    let upload = match multipart {
+        Ok(multipart) => read_evaluation_upload(multipart, &state.config).await?,
+        Err(rejection) if is_multipart(&headers) => {
+            return Err(ServiceError::BadRequest(rejection.body_text()));
+        }
+        Err(_) => EvaluationUpload::default(),
     };
 
-    info!(count = samples.len(), "running evaluation benchmark");
+    let mut config = AppConfig::clone(&state.config);
+    if let Some(max_new_tokens) = upload.max_new_tokens {
+        config.max_new_tokens = max_new_tokens;
+    }
+    if let Some(temperature) = upload.temperature {
+        config.temperature = temperature;
+    }
+    let samples = match upload.samples {
+        Some(samples) => samples,
+        None => match config.eval_prompts_path.as_ref() {
+            Some(path) => {
+                load_samples_with_references(path, config.eval_reference_path.as_deref())?
+            }
+            None => fallback_samples(),
+        },
+    };
 
-    let report = run_benchmark(state.registry.clone(), &state.config, samples).await?;
+    info!(
+        count = samples.len(),
+        mode = ?query.mode,
+        uploaded = upload.uploaded,
+        "running evaluation benchmark"
+    );
+
+    let progress = state.progress.start(samples.len());
+    let report = match run_benchmark_with_progress(
+        state.registry.clone(),
+        &config,
+        samples,
+        query.mode,
+        progress.as_ref(),
+    )
+    .await
+    {
+        Ok(report) => report,
+        Err(err) => {
+            if let Some(progress) = progress {
+                progress.finish(None, Some(err.to_string()));
+            }
+            return Err(err);
+        }
+    };
     state.evaluation.write().replace(report.clone());
+    let mut report_id = None;
+    if let Some(store) = state.store.as_ref() {
+        // The report is still worth returning if it couldn't be kept.
+        match store.save_evaluation(&report).await {
+            Ok(id) => {
+                info!(id, "saved evaluation report");
+                report_id = Some(id);
+            }
+            Err(err) => tracing::warn!(error = %err, "failed to save evaluation report"),
+        }
+    }
+    if let Some(progress) = progress {
+        progress.finish(report_id, None);
+    }
 
     Ok(Json(report))
 }
+
+#[utoipa::path(
+    get,
+    path = "/evaluate/progress",
+    tag = "evaluation",
+    responses(
+        (status = 200, description = "Server-sent events for the running evaluation: a `snapshot` first, then `sample` per finished sample, periodic `snapshot`s and a closing `finished`", body = ProgressEvent, content_type = "text/event-stream"),
+        (status = 204, description = "No evaluation is running"),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn evaluation_progress(State(state): State<AppState>) -> Response {
+    let Some((snapshot, receiver)) = state.progress.subscribe() else {
+        return StatusCode::NO_CONTENT.into_response();
+    };
+    // Events missed by a lagging subscriber are skipped; the next snapshot
+    // brings its totals up to date.
+    let events = stream::unfold(Some(receiver), |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let last = matches!(event, ProgressEvent::Finished { .. });
+                    return Some((event, (!last).then_some(receiver)));
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = stream::once(future::ready(ProgressEvent::Snapshot(snapshot)))
+        .chain(events)
+        .map(|event| Event::default().event(event.name()).json_data(&event));
+    let sse = Sse::new(events);
+    let mut response = match state.config.keepalive_interval() {
+        Some(interval) => sse
+            .keep_alive(KeepAlive::new().interval(interval).text("keepalive"))
+            .into_response(),
+        None => sse.into_response(),
+    };
+    // Keeps nginx from holding events back until its buffer fills.
+    response.headers_mut().insert(
+        HeaderName::from_static("x-accel-buffering"),
+        HeaderValue::from_static("no"),
+    );
+    response
+}
+
+#[derive(Default)]
+struct EvaluationUpload {
+    samples: Option<Vec<BenchmarkSample>>,
+    uploaded: bool,
+    max_new_tokens: Option<usize>,
+    temperature: Option<f64>,
+}
+
+fn is_multipart(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/"))
+}
+
+/// Reads the `/evaluate` form. The body is already bounded by
+/// `max_request_bytes`, so parts are read into memory.
+async fn read_evaluation_upload(
+    mut multipart: Multipart,
+    config: &AppConfig,
+) -> Result<EvaluationUpload, ServiceError> {
+    let form_error = |err: MultipartError| {
+        if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            ServiceError::PayloadTooLarge {
+                limit_bytes: config.max_request_bytes,
+            }
+        } else {
+            ServiceError::BadRequest(format!("invalid multipart body: {}", err.body_text()))
+        }
+    };
+
+    let mut upload = EvaluationUpload::default();
+    while let Some(field) = multipart.next_field().await.map_err(form_error)? {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "samples" => {
+                let format = SampleFormat::from_file_name(field.file_name().unwrap_or_default());
+                let raw = field.text().await.map_err(form_error)?;
+                upload.samples = Some(parse_samples(&raw, format)?);
+                upload.uploaded = true;
+            }
+            "max_new_tokens" => {
+                let raw = field.text().await.map_err(form_error)?;
+                let value = raw
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&n| n >= 1 && n < config.max_context_tokens)
+                    .ok_or_else(|| {
+                        ServiceError::validation(
+                            "max_new_tokens",
+                            format!(
+                                "must be an integer from 1 to {}",
+                                config.max_context_tokens - 1
+                            ),
+                        )
+                    })?;
+                upload.max_new_tokens = Some(value);
+            }
+            "temperature" => {
+                let raw = field.text().await.map_err(form_error)?;
+                let value = raw
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|t| t.is_finite() && *t >= 0.0)
+                    .ok_or_else(|| {
+                        ServiceError::validation(
+                            "temperature",
+                            "must be zero (greedy) or a positive number",
+                        )
+                    })?;
+                upload.temperature = Some(value);
+            }
+            _ => {
+                return Err(ServiceError::BadRequest(format!(
+                    "unexpected form field '{name}'; expected samples, max_new_tokens or temperature"
+                )));
+            }
+        }
+    }
+    Ok(upload)
+}
+
+#[utoipa::path(
+    get,
+    path = "/evaluate/history",
+    tag = "evaluation",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "Stored evaluation runs, most recent first", body = [StoredEvaluation]),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 501, description = "No database configured", body = ErrorBody)
+    )
+)]
+async fn evaluation_history(
+    State(state): State<AppState>,
+    ApiQuery(query): ApiQuery<HistoryQuery>,
+) -> Result<Json<Vec<StoredEvaluation>>, ServiceError> {
+    let store = state.store.as_ref().ok_or_else(|| {
+        ServiceError::NotImplemented("evaluation history needs database_path to be set".into())
+    })?;
+    Ok(Json(store.evaluation_history(query.limit).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/evaluate/compare",
+    tag = "evaluation",
+    params(CompareQuery),
+    responses(
+        (status = 200, description = "Metric deltas and flipped samples between two stored runs", body = ReportComparison),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 404, description = "No stored run with one of the ids", body = ErrorBody),
+        (status = 501, description = "No database configured", body = ErrorBody)
+    )
+)]
+async fn compare_evaluations(
+    State(state): State<AppState>,
+    ApiQuery(query): ApiQuery<CompareQuery>,
+) -> Result<Json<ReportComparison>, ServiceError> {
+    let store = state.store.as_ref().ok_or_else(|| {
+        ServiceError::NotImplemented("evaluation comparison needs database_path to be set".into())
+    })?;
+    let load = |id| async move {
+        store
+            .evaluation_report(id)
+            .await?
+            .ok_or_else(|| ServiceError::NotFound(format!("no evaluation report with id {id}")))
+    };
+    let base = load(query.base).await?;
+    let candidate = load(query.candidate).await?;
+    Ok(Json(compare_reports(&base, &candidate)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/evaluate/report",
+    tag = "evaluation",
+    params(ReportQuery),
+    responses(
+        (status = 200, description = "The latest evaluation as a download: the JSON report, Markdown tables or one CSV row per sample", content(
+            ("application/json" = EvaluationReport),
+            ("text/markdown" = String),
+            ("text/csv" = String)
+        )),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 404, description = "No evaluation has run since startup", body = ErrorBody)
+    )
+)]
+async fn latest_report(
+    State(state): State<AppState>,
+    ApiQuery(query): ApiQuery<ReportQuery>,
+) -> Result<Response, ServiceError> {
+    let report = state
+        .evaluation
+        .read()
+        .clone()
+        .ok_or_else(|| ServiceError::NotFound("no evaluation has run since startup".into()))?;
+    let (body, content_type, extension) = match query.format {
+        ReportFormat::Json => (
+            serde_json::to_string_pretty(&report)
+                .map_err(|e| ServiceError::Other(e.to_string()))?,
+            "application/json",
+            "json",
+        ),
+        ReportFormat::Markdown => (
+            report.to_markdown(MARKDOWN_PROMPT_CHARS),
+            "text/markdown; charset=utf-8",
+            "md",
+        ),
+        ReportFormat::Csv => (report.to_csv()?, "text/csv; charset=utf-8", "csv"),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"evaluation.{extension}\""),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/evaluate/report.html",
+    tag = "evaluation",
+    responses(
+        (status = 200, description = "The latest evaluation as a standalone HTML page", content_type = "text/html", body = String),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 404, description = "No evaluation has run since startup", body = ErrorBody)
+    )
+)]
+async fn latest_report_html(State(state): State<AppState>) -> Result<Response, ServiceError> {
+    let report = state
+        .evaluation
+        .read()
+        .clone()
+        .ok_or_else(|| ServiceError::NotFound("no evaluation has run since startup".into()))?;
+    let samples = report.samples.into_iter().map(Into::into).collect();
+    Ok(html_report::render(
+        "Latest evaluation",
+        &report.aggregate,
+        samples,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/evaluate/{id}.html",
+    tag = "evaluation",
+    params(("id" = i64, Path, description = "Stored report id, as listed by /evaluate/history")),
+    responses(
+        (status = 200, description = "A stored evaluation as a standalone HTML page", content_type = "text/html", body = String),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 404, description = "No stored run with that id", body = ErrorBody),
+        (status = 501, description = "No database configured", body = ErrorBody)
+    )
+)]
+async fn stored_report_html(
+    State(state): State<AppState>,
+    Path(page): Path<String>,
+) -> Result<Response, ServiceError> {
+    let id: i64 = page
+        .strip_suffix(".html")
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| ServiceError::NotFound(format!("no page /evaluate/{page}")))?;
+    let store = state.store.as_ref().ok_or_else(|| {
+        ServiceError::NotImplemented("stored reports need database_path to be set".into())
+    })?;
+    let report = store
+        .evaluation_report(id)
+        .await?
+        .ok_or_else(|| ServiceError::NotFound(format!("no evaluation report with id {id}")))?;
+    let samples = report.samples.into_iter().map(Into::into).collect();
+    Ok(html_report::render(
+        &format!("Evaluation {id}"),
+        &report.evaluation.aggregate,
+        samples,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "metadata",
+    responses((status = 200, description = "Per-model counters keyed by model name", body = BTreeMap<String, ModelStatsSnapshot>))
+)]
+async fn stats(State(state): State<AppState>) -> Json<BTreeMap<String, ModelStatsSnapshot>> {
+    Json(state.registry.stats())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/stats/reset",
+    tag = "admin",
+    responses(
+        (status = 204, description = "Counters cleared"),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn reset_stats(State(state): State<AppState>) -> StatusCode {
+    state.registry.reset_stats();
+    StatusCode::NO_CONTENT
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/shadow/diffs",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Recent shadow runs that diverged from the baseline", body = [ShadowDiff]),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn shadow_diffs(State(state): State<AppState>) -> Json<Vec<ShadowDiff>> {
+    Json(state.shadow.recent_diffs())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/slow-requests",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Recent generations over slow_request_ms, most recent first", body = [SlowRequest]),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn slow_requests(State(state): State<AppState>) -> Json<Vec<SlowRequest>> {
+    Json(state.slow_requests.recent())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/canary",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Share of /generate traffic sent to the quantized model", body = Canary),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn canary(State(state): State<AppState>) -> Json<Canary> {
+    Json(Canary {
+        percent: *state.canary_quantized_percent.read(),
+    })
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/canary",
+    tag = "admin",
+    request_body = Canary,
+    responses(
+        (status = 200, description = "Split updated", body = Canary),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 422, description = "Body does not match the schema, e.g. an unknown field", body = ErrorBody),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn set_canary(
+    State(state): State<AppState>,
+    ApiJson(update): ApiJson<Canary>,
+) -> Result<Json<Canary>, ServiceError> {
+    if !(0.0..=100.0).contains(&update.percent) {
+        return Err(ServiceError::validation(
+            "percent",
+            "must be between 0 and 100",
+        ));
+    }
+    *state.canary_quantized_percent.write() = update.percent;
+    info!(percent = update.percent, "canary split updated");
+    Ok(Json(update))
+}
+
+#[utoipa::path(
+    get,
+    path = "/presets",
+    tag = "metadata",
+    responses(
+        (status = 200, description = "Configured presets by name, as selected with a request's `preset`", body = BTreeMap<String, GenerationPreset>)
+    )
+)]
+async fn presets(State(state): State<AppState>) -> Json<BTreeMap<String, GenerationPreset>> {
+    Json(state.config.presets.clone())
+}
+
+#[utoipa::path(
+    get,
+    path = "/models",
+    tag = "metadata",
+    responses(
+        (status = 200, description = "Loaded models and the aliases that point at them", body = ModelList)
+    )
+)]
+async fn models(State(state): State<AppState>) -> Json<ModelList> {
+    Json(model_list(&state.registry))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/aliases",
+    tag = "admin",
+    request_body = AliasUpdate,
+    responses(
+        (status = 200, description = "Aliases replaced", body = ModelList),
+        (status = 400, description = "An alias is part of a cycle, leads nowhere, or default_model would no longer resolve", body = ErrorBody),
+        (status = 422, description = "Body does not match the schema, e.g. an unknown field", body = ErrorBody),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn set_aliases(
+    State(state): State<AppState>,
+    ApiJson(update): ApiJson<AliasUpdate>,
+) -> Result<Json<ModelList>, ServiceError> {
+    state.registry.set_aliases(update.aliases)?;
+    let list = model_list(&state.registry);
+    info!(aliases = list.aliases.len(), "model aliases updated");
+    Ok(Json(list))
+}
+
+fn model_list(registry: &ModelRegistry) -> ModelList {
+    let (quantized, baseline) = registry.metadata();
+    let aliases = registry.aliases();
+    let aliases = aliases
+        .iter()
+        .map(|(alias, target)| ModelAlias {
+            alias: alias.clone(),
+            target: target.clone(),
+            // Validated at startup and on every update.
+            model: resolve_alias(&aliases, alias)
+                .map(|slot| slot.name().to_string())
+                .unwrap_or_default(),
+        })
+        .collect();
+    ModelList {
+        models: quantized.into_iter().chain(baseline).collect(),
+        aliases,
+        default_model: registry.default_model().map(str::to_string),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/reload",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The models now serving", body = ReloadResponse),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 500, description = "Reload failed; the previous models keep serving", body = ErrorBody)
+    )
+)]
+async fn reload_models(
+    State(state): State<AppState>,
+) -> Result<Json<ReloadResponse>, ServiceError> {
+    let started = Instant::now();
+    state.registry.reload(state.config.clone()).await?;
+    let (quantized, baseline) = state.registry.metadata();
+    Ok(Json(ReloadResponse {
+        reload_time_ms: started.elapsed().as_millis(),
+        quantized,
+        baseline,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/models/{name}/unload",
+    tag = "admin",
+    params(("name" = String, Path, description = "`baseline`, `quantized` or an alias")),
+    responses(
+        (status = 200, description = "The model no longer takes requests", body = UnloadResponse),
+        (status = 400, description = "Unknown model, not loaded, or the only one loaded", body = ErrorBody),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn unload_model(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<UnloadResponse>, ServiceError> {
+    let slot = model_slot(&state.registry, &name)?;
+    let state = state.registry.unload(slot).await?;
+    Ok(Json(UnloadResponse {
+        model: slot.name().to_string(),
+        state,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/models/{name}/load",
+    tag = "admin",
+    params(("name" = String, Path, description = "`baseline`, `quantized` or an alias")),
+    responses(
+        (status = 200, description = "The model is serving again", body = LoadResponse),
+        (status = 400, description = "Unknown model or already loaded", body = ErrorBody),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 500, description = "Load failed", body = ErrorBody)
+    )
+)]
+async fn load_model(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<LoadResponse>, ServiceError> {
+    let slot = model_slot(&state.registry, &name)?;
+    let started = Instant::now();
+    let model = state.registry.load(slot, state.config.clone()).await?;
+    Ok(Json(LoadResponse {
+        load_time_ms: started.elapsed().as_millis(),
+        model,
+    }))
+}
+
+fn model_slot(registry: &ModelRegistry, name: &str) -> Result<ModelSlot, ServiceError> {
+    resolve_alias(&registry.aliases(), name).map_err(|err| ServiceError::validation("model", err))
+}
+
+#[utoipa::path(
+    post,
+    path = "/debug/compare",
+    tag = "admin",
+    request_body = CompareRequest,
+    responses(
+        (status = 200, description = "Both greedy completions and where they first differ", body = DivergenceReport),
+        (status = 400, description = "Invalid request, or only one model is loaded", body = ErrorBody),
+        (status = 422, description = "Body does not match the schema, e.g. an unknown field", body = ErrorBody),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 503, description = "Model loading or overloaded", body = ErrorBody)
+    )
+)]
+async fn debug_compare(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<CompareRequest>,
+) -> Result<Json<DivergenceReport>, ServiceError> {
+    Ok(Json(
+        divergence::compare(&state.registry, &state.config, request).await?,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/quantization/analyze",
+    tag = "admin",
+    request_body = AnalyzeRequest,
+    responses(
+        (status = 200, description = "How far the quantized model's logits and hidden states drift from the baseline's", body = QuantizationAnalysis),
+        (status = 400, description = "Invalid request, or the quantized or baseline model is not loaded", body = ErrorBody),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 422, description = "Body does not match the schema, e.g. an unknown field", body = ErrorBody),
+        (status = 501, description = "A model's backend cannot expose its logits", body = ErrorBody)
+    )
+)]
+async fn analyze_quantization(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<AnalyzeRequest>,
+) -> Result<Json<QuantizationAnalysis>, ServiceError> {
+    info!(
+        prompts = request.prompts.len(),
+        "running quantization analysis"
+    );
+    let analysis = quantization::analyze(&state.registry, &request.prompts).await?;
+    state
+        .quantization_analysis
+        .write()
+        .replace(analysis.clone());
+    Ok(Json(analysis))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/quantization/analysis",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The most recent quantization analysis", body = QuantizationAnalysis),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 404, description = "No analysis has run since startup", body = ErrorBody)
+    )
+)]
+async fn quantization_analysis(
+    State(state): State<AppState>,
+) -> Result<Json<QuantizationAnalysis>, ServiceError> {
+    state
+        .quantization_analysis
+        .read()
+        .clone()
+        .map(Json)
+        .ok_or_else(|| {
+            ServiceError::NotFound("no quantization analysis has run since startup".into())
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/rate-limits",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Per-client rate limit usage", body = RateLimitSnapshot),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn rate_limits(State(state): State<AppState>) -> Json<RateLimitSnapshot> {
+    Json(state.rate_limiter.snapshot())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UsageResetRequest {
+    /// API key label, or `anonymous`.
+    pub key: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/usage",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Tokens charged to each API key and its remaining quota", body = UsageSnapshot),
+        (status = 403, description = "Requires an admin key", body = ErrorBody)
+    )
+)]
+async fn usage(State(state): State<AppState>) -> Json<UsageSnapshot> {
+    Json(state.registry.usage().snapshot())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/usage/reset",
+    tag = "admin",
+    request_body = UsageResetRequest,
+    responses(
+        (status = 200, description = "The key's usage after the reset", body = KeyUsageReport),
+        (status = 404, description = "No usage recorded for the key", body = ErrorBody),
+        (status = 403, description = "Requires an admin key", body = ErrorBody),
+        (status = 500, description = "The stored usage could not be cleared", body = ErrorBody)
+    )
+)]
+async fn reset_usage(
+    State(state): State<AppState>,
+    ApiJson(request): ApiJson<UsageResetRequest>,
+) -> Result<Json<KeyUsageReport>, ServiceError> {
+    state.registry.usage().reset(&request.key).await.map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{CapturedLogs, Reply, get, post_json, router, send};
+
+    const ORIGIN: &str = "https://app.example";
+
+    async fn preflight(router: &Router, uri: &str, origin: &str, method: Method) -> Reply {
+        let request = axum::http::Request::options(uri)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method.as_str())
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(Body::empty())
+            .unwrap();
+        send(router, request).await
+    }
+
+    fn with_cors(config: &mut AppConfig) {
+        config.cors_allowed_origins = vec![ORIGIN.to_string()];
+    }
+
+    #[tokio::test]
+    async fn preflight_allows_configured_origins() {
+        let router = router("server-cors", with_cors);
+        for uri in ["/generate", "/generate/baseline", "/ws/generate"] {
+            let reply = preflight(&router, uri, ORIGIN, Method::POST).await;
+            assert_eq!(reply.status, StatusCode::OK, "{uri}");
+            assert_eq!(
+                reply.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                Some(ORIGIN)
+            );
+            let methods = reply.header(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap();
+            assert!(methods.contains("POST"), "{methods}");
+            let headers = reply.header(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap();
+            assert!(headers.contains("content-type"), "{headers}");
+            assert!(reply.header(header::ACCESS_CONTROL_MAX_AGE).is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn preflight_from_other_origins_gets_no_grant() {
+        let router = router("server-cors-other", with_cors);
+        let reply = preflight(&router, "/generate", "https://evil.example", Method::POST).await;
+        assert_eq!(reply.header(header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+
+        let request = axum::http::Request::get("/health/live")
+            .header(header::ORIGIN, "https://evil.example")
+            .body(Body::empty())
+            .unwrap();
+        let reply = send(&router, request).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.header(header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+    }
+
+    #[tokio::test]
+    async fn cross_origin_generation_exposes_the_served_model() {
+        let router = router("server-cors-generate", with_cors);
+        let mut request = post_json("/generate", json!({"prompt": "Hello", "max_new_tokens": 2}));
+        request
+            .headers_mut()
+            .insert(header::ORIGIN, HeaderValue::from_static(ORIGIN));
+        let reply = send(&router, request).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        assert_eq!(reply.json()["tokens_generated"], 2);
+        assert_eq!(
+            reply.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some(ORIGIN)
+        );
+        let exposed = reply.header(header::ACCESS_CONTROL_EXPOSE_HEADERS).unwrap();
+        assert!(exposed.contains("x-served-model"), "{exposed}");
+    }
+
+    #[tokio::test]
+    async fn preflight_allows_the_admin_puts() {
+        let router = router("server-cors-put", with_cors);
+        for uri in ["/admin/canary", "/admin/aliases"] {
+            let reply = preflight(&router, uri, ORIGIN, Method::PUT).await;
+            assert_eq!(reply.status, StatusCode::OK, "{uri}");
+            let methods = reply.header(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap();
+            assert!(methods.contains("PUT"), "{methods}");
+        }
+    }
+
+    /// One quick pass over the built-in samples.
+    fn quick_evaluation(config: &mut AppConfig) {
+        config.eval_warmup_iters = 0;
+        config.eval_benchmark_iters = 1;
+        config.max_new_tokens = 4;
+    }
+
+    #[tokio::test]
+    async fn evaluation_reports_are_compressed_on_request() {
+        let router = router("server-gzip", quick_evaluation);
+        let request = axum::http::Request::post("/evaluate")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let reply = send(&router, request).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.header(header::CONTENT_ENCODING), Some("gzip"));
+        assert_eq!(reply.body[..2], [0x1f, 0x8b], "gzip magic");
+
+        let request = axum::http::Request::post("/evaluate")
+            .body(Body::empty())
+            .unwrap();
+        let reply = send(&router, request).await;
+        assert_eq!(reply.header(header::CONTENT_ENCODING), None);
+        assert!(reply.json()["samples"].is_array());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn memory_is_measured_on_linux() {
+        let router = router("server-memory", |config| {
+            quick_evaluation(config);
+            config.measure_memory = true;
+        });
+        let reply = send(&router, post_json("/generate", json!({"prompt": "Hello"}))).await;
+        assert!(
+            reply.json()["peak_rss_delta_bytes"].is_u64(),
+            "{}",
+            reply.text()
+        );
+
+        let request = axum::http::Request::post("/evaluate")
+            .body(Body::empty())
+            .unwrap();
+        let memory = send(&router, request).await.json()["memory"].clone();
+        for field in [
+            "rss_before_bytes",
+            "rss_after_bytes",
+            "quantized_peak_rss_delta_bytes",
+            "baseline_peak_rss_delta_bytes",
+        ] {
+            assert!(memory[field].is_u64(), "{field} in {memory}");
+        }
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_504s_and_partial_samples_in_evaluations() {
+        let router = router("server-timeout", |config| {
+            quick_evaluation(config);
+            // Each step of the fake models sleeps 20 ms.
+            std::fs::write(&config.quantized_module_path, "20").unwrap();
+            std::fs::write(&config.baseline_module_path, "20").unwrap();
+            config.generation_timeout = Duration::from_millis(50);
+            config.eval_timeout = Duration::from_millis(50);
+        });
+        let before = generation_timeouts();
+        let request = post_json(
+            "/generate",
+            json!({"prompt": "Hello", "max_new_tokens": 50}),
+        );
+        let reply = send(&router, request).await;
+        assert_eq!(reply.status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(reply.error_code(), "timeout");
+        let error = &reply.json()["error"];
+        let tokens_generated = error["details"]["tokens_generated"].as_u64().unwrap();
+        assert!((1..50).contains(&tokens_generated), "{error}");
+        assert!(error["details"]["elapsed_ms"].as_u64().unwrap() >= 50);
+        let message = error["message"].as_str().unwrap();
+        assert!(
+            message.ends_with(&format!("with {tokens_generated} tokens generated")),
+            "{message}"
+        );
+        assert!(generation_timeouts() > before);
+        let metadata = send(&router, get("/metadata")).await.json();
+        assert!(metadata["generation_timeouts"].as_u64().unwrap() > before);
+
+        // Asked for, what was generated by the deadline is returned.
+        let request = post_json(
+            "/generate",
+            json!({"prompt": "Hello", "max_new_tokens": 50, "on_timeout": "partial"}),
+        );
+        let reply = send(&router, request).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        let response = reply.json();
+        assert_eq!(response["finish_reason"], "timeout");
+        let tokens_generated = response["tokens_generated"].as_u64().unwrap();
+        assert!((1..50).contains(&tokens_generated), "{response}");
+        assert!(!response["completion"].as_str().unwrap().is_empty());
+        assert_eq!(response["timings"]["deadline_ms"], 50.0);
+
+        // Four tokens at 20 ms each run past the timeout too. The regex
+        // matches any completion, but a partial one never counts.
+        let csv = "prompt,reference_substring,match_mode\nHello,^,regex\n";
+        let reply = upload(&router, evaluation_form(Some(("samples.csv", csv)), &[])).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        let report = reply.json();
+        assert_eq!(report["aggregate"]["failed_samples"], 0);
+        for sample in report["samples"].as_array().unwrap() {
+            assert!(sample.get("error").is_none(), "{sample}");
+            assert_eq!(sample["quantized"]["finish_reason"], "timeout");
+            assert_eq!(sample["reference_match_quantized"], false);
+        }
+    }
+
+    #[tokio::test]
+    async fn gpu_memory_is_omitted_on_cpu() {
+        let router = router("server-gpu-memory", |_| {});
+        let ready = send(&router, get("/health/ready")).await.json();
+        assert_eq!(ready["quantized"]["device"], "cpu");
+        assert!(ready.get("gpu_memory").is_none(), "{ready}");
+        let metadata = send(&router, get("/metadata")).await.json();
+        assert!(metadata.get("gpu_memory").is_none(), "{metadata}");
+    }
+
+    #[tokio::test]
+    async fn an_unloaded_model_is_a_400_until_loaded_again() {
+        let router = router("server-unload", |_| {});
+        let admin = |uri: &str| axum::http::Request::post(uri).body(Body::empty()).unwrap();
+        let generate = || post_json("/generate/baseline", json!({"prompt": "Hello"}));
+
+        let reply = send(&router, admin("/admin/models/baseline/unload")).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        assert_eq!(
+            reply.json(),
+            json!({"model": "baseline", "state": "unloaded"})
+        );
+        let metadata = send(&router, get("/metadata")).await.json();
+        assert_eq!(metadata["model_states"]["baseline"], "unloaded");
+        assert_eq!(metadata["model_states"]["quantized"], "loaded");
+
+        let reply = send(&router, generate()).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert_eq!(reply.json()["error"]["details"]["field"], "model");
+        assert!(
+            reply.text().contains("the baseline model is not loaded"),
+            "{}",
+            reply.text()
+        );
+        let reply = send(&router, admin("/admin/models/quantized/unload")).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert!(
+            reply.text().contains("the only one loaded"),
+            "{}",
+            reply.text()
+        );
+
+        let reply = send(&router, admin("/admin/models/baseline/load")).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        assert_eq!(reply.json()["model"]["name"], "baseline");
+        let reply = send(&router, admin("/admin/models/baseline/load")).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        let reply = send(&router, generate()).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        let metadata = send(&router, get("/metadata")).await.json();
+        assert_eq!(metadata["model_states"]["baseline"], "loaded");
+    }
+
+    #[tokio::test]
+    async fn generate_falls_back_to_the_baseline_only_when_allowed() {
+        let generate = || post_json("/generate", json!({"prompt": "Hello", "max_new_tokens": 2}));
+        let reply = send(&router("server-no-fallback-needed", |_| {}), generate()).await;
+        assert_eq!(reply.header(SERVED_MODEL_HEADER), Some("quantized"));
+        assert_eq!(reply.json()["fallback"], false);
+
+        let without_quantized = |config: &mut AppConfig| {
+            std::fs::remove_file(&config.quantized_module_path).unwrap();
+        };
+        let fallback = router("server-fallback", without_quantized);
+        let logs = CapturedLogs::default();
+        {
+            let _default = tracing::dispatcher::set_default(&logs.dispatch());
+            for _ in 0..2 {
+                let reply = send(&fallback, generate()).await;
+                assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+                assert_eq!(reply.header(SERVED_MODEL_HEADER), Some("baseline"));
+                let response = reply.json();
+                assert_eq!(response["fallback"], true);
+                assert_eq!(response["model"]["name"], "baseline");
+            }
+        }
+        let warnings = logs
+            .lines()
+            .into_iter()
+            .filter(|line| {
+                line["fields"]["message"]
+                    == "quantized model unavailable, serving the baseline instead"
+            })
+            .count();
+        assert_eq!(warnings, 1, "rate-limited to one a minute");
+
+        let fallback_off = router("server-fallback-off", |config| {
+            without_quantized(config);
+            config.fallback_to_baseline = false;
+        });
+        let reply = send(&fallback_off, generate()).await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(reply.error_code(), "model_unavailable");
+        assert_eq!(reply.header(SERVED_MODEL_HEADER), None);
+    }
+
+    #[tokio::test]
+    async fn evaluation_progress_is_kept_alive_and_unbuffered() {
+        let router = router("server-progress-keepalive", |config| {
+            quick_evaluation(config);
+            // Each step of the fake models sleeps 50 ms.
+            std::fs::write(&config.quantized_module_path, "50").unwrap();
+            std::fs::write(&config.baseline_module_path, "50").unwrap();
+            config.stream_keepalive = Duration::from_millis(20);
+        });
+        let evaluation = tokio::spawn({
+            let router = router.clone();
+            async move {
+                let request = axum::http::Request::post("/evaluate")
+                    .body(Body::empty())
+                    .unwrap();
+                send(&router, request).await
+            }
+        });
+        let progress = loop {
+            let reply = send(&router, get("/evaluate/progress")).await;
+            if reply.status != StatusCode::NO_CONTENT {
+                break reply;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        };
+        assert_eq!(evaluation.await.unwrap().status, StatusCode::OK);
+        assert_eq!(progress.status, StatusCode::OK);
+        assert_eq!(
+            progress.header(header::CONTENT_TYPE),
+            Some("text/event-stream")
+        );
+        assert_eq!(progress.header(header::CACHE_CONTROL), Some("no-cache"));
+        assert_eq!(progress.header("x-accel-buffering"), Some("no"));
+        let events = progress.text();
+        assert!(events.contains(": keepalive\n"), "{events}");
+        assert!(events.contains("event: finished"), "{events}");
+    }
+
+    #[tokio::test]
+    async fn a_key_is_cut_off_at_its_quota_until_reset() {
+        let router = router("server-quota", |config| {
+            config.api_keys = vec!["alice:user-secret".into()];
+            config.admin_api_keys = vec!["ops:admin-secret".into()];
+            config.token_quotas = [("alice".to_string(), 10)].into();
+        });
+        let with_key = |mut request: axum::http::Request<Body>, key: &'static str| {
+            request
+                .headers_mut()
+                .insert("x-api-key", HeaderValue::from_static(key));
+            request
+        };
+        // One prompt token and up to three new ones are held up front.
+        let generate = || {
+            let request = post_json("/generate", json!({"prompt": "Hello", "max_new_tokens": 3}));
+            with_key(request, "user-secret")
+        };
+        for _ in 0..2 {
+            let reply = send(&router, generate()).await;
+            assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        }
+        let reply = send(&router, generate()).await;
+        assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(reply.error_code(), "quota_exceeded");
+        assert_eq!(
+            reply.json()["error"]["details"],
+            json!({"quota": 10, "remaining": 2, "requested": 4})
+        );
+
+        let usage = send(&router, with_key(get("/admin/usage"), "admin-secret"))
+            .await
+            .json();
+        assert_eq!(
+            usage["keys"],
+            json!([{
+                "key": "alice",
+                "prompt_tokens": 2,
+                "completion_tokens": 6,
+                "requests": 2,
+                "total_tokens": 8,
+                "reserved_tokens": 0,
+                "quota": 10,
+                "remaining": 2,
+            }])
+        );
+
+        let reset = post_json("/admin/usage/reset", json!({"key": "alice"}));
+        let reply = send(&router, with_key(reset, "admin-secret")).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        assert_eq!(reply.json()["remaining"], 10);
+        assert_eq!(send(&router, generate()).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn a_model_failing_its_self_test_is_not_ready() {
+        let router = router("server-self-test", |config| {
+            std::fs::write(&config.quantized_module_path, "eos").unwrap();
+        });
+        let reply = send(&router, get("/health/ready")).await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+        let report = reply.json();
+        assert_eq!(report["quantized"]["self_test"]["passed"], false);
+        let problem = report["problems"][0].as_str().unwrap();
+        assert!(
+            problem.starts_with("quantized model failed its startup self-test"),
+            "{problem}"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn cpu_time_is_reported_and_totalled_per_model() {
+        let router = router("server-cpu-time", |_| {});
+        let body = json!({ "prompt": "Hello", "max_new_tokens": 8 });
+        let response = send(&router, post_json("/generate", body)).await.json();
+        let cpu_time_ms = response["timings"]["cpu_time_ms"].as_f64().unwrap();
+        let total_time_ms = response["total_time_ms"].as_f64().unwrap();
+        assert!(cpu_time_ms > 0.0, "{response}");
+        // The total is rounded down to the millisecond.
+        assert!(
+            cpu_time_ms <= total_time_ms + 1.0,
+            "{cpu_time_ms} > {total_time_ms}"
+        );
+
+        let stats = send(&router, get("/stats")).await.json();
+        // Totals are kept in whole microseconds.
+        let total_cpu_time_ms = stats["quantized"]["cpu_time_ms"].as_f64().unwrap();
+        assert!((total_cpu_time_ms - cpu_time_ms).abs() < 0.001, "{stats}");
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_get_a_structured_413() {
+        let router = router("server-413", |config| config.max_request_bytes = 64);
+        let prompt = "x".repeat(100);
+        let reply = send(&router, post_json("/generate", json!({"prompt": prompt}))).await;
+        assert_eq!(reply.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(reply.error_code(), "payload_too_large");
+        assert_eq!(reply.json()["error"]["details"]["limit_bytes"], 64);
+    }
+
+    /// Every route `build_router` registers, as documented.
+    const ROUTES: &[(&str, &str)] = &[
+        ("get", "/"),
+        ("get", "/playground"),
+        ("get", "/health"),
+        ("get", "/health/live"),
+        ("get", "/health/ready"),
+        ("post", "/generate"),
+        ("post", "/generate/baseline"),
+        ("post", "/score"),
+        ("post", "/embed"),
+        ("get", "/metadata"),
+        ("get", "/models"),
+        ("get", "/presets"),
+        ("get", "/version"),
+        ("get", "/openapi.json"),
+        ("post", "/evaluate"),
+        ("get", "/evaluate/progress"),
+        ("get", "/evaluate/history"),
+        ("get", "/evaluate/compare"),
+        ("get", "/evaluate/report"),
+        ("get", "/evaluate/report.html"),
+        ("get", "/evaluate/{id}.html"),
+        ("get", "/ws/generate"),
+        ("get", "/stats"),
+        ("get", "/admin/rate-limits"),
+        ("get", "/admin/usage"),
+        ("post", "/admin/usage/reset"),
+        ("post", "/admin/stats/reset"),
+        ("get", "/admin/shadow/diffs"),
+        ("get", "/admin/slow-requests"),
+        ("get", "/admin/canary"),
+        ("put", "/admin/canary"),
+        ("put", "/admin/aliases"),
+        ("post", "/admin/reload"),
+        ("post", "/admin/models/{name}/unload"),
+        ("post", "/admin/models/{name}/load"),
+        ("post", "/admin/quantization/analyze"),
+        ("get", "/admin/quantization/analysis"),
+        ("post", "/debug/compare"),
+    ];
+
+    #[tokio::test]
+    async fn openapi_documents_every_route() {
+        let router = router("server-openapi", |_| {});
+        let reply = send(&router, get("/openapi.json")).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let document = reply.json();
+        assert!(document["openapi"].as_str().unwrap().starts_with('3'));
+        for (method, path) in ROUTES {
+            assert!(
+                document["paths"][path][method].is_object(),
+                "{method} {path} is not documented"
+            );
+            // No route answers DELETE, so a registered path says 405 where
+            // an unknown one says 404.
+            let uri = path.replace("{id}", "1").replace("{name}", "baseline");
+            let request = axum::http::Request::delete(&uri)
+                .body(Body::empty())
+                .unwrap();
+            let reply = send(&router, request).await;
+            assert_eq!(reply.status, StatusCode::METHOD_NOT_ALLOWED, "{uri}");
+        }
+        let documented: usize = document["paths"]
+            .as_object()
+            .unwrap()
+            .values()
+            .map(|item| item.as_object().unwrap().len())
+            .sum();
+        assert_eq!(documented, ROUTES.len());
+    }
+
+    #[tokio::test]
+    async fn swagger_ui_is_served_only_when_enabled() {
+        let enabled = router("server-docs", |config| config.swagger_ui_enabled = true);
+        let reply = send(&enabled, get("/docs/")).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.text().contains("swagger"), "{}", reply.text());
+
+        let disabled = router("server-no-docs", |config| config.swagger_ui_enabled = false);
+        let reply = send(&disabled, get("/docs/")).await;
+        assert_eq!(reply.status, StatusCode::NOT_FOUND);
+        assert_eq!(reply.error_code(), "not_found");
+    }
+
+    const BOUNDARY: &str = "qls-boundary";
+
+    /// A form with `fields` as plain parts and `samples` as a file part.
+    fn evaluation_form(samples: Option<(&str, &str)>, fields: &[(&str, &str)]) -> Body {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body += &format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            );
+        }
+        if let Some((file_name, contents)) = samples {
+            body += &format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"samples\"; filename=\"{file_name}\"\r\nContent-Type: text/csv\r\n\r\n{contents}\r\n"
+            );
+        }
+        body += &format!("--{BOUNDARY}--\r\n");
+        Body::from(body)
+    }
+
+    async fn upload(router: &Router, form: Body) -> Reply {
+        let request = axum::http::Request::post("/evaluate")
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .body(form)
+            .unwrap();
+        send(router, request).await
+    }
+
+    #[tokio::test]
+    async fn evaluates_an_uploaded_csv() {
+        let router = router("server-upload", quick_evaluation);
+        let csv = "prompt,reference\nOnce upon a time,there\nThe capital of France is,Paris\n";
+        let form = evaluation_form(Some(("samples.csv", csv)), &[("max_new_tokens", "3")]);
+        let reply = upload(&router, form).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        let report = reply.json();
+        let samples = report["samples"].as_array().unwrap();
+        let prompts: Vec<&str> = samples
+            .iter()
+            .map(|sample| sample["prompt"].as_str().unwrap())
+            .collect();
+        assert_eq!(prompts, ["Once upon a time", "The capital of France is"]);
+        for sample in samples {
+            assert_eq!(sample["quantized"]["tokens_generated"], 3);
+            assert_eq!(sample["baseline"]["tokens_generated"], 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failing_sample_leaves_a_partial_report() {
+        let router = router("server-partial", quick_evaluation);
+        let csv = "prompt\nOnce upon a time\n\"   \"\nThe capital of France is\n";
+        let reply = upload(&router, evaluation_form(Some(("samples.csv", csv)), &[])).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.text());
+        let report = reply.json();
+        assert_eq!(report["aggregate"]["failed_samples"], 1);
+        let samples = report["samples"].as_array().unwrap();
+        assert_eq!(samples.len(), 3);
+        let error = samples[1]["error"].as_str().unwrap();
+        assert!(error.contains("must not be empty"), "{error}");
+        assert!(samples[1]["quantized"].is_null());
+        for sample in [&samples[0], &samples[2]] {
+            assert!(sample.get("error").is_none(), "{sample}");
+            assert_eq!(sample["quantized"]["tokens_generated"], 4);
+        }
+
+        // With nothing to report, the first failure is the answer.
+        let csv = "prompt\n\"   \"\n\"  \"\n";
+        let reply = upload(&router, evaluation_form(Some(("samples.csv", csv)), &[])).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert_eq!(reply.json()["error"]["details"]["field"], "prompt");
+    }
+
+    #[tokio::test]
+    async fn bad_uploads_are_structured_400s() {
+        let router = router("server-bad-upload", quick_evaluation);
+        let form = evaluation_form(Some(("samples.csv", "text\nno prompt column\n")), &[]);
+        let reply = upload(&router, form).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert!(
+            reply.json()["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("'prompt'")
+        );
+
+        let form = evaluation_form(None, &[("max_new_tokens", "many")]);
+        let reply = upload(&router, form).await;
+        assert_eq!(reply.status, StatusCode::BAD_REQUEST);
+        assert_eq!(reply.json()["error"]["details"]["field"], "max_new_tokens");
+
+        let form = evaluation_form(None, &[("samples_path", "/etc/passwd")]);
+        assert_eq!(upload(&router, form).await.error_code(), "bad_request");
+    }
+
+    #[tokio::test]
+    async fn no_cors_headers_unless_configured() {
+        let router = router("server-no-cors", |_| {});
+        let request = axum::http::Request::get("/health/live")
+            .header(header::ORIGIN, ORIGIN)
+            .body(Body::empty())
+            .unwrap();
+        let reply = send(&router, request).await;
+        assert_eq!(reply.header(header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        assert_eq!(send(&router, get("/health/live")).await.text(), "ok");
+    }
+
+    #[tokio::test]
+    async fn stats_count_generations_until_reset() {
+        let router = router("server-stats", |_| {});
+        for _ in 0..2 {
+            let body = json!({ "prompt": "Hello", "max_new_tokens": 3, "temperature": 0.0 });
+            assert!(
+                send(&router, post_json("/generate", body))
+                    .await
+                    .status
+                    .is_success()
+            );
+        }
+        let stats = send(&router, get("/stats")).await.json();
+        assert_eq!(stats["quantized"]["requests"], 2);
+        assert_eq!(stats["quantized"]["tokens_generated"], 6);
+        assert_eq!(stats["quantized"]["window"], 2);
+        assert!(stats["quantized"]["avg_latency_ms"].is_number());
+        assert_eq!(stats["baseline"]["requests"], 0);
+
+        let metadata = send(&router, get("/metadata")).await.json();
+        assert_eq!(metadata["stats"]["quantized"]["requests"], 2);
+
+        let reset = send(&router, post_json("/admin/stats/reset", json!({}))).await;
+        assert_eq!(reset.status, StatusCode::NO_CONTENT);
+        let stats = send(&router, get("/stats")).await.json();
+        assert_eq!(stats["quantized"]["requests"], 0);
+        assert!(stats["quantized"]["avg_latency_ms"].is_null());
+    }
+}
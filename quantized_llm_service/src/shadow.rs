@@ -0,0 +1,147 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use utoipa::ToSchema;
+
+use crate::{
+    config::AppConfig,
+    model::{GenerationParams, GenerationRequest, GenerationResponse, ModelRegistry, Priority},
+};
+
+/// Divergent examples kept for `/admin/shadow/diffs`.
+const MAX_DIFFS: usize = 50;
+
+/// A quantized response whose baseline re-run produced something else.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ShadowDiff {
+    pub prompt: String,
+    pub max_new_tokens: usize,
+    pub temperature: f64,
+    pub top_k: usize,
+    pub seed: Option<u64>,
+    pub quantized_completion: String,
+    pub baseline_completion: String,
+    /// Fraction of word positions where the two completions agree.
+    pub word_agreement: f64,
+}
+
+/// Re-runs a sample of quantized `/generate` responses on the baseline in the
+/// background and records where they diverge.
+pub struct ShadowCompare {
+    sample_rate: f64,
+    // One shadow run at a time, and none while one is already queued, so
+    // shadow traffic can never pile up in front of real requests.
+    permit: Arc<Semaphore>,
+    diffs: Mutex<VecDeque<ShadowDiff>>,
+}
+
+impl ShadowCompare {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            sample_rate: config.shadow_sample_rate,
+            permit: Arc::new(Semaphore::new(1)),
+            diffs: Mutex::new(VecDeque::with_capacity(MAX_DIFFS)),
+        }
+    }
+
+    pub fn should_sample(&self) -> bool {
+        self.sample_rate > 0.0 && rand::random::<f64>() < self.sample_rate
+    }
+
+    /// Pins a seed on sampled requests so the baseline draws the same random
+    /// numbers; greedy and already-seeded requests are left alone.
+    pub fn prepare(&self, request: &mut GenerationRequest, config: &AppConfig) {
+        if !GenerationParams::resolve(request, config).is_deterministic() {
+            request.seed = Some(rand::random());
+        }
+    }
+
+    /// Compares `served` with a baseline run of the same request once a
+    /// shadow slot is free; skipped entirely when one is already in use.
+    pub fn spawn(
+        self: &Arc<Self>,
+        registry: Arc<ModelRegistry>,
+        config: Arc<AppConfig>,
+        params: GenerationParams,
+        served: GenerationResponse,
+    ) {
+        let Ok(permit) = self.permit.clone().try_acquire_owned() else {
+            tracing::debug!("shadow comparison skipped, previous run still in progress");
+            return;
+        };
+        let shadow = self.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let request = GenerationRequest {
+                prompt: served.prompt.clone(),
+                model: None,
+                max_new_tokens: Some(params.max_new_tokens),
+                temperature: Some(params.temperature),
+                top_k: Some(params.top_k),
+                context_strategy: Some(params.context_strategy),
+                truncate_prompt: false,
+                seed: params.seed,
+                priority: Some(Priority::Batch),
+                template: None,
+                system: None,
+                preset: None,
+                add_special_tokens: Some(params.add_special_tokens),
+                skip_special_tokens: Some(params.skip_special_tokens),
+                return_token_details: None,
+                on_timeout: None,
+                response_format: Some(params.response_format),
+                api_key: None,
+            };
+            let baseline = match registry.generate_shadow(request, &config).await {
+                Ok(baseline) => baseline,
+                Err(err) => {
+                    tracing::warn!(error = %err, "shadow baseline run failed");
+                    return;
+                }
+            };
+            let matched = baseline.completion == served.completion;
+            registry.record_shadow(&served.model.name, matched);
+            if matched {
+                return;
+            }
+            let word_agreement = word_agreement(&served.completion, &baseline.completion);
+            tracing::info!(word_agreement, "shadow comparison diverged from baseline");
+            shadow.push(ShadowDiff {
+                prompt: served.prompt,
+                max_new_tokens: params.max_new_tokens,
+                temperature: params.temperature,
+                top_k: params.top_k,
+                seed: params.seed,
+                quantized_completion: served.completion,
+                baseline_completion: baseline.completion,
+                word_agreement,
+            });
+        });
+    }
+
+    fn push(&self, diff: ShadowDiff) {
+        let mut diffs = self.diffs.lock();
+        if diffs.len() == MAX_DIFFS {
+            diffs.pop_front();
+        }
+        diffs.push_back(diff);
+    }
+
+    /// Most recent first.
+    pub fn recent_diffs(&self) -> Vec<ShadowDiff> {
+        self.diffs.lock().iter().rev().cloned().collect()
+    }
+}
+
+fn word_agreement(a: &str, b: &str) -> f64 {
+    let a: Vec<&str> = a.split_whitespace().collect();
+    let b: Vec<&str> = b.split_whitespace().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    let same = a.iter().zip(&b).filter(|(x, y)| x == y).count();
+    same as f64 / longest as f64
+}
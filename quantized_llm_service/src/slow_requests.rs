@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::{
+    config::AppConfig,
+    model::{GenerationResponse, GenerationTimings},
+    telemetry::ContentLogging,
+};
+
+/// Slow generations kept for `/admin/slow-requests`.
+const MAX_SLOW_REQUESTS: usize = 100;
+
+/// A generation that took longer than `slow_request_ms`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SlowRequest {
+    pub request_id: Option<String>,
+    pub model: String,
+    pub prompt_tokens: usize,
+    pub tokens_generated: usize,
+    pub total_time_ms: u128,
+    pub timings: GenerationTimings,
+    /// Salted SHA-256 of the prompt as sent, so repeats can be matched up
+    /// without keeping the text.
+    pub prompt_fingerprint: String,
+    /// Only kept when `log_prompts` allows, and truncated like it says.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+}
+
+/// Logs generations over the configured latency threshold and keeps the
+/// most recent ones. Prompt text is never logged while `log_prompts` is off.
+pub struct SlowRequests {
+    threshold_ms: u64,
+    logging: ContentLogging,
+    salt: String,
+    recent: Mutex<VecDeque<SlowRequest>>,
+}
+
+impl SlowRequests {
+    pub fn from_config(config: &AppConfig) -> Self {
+        // Without a configured salt, fingerprints only match within one run.
+        let salt = config
+            .prompt_fingerprint_salt
+            .clone()
+            .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+        Self {
+            threshold_ms: config.slow_request_ms,
+            logging: ContentLogging::from_config(config),
+            salt,
+            recent: Mutex::new(VecDeque::with_capacity(MAX_SLOW_REQUESTS)),
+        }
+    }
+
+    /// Records `response` if it was slow. Cache hits are skipped since their
+    /// timings belong to the original run.
+    pub fn observe(&self, request_id: Option<&str>, response: &GenerationResponse) {
+        if self.threshold_ms == 0
+            || response.cached
+            || response.total_time_ms < u128::from(self.threshold_ms)
+        {
+            return;
+        }
+        let slow = SlowRequest {
+            request_id: request_id.map(str::to_owned),
+            model: response.model.name.clone(),
+            prompt_tokens: response.usage.prompt_tokens,
+            tokens_generated: response.tokens_generated,
+            total_time_ms: response.total_time_ms,
+            timings: response.timings,
+            prompt_fingerprint: self.fingerprint(&response.raw_prompt),
+            prompt: self
+                .logging
+                .enabled()
+                .then(|| self.logging.text(&response.raw_prompt).to_string()),
+        };
+        tracing::warn!(
+            request_id = slow.request_id.as_deref(),
+            model = %slow.model,
+            prompt_tokens = slow.prompt_tokens,
+            tokens_generated = slow.tokens_generated,
+            total_time_ms = slow.total_time_ms as u64,
+            tokenize_ms = slow.timings.tokenize_ms,
+            queue_wait_ms = slow.timings.queue_wait_ms,
+            time_to_first_token_ms = slow.timings.time_to_first_token_ms,
+            decode_ms = slow.timings.decode_ms,
+            prompt_fingerprint = %slow.prompt_fingerprint,
+            prompt = slow.prompt.as_deref(),
+            "slow generation"
+        );
+
+        let mut recent = self.recent.lock();
+        if recent.len() == MAX_SLOW_REQUESTS {
+            recent.pop_front();
+        }
+        recent.push_back(slow);
+    }
+
+    /// Most recent first.
+    pub fn recent(&self) -> Vec<SlowRequest> {
+        self.recent.lock().iter().rev().cloned().collect()
+    }
+
+    fn fingerprint(&self, prompt: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update(prompt.as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
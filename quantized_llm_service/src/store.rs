@@ -0,0 +1,355 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use tokio::task;
+use utoipa::ToSchema;
+
+use crate::{
+    error::ServiceError,
+    evaluation::{AggregateMetrics, EvaluationReport},
+    quota::KeyUsage,
+};
+
+/// Schema changes, applied in order; `PRAGMA user_version` records how many
+/// have run. Only ever append.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE evaluation_reports (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at INTEGER NOT NULL,
+        aggregate TEXT NOT NULL
+    );
+    CREATE TABLE evaluation_samples (
+        report_id INTEGER NOT NULL REFERENCES evaluation_reports(id) ON DELETE CASCADE,
+        idx INTEGER NOT NULL,
+        prompt TEXT NOT NULL,
+        quantized_completion TEXT NOT NULL,
+        quantized_latency_ms INTEGER NOT NULL,
+        baseline_completion TEXT,
+        baseline_latency_ms INTEGER,
+        reference_match_quantized INTEGER,
+        reference_match_baseline INTEGER,
+        PRIMARY KEY (report_id, idx)
+    );
+    CREATE TABLE request_metrics (
+        hour INTEGER NOT NULL,
+        model TEXT NOT NULL,
+        requests INTEGER NOT NULL,
+        total_latency_ms REAL NOT NULL,
+        tokens_generated INTEGER NOT NULL,
+        PRIMARY KEY (hour, model)
+    );",
+    "ALTER TABLE evaluation_samples ADD COLUMN error TEXT;",
+    "CREATE TABLE api_key_usage (
+        key TEXT PRIMARY KEY,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        requests INTEGER NOT NULL
+    );",
+];
+
+/// A stored evaluation run without its per-sample rows.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct StoredEvaluation {
+    pub id: i64,
+    /// Unix seconds.
+    pub created_at: i64,
+    pub aggregate: AggregateMetrics,
+}
+
+/// A stored evaluation run with the per-sample rows that were kept.
+#[derive(Debug, Clone)]
+pub struct StoredReport {
+    pub evaluation: StoredEvaluation,
+    pub samples: Vec<StoredSample>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredSample {
+    pub prompt: String,
+    pub quantized_completion: String,
+    pub quantized_latency_ms: i64,
+    pub baseline_completion: Option<String>,
+    pub baseline_latency_ms: Option<i64>,
+    pub reference_match_quantized: Option<bool>,
+    pub reference_match_baseline: Option<bool>,
+    pub error: Option<String>,
+}
+
+/// SQLite persistence for evaluation reports, hourly request metrics and
+/// API key usage.
+/// Every call runs on the blocking pool so async handlers never wait on disk.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Store {
+    /// Opens (or creates) the database and brings its schema up to date.
+    pub fn open(path: &Path) -> Result<Self, ServiceError> {
+        let mut conn = Connection::open(path).map_err(db_error)?;
+        conn.pragma_update(None, "foreign_keys", true)
+            .map_err(db_error)?;
+        migrate(&mut conn)?;
+        tracing::info!(path = %path.display(), "opened metrics database");
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub async fn save_evaluation(&self, report: &EvaluationReport) -> Result<i64, ServiceError> {
+        let aggregate = serde_json::to_string(&report.aggregate)
+            .map_err(|e| ServiceError::Database(e.to_string()))?;
+        let samples: Vec<_> = report
+            .samples
+            .iter()
+            .map(|sample| {
+                // A failed sample is kept with an empty completion and its error.
+                (
+                    sample.prompt.clone(),
+                    sample
+                        .quantized
+                        .as_ref()
+                        .map(|q| q.completion.clone())
+                        .unwrap_or_default(),
+                    sample
+                        .quantized
+                        .as_ref()
+                        .map_or(0, |q| q.total_time_ms as i64),
+                    sample.baseline.as_ref().map(|b| b.completion.clone()),
+                    sample.baseline.as_ref().map(|b| b.total_time_ms as i64),
+                    sample.reference_match_quantized,
+                    sample.reference_match_baseline,
+                    sample.error.clone(),
+                )
+            })
+            .collect();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO evaluation_reports (created_at, aggregate) VALUES (?1, ?2)",
+                params![unix_now(), aggregate],
+            )?;
+            let id = tx.last_insert_rowid();
+            {
+                let mut insert = tx.prepare(
+                    "INSERT INTO evaluation_samples (report_id, idx, prompt, \
+                     quantized_completion, quantized_latency_ms, baseline_completion, \
+                     baseline_latency_ms, reference_match_quantized, reference_match_baseline, \
+                     error) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                )?;
+                for (idx, sample) in samples.into_iter().enumerate() {
+                    insert.execute(params![
+                        id, idx as i64, sample.0, sample.1, sample.2, sample.3, sample.4, sample.5,
+                        sample.6, sample.7
+                    ])?;
+                }
+            }
+            tx.commit()?;
+            Ok(id)
+        })
+        .await
+    }
+
+    /// Most recent first.
+    pub async fn evaluation_history(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<StoredEvaluation>, ServiceError> {
+        let rows: Vec<(i64, i64, String)> = self
+            .with_conn(move |conn| {
+                let mut query = conn.prepare(
+                    "SELECT id, created_at, aggregate FROM evaluation_reports \
+                     ORDER BY id DESC LIMIT ?1",
+                )?;
+                let rows = query
+                    .query_map(params![limit as i64], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+        rows.into_iter()
+            .map(|(id, created_at, aggregate)| stored_evaluation(id, created_at, &aggregate))
+            .collect()
+    }
+
+    /// The run with `id` and its samples in their original order, if it exists.
+    pub async fn evaluation_report(&self, id: i64) -> Result<Option<StoredReport>, ServiceError> {
+        let row = self
+            .with_conn(move |conn| {
+                let Some((created_at, aggregate)) = conn
+                    .query_row(
+                        "SELECT created_at, aggregate FROM evaluation_reports WHERE id = ?1",
+                        params![id],
+                        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+                let mut query = conn.prepare(
+                    "SELECT prompt, quantized_completion, quantized_latency_ms, \
+                     baseline_completion, baseline_latency_ms, reference_match_quantized, \
+                     reference_match_baseline, error FROM evaluation_samples \
+                     WHERE report_id = ?1 ORDER BY idx",
+                )?;
+                let samples = query
+                    .query_map(params![id], |row| {
+                        Ok(StoredSample {
+                            prompt: row.get(0)?,
+                            quantized_completion: row.get(1)?,
+                            quantized_latency_ms: row.get(2)?,
+                            baseline_completion: row.get(3)?,
+                            baseline_latency_ms: row.get(4)?,
+                            reference_match_quantized: row.get(5)?,
+                            reference_match_baseline: row.get(6)?,
+                            error: row.get(7)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Some((created_at, aggregate, samples)))
+            })
+            .await?;
+        row.map(|(created_at, aggregate, samples)| {
+            Ok(StoredReport {
+                evaluation: stored_evaluation(id, created_at, &aggregate)?,
+                samples,
+            })
+        })
+        .transpose()
+    }
+
+    /// Adds a served generation to its model's bucket for the current hour.
+    pub async fn record_request(
+        &self,
+        model: String,
+        latency_ms: f64,
+        tokens_generated: usize,
+    ) -> Result<(), ServiceError> {
+        let tokens = tokens_generated as i64;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO request_metrics (hour, model, requests, total_latency_ms, \
+                 tokens_generated) VALUES (?1, ?2, 1, ?3, ?4) \
+                 ON CONFLICT (hour, model) DO UPDATE SET requests = requests + 1, \
+                 total_latency_ms = total_latency_ms + excluded.total_latency_ms, \
+                 tokens_generated = tokens_generated + excluded.tokens_generated",
+                params![unix_now() / 3600 * 3600, model, latency_ms, tokens],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Usage per API key since each key's last reset.
+    pub async fn load_usage(&self) -> Result<Vec<(String, KeyUsage)>, ServiceError> {
+        self.with_conn(|conn| {
+            let mut select = conn.prepare(
+                "SELECT key, prompt_tokens, completion_tokens, requests FROM api_key_usage",
+            )?;
+            let rows = select.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    KeyUsage {
+                        prompt_tokens: row.get::<_, i64>(1)? as u64,
+                        completion_tokens: row.get::<_, i64>(2)? as u64,
+                        requests: row.get::<_, i64>(3)? as u64,
+                    },
+                ))
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Adds one served generation to `key`'s totals.
+    pub async fn record_usage(
+        &self,
+        key: String,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) -> Result<(), ServiceError> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO api_key_usage (key, prompt_tokens, completion_tokens, requests) \
+                 VALUES (?1, ?2, ?3, 1) \
+                 ON CONFLICT (key) DO UPDATE SET \
+                 prompt_tokens = prompt_tokens + excluded.prompt_tokens, \
+                 completion_tokens = completion_tokens + excluded.completion_tokens, \
+                 requests = requests + 1",
+                params![key, prompt_tokens as i64, completion_tokens as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn reset_usage(&self, key: String) -> Result<(), ServiceError> {
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM api_key_usage WHERE key = ?1", params![key])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn with_conn<T, F>(&self, work: F) -> Result<T, ServiceError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        task::spawn_blocking(move || work(&mut conn.lock()).map_err(db_error))
+            .await
+            .map_err(|e| ServiceError::Database(format!("database task failed: {e}")))?
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), ServiceError> {
+    let applied: Option<i64> = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .optional()
+        .map_err(db_error)?;
+    let applied = applied.unwrap_or(0) as usize;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction().map_err(db_error)?;
+        tx.execute_batch(migration).map_err(db_error)?;
+        tx.pragma_update(None, "user_version", (version + 1) as i64)
+            .map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        tracing::info!(version = version + 1, "applied database migration");
+    }
+    Ok(())
+}
+
+fn stored_evaluation(
+    id: i64,
+    created_at: i64,
+    aggregate: &str,
+) -> Result<StoredEvaluation, ServiceError> {
+    let aggregate = serde_json::from_str(aggregate).map_err(|e| {
+        ServiceError::Database(format!("evaluation report {id} is unreadable: {e}"))
+    })?;
+    Ok(StoredEvaluation {
+        id,
+        created_at,
+        aggregate,
+    })
+}
+
+fn db_error(err: rusqlite::Error) -> ServiceError {
+    ServiceError::Database(err.to_string())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,466 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, layer::SubscriberExt, util::SubscriberInitExt,
+};
+
+use crate::config::AppConfig;
+
+const DEFAULT_FILTER: &str = "info,hyper=warn,axum::rejection=trace";
+
+/// Shape of each log line written to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Single-line human-readable output.
+    #[default]
+    Compact,
+    /// Multi-line human-readable output for local debugging.
+    Pretty,
+    /// One JSON object per line, event and span fields flattened.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "compact" => Ok(Self::Compact),
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            other => Err(format!(
+                "unknown log format {other:?}, expected compact, pretty or json"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Compact => "compact",
+            Self::Pretty => "pretty",
+            Self::Json => "json",
+        })
+    }
+}
+
+/// How much generation content `debug` events may carry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogPrompts {
+    /// Prompts and completions only ever appear as their length.
+    #[default]
+    Off,
+    /// Cut to `log_prompts_max_chars` characters.
+    Truncated,
+    Full,
+}
+
+impl FromStr for LogPrompts {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // `true` and `false` are what the setting took when it was a flag.
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" | "false" => Ok(Self::Off),
+            "truncated" => Ok(Self::Truncated),
+            "full" | "true" => Ok(Self::Full),
+            other => Err(format!(
+                "unknown log_prompts {other:?}, expected off, truncated or full"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogPrompts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Truncated => "truncated",
+            Self::Full => "full",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for LogPrompts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Flag(bool),
+            Mode(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Flag(true) => Ok(Self::Full),
+            Raw::Flag(false) => Ok(Self::Off),
+            Raw::Mode(mode) => mode.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// The configured `log_prompts` policy, applied through [`ContentLogging::text`].
+#[derive(Debug, Clone, Copy)]
+pub struct ContentLogging {
+    pub mode: LogPrompts,
+    pub max_chars: usize,
+}
+
+impl ContentLogging {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            mode: config.log_prompts,
+            max_chars: config.log_prompts_max_chars,
+        }
+    }
+
+    pub fn enabled(self) -> bool {
+        self.mode != LogPrompts::Off
+    }
+
+    pub fn text(self, text: &str) -> LoggedText<'_> {
+        LoggedText {
+            text,
+            logging: self,
+        }
+    }
+}
+
+/// Prompt or completion text headed for a log line or error message.
+/// Formatting it, with `{}` or `{:?}`, shows only its length unless
+/// `log_prompts` allows more, so content can't leak by accident.
+#[derive(Clone, Copy)]
+pub struct LoggedText<'a> {
+    text: &'a str,
+    logging: ContentLogging,
+}
+
+impl<'a> LoggedText<'a> {
+    /// Always formats as its length.
+    pub fn redacted(text: &'a str) -> Self {
+        Self {
+            text,
+            logging: ContentLogging {
+                mode: LogPrompts::Off,
+                max_chars: 0,
+            },
+        }
+    }
+}
+
+impl fmt::Display for LoggedText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chars = self.text.chars().count();
+        match self.logging.mode {
+            LogPrompts::Off => write!(f, "<redacted, {chars} chars>"),
+            LogPrompts::Truncated if chars > self.logging.max_chars => {
+                let kept: String = self.text.chars().take(self.logging.max_chars).collect();
+                write!(f, "{kept}…(+{} chars)", chars - self.logging.max_chars)
+            }
+            LogPrompts::Truncated | LogPrompts::Full => f.write_str(self.text),
+        }
+    }
+}
+
+impl fmt::Debug for LoggedText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.logging.mode {
+            LogPrompts::Off => fmt::Display::fmt(self, f),
+            _ => fmt::Debug::fmt(&self.to_string(), f),
+        }
+    }
+}
+
+/// Builds the stdout layer for `format`.
+pub fn fmt_layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let layer = tracing_subscriber::fmt::layer().with_target(false);
+    match format {
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        // The current span carries the request id, route and model name, so
+        // it is kept alongside the event's own fields.
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+/// Flushes exported spans when dropped; keep it alive until shutdown.
+#[derive(Default)]
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.tracer_provider.take()
+            && let Err(err) = provider.shutdown()
+        {
+            eprintln!("failed to flush OpenTelemetry spans: {err}");
+        }
+    }
+}
+
+/// Installs the global subscriber; `RUST_LOG` overrides the default filter.
+/// Does nothing when a subscriber is already set.
+pub fn init_tracing(config: &AppConfig) -> anyhow::Result<TelemetryGuard> {
+    if tracing::dispatcher::has_been_set() {
+        return Ok(TelemetryGuard::default());
+    }
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| DEFAULT_FILTER.into());
+    let registry = Registry::default()
+        .with(env_filter)
+        .with(fmt_layer(config.log_format));
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider as _;
+
+        let tracer_provider = config
+            .otlp_endpoint
+            .as_deref()
+            .map(otel::tracer_provider)
+            .transpose()?;
+        let otel_layer = tracer_provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        });
+        registry.with(otel_layer).init();
+        if let Some(endpoint) = &config.otlp_endpoint {
+            tracing::info!(%endpoint, "exporting traces over OTLP");
+        }
+        Ok(TelemetryGuard { tracer_provider })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        if config.otlp_endpoint.is_some() {
+            tracing::warn!(
+                "OTEL_EXPORTER_OTLP_ENDPOINT is set but this build lacks the `otel` feature; \
+                 traces are not exported"
+            );
+        }
+        Ok(TelemetryGuard::default())
+    }
+}
+
+#[cfg(feature = "otel")]
+pub use otel::set_remote_parent;
+
+#[cfg(feature = "otel")]
+mod otel {
+    use axum::http::HeaderMap;
+    use opentelemetry::{global, propagation::Extractor};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{
+        Resource, propagation::TraceContextPropagator, trace::SdkTracerProvider,
+    };
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    pub(super) fn tracer_provider(endpoint: &str) -> anyhow::Result<SdkTracerProvider> {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let resource = Resource::builder()
+            .with_service_name(env!("CARGO_PKG_NAME"))
+            .build();
+        Ok(SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource)
+            .build())
+    }
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|name| name.as_str()).collect()
+        }
+    }
+
+    /// Continues the caller's trace when the request carries a W3C
+    /// `traceparent` header.
+    pub fn set_remote_parent(span: &tracing::Span, headers: &HeaderMap) {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        let _ = span.set_parent(parent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{CapturedLogs, post_json, router, send};
+
+    #[test]
+    fn logged_text_shows_only_what_the_mode_allows() {
+        let logging = |mode| ContentLogging { mode, max_chars: 5 };
+        let text = "Hello, world";
+        let off = logging(LogPrompts::Off).text(text);
+        assert_eq!(off.to_string(), "<redacted, 12 chars>");
+        assert_eq!(format!("{off:?}"), "<redacted, 12 chars>");
+        let truncated = logging(LogPrompts::Truncated).text(text);
+        assert_eq!(truncated.to_string(), "Hello…(+7 chars)");
+        assert_eq!(format!("{truncated:?}"), "\"Hello…(+7 chars)\"");
+        assert_eq!(logging(LogPrompts::Full).text(text).to_string(), text);
+        assert_eq!(
+            LoggedText::redacted(text).to_string(),
+            "<redacted, 12 chars>"
+        );
+    }
+
+    /// Every line logged while `router` serves a generation of `prompt`,
+    /// on the blocking pool as well.
+    fn generation_logs(log_prompts: LogPrompts, prompt: &str) -> String {
+        let logs = CapturedLogs::default();
+        let dispatch = logs.dispatch();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .on_thread_start({
+                let dispatch = dispatch.clone();
+                move || mem::forget(tracing::dispatcher::set_default(&dispatch))
+            })
+            .build()
+            .unwrap();
+        let _default = tracing::dispatcher::set_default(&dispatch);
+        runtime.block_on(async {
+            let router = router(&format!("telemetry-log-prompts-{log_prompts}"), |config| {
+                config.log_prompts = log_prompts;
+            });
+            let reply = send(&router, post_json("/generate", json!({ "prompt": prompt }))).await;
+            assert!(reply.status.is_success(), "{}", reply.text());
+        });
+        drop(runtime);
+        logs.lines()
+            .iter()
+            .map(|line| format!("{line}\n"))
+            .collect()
+    }
+
+    #[test]
+    fn no_log_line_carries_the_prompt_by_default() {
+        let prompt = "my very private prompt";
+        let logs = generation_logs(LogPrompts::default(), prompt);
+        // The generation's own spans, closed on the blocking pool, are in.
+        assert!(logs.contains("\"prefill\""), "{logs}");
+        assert!(!logs.contains("private"), "{logs}");
+
+        let logs = generation_logs(LogPrompts::Full, prompt);
+        assert!(logs.contains(prompt), "{logs}");
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod otel_tests {
+    use std::sync::OnceLock;
+
+    use axum::http::HeaderValue;
+    use opentelemetry::{
+        KeyValue, global,
+        trace::{SpanId, TraceId, TracerProvider as _},
+    };
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator,
+        trace::{InMemorySpanExporter, SdkTracerProvider, SpanData},
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{post_json, router, send};
+
+    /// Spans from every test in the process end up here: the generation
+    /// spans are opened on the blocking pool, so only a global subscriber
+    /// sees them.
+    fn exported() -> &'static InMemorySpanExporter {
+        static EXPORTER: OnceLock<InMemorySpanExporter> = OnceLock::new();
+        EXPORTER.get_or_init(|| {
+            let exporter = InMemorySpanExporter::default();
+            let provider = SdkTracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .build();
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+            tracing::subscriber::set_global_default(Registry::default().with(layer))
+                .expect("no other global subscriber");
+            exporter
+        })
+    }
+
+    /// The attribute's value as text, however the layer typed it.
+    fn attribute(span: &SpanData, key: &str) -> Option<String> {
+        span.attributes
+            .iter()
+            .find(|KeyValue { key: name, .. }| name.as_str() == key)
+            .map(|KeyValue { value, .. }| value.as_str().into_owned())
+    }
+
+    #[tokio::test]
+    async fn generation_spans_continue_the_callers_trace() {
+        let exporter = exported();
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let router = router("telemetry-spans", |_| {});
+        let mut request = post_json(
+            "/generate",
+            json!({ "prompt": "Hello", "max_new_tokens": 3, "temperature": 0.0 }),
+        );
+        request.headers_mut().insert(
+            "traceparent",
+            HeaderValue::from_str(&format!("00-{trace_id}-00f067aa0ba902b7-01")).unwrap(),
+        );
+        assert!(send(&router, request).await.status.is_success());
+
+        let trace_id = TraceId::from_hex(trace_id).unwrap();
+        let spans: Vec<SpanData> = exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .filter(|span| span.span_context.trace_id() == trace_id)
+            .collect();
+        let named = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("no {name} span in {spans:#?}"))
+        };
+        let request = named("request");
+        assert_eq!(
+            request.parent_span_id,
+            SpanId::from_hex("00f067aa0ba902b7").unwrap()
+        );
+        let inference = named("inference");
+        assert_eq!(inference.parent_span_id, request.span_context.span_id());
+        assert_eq!(attribute(inference, "model").as_deref(), Some("quantized"));
+        assert_eq!(
+            attribute(inference, "generated_tokens").as_deref(),
+            Some("3")
+        );
+        assert!(attribute(inference, "prompt_tokens").is_some());
+        for phase in ["tokenize", "prefill", "decode"] {
+            assert_eq!(
+                named(phase).parent_span_id,
+                inference.span_context.span_id(),
+                "{phase}"
+            );
+        }
+    }
+}
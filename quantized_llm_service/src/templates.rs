@@ -0,0 +1,70 @@
+use crate::{config::AppConfig, error::ServiceError, model::GenerationRequest};
+
+const PROMPT_PLACEHOLDER: &str = "{prompt}";
+const SYSTEM_PLACEHOLDER: &str = "{system}";
+
+/// Fills the configured template `name` with `prompt` and `system` (empty
+/// when absent). Placeholders are substituted in a single pass, so braces in
+/// the user's text are never expanded.
+pub fn render(
+    config: &AppConfig,
+    name: &str,
+    prompt: &str,
+    system: Option<&str>,
+) -> Result<String, ServiceError> {
+    let template = config.prompt_templates.get(name).ok_or_else(|| {
+        let available = if config.prompt_templates.is_empty() {
+            "none are configured".to_string()
+        } else {
+            let names: Vec<&str> = config.prompt_templates.keys().map(String::as_str).collect();
+            format!("available: {}", names.join(", "))
+        };
+        ServiceError::validation(
+            "template",
+            format!("unknown template '{name}'; {available}"),
+        )
+    })?;
+
+    let mut rendered = String::with_capacity(template.len() + prompt.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if let Some(after) = tail.strip_prefix(PROMPT_PLACEHOLDER) {
+            rendered.push_str(prompt);
+            rest = after;
+        } else if let Some(after) = tail.strip_prefix(SYSTEM_PLACEHOLDER) {
+            rendered.push_str(system.unwrap_or_default());
+            rest = after;
+        } else {
+            rendered.push('{');
+            rest = &tail[1..];
+        }
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Replaces the request's prompt with its rendered template, if it names one,
+/// and returns the prompt as the client sent it.
+pub fn apply_template(
+    request: &mut GenerationRequest,
+    config: &AppConfig,
+) -> Result<String, ServiceError> {
+    let raw_prompt = request.prompt.clone();
+    if let Some(name) = request.template.take() {
+        request.prompt = render(config, &name, &raw_prompt, request.system.as_deref())?;
+    }
+    Ok(raw_prompt)
+}
+
+/// Config validation: every template must place the user's prompt.
+pub(crate) fn check_templates(config: &AppConfig, problems: &mut Vec<String>) {
+    for (name, template) in &config.prompt_templates {
+        if !template.contains(PROMPT_PLACEHOLDER) {
+            problems.push(format!(
+                "prompt template '{name}' must contain {PROMPT_PLACEHOLDER}"
+            ));
+        }
+    }
+}
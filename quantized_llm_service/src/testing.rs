@@ -0,0 +1,129 @@
+//! Drives the router in-process, over fake models, for the handler and
+//! middleware tests.
+
+use std::{io, sync::Arc};
+
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    http::{HeaderMap, Request, StatusCode, header},
+};
+use parking_lot::Mutex;
+use serde_json::Value;
+use tower::ServiceExt;
+use tracing::{Dispatch, Level};
+use tracing_subscriber::fmt::format::FmtSpan;
+
+use crate::{
+    config::AppConfig,
+    model::testing::{fake_config, fake_registry},
+    server::build_router,
+};
+
+/// The router over fake models for both slots, configured by `configure`.
+pub(crate) fn router(name: &str, configure: impl FnOnce(&mut AppConfig)) -> Router {
+    let mut config = fake_config(name, true);
+    configure(&mut config);
+    let registry = fake_registry(&config);
+    build_router(Arc::new(config), Arc::new(registry), None, None)
+}
+
+pub(crate) fn get(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+pub(crate) fn post_json(uri: &str, body: Value) -> Request<Body> {
+    Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub(crate) struct Reply {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Reply {
+    /// The body as JSON; panics, showing the body, when it isn't.
+    pub(crate) fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|err| {
+            panic!(
+                "{} body is not JSON ({err}): {}",
+                self.status,
+                String::from_utf8_lossy(&self.body)
+            )
+        })
+    }
+
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub(crate) fn header(&self, name: impl header::AsHeaderName) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    /// The structured error's `code`, asserting the body has that shape.
+    pub(crate) fn error_code(&self) -> String {
+        let body = self.json();
+        match (body["error"]["code"].as_str(), &body["error"]["message"]) {
+            (Some(code), Value::String(_)) => code.to_string(),
+            _ => panic!("{} is not a structured error: {body}", self.status),
+        }
+    }
+}
+
+/// Sends `request` through a clone of `router` and reads the whole reply.
+pub(crate) async fn send(router: &Router, request: Request<Body>) -> Reply {
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .to_vec();
+    Reply {
+        status,
+        headers,
+        body,
+    }
+}
+
+/// Everything logged through [`CapturedLogs::dispatch`], at every level and
+/// with a line for each closed span, as JSON lines.
+#[derive(Clone, Default)]
+pub(crate) struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    pub(crate) fn dispatch(&self) -> Dispatch {
+        let logs = self.clone();
+        Dispatch::new(
+            tracing_subscriber::fmt()
+                .json()
+                .with_max_level(Level::TRACE)
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(move || logs.clone())
+                .finish(),
+        )
+    }
+
+    pub(crate) fn lines(&self) -> Vec<Value> {
+        String::from_utf8_lossy(&self.0.lock())
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use std::{fs, io::BufReader, path::Path, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{ServerConfig, crypto::ring};
+
+/// Reads a PEM certificate chain and private key (PKCS#8, PKCS#1 or SEC1)
+/// and checks that the key belongs to the leaf certificate.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<ServerConfig> {
+    let cert_pem = fs::read(cert_path)
+        .with_context(|| format!("failed to read TLS certificate {}", cert_path.display()))?;
+    let key_pem = fs::read(key_path)
+        .with_context(|| format!("failed to read TLS key {}", key_path.display()))?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_pem.as_slice()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid PEM in {}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", cert_path.display());
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_pem.as_slice()))
+        .with_context(|| format!("invalid PEM in {}", key_path.display()))?
+        .with_context(|| format!("no private key found in {}", key_path.display()))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .with_context(|| {
+            format!(
+                "TLS key {} does not match certificate {}",
+                key_path.display(),
+                cert_path.display()
+            )
+        })?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Swaps in the files' current contents every `every`, so renewed
+/// certificates are picked up without a restart. A failed reload keeps the
+/// previous certificate.
+pub async fn reload_periodically(
+    tls: RustlsConfig,
+    cert_path: &Path,
+    key_path: &Path,
+    every: Duration,
+) {
+    let mut interval = tokio::time::interval(every);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match load_server_config(cert_path, key_path) {
+            Ok(config) => {
+                tls.reload_from_config(Arc::new(config));
+                tracing::debug!("reloaded TLS certificate");
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "TLS reload failed, keeping current certificate")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpStream},
+        path::PathBuf,
+    };
+
+    use rustls::{
+        ClientConfig, ClientConnection, RootCertStore, StreamOwned, pki_types::ServerName,
+    };
+
+    use super::*;
+    use crate::testing::router;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    /// Trusts only the self-signed fixture certificate.
+    fn client_config() -> ClientConfig {
+        let pem = fs::read(fixture("localhost.crt")).unwrap();
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(pem.as_slice())) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    fn get_over_https(addr: SocketAddr, path: &str) -> String {
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(Arc::new(client_config()), name).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        // The server may close without a TLS close_notify once it has
+        // answered; what arrived before that is the whole response.
+        let _ = stream.read_to_string(&mut response);
+        response
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serves_health_over_https() {
+        let config =
+            load_server_config(&fixture("localhost.crt"), &fixture("localhost.key")).unwrap();
+        let router = router("tls", |_| {});
+        let handle = axum_server::Handle::new();
+        let server = axum_server::bind_rustls(
+            "127.0.0.1:0".parse().unwrap(),
+            RustlsConfig::from_config(Arc::new(config)),
+        )
+        .handle(handle.clone())
+        .serve(router.into_make_service());
+        let server = tokio::spawn(server);
+        let addr = handle.listening().await.expect("server listens");
+
+        let response = tokio::task::spawn_blocking(move || get_over_https(addr, "/health/live"))
+            .await
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains("\r\n\r\n2\r\nok\r\n"), "{response}");
+
+        handle.shutdown();
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_key_for_another_certificate_is_refused() {
+        let err = load_server_config(&fixture("localhost.crt"), &fixture("other.key"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("does not match"), "{err}");
+    }
+
+    #[test]
+    fn unreadable_files_are_named() {
+        let missing = fixture("missing.crt");
+        let err = load_server_config(&missing, &fixture("localhost.key"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(&missing.display().to_string()), "{err}");
+    }
+}
@@ -0,0 +1,101 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::config::AppConfig;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("BUILD_GIT_COMMIT");
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+pub const TCH_VERSION: &str = env!("BUILD_TCH_VERSION");
+
+/// What is running: build provenance baked in at compile time plus the
+/// model the service was configured with.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ServiceInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_timestamp: &'static str,
+    pub tch_version: &'static str,
+    pub tch_backend: bool,
+    /// Effective LibTorch thread pool sizes, absent without the tch backend.
+    pub torch_num_threads: Option<i32>,
+    pub torch_num_interop_threads: Option<i32>,
+    pub model_id: String,
+    pub revision: Option<String>,
+}
+
+impl ServiceInfo {
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            version: VERSION,
+            git_commit: GIT_COMMIT,
+            build_timestamp: BUILD_TIMESTAMP,
+            tch_version: TCH_VERSION,
+            tch_backend: cfg!(feature = "tch-backend"),
+            #[cfg(feature = "tch-backend")]
+            torch_num_threads: Some(tch::get_num_threads()),
+            #[cfg(feature = "tch-backend")]
+            torch_num_interop_threads: Some(tch::get_num_interop_threads()),
+            #[cfg(not(feature = "tch-backend"))]
+            torch_num_threads: None,
+            #[cfg(not(feature = "tch-backend"))]
+            torch_num_interop_threads: None,
+            model_id: config.model_id.clone(),
+            revision: config.revision.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::testing::{get, router, send};
+
+    /// `MAJOR.MINOR.PATCH`, optionally followed by `-pre` or `+build`.
+    fn is_semver(version: &str) -> bool {
+        let core = version.split(['-', '+']).next().unwrap_or_default();
+        let parts: Vec<&str> = core.split('.').collect();
+        parts.len() == 3
+            && parts.iter().all(|part| {
+                !part.is_empty()
+                    && part.chars().all(|c| c.is_ascii_digit())
+                    && (*part == "0" || !part.starts_with('0'))
+            })
+    }
+
+    #[tokio::test]
+    async fn version_reports_a_semver_and_the_configured_model() {
+        let router = router("version", |config| {
+            config.model_id = "acme/gpt2-int8".to_string();
+            config.revision = Some("main".to_string());
+        });
+        let reply = send(&router, get("/version")).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let body = reply.json();
+        let version = body["version"].as_str().unwrap();
+        assert!(is_semver(version), "{version}");
+        assert_eq!(version, VERSION);
+        assert_eq!(body["git_commit"], GIT_COMMIT);
+        assert_eq!(body["build_timestamp"], BUILD_TIMESTAMP);
+        assert_eq!(body["tch_backend"], cfg!(feature = "tch-backend"));
+        assert_eq!(body["model_id"], "acme/gpt2-int8");
+        assert_eq!(body["revision"], "main");
+
+        let metadata = send(&router, get("/metadata")).await.json();
+        assert_eq!(metadata["service"], body);
+    }
+
+    #[test]
+    fn the_build_timestamp_is_rfc3339_utc() {
+        let bytes = BUILD_TIMESTAMP.as_bytes();
+        assert_eq!(bytes.len(), 20, "{BUILD_TIMESTAMP}");
+        assert_eq!(
+            [
+                bytes[4], bytes[7], bytes[10], bytes[13], bytes[16], bytes[19]
+            ],
+            *b"--T::Z"
+        );
+    }
+}
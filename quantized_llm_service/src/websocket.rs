@@ -0,0 +1,431 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use axum::{
+    Extension,
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::Response,
+};
+use tokio::{
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+use crate::{
+    auth::ApiKeyLabel,
+    error::ServiceError,
+    middleware::REQUEST_ID_HEADER,
+    model::{ClientFrame, GenerationRequest, HistoryTrim, ModelRegistry, ServerFrame},
+    server::AppState,
+};
+
+/// Earlier turns of one connection, each a prompt and its completion, that
+/// `keep_history` prepends to the next prompt.
+#[derive(Default)]
+struct History {
+    turns: VecDeque<Turn>,
+}
+
+struct Turn {
+    text: String,
+    tokens: usize,
+}
+
+impl History {
+    fn push(&mut self, registry: &ModelRegistry, text: String) {
+        // Bytes never undercount tokens, should the text fail to encode.
+        let tokens = registry
+            .tokenize(&text, false)
+            .map_or(text.len(), |(ids, _)| ids.len());
+        self.turns.push_back(Turn { text, tokens });
+    }
+
+    /// Drops whole turns, oldest first, until the rest plus `prompt_tokens`
+    /// fit in `budget`.
+    fn fit(&mut self, prompt_tokens: usize, budget: usize) -> Option<HistoryTrim> {
+        let mut kept: usize = self.turns.iter().map(|turn| turn.tokens).sum();
+        let mut trim = HistoryTrim {
+            turns: 0,
+            tokens: 0,
+        };
+        while kept + prompt_tokens > budget
+            && let Some(turn) = self.turns.pop_front()
+        {
+            kept -= turn.tokens;
+            trim.turns += 1;
+            trim.tokens += turn.tokens;
+        }
+        (trim.turns > 0).then_some(trim)
+    }
+
+    fn prepend_to(&self, prompt: &str) -> String {
+        let mut text: String = self.turns.iter().map(|turn| turn.text.as_str()).collect();
+        text.push_str(prompt);
+        text
+    }
+}
+
+/// Client frames are `{"type": "generate", ...}` or `{"type": "cancel"}`;
+/// the server answers with `token` frames and one `done`, `cancelled` or
+/// `error` frame per generation.
+#[utoipa::path(
+    get,
+    path = "/ws/generate",
+    tag = "generation",
+    responses((status = 101, description = "Switched to a WebSocket carrying generation frames"))
+)]
+pub async fn ws_generate(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    api_key: Option<Extension<ApiKeyLabel>>,
+    headers: HeaderMap,
+) -> Response {
+    let request_id = headers
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let api_key = api_key.map(|Extension(ApiKeyLabel(label))| label);
+    ws.on_upgrade(move |socket| session(socket, state, request_id, api_key))
+}
+
+async fn session(
+    mut socket: WebSocket,
+    state: AppState,
+    request_id: Option<String>,
+    api_key: Option<String>,
+) {
+    let mut history = History::default();
+
+    while let Some(Ok(message)) = socket.recv().await {
+        let frame = match parse_frame(message) {
+            Some(Ok(frame)) => frame,
+            Some(Err(err)) => {
+                if send(&mut socket, error_frame(&err)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            None => continue,
+        };
+
+        let ClientFrame::Generate {
+            prompt,
+            params,
+            keep_history,
+        } = frame
+        else {
+            // Nothing is running, so there is nothing to cancel.
+            continue;
+        };
+
+        let (full_prompt, history_trimmed) = if keep_history {
+            let max_new_tokens = params.max_new_tokens.unwrap_or(state.config.max_new_tokens);
+            let add_special_tokens = params.add_special_tokens.unwrap_or(true);
+            let prompt_tokens = match state.registry.tokenize(&prompt, add_special_tokens) {
+                Ok((ids, _)) => ids.len(),
+                Err(err) => {
+                    if send(&mut socket, error_frame(&err)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let budget = state.registry.prompt_budget(max_new_tokens);
+            let trimmed = history.fit(prompt_tokens, budget);
+            (history.prepend_to(&prompt), trimmed)
+        } else {
+            (prompt.clone(), None)
+        };
+        let request = GenerationRequest {
+            prompt: full_prompt,
+            model: None,
+            max_new_tokens: params.max_new_tokens,
+            temperature: params.temperature,
+            top_k: params.top_k,
+            context_strategy: params.context_strategy,
+            truncate_prompt: params.truncate_prompt,
+            seed: params.seed,
+            priority: None,
+            template: None,
+            system: None,
+            preset: None,
+            add_special_tokens: params.add_special_tokens,
+            skip_special_tokens: params.skip_special_tokens,
+            return_token_details: params.return_token_details,
+            on_timeout: params.on_timeout,
+            response_format: params.response_format,
+            api_key: api_key.clone(),
+        };
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (tokens_tx, mut tokens_rx) = mpsc::unbounded_channel();
+        let registry = state.registry.clone();
+        let config = state.config.clone();
+        let stream_cancel = cancel.clone();
+        let mut generation = tokio::spawn(async move {
+            registry
+                .generate_stream(request, &config, tokens_tx, stream_cancel)
+                .await
+        });
+
+        // Pings while nothing else is sent, e.g. during a long prefill, so
+        // proxies don't take the connection for idle.
+        let keepalive = state.config.keepalive_interval();
+        let idle = time::sleep(keepalive.unwrap_or_default());
+        tokio::pin!(idle);
+        let result = loop {
+            tokio::select! {
+                Some(text) = tokens_rx.recv() => {
+                    if send(&mut socket, ServerFrame::Token { text }).await.is_err() {
+                        cancel.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    if let Some(interval) = keepalive {
+                        idle.as_mut().reset(Instant::now() + interval);
+                    }
+                }
+                () = &mut idle, if keepalive.is_some() => {
+                    if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                        cancel.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    if let Some(interval) = keepalive {
+                        idle.as_mut().reset(Instant::now() + interval);
+                    }
+                }
+                joined = &mut generation => {
+                    break joined.map_err(|err| {
+                        ServiceError::Inference(format!("inference task failed: {err}"))
+                    }).and_then(|result| result);
+                }
+                incoming = socket.recv() => match incoming {
+                    Some(Ok(message)) => match parse_frame(message) {
+                        Some(Ok(ClientFrame::Cancel)) => cancel.store(true, Ordering::Relaxed),
+                        Some(rejected) => {
+                            let err = rejected.err().unwrap_or_else(|| {
+                                ServiceError::BadRequest(
+                                    "a generation is already running on this connection".into(),
+                                )
+                            });
+                            if send(&mut socket, error_frame(&err)).await.is_err() {
+                                cancel.store(true, Ordering::Relaxed);
+                                return;
+                            }
+                        }
+                        None => {}
+                    },
+                    _ => {
+                        cancel.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                },
+            }
+        };
+
+        // Tokens produced right before the task finished may still be queued.
+        while let Ok(text) = tokens_rx.try_recv() {
+            if send(&mut socket, ServerFrame::Token { text })
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        let frame = match result {
+            Ok(response) => {
+                if let Some(audit) = &state.audit {
+                    audit.record(request_id.as_deref(), &response);
+                }
+                if keep_history {
+                    history.push(&state.registry, format!("{prompt}{}", response.completion));
+                }
+                if cancel.load(Ordering::Relaxed) {
+                    ServerFrame::Cancelled {
+                        response,
+                        history_trimmed,
+                    }
+                } else {
+                    ServerFrame::Done {
+                        response,
+                        history_trimmed,
+                    }
+                }
+            }
+            Err(err) => error_frame(&err),
+        };
+        if send(&mut socket, frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Returns `None` for frames that carry no request (pings, binary, close).
+fn parse_frame(message: Message) -> Option<Result<ClientFrame, ServiceError>> {
+    match message {
+        Message::Text(text) => Some(
+            serde_json::from_str(&text)
+                .map_err(|e| ServiceError::BadRequest(format!("invalid frame: {e}"))),
+        ),
+        _ => None,
+    }
+}
+
+fn error_frame(err: &ServiceError) -> ServerFrame {
+    ServerFrame::Error {
+        error: err.to_body().error,
+    }
+}
+
+async fn send(socket: &mut WebSocket, frame: ServerFrame) -> Result<(), axum::Error> {
+    let payload = serde_json::to_string(&frame).expect("server frames always serialize");
+    socket.send(Message::Text(payload)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+    use crate::testing::router;
+
+    /// Runs one generation on a slow model over a real connection, with
+    /// pings every `keepalive`, and returns what came before the first
+    /// token: how many pings, and whether the token came at all.
+    async fn pings_before_first_token(name: &str, keepalive: Duration) -> (usize, bool) {
+        let router = router(name, |config| {
+            // Each step of the fake models sleeps 100 ms.
+            std::fs::write(&config.quantized_module_path, "100").unwrap();
+            config.stream_keepalive = keepalive;
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws/generate"))
+            .await
+            .unwrap();
+        let frame = serde_json::json!({
+            "type": "generate",
+            "prompt": "Hello",
+            "params": {"max_new_tokens": 1},
+        });
+        socket
+            .send(tungstenite::Message::Text(frame.to_string()))
+            .await
+            .unwrap();
+
+        let mut pings = 0;
+        while let Some(message) = socket.next().await {
+            match message.unwrap() {
+                tungstenite::Message::Ping(_) => pings += 1,
+                tungstenite::Message::Text(text) => {
+                    let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    return (pings, frame["type"] == "token");
+                }
+                _ => {}
+            }
+        }
+        (pings, false)
+    }
+
+    #[tokio::test]
+    async fn slow_prefills_are_kept_alive_with_pings() {
+        let (pings, token) =
+            pings_before_first_token("ws-keepalive", Duration::from_millis(20)).await;
+        assert!(token);
+        assert!(pings >= 1, "{pings} pings");
+
+        let (pings, token) = pings_before_first_token("ws-keepalive-off", Duration::ZERO).await;
+        assert!(token);
+        assert_eq!(pings, 0);
+    }
+
+    /// Ten turns of a long conversation, the nth costing n + 1 tokens.
+    fn conversation() -> History {
+        History {
+            turns: (0..10)
+                .map(|n| Turn {
+                    text: format!("[turn {n}]"),
+                    tokens: n + 1,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn history_that_fits_is_kept() {
+        let mut history = conversation();
+        // The turns cost 55 tokens.
+        assert_eq!(history.fit(5, 60), None);
+        assert_eq!(history.turns.len(), 10);
+    }
+
+    #[test]
+    fn oldest_turns_go_first() {
+        let mut history = conversation();
+        // 55 + 10 must come down to 45: dropping turns 0 to 4 frees 15,
+        // which is not enough, and turn 5 brings it to 21.
+        assert_eq!(
+            history.fit(10, 45),
+            Some(HistoryTrim {
+                turns: 6,
+                tokens: 21
+            })
+        );
+        assert_eq!(
+            history.prepend_to("now"),
+            "[turn 6][turn 7][turn 8][turn 9]now"
+        );
+    }
+
+    #[test]
+    fn turns_are_dropped_whole() {
+        let mut history = conversation();
+        // One token over: the oldest turn goes entirely rather than in part.
+        assert_eq!(
+            history.fit(0, 54),
+            Some(HistoryTrim {
+                turns: 1,
+                tokens: 1
+            })
+        );
+        let mut history = conversation();
+        // Two over: turn 0 frees one token, so turn 1 goes too.
+        assert_eq!(
+            history.fit(0, 53),
+            Some(HistoryTrim {
+                turns: 2,
+                tokens: 3
+            })
+        );
+        assert!(history.prepend_to("").starts_with("[turn 2]"));
+    }
+
+    #[test]
+    fn prompt_over_budget_empties_the_history() {
+        let mut history = conversation();
+        assert_eq!(
+            history.fit(100, 50),
+            Some(HistoryTrim {
+                turns: 10,
+                tokens: 55
+            })
+        );
+        assert_eq!(history.prepend_to("now"), "now");
+        // Later turns fit against an empty history.
+        assert_eq!(history.fit(100, 50), None);
+    }
+}